@@ -0,0 +1,216 @@
+//! Requests per second and p99 latency for each [`Engine`] on loopback.
+//!
+//! Run with `cargo bench`. `BENCH_SECS`, `BENCH_WORKERS`, `BENCH_CLIENTS` and
+//! `BENCH_IDLE` change how long each run lasts, the server's workers, the
+//! busy connections and the idle ones held open beside them.
+
+use std::{
+    env,
+    net::SocketAddr,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use host::{Engine, KeepAlive, Params, Request, Response, Server};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    runtime::Runtime,
+    time,
+};
+
+const REQUEST: &[u8] = b"GET /hello HTTP/1.1\r\nHost: bench\r\n\r\n";
+
+fn setting<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+struct Run {
+    requests: usize,
+    /// Idle connections the server let in.
+    opened: usize,
+    /// Busy connections that got at least one answer.
+    answered: usize,
+    clients: usize,
+    elapsed: Duration,
+    /// Latencies of the answered requests, sorted.
+    latencies: Vec<Duration>,
+}
+
+impl Run {
+    fn per_second(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn p99(&self) -> Option<Duration> {
+        let at = (self.latencies.len() * 99 / 100).min(self.latencies.len().checked_sub(1)?);
+        Some(self.latencies[at])
+    }
+}
+
+fn main() {
+    let secs = setting("BENCH_SECS", 5);
+    let workers = setting("BENCH_WORKERS", 4);
+    let clients = setting("BENCH_CLIENTS", 32);
+    let idle = setting("BENCH_IDLE", 1000);
+    let runtime = Runtime::new().unwrap();
+
+    println!(
+        "{secs}s per run, {workers} workers, {clients} busy connections, \
+         {idle} idle ones in the second scenario\n"
+    );
+    println!(
+        "{:<10} {:>10} {:>12} {:>12} {:>10}",
+        "engine", "idle open", "requests/s", "p99", "answered"
+    );
+    for idle in [0, idle] {
+        for engine in [Engine::Threads, Engine::Async] {
+            let run = bench(&runtime, engine, workers, clients, idle, secs);
+            let p99 = run
+                .p99()
+                .map_or("-".to_string(), |p99| format!("{:.2?}", p99));
+            println!(
+                "{:<10} {:>10} {:>12.0} {:>12} {:>10}",
+                engine.to_string(),
+                run.opened,
+                run.per_second(),
+                p99,
+                format!("{}/{}", run.answered, run.clients)
+            );
+        }
+    }
+}
+
+fn bench(
+    runtime: &Runtime,
+    engine: Engine,
+    workers: usize,
+    clients: usize,
+    idle: usize,
+    secs: u64,
+) -> Run {
+    let handler = |_: &Request, _: &Params| Response::html("<p>Hello, world!</p>\n");
+    // like browsers, idle clients keep their connections for as long as they are let
+    let server = Server::bind("127.0.0.1:0", workers, handler)
+        .unwrap()
+        .engine(engine)
+        .max_connections(idle + clients + 64)
+        .keep_alive(KeepAlive {
+            idle_timeout: Duration::from_secs(60),
+            max_requests: usize::MAX,
+            ..KeepAlive::default()
+        });
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let server = thread::spawn(move || server.run());
+
+    let (run, parked) = runtime.block_on(async {
+        // each idle connection makes one request, then says nothing more;
+        // a server that is full turns the rest away
+        let mut parked = Vec::new();
+        for _ in 0..idle {
+            let connect = time::timeout(Duration::from_secs(1), TcpStream::connect(addr));
+            let Ok(Ok(mut stream)) = connect.await else {
+                break;
+            };
+            stream.write_all(REQUEST).await.unwrap();
+            parked.push(stream);
+        }
+        time::sleep(Duration::from_millis(200)).await;
+        let mut head = [0; 12];
+        let opened = parked
+            .iter()
+            .filter(
+                |stream| !matches!(stream.try_read(&mut head), Ok(12) if &head == b"HTTP/1.1 503"),
+            )
+            .count();
+
+        let started = Instant::now();
+        let deadline = time::Instant::now() + Duration::from_secs(secs);
+        let tasks: Vec<_> = (0..clients)
+            .map(|_| tokio::spawn(client(addr, deadline)))
+            .collect();
+        let mut latencies = Vec::new();
+        let mut answered = 0;
+        for task in tasks {
+            let (times, stream) = task.await.unwrap();
+            answered += usize::from(!times.is_empty());
+            latencies.extend(times);
+            parked.extend(stream);
+        }
+        let elapsed = started.elapsed();
+        // half-closed, a connection can still be answered but is not idle
+        // any more: a server thread waiting on it sees the end of it
+        for stream in &mut parked {
+            let _ = stream.shutdown().await;
+        }
+        latencies.sort();
+        let run = Run {
+            requests: latencies.len(),
+            opened,
+            answered,
+            clients,
+            elapsed,
+            latencies,
+        };
+        (run, parked)
+    });
+
+    // every connection stays open until the server is done with it, so
+    // requests left waiting are not answered to a closed socket
+    shutdown.trigger();
+    server.join().unwrap();
+    drop(parked);
+    run
+}
+
+/// Make requests one after another on one connection until `deadline`,
+/// returning how long each answered one took, and the connection.
+async fn client(addr: SocketAddr, deadline: time::Instant) -> (Vec<Duration>, Option<TcpStream>) {
+    let mut latencies = Vec::new();
+    let Ok(Ok(stream)) = time::timeout_at(deadline, TcpStream::connect(addr)).await else {
+        return (latencies, None);
+    };
+    let mut stream = BufReader::new(stream);
+    loop {
+        let started = Instant::now();
+        match time::timeout_at(deadline, exchange(&mut stream)).await {
+            Ok(Ok(())) => latencies.push(started.elapsed()),
+            // turned away by a full server
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                return (latencies, None);
+            }
+            Ok(Err(e)) => panic!("request failed: {e}"),
+            // unanswered by the end of the run
+            Err(_) => return (latencies, Some(stream.into_inner())),
+        }
+    }
+}
+
+async fn exchange(stream: &mut BufReader<TcpStream>) -> std::io::Result<()> {
+    stream.get_mut().write_all(REQUEST).await?;
+    let mut length = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if line == "\r\n" {
+            break;
+        }
+        if line.starts_with("HTTP/1.1 503") {
+            return Err(std::io::ErrorKind::ConnectionRefused.into());
+        }
+        if let Some(n) = line.strip_prefix("Content-Length: ") {
+            length = n.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+    Ok(())
+}
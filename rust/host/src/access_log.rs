@@ -0,0 +1,308 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::date::{DateTime, rfc3339};
+use crate::escape;
+use crate::response::Status;
+
+/// Line format of the access log.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Apache/nginx "combined", with the handling time in microseconds
+    /// appended as a final field (Apache's `%D`).
+    #[default]
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format {s:?} (expected combined or json)"
+            )),
+        }
+    }
+}
+
+/// One served request, as recorded in the access log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub remote: Option<SocketAddr>,
+    /// When the request started arriving.
+    pub time: SystemTime,
+    /// `GET /path HTTP/1.1`, or `None` when the request could not be parsed.
+    pub request_line: Option<String>,
+    pub status: Status,
+    /// Body bytes sent, excluding headers and chunk framing.
+    pub bytes: u64,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub duration: Duration,
+}
+
+impl LogEntry {
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Combined => self.combined(),
+            LogFormat::Json => self.json(),
+        }
+    }
+
+    fn combined(&self) -> String {
+        let t = DateTime::from_system_time(self.time);
+        let quoted = |value: Option<&str>| match value {
+            Some(value) => format!("\"{}\"", clf_escape(value)),
+            None => "\"-\"".to_string(),
+        };
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            n => n.to_string(),
+        };
+        format!(
+            "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] {} {} {bytes} {} {} {}",
+            self.remote
+                .map_or("-".to_string(), |addr| addr.ip().to_string()),
+            t.day,
+            t.month_name(),
+            t.year,
+            t.hour,
+            t.minute,
+            t.second,
+            quoted(self.request_line.as_deref()),
+            self.status.code(),
+            quoted(self.referer.as_deref()),
+            quoted(self.user_agent.as_deref()),
+            self.duration.as_micros(),
+        )
+    }
+
+    fn json(&self) -> String {
+        let string = |value: Option<&str>| value.map_or("null".to_string(), escape::json);
+        format!(
+            r#"{{"time":"{}","remote":{},"request":{},"status":{},"bytes":{},"referer":{},"user_agent":{},"duration_us":{}}}"#,
+            rfc3339(self.time),
+            string(self.remote.map(|addr| addr.ip().to_string()).as_deref()),
+            string(self.request_line.as_deref()),
+            self.status.code(),
+            self.bytes,
+            string(self.referer.as_deref()),
+            string(self.user_agent.as_deref()),
+            self.duration.as_micros(),
+        )
+    }
+}
+
+/// Escape quotes, backslashes and anything unprintable as Apache does, so a
+/// crafted header cannot forge a log line.
+fn clf_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    let _ = write!(out, "\\x{b:02x}");
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Writes one line per request to stdout or to a file that rotates by size.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: LogFormat,
+    sink: Mutex<Sink>,
+}
+
+#[derive(Debug)]
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl AccessLog {
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            sink: Mutex::new(Sink::Stdout),
+        }
+    }
+
+    /// Append to `path`. Once it would grow past `max_bytes` it is renamed to
+    /// `path.1` (shifting older files up to `path.<keep>`) and a new one started.
+    pub fn file(
+        path: impl Into<PathBuf>,
+        format: LogFormat,
+        max_bytes: u64,
+        keep: usize,
+    ) -> io::Result<AccessLog> {
+        Ok(AccessLog {
+            format,
+            sink: Mutex::new(Sink::File(RotatingFile::open(
+                path.into(),
+                max_bytes,
+                keep,
+            )?)),
+        })
+    }
+
+    /// Record `entry`. Failures are reported on stderr rather than failing the request.
+    pub fn log(&self, entry: &LogEntry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match &mut *sink {
+            Sink::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Sink::File(file) => file.write_line(line.as_bytes()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to write access log: {e}");
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            written,
+            max_bytes,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        // a file is never left empty, however long the line
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = numbered(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, numbered(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// `access.log` -> `access.log.3`.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> LogEntry {
+        LogEntry {
+            remote: Some("127.0.0.1:52100".parse().unwrap()),
+            time: UNIX_EPOCH + Duration::from_secs(784_111_777),
+            request_line: Some("GET /apache_pb.gif HTTP/1.0".to_string()),
+            status: Status::OK,
+            bytes: 2326,
+            referer: Some("http://www.example.com/start.html".to_string()),
+            user_agent: Some("Mozilla/4.08 \"quoted\"\n".to_string()),
+            duration: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn formats_combined() {
+        assert_eq!(
+            entry().format(LogFormat::Combined),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /apache_pb.gif HTTP/1.0\" 200 2326 \
+             \"http://www.example.com/start.html\" \"Mozilla/4.08 \\\"quoted\\\"\\x0a\" 1500"
+        );
+
+        let bare = LogEntry {
+            remote: None,
+            request_line: None,
+            status: Status::BAD_REQUEST,
+            bytes: 0,
+            referer: None,
+            user_agent: None,
+            ..entry()
+        };
+        assert_eq!(
+            bare.format(LogFormat::Combined),
+            "- - - [06/Nov/1994:08:49:37 +0000] \"-\" 400 - \"-\" \"-\" 1500"
+        );
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        assert_eq!(
+            entry().format(LogFormat::Json),
+            r#"{"time":"1994-11-06T08:49:37Z","remote":"127.0.0.1","request":"GET /apache_pb.gif HTTP/1.0","status":200,"bytes":2326,"referer":"http://www.example.com/start.html","user_agent":"Mozilla/4.08 \"quoted\"\n","duration_us":1500}"#
+        );
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let line_len = entry().format(LogFormat::Json).len() as u64 + 1;
+        let log = AccessLog::file(&path, LogFormat::Json, line_len * 2, 2).unwrap();
+
+        for _ in 0..7 {
+            log.log(&entry());
+        }
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&numbered(&path, 1)), 2);
+        assert_eq!(lines(&numbered(&path, 2)), 2);
+        assert!(!numbered(&path, 3).exists());
+    }
+}
@@ -0,0 +1,479 @@
+//! HTTP authentication (RFC 7617 Basic and RFC 6750 bearer tokens) for parts
+//! of a site.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use sha1::{Digest, Sha1};
+use subtle::ConstantTimeEq;
+
+use crate::handler::{Handler, Params};
+use crate::request::Request;
+use crate::response::{Response, Status};
+use crate::url::percent_decode;
+
+/// Realm named in challenges when a rule does not set one.
+pub const DEFAULT_REALM: &str = "host";
+
+/// Users and password hashes from an htpasswd-style file.
+///
+/// Each line is `user:hash`, where the hash is bcrypt (`$2y$…`, as written
+/// by `htpasswd -B`) or argon2 (`$argon2id$…`). Blank lines and lines
+/// starting with `#` are skipped.
+#[derive(Debug, Default)]
+pub struct Htpasswd {
+    users: HashMap<String, Hash>,
+}
+
+#[derive(Debug)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Result<Hash, String> {
+        if hash.starts_with("$2") {
+            let parts: Result<bcrypt::HashParts, _> = hash.parse();
+            parts.map_err(|e| format!("invalid bcrypt hash ({e})"))?;
+            Ok(Hash::Bcrypt(hash.to_string()))
+        } else if hash.starts_with("$argon2") {
+            PasswordHash::new(hash).map_err(|e| format!("invalid argon2 hash ({e})"))?;
+            Ok(Hash::Argon2(hash.to_string()))
+        } else {
+            Err("unsupported hash; use bcrypt (htpasswd -B) or argon2".to_string())
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+impl Htpasswd {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Htpasswd> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        Htpasswd::parse(&text)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{}: {e}", path.display())))
+    }
+
+    pub fn parse(text: &str) -> Result<Htpasswd, String> {
+        let mut users = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at = |e: String| format!("line {}: {e}", i + 1);
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| at("expected user:hash".to_string()))?;
+            let hash = Hash::parse(hash).map_err(|e| at(format!("user {user:?}: {e}")))?;
+            if users.insert(user.to_string(), hash).is_some() {
+                return Err(at(format!("user {user:?} is listed twice")));
+            }
+        }
+        Ok(Htpasswd { users })
+    }
+
+    /// Whether `password` is `user`'s.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(hash) => hash.verify(password),
+            None => {
+                // as slow as a wrong password, so timing does not tell which users exist
+                if let Some(hash) = self.users.values().next() {
+                    hash.verify(password);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Who may see the paths below one prefix.
+///
+/// Requests need a password from the htpasswd file, or one of the bearer
+/// tokens. A rule with neither turns everyone away.
+pub struct Rule {
+    /// Decoded segments, so `/inputs` is `["inputs"]`.
+    prefix: Vec<String>,
+    realm: String,
+    users: Option<Htpasswd>,
+    /// Only these users, not everyone in the file.
+    allow: Option<Vec<String>>,
+    /// SHA-1 of each token, so comparisons are all the same length.
+    tokens: Vec<Vec<u8>>,
+}
+
+impl Rule {
+    /// Protect `prefix` and every path below it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `prefix` does not start with `/`.
+    pub fn new(prefix: &str) -> Rule {
+        assert!(
+            prefix.starts_with('/'),
+            "prefix {prefix:?} must start with '/'"
+        );
+        Rule {
+            prefix: segments(prefix).expect("invalid prefix"),
+            realm: DEFAULT_REALM.to_string(),
+            users: None,
+            allow: None,
+            tokens: Vec::new(),
+        }
+    }
+
+    /// Name shown in the browser's login prompt.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `realm` has a quote, backslash or control character.
+    pub fn realm(mut self, realm: &str) -> Rule {
+        assert!(
+            !realm
+                .chars()
+                .any(|c| c == '"' || c == '\\' || c.is_control()),
+            "realm {realm:?} cannot be quoted"
+        );
+        self.realm = realm.to_string();
+        self
+    }
+
+    /// Accept Basic credentials from `users`.
+    pub fn htpasswd(mut self, users: Htpasswd) -> Rule {
+        self.users = Some(users);
+        self
+    }
+
+    /// Accept only these users from the htpasswd file; others get 403.
+    pub fn allow(mut self, users: Vec<String>) -> Rule {
+        self.allow = Some(users);
+        self
+    }
+
+    /// Accept `Authorization: Bearer <token>`.
+    pub fn token(mut self, token: &str) -> Rule {
+        self.tokens.push(Sha1::digest(token.as_bytes()).to_vec());
+        self
+    }
+
+    fn matches(&self, path: &[String]) -> bool {
+        path.starts_with(&self.prefix)
+    }
+
+    fn check(&self, request: &Request) -> Result<(), Response> {
+        let Some(credentials) = request.header("Authorization") else {
+            return Err(self.challenge(None));
+        };
+        let credentials = credentials.trim();
+        let (scheme, value) = credentials.split_once(' ').unwrap_or((credentials, ""));
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("Bearer") && !self.tokens.is_empty() {
+            let digest = Sha1::digest(value.as_bytes());
+            // every token is compared, so timing does not tell which one was close
+            let valid = self
+                .tokens
+                .iter()
+                .fold(subtle::Choice::from(0), |valid, token| {
+                    valid | token.as_slice().ct_eq(digest.as_slice())
+                });
+            if bool::from(valid) {
+                return Ok(());
+            }
+            return Err(self.challenge(Some("invalid_token")));
+        }
+        if scheme.eq_ignore_ascii_case("Basic")
+            && let Some(users) = &self.users
+            && let Some((user, password)) = basic_credentials(value)
+            && users.verify(&user, &password)
+        {
+            if self
+                .allow
+                .as_ref()
+                .is_none_or(|allow| allow.contains(&user))
+            {
+                return Ok(());
+            }
+            return Err(Response::error(Status::FORBIDDEN));
+        }
+        Err(self.challenge(None))
+    }
+
+    /// 401 naming the schemes this rule accepts.
+    fn challenge(&self, bearer_error: Option<&str>) -> Response {
+        let mut response = Response::error(Status::UNAUTHORIZED);
+        let realm = &self.realm;
+        if self.users.is_some() {
+            response.headers.add(
+                "WWW-Authenticate",
+                format!(r#"Basic realm="{realm}", charset="UTF-8""#),
+            );
+        }
+        if !self.tokens.is_empty() {
+            let challenge = match bearer_error {
+                Some(error) => format!(r#"Bearer realm="{realm}", error="{error}""#),
+                None => format!(r#"Bearer realm="{realm}""#),
+            };
+            response.headers.add("WWW-Authenticate", challenge);
+        }
+        response
+    }
+}
+
+/// `user` and `password` from base64 `user:password`.
+fn basic_credentials(value: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(BASE64.decode(value).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// The segments `path` names once decoded, with `.`, `..` and empty segments
+/// resolved, so `/a/./b//../%63` is `["a", "c"]`. `None` for a bad escape.
+fn segments(path: &str) -> Option<Vec<String>> {
+    let decoded = percent_decode(path)?;
+    let mut segments: Vec<String> = Vec::new();
+    for segment in String::from_utf8_lossy(&decoded).split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s.to_string()),
+        }
+    }
+    Some(segments)
+}
+
+/// Puts a handler behind per-path authentication rules.
+///
+/// The rule with the longest prefix matching the request's path applies;
+/// paths no rule covers are served to everyone. Paths are compared decoded
+/// and with dot segments resolved, so `/%69nputs` and `/x/../inputs` are
+/// still `/inputs`.
+///
+/// ```no_run
+/// # use host::{StaticFiles};
+/// # use host::auth::{Auth, Htpasswd, Rule};
+/// let site = Auth::new(StaticFiles::new(".")?)
+///     .rule(Rule::new("/inputs").realm("puzzle inputs").htpasswd(Htpasswd::load("host.htpasswd")?))
+///     .rule(Rule::new("/api/private").token("s3cret"));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Auth {
+    rules: Vec<Rule>,
+    inner: Box<dyn Handler>,
+}
+
+impl Auth {
+    pub fn new(inner: impl Handler) -> Auth {
+        Auth {
+            rules: Vec::new(),
+            inner: Box::new(inner),
+        }
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Auth {
+        self.rules.push(rule);
+        self
+    }
+}
+
+impl Handler for Auth {
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        if !self.rules.is_empty() {
+            let Some(path) = segments(request.path()) else {
+                return Response::error(Status::BAD_REQUEST);
+            };
+            let rule = self
+                .rules
+                .iter()
+                .filter(|rule| rule.matches(&path))
+                .max_by_key(|rule| rule.prefix.len());
+            if let Some(rule) = rule
+                && let Err(response) = rule.check(request)
+            {
+                return response;
+            }
+        }
+        self.inner.handle(request, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    fn users() -> Htpasswd {
+        // the lowest costs, to keep the tests quick
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        let params = argon2::Params::new(8, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(
+                b"correct horse",
+                &SaltString::from_b64("c2FsdHNhbHQ").unwrap(),
+            )
+            .unwrap()
+            .to_string();
+        Htpasswd::parse(&format!("# dev box\nalice:{bcrypt}\n\nbob:{argon2}\n")).unwrap()
+    }
+
+    fn get(auth: &Auth, path: &str, authorization: Option<&str>) -> Response {
+        let mut raw = format!("GET {path} HTTP/1.1\r\nHost: x\r\n");
+        if let Some(value) = authorization {
+            raw.push_str(&format!("Authorization: {value}\r\n"));
+        }
+        raw.push_str("\r\n");
+        let request = Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap();
+        auth.handle(&request, &Params::new())
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{user}:{password}")))
+    }
+
+    fn site() -> Auth {
+        Auth::new(|_: &Request, _: &Params| Response::html("ok"))
+            .rule(
+                Rule::new("/inputs/")
+                    .realm("puzzle inputs")
+                    .htpasswd(users()),
+            )
+            .rule(
+                Rule::new("/inputs/bob")
+                    .htpasswd(users())
+                    .allow(vec!["bob".to_string()]),
+            )
+            .rule(Rule::new("/api").token("s3cret").token("other"))
+    }
+
+    #[test]
+    fn verifies_bcrypt_and_argon2_passwords() {
+        let users = users();
+        assert!(users.verify("alice", "hunter2"));
+        assert!(users.verify("bob", "correct horse"));
+        assert!(!users.verify("alice", "hunter3"));
+        assert!(!users.verify("bob", "hunter2"));
+        assert!(!users.verify("carol", "hunter2"));
+    }
+
+    #[test]
+    fn rejects_bad_password_files() {
+        let error = |text: &str| Htpasswd::parse(text).unwrap_err();
+        assert_eq!(error("alice"), "line 1: expected user:hash");
+        assert_eq!(
+            error("\nalice:$apr1$abc$def"),
+            "line 2: user \"alice\": unsupported hash; use bcrypt (htpasswd -B) or argon2"
+        );
+        assert!(error("alice:$2y$04$short").contains("invalid bcrypt hash"));
+        let hash = bcrypt::hash("x", 4).unwrap();
+        assert_eq!(
+            error(&format!("alice:{hash}\nalice:{hash}")),
+            "line 2: user \"alice\" is listed twice"
+        );
+    }
+
+    #[test]
+    fn challenges_then_checks_basic_credentials() {
+        let site = site();
+        assert_eq!(get(&site, "/", None).status, Status::OK);
+        assert_eq!(get(&site, "/inputsx", None).status, Status::OK);
+
+        let response = get(&site, "/inputs/day1.txt", None);
+        assert_eq!(response.status, Status::UNAUTHORIZED);
+        assert_eq!(
+            response.headers.get("WWW-Authenticate"),
+            Some(r#"Basic realm="puzzle inputs", charset="UTF-8""#)
+        );
+        let alice = basic("alice", "hunter2");
+        assert_eq!(
+            get(&site, "/inputs/day1.txt", Some(&alice)).status,
+            Status::OK
+        );
+        let wrong = basic("alice", "hunter3");
+        assert_eq!(
+            get(&site, "/inputs", Some(&wrong)).status,
+            Status::UNAUTHORIZED
+        );
+        assert_eq!(
+            get(&site, "/inputs", Some("Basic !!")).status,
+            Status::UNAUTHORIZED
+        );
+
+        // the longer prefix wins, and only lets bob in
+        assert_eq!(
+            get(&site, "/inputs/bob/", Some(&alice)).status,
+            Status::FORBIDDEN
+        );
+        let bob = basic("bob", "correct horse");
+        assert_eq!(get(&site, "/inputs/bob/", Some(&bob)).status, Status::OK);
+    }
+
+    #[test]
+    fn encoded_and_dotted_paths_are_still_protected() {
+        let site = site();
+        for path in [
+            "/%69nputs/day1.txt",
+            "/x/../inputs",
+            "//inputs",
+            "/./inputs/",
+        ] {
+            assert_eq!(
+                get(&site, path, None).status,
+                Status::UNAUTHORIZED,
+                "{path}"
+            );
+        }
+        assert_eq!(get(&site, "/inputs%zz", None).status, Status::BAD_REQUEST);
+    }
+
+    #[test]
+    fn checks_bearer_tokens() {
+        let site = site();
+        let response = get(&site, "/api/stats", None);
+        assert_eq!(response.status, Status::UNAUTHORIZED);
+        assert_eq!(
+            response.headers.get("WWW-Authenticate"),
+            Some(r#"Bearer realm="host""#)
+        );
+        assert_eq!(
+            get(&site, "/api/stats", Some("Bearer s3cret")).status,
+            Status::OK
+        );
+        assert_eq!(get(&site, "/api", Some("bearer other")).status, Status::OK);
+
+        let response = get(&site, "/api/stats", Some("Bearer s3cre"));
+        assert_eq!(response.status, Status::UNAUTHORIZED);
+        assert_eq!(
+            response.headers.get("WWW-Authenticate"),
+            Some(r#"Bearer realm="host", error="invalid_token""#)
+        );
+        // a password is no use where only tokens are accepted
+        let alice = basic("alice", "hunter2");
+        assert_eq!(
+            get(&site, "/api", Some(&alice)).status,
+            Status::UNAUTHORIZED
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::date::{http_date, parse_http_date};
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+
+/// How file responses are validated and cached.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachePolicy {
+    pub etag: ETagKind,
+    /// Send `Last-Modified` and honour `If-Modified-Since`.
+    pub last_modified: bool,
+    /// Value for `Cache-Control`, e.g. `no-cache` or `public, max-age=3600`.
+    pub cache_control: Option<String>,
+}
+
+impl Default for CachePolicy {
+    /// Browsers keep a copy but revalidate it on every use, so edits show up
+    /// immediately while unchanged files cost a 304 instead of a full send.
+    fn default() -> CachePolicy {
+        CachePolicy {
+            etag: ETagKind::Weak,
+            last_modified: true,
+            cache_control: Some("no-cache".to_string()),
+        }
+    }
+}
+
+/// Which kind of `ETag` to generate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ETagKind {
+    None,
+    /// `W/"<size>-<mtime>"`: cheap, from file metadata alone.
+    Weak,
+    /// `"<hash>"`: a hash of the exact bytes served; for a file streamed
+    /// from disk, `"<size>-<mtime>"` instead.
+    Strong,
+}
+
+/// An entity tag as sent in `ETag` and `If-None-Match`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ETag {
+    pub weak: bool,
+    /// The opaque tag, without quotes.
+    pub tag: String,
+}
+
+impl ETag {
+    /// Weak tag built from a file's size and modification time.
+    pub fn from_metadata(len: u64, modified: SystemTime) -> ETag {
+        let mtime = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        ETag {
+            weak: true,
+            tag: format!("{len:x}-{:x}", mtime.as_nanos()),
+        }
+    }
+
+    /// Strong tag built from a 64-bit FNV-1a hash of `bytes`.
+    pub fn from_contents(bytes: &[u8]) -> ETag {
+        let hash = fnv1a(FNV_OFFSET, bytes);
+        ETag {
+            weak: false,
+            tag: format!("{hash:016x}-{:x}", bytes.len()),
+        }
+    }
+
+    /// Weak comparison: the opaque tags match, whatever their weakness.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+
+    /// Strong comparison: both tags strong and identical.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Parse one entity-tag, e.g. `"abc"` or `W/"abc"`.
+    pub fn parse(s: &str) -> Option<ETag> {
+        let s = s.trim();
+        let (weak, quoted) = match s.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        if tag.contains('"') {
+            return None;
+        }
+        Some(ETag {
+            weak,
+            tag: tag.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+/// The list in an `If-None-Match` or `If-Match` header.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ETagMatch {
+    Any,
+    Tags(Vec<ETag>),
+}
+
+impl ETagMatch {
+    /// Parse `*` or a comma-separated list of entity-tags, skipping malformed ones.
+    pub fn parse(s: &str) -> ETagMatch {
+        if s.trim() == "*" {
+            return ETagMatch::Any;
+        }
+        // split on commas outside quotes; a quoted tag may itself contain commas
+        let mut tags = Vec::new();
+        let mut start = 0;
+        let mut quoted = false;
+        for (i, c) in s.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                ',' if !quoted => {
+                    tags.extend(ETag::parse(&s[start..i]));
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        tags.extend(ETag::parse(&s[start..]));
+        ETagMatch::Tags(tags)
+    }
+
+    pub fn matches_weak(&self, etag: &ETag) -> bool {
+        match self {
+            ETagMatch::Any => true,
+            ETagMatch::Tags(tags) => tags.iter().any(|t| t.weak_eq(etag)),
+        }
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// What a response can be validated against.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<ETag>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Validators for a file under `policy`.
+    pub fn for_file(
+        policy: &CachePolicy,
+        contents: &[u8],
+        modified: Option<SystemTime>,
+    ) -> Validators {
+        let etag = match (policy.etag, modified) {
+            (ETagKind::None, _) => None,
+            (ETagKind::Strong, _) => Some(ETag::from_contents(contents)),
+            (ETagKind::Weak, Some(modified)) => {
+                Some(ETag::from_metadata(contents.len() as u64, modified))
+            }
+            (ETagKind::Weak, None) => None,
+        };
+        Validators {
+            etag,
+            last_modified: modified.filter(|_| policy.last_modified),
+        }
+    }
+
+    /// Like [`for_file`](Validators::for_file) for a file of `len` bytes
+    /// that is not in memory. Hashing it would read all of it on every
+    /// request, 304s and ranges included, so a strong tag is made from its
+    /// metadata instead.
+    pub fn for_large_file(
+        policy: &CachePolicy,
+        len: u64,
+        modified: Option<SystemTime>,
+    ) -> Validators {
+        let etag = match (policy.etag, modified) {
+            (ETagKind::None, _) | (_, None) => None,
+            (kind, Some(modified)) => Some(ETag {
+                weak: kind == ETagKind::Weak,
+                ..ETag::from_metadata(len, modified)
+            }),
+        };
+        Validators {
+            etag,
+            last_modified: modified.filter(|_| policy.last_modified),
+        }
+    }
+
+    /// Whether a `GET`/`HEAD` may be answered with 304 (RFC 9110 §13.2.2).
+    ///
+    /// `If-None-Match` wins when present; `If-Modified-Since` is only
+    /// consulted without it, at one-second resolution.
+    pub fn not_modified(&self, request: &Request) -> bool {
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return false;
+        }
+        if let Some(header) = request.header("If-None-Match") {
+            return match &self.etag {
+                Some(etag) => ETagMatch::parse(header).matches_weak(etag),
+                None => false,
+            };
+        }
+        match (request.header("If-Modified-Since"), self.last_modified) {
+            (Some(since), Some(modified)) => match parse_http_date(since) {
+                Some(since) => truncate_to_secs(modified) <= since,
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Add `ETag` and `Last-Modified` headers to `response`.
+    pub fn apply(&self, response: &mut Response) {
+        if let Some(etag) = &self.etag {
+            response.headers.set("ETag", etag.to_string());
+        }
+        if let Some(modified) = self.last_modified {
+            response.headers.set("Last-Modified", http_date(modified));
+        }
+    }
+}
+
+/// Headers-only 304 carrying the validators and `Cache-Control`.
+pub fn not_modified(validators: &Validators, policy: &CachePolicy) -> Response {
+    let mut response = Response::new(Status::NOT_MODIFIED);
+    validators.apply(&mut response);
+    if let Some(cache_control) = &policy.cache_control {
+        response.headers.set("Cache-Control", cache_control.clone());
+    }
+    response
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + Duration::from_secs(d.as_secs()),
+        Err(_) => UNIX_EPOCH,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\nHost: x\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn modified() -> SystemTime {
+        // Sun, 06 Nov 1994 08:49:37 GMT plus a fraction of a second
+        UNIX_EPOCH + Duration::from_millis(784_111_777_250)
+    }
+
+    #[test]
+    fn etags_parse_and_display() {
+        let strong = ETag::parse(r#""abc""#).unwrap();
+        let weak = ETag::parse(r#"W/"abc""#).unwrap();
+        assert!(!strong.weak && weak.weak);
+        assert_eq!(weak.to_string(), r#"W/"abc""#);
+        assert!(strong.weak_eq(&weak) && !strong.strong_eq(&weak));
+        assert_eq!(ETag::parse("abc"), None);
+
+        let list = ETagMatch::parse(r#""a,b", W/"c" , junk, "d""#);
+        let ETagMatch::Tags(tags) = list else {
+            panic!("expected tags")
+        };
+        let tags: Vec<_> = tags.iter().map(|t| t.tag.as_str()).collect();
+        assert_eq!(tags, ["a,b", "c", "d"]);
+    }
+
+    #[test]
+    fn strong_tags_follow_contents() {
+        assert_eq!(ETag::from_contents(b"hello"), ETag::from_contents(b"hello"));
+        assert_ne!(ETag::from_contents(b"hello"), ETag::from_contents(b"hellp"));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let validators = Validators::for_file(&CachePolicy::default(), b"body", Some(modified()));
+        let etag = validators.etag.clone().unwrap();
+        assert!(etag.weak);
+
+        assert!(validators.not_modified(&request(&format!("If-None-Match: {etag}\r\n"))));
+        assert!(validators.not_modified(&request(&format!(
+            "If-None-Match: \"x\", \"{}\"\r\n",
+            etag.tag
+        ))));
+        assert!(validators.not_modified(&request("If-None-Match: *\r\n")));
+        assert!(!validators.not_modified(&request("If-None-Match: \"other\"\r\n")));
+
+        // a failed If-None-Match is not rescued by If-Modified-Since
+        let both =
+            "If-None-Match: \"other\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n";
+        assert!(!validators.not_modified(&request(both)));
+    }
+
+    #[test]
+    fn if_modified_since_has_second_resolution() {
+        let validators = Validators::for_file(&CachePolicy::default(), b"body", Some(modified()));
+        let check = |date: &str| {
+            validators.not_modified(&request(&format!("If-Modified-Since: {date}\r\n")))
+        };
+
+        assert!(check("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(check("Sun, 06 Nov 1994 09:00:00 GMT"));
+        assert!(!check("Sun, 06 Nov 1994 08:49:36 GMT"));
+        assert!(!check("not a date"));
+    }
+
+    #[test]
+    fn policy_controls_validators() {
+        let policy = CachePolicy {
+            etag: ETagKind::Strong,
+            last_modified: false,
+            cache_control: None,
+        };
+        let validators = Validators::for_file(&policy, b"body", Some(modified()));
+        assert_eq!(validators.etag, Some(ETag::from_contents(b"body")));
+        assert_eq!(validators.last_modified, None);
+
+        let response = not_modified(&validators, &policy);
+        assert_eq!(response.status, Status::NOT_MODIFIED);
+        assert!(response.headers.contains("ETag"));
+        assert!(!response.headers.contains("Cache-Control"));
+    }
+}
@@ -0,0 +1,477 @@
+//! CGI/1.1 (RFC 3875): run scripts from a directory as request handlers.
+
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::handler::{Handler, Params};
+use crate::headers::Headers;
+use crate::request::Request;
+use crate::response::{Response, Status};
+use crate::router::Router;
+
+/// How long a script may run, output included, before it is killed and the
+/// client gets 504.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Output beyond this is an error rather than a response.
+const MAX_OUTPUT: u64 = 64 * 1024 * 1024;
+
+/// How often a running script is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs executables under a directory, e.g. `/cgi-bin/solve/day/7` runs
+/// `cgi-bin/solve` with `PATH_INFO=/day/7`.
+///
+/// Request metadata goes to the script in environment variables and the
+/// body on stdin. The script answers on stdout with header lines, such as
+/// `Content-Type: text/plain` or `Status: 404 Not Found`, a blank line and
+/// the body. Its stderr is copied to the server's, one line at a time.
+pub struct Cgi {
+    dir: PathBuf,
+    prefix: String,
+    timeout: Duration,
+}
+
+impl Cgi {
+    /// Serve the scripts in `dir`, which must exist.
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Cgi> {
+        let dir = dir.as_ref().canonicalize()?;
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a directory", dir.display()),
+            ));
+        }
+        Ok(Cgi {
+            dir,
+            prefix: String::new(),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Set how long a script may run before it is killed.
+    pub fn timeout(mut self, timeout: Duration) -> Cgi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Route every path below `prefix`, for every method, to these scripts.
+    pub fn mount(mut self, router: Router, prefix: &str) -> Router {
+        self.prefix = prefix.trim_end_matches('/').to_string();
+        let pattern = format!("{}/*path", self.prefix);
+        router.any(&pattern, self)
+    }
+
+    /// The script a decoded path below the prefix names, and the path after it.
+    fn find(&self, path: &str) -> Option<(PathBuf, String, String)> {
+        let segments: Vec<&str> = path.split('/').collect();
+        let mut script = self.dir.clone();
+        for (i, segment) in segments.iter().enumerate() {
+            // no `..`, and no hidden files such as editor backups or `.git`
+            if segment.is_empty() || segment.starts_with('.') || segment.contains('\\') {
+                return None;
+            }
+            script.push(segment);
+            if script.is_file() {
+                let name = format!("{}/{}", self.prefix, segments[..=i].join("/"));
+                let path_info = if i + 1 == segments.len() {
+                    String::new()
+                } else {
+                    format!("/{}", segments[i + 1..].join("/"))
+                };
+                return Some((script, name, path_info));
+            }
+            if !script.is_dir() {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn run(&self, request: &Request, script: &Path, name: &str, path_info: &str) -> Response {
+        let mut command = Command::new(script);
+        command
+            .env_clear()
+            .envs(environment(request, name, path_info))
+            .current_dir(script.parent().unwrap_or(&self.dir))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // scripts are mostly shell, which needs to find its tools
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        // its own process group, so a timeout also kills what it started
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let deadline = Instant::now() + self.timeout;
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                eprintln!("CGI script {name} is not executable");
+                return Response::error(Status::FORBIDDEN);
+            }
+            Err(e) => {
+                eprintln!("CGI script {name} failed to start: {e}");
+                return Response::error(Status::INTERNAL_SERVER_ERROR);
+            }
+        };
+
+        // each pipe gets a thread so a script blocked on one cannot stall the others
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let body = request.body.clone();
+        // a script that does not read its input makes this fail, which is fine
+        thread::spawn(move || stdin.write_all(&body));
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let (sender, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let read = (&mut stdout).take(MAX_OUTPUT + 1).read_to_end(&mut output);
+            let _ = sender.send(read.map(|_| output));
+        });
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let label = name.to_string();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("CGI {label}: {line}");
+            }
+        });
+
+        // whatever the script left running may hold stdout open after it exits
+        let output = match wait(&mut child, deadline) {
+            true => output
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok(),
+            false => None,
+        };
+        let output = match output {
+            Some(Ok(output)) if output.len() as u64 <= MAX_OUTPUT => output,
+            Some(Ok(_)) => {
+                eprintln!("CGI script {name} wrote more than {MAX_OUTPUT} bytes");
+                return Response::error(Status::BAD_GATEWAY);
+            }
+            Some(Err(_)) => return Response::error(Status::BAD_GATEWAY),
+            None => {
+                kill(&mut child);
+                eprintln!("CGI script {name} timed out after {:?}", self.timeout);
+                return Response::error(Status::GATEWAY_TIMEOUT);
+            }
+        };
+        parse_output(&output).unwrap_or_else(|why| {
+            eprintln!("CGI script {name} sent a bad response: {why}");
+            Response::error(Status::BAD_GATEWAY)
+        })
+    }
+}
+
+impl Handler for Cgi {
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        let path = params.get("path").unwrap_or_default();
+        match self.find(path) {
+            Some((script, name, path_info)) => self.run(request, &script, &name, &path_info),
+            None => Response::error(Status::NOT_FOUND),
+        }
+    }
+}
+
+/// Wait until `deadline` for `child` to exit. False if it has not.
+fn wait(child: &mut Child, deadline: Instant) -> bool {
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => return true,
+            Ok(None) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            _ => return false,
+        }
+    }
+}
+
+/// Kill `child` and everything else in its process group.
+fn kill(child: &mut Child) {
+    // the group outlives the script while anything in it runs, so its id
+    // cannot have been reused yet
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// The meta-variables of RFC 3875 §4.1, plus an `HTTP_*` variable per header.
+fn environment(request: &Request, name: &str, path_info: &str) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = Vec::new();
+    let mut set = |key: &str, value: &str| env.push((key.to_string(), value.to_string()));
+
+    set("GATEWAY_INTERFACE", "CGI/1.1");
+    set(
+        "SERVER_SOFTWARE",
+        concat!("host/", env!("CARGO_PKG_VERSION")),
+    );
+    set("SERVER_PROTOCOL", request.version.as_str());
+    set("REQUEST_METHOD", request.method.as_str());
+    set("REQUEST_URI", &request.target);
+    set("SCRIPT_NAME", name);
+    if !path_info.is_empty() {
+        set("PATH_INFO", path_info);
+    }
+    set("QUERY_STRING", request.query().unwrap_or_default());
+    let host = request.header("Host").unwrap_or_default().trim();
+    set("SERVER_NAME", request.host().unwrap_or("localhost"));
+    let port = match host.rsplit_once(':') {
+        Some((_, port)) if port.bytes().all(|b| b.is_ascii_digit()) => port,
+        _ if request.secure => "443",
+        _ => "80",
+    };
+    set("SERVER_PORT", port);
+    if request.secure {
+        set("HTTPS", "on");
+    }
+    if let Some(remote) = request.remote {
+        set("REMOTE_ADDR", &remote.ip().to_canonical().to_string());
+        set("REMOTE_PORT", &remote.port().to_string());
+    }
+    if !request.body.is_empty() {
+        set("CONTENT_LENGTH", &request.body.len().to_string());
+    }
+    if let Some(content_type) = request.header("Content-Type") {
+        set("CONTENT_TYPE", content_type);
+    }
+
+    for (header, value) in request.headers.iter() {
+        let key = format!("HTTP_{}", header.to_ascii_uppercase().replace('-', "_"));
+        // the body's headers have variables of their own; `Proxy` would set
+        // HTTP_PROXY, which many tools take as their proxy (httpoxy); and
+        // scripts have no business seeing the client's password
+        if matches!(
+            key.as_str(),
+            "HTTP_CONTENT_TYPE" | "HTTP_CONTENT_LENGTH" | "HTTP_PROXY" | "HTTP_AUTHORIZATION"
+        ) {
+            continue;
+        }
+        match env.iter_mut().find(|(k, _)| *k == key) {
+            // repeated headers are combined, as a list
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => env.push((key, value.to_string())),
+        }
+    }
+    env
+}
+
+/// Turn a script's output into a response (RFC 3875 §6).
+fn parse_output(output: &[u8]) -> Result<Response, &'static str> {
+    let (head, body) = split_head(output).ok_or("no blank line after the headers")?;
+    let mut headers = Headers::new();
+    let mut status = None;
+    for line in head.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = std::str::from_utf8(line).map_err(|_| "header is not UTF-8")?;
+        // some clients end a line at a bare CR, which would let it add headers
+        if line.contains('\r') {
+            return Err("bare CR in header");
+        }
+        let (name, value) = line.split_once(':').ok_or("header line without colon")?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.is_empty() || name.contains(' ') {
+            return Err("invalid header name");
+        }
+        if name.eq_ignore_ascii_case("Status") {
+            let code = value.split(' ').next().unwrap_or_default();
+            let code: u16 = code.parse().map_err(|_| "invalid Status")?;
+            if !(200..600).contains(&code) {
+                return Err("invalid Status");
+            }
+            status = Some(Status(code));
+        } else if !is_hop_by_hop(name) {
+            headers.add(name, value);
+        }
+    }
+    let status = match status {
+        Some(status) => status,
+        None if headers.contains("Location") => Status::FOUND,
+        None => Status::OK,
+    };
+    let mut response = Response::new(status).with_body(body.to_vec());
+    response.headers = headers;
+    Ok(response)
+}
+
+/// Split at the first blank line, which may end in `\n` or `\r\n`.
+fn split_head(output: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut start = 0;
+    while let Some(end) = output[start..].iter().position(|&b| b == b'\n') {
+        let line = &output[start..start + end];
+        if line.is_empty() || line == b"\r" {
+            let head = output[..start]
+                .strip_suffix(b"\n")
+                .unwrap_or(&output[..start]);
+            return Some((head, &output[start + end + 1..]));
+        }
+        start += end + 1;
+    }
+    None
+}
+
+/// Headers the server sets itself.
+fn is_hop_by_hop(name: &str) -> bool {
+    [
+        "Connection",
+        "Content-Length",
+        "Transfer-Encoding",
+        "Keep-Alive",
+    ]
+    .iter()
+    .any(|h| h.eq_ignore_ascii_case(name))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    fn script(dir: &Path, name: &str, body: &str) {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    fn serve(router: &Router, raw: &str) -> Response {
+        let mut request = Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap();
+        request.remote = Some("127.0.0.1:5555".parse().unwrap());
+        router.handle(&request, &Params::new())
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(&response.body).unwrap()
+    }
+
+    fn router(dir: &Path) -> Router {
+        Cgi::new(dir)
+            .unwrap()
+            .timeout(Duration::from_millis(500))
+            .mount(Router::new(), "/cgi-bin/")
+    }
+
+    #[test]
+    fn passes_the_request_and_parses_the_response() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("aoc")).unwrap();
+        script(
+            &dir.path().join("aoc"),
+            "solve",
+            r#"printf 'Content-Type: text/plain\r\nX-Day: %s\r\n\r\n' "${PATH_INFO#/day/}"
+echo "$REQUEST_METHOD $SCRIPT_NAME $QUERY_STRING $CONTENT_LENGTH $CONTENT_TYPE"
+echo "$SERVER_NAME:$SERVER_PORT $REMOTE_ADDR $HTTP_X_TOKEN ${HTTP_PROXY:-none}"
+cat"#,
+        );
+        let response = serve(
+            &router(dir.path()),
+            "POST /cgi-bin/aoc/solve/day/7?part=2 HTTP/1.1\r\nHost: aoc.localhost:7878\r\n\
+             X-Token: a\r\nX-Token: b\r\nProxy: evil\r\nContent-Type: text/plain\r\n\
+             Content-Length: 6\r\n\r\ninput\n",
+        );
+        assert_eq!(response.status, Status::OK);
+        assert_eq!(response.headers.get("X-Day"), Some("7"));
+        assert_eq!(
+            body(&response),
+            "POST /cgi-bin/aoc/solve part=2 6 text/plain\n\
+             aoc.localhost:7878 127.0.0.1 a, b none\ninput\n"
+        );
+    }
+
+    #[test]
+    fn status_and_location_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        script(
+            dir.path(),
+            "missing",
+            "echo 'Status: 404 Nope'\necho\necho gone",
+        );
+        script(dir.path(), "moved", "printf 'Location: /elsewhere\\n\\n'");
+        let router = router(dir.path());
+
+        let response = serve(&router, "GET /cgi-bin/missing HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(response.status, Status::NOT_FOUND);
+        assert_eq!(body(&response), "gone\n");
+        let response = serve(&router, "GET /cgi-bin/moved HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(response.status, Status::FOUND);
+        assert_eq!(response.headers.get("Location"), Some("/elsewhere"));
+    }
+
+    #[test]
+    fn failures_map_to_error_statuses() {
+        let dir = tempfile::tempdir().unwrap();
+        script(dir.path(), "slow", "sleep 5");
+        script(dir.path(), "garbage", "echo no headers here");
+        fs::write(dir.path().join("plain"), "not a program").unwrap();
+        script(dir.path(), ".hidden", "echo");
+        let router = router(dir.path());
+        let status = |target: &str| {
+            serve(
+                &router,
+                &format!("GET {target} HTTP/1.1\r\nHost: x\r\n\r\n"),
+            )
+            .status
+        };
+
+        let started = Instant::now();
+        assert_eq!(status("/cgi-bin/slow"), Status::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(status("/cgi-bin/garbage"), Status::BAD_GATEWAY);
+        assert_eq!(status("/cgi-bin/plain"), Status::FORBIDDEN);
+        assert_eq!(status("/cgi-bin/nope"), Status::NOT_FOUND);
+        assert_eq!(status("/cgi-bin/.hidden"), Status::NOT_FOUND);
+        assert_eq!(status("/cgi-bin/%2E%2E/etc/passwd"), Status::NOT_FOUND);
+        assert_eq!(status("/cgi-bin/"), Status::NOT_FOUND);
+    }
+
+    #[test]
+    fn background_processes_cannot_outlive_the_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("marker");
+        // the background job keeps stdout open after the script exits
+        script(
+            dir.path(),
+            "daemon",
+            &format!(
+                "printf 'Content-Type: text/plain\\n\\n'\n(sleep 1; touch {}) &",
+                marker.display()
+            ),
+        );
+        let router = router(dir.path());
+
+        let started = Instant::now();
+        let response = serve(&router, "GET /cgi-bin/daemon HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(response.status, Status::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // the whole group was killed, not just the script
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn bare_cr_in_a_header_is_a_bad_gateway() {
+        let dir = tempfile::tempdir().unwrap();
+        script(
+            dir.path(),
+            "split",
+            "printf 'X-Note: a\\rSet-Cookie: b\\r\\n\\r\\n'",
+        );
+        let response = serve(
+            &router(dir.path()),
+            "GET /cgi-bin/split HTTP/1.1\r\nHost: x\r\n\r\n",
+        );
+        assert_eq!(response.status, Status::BAD_GATEWAY);
+    }
+}
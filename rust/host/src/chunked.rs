@@ -0,0 +1,449 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::headers::Headers;
+use crate::request::{Limits, Line, ParseError, read_headers, read_line};
+
+/// A streamed response body, produced by writing into a [`BodyWriter`].
+pub type BodyStream = Box<dyn FnOnce(&mut BodyWriter<'_>) -> io::Result<()> + Send>;
+
+/// Where a streamed body goes. Every `write` becomes one chunk when the
+/// response is chunked; otherwise the bytes go out as they are and the end of
+/// the body is marked by closing the connection.
+pub struct BodyWriter<'a> {
+    out: &'a mut dyn Write,
+    chunked: bool,
+    trailers: Headers,
+    written: u64,
+}
+
+impl<'a> BodyWriter<'a> {
+    pub(crate) fn new(out: &'a mut dyn Write, chunked: bool) -> BodyWriter<'a> {
+        BodyWriter {
+            out,
+            chunked,
+            trailers: Headers::new(),
+            written: 0,
+        }
+    }
+
+    /// Send a trailer field after the last chunk, e.g. a checksum of the body.
+    ///
+    /// Dropped silently when the body is not chunked, since there is nowhere
+    /// to put it.
+    pub fn trailer(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.trailers.add(name, value);
+    }
+
+    /// Write the last chunk and the trailers, returning the body bytes sent
+    /// (not counting chunk framing).
+    pub(crate) fn finish(self) -> io::Result<u64> {
+        if self.chunked {
+            write!(self.out, "0\r\n{}\r\n", self.trailers)?;
+        }
+        self.out.flush()?;
+        Ok(self.written)
+    }
+}
+
+impl Write for BodyWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        // an empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(());
+        }
+        if self.chunked {
+            write!(self.out, "{:x}\r\n", buf.len())?;
+            self.out.write_all(buf)?;
+            self.out.write_all(b"\r\n")?;
+        } else {
+            self.out.write_all(buf)?;
+        }
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Stream everything `reader` yields, one chunk per read.
+pub fn from_reader<R: Read + Send + 'static>(mut reader: R) -> BodyStream {
+    Box::new(move |out| {
+        let mut buf = [0; 8 * 1024];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    out.write_all(&buf[..n])?;
+                    out.flush()?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    })
+}
+
+/// Decode a chunked request body (RFC 9112 §7.1), returning it with its trailers.
+///
+/// Chunk extensions are ignored. The decoded size counts against
+/// `max_body`, and the trailer section against the header limits.
+pub(crate) fn read_chunked<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<(Vec<u8>, Headers), ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let line = match read_line(reader, limits.max_header_line)? {
+            None => return Err(ParseError::Io(io::ErrorKind::UnexpectedEof.into())),
+            Some(Line::TooLong) => return Err(ParseError::BadRequest("chunk size line too long")),
+            Some(Line::Complete(line)) => line,
+        };
+        let size = parse_chunk_size(&line)?;
+        if size == 0 {
+            break;
+        }
+        if size > (limits.max_body - body.len()) as u64 {
+            return Err(ParseError::PayloadTooLarge);
+        }
+
+        let start = body.len();
+        reader.take(size).read_to_end(&mut body)?;
+        if (body.len() - start) as u64 != size {
+            return Err(ParseError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf[..1])?;
+        if crlf[0] == b'\r' {
+            reader.read_exact(&mut crlf[1..])?;
+        }
+        if !matches!(crlf, [b'\n', 0] | [b'\r', b'\n']) {
+            return Err(ParseError::BadRequest("chunk not followed by CRLF"));
+        }
+    }
+    let trailers = read_headers(reader, limits)?;
+    Ok((body, trailers))
+}
+
+/// Finds where a chunked body ends as its bytes arrive, picking up where the
+/// last call stopped so that a growing buffer is only looked through once.
+///
+/// It checks the framing and limits [`read_chunked`] does, so a body it
+/// accepts can then be decoded in one pass.
+#[derive(Default)]
+pub(crate) struct ChunkedEnd {
+    /// Where the next unread part of the body starts.
+    at: usize,
+    /// Decoded bytes so far.
+    size: u64,
+    state: Scan,
+}
+
+#[derive(Default)]
+enum Scan {
+    #[default]
+    SizeLine,
+    /// A chunk of this many bytes, then its CRLF.
+    Data(u64),
+    /// This many trailer fields so far.
+    Trailers(usize),
+}
+
+impl ChunkedEnd {
+    /// The length of the whole body if `body` holds all of it yet.
+    /// `body` must start where the body does and only ever grow between calls.
+    pub(crate) fn find(
+        &mut self,
+        body: &[u8],
+        limits: &Limits,
+    ) -> Result<Option<usize>, ParseError> {
+        loop {
+            match self.state {
+                Scan::SizeLine => {
+                    let too_long = ParseError::BadRequest("chunk size line too long");
+                    let Some((line, next)) =
+                        line_at(body, self.at, limits.max_header_line, too_long)?
+                    else {
+                        return Ok(None);
+                    };
+                    let size = parse_chunk_size(line)?;
+                    if size > limits.max_body as u64 - self.size {
+                        return Err(ParseError::PayloadTooLarge);
+                    }
+                    self.at = next;
+                    self.state = if size == 0 {
+                        Scan::Trailers(0)
+                    } else {
+                        Scan::Data(size)
+                    };
+                }
+                Scan::Data(size) => {
+                    let end = self.at + size as usize;
+                    if body.len() <= end {
+                        return Ok(None);
+                    }
+                    let crlf = match &body[end..] {
+                        [b'\n', ..] => 1,
+                        [b'\r', b'\n', ..] => 2,
+                        [b'\r'] => return Ok(None),
+                        _ => return Err(ParseError::BadRequest("chunk not followed by CRLF")),
+                    };
+                    self.at = end + crlf;
+                    self.size += size;
+                    self.state = Scan::SizeLine;
+                }
+                Scan::Trailers(fields) => {
+                    let too_long = ParseError::HeadersTooLarge;
+                    let Some((line, next)) =
+                        line_at(body, self.at, limits.max_header_line, too_long)?
+                    else {
+                        return Ok(None);
+                    };
+                    self.at = next;
+                    if line.is_empty() {
+                        return Ok(Some(next));
+                    }
+                    if fields == limits.max_headers {
+                        return Err(ParseError::HeadersTooLarge);
+                    }
+                    self.state = Scan::Trailers(fields + 1);
+                }
+            }
+        }
+    }
+}
+
+/// The line starting at `from` without its line end, and where the next one
+/// starts; `None` until its `\n` arrives, and `too_long` past `limit` bytes.
+fn line_at(
+    buf: &[u8],
+    from: usize,
+    limit: usize,
+    too_long: ParseError,
+) -> Result<Option<(&[u8], usize)>, ParseError> {
+    let rest = &buf[from..];
+    let Some(end) = rest.iter().take(limit + 2).position(|&b| b == b'\n') else {
+        return if rest.len() > limit + 1 {
+            Err(too_long)
+        } else {
+            Ok(None)
+        };
+    };
+    let line = rest[..end].strip_suffix(b"\r").unwrap_or(&rest[..end]);
+    if line.len() > limit {
+        return Err(too_long);
+    }
+    Ok(Some((line, from + end + 1)))
+}
+
+/// Decodes a chunked body as it is read, for relaying one without buffering it.
+///
+/// Trailers are read and discarded.
+pub(crate) struct ChunkedReader<R> {
+    inner: R,
+    limits: Limits,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub(crate) fn new(inner: R, limits: Limits) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            limits,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<u64, ParseError> {
+        let line = match read_line(&mut self.inner, self.limits.max_header_line)? {
+            None => return Err(ParseError::Io(io::ErrorKind::UnexpectedEof.into())),
+            Some(Line::TooLong) => return Err(ParseError::BadRequest("chunk size line too long")),
+            Some(Line::Complete(line)) => line,
+        };
+        let size = parse_chunk_size(&line)?;
+        if size == 0 {
+            read_headers(&mut self.inner, &self.limits)?;
+        }
+        Ok(size)
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.remaining = self.next_chunk().map_err(|e| match e {
+                ParseError::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+            })?;
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            match read_line(&mut self.inner, 0)? {
+                Some(Line::Complete(line)) if line.is_empty() => {}
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "chunk not followed by CRLF",
+                    ));
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<u64, ParseError> {
+    let size = line.split(|&b| b == b';').next().unwrap_or_default();
+    let size = size.trim_ascii_end();
+    // sixteen hex digits already overflow any sane body limit
+    if size.is_empty() || size.len() > 16 {
+        return Err(ParseError::BadRequest("invalid chunk size"));
+    }
+    size.iter().try_fold(0_u64, |n, &b| {
+        let digit = char::from(b)
+            .to_digit(16)
+            .ok_or(ParseError::BadRequest("invalid chunk size"))?;
+        Ok(n << 4 | u64::from(digit))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(raw: &[u8], limits: &Limits) -> Result<(Vec<u8>, Headers), ParseError> {
+        read_chunked(&mut &raw[..], limits)
+    }
+
+    #[test]
+    fn encodes_chunks_and_trailers() {
+        let mut out = Vec::new();
+        let mut writer = BodyWriter::new(&mut out, true);
+        writer.write_all(b"hello, ").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"chunked world").unwrap();
+        writer.trailer("X-Checksum", "abc");
+        assert_eq!(writer.finish().unwrap(), 20);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "7\r\nhello, \r\nd\r\nchunked world\r\n0\r\nX-Checksum: abc\r\n\r\n"
+        );
+
+        let mut out = Vec::new();
+        let mut writer = BodyWriter::new(&mut out, false);
+        writer.write_all(b"raw").unwrap();
+        writer.trailer("X-Checksum", "abc");
+        writer.finish().unwrap();
+        assert_eq!(out, b"raw");
+    }
+
+    #[test]
+    fn decodes_chunks_extensions_and_trailers() {
+        let (body, trailers) = decode(
+            b"5;name=value\r\nhello\r\n1\r\n \r\nA\r\n0123456789\r\n0\r\nX-Sum: 42\r\n\r\nNEXT",
+            &Limits::default(),
+        )
+        .unwrap();
+        assert_eq!(body, b"hello 0123456789");
+        assert_eq!(trailers.get("x-sum"), Some("42"));
+
+        let (body, _) = decode(b"3\nabc\n0\n\n", &Limits::default()).unwrap();
+        assert_eq!(body, b"abc");
+    }
+
+    #[test]
+    fn decodes_while_reading() {
+        let raw = &b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Sum: 1\r\n\r\nNEXT"[..];
+        let mut input = raw;
+        let mut body = String::new();
+        ChunkedReader::new(&mut input, Limits::default())
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "hello, world");
+        assert_eq!(input, b"NEXT");
+
+        let mut body = Vec::new();
+        let err = ChunkedReader::new(&b"3\r\nabcd\r\n0\r\n\r\n"[..], Limits::default())
+            .read_to_end(&mut body)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn finds_the_end_as_bytes_arrive() {
+        let limits = Limits::default();
+        let raw = &b"5\r\nhello\r\n7;ext=1\r\n, world\n0\r\nX-Sum: 1\r\n\r\nNEXT"[..];
+        let end = raw.len() - 4;
+        let mut scan = ChunkedEnd::default();
+        for arrived in 0..end {
+            assert_eq!(scan.find(&raw[..arrived], &limits).unwrap(), None);
+        }
+        assert_eq!(scan.find(raw, &limits).unwrap(), Some(end));
+        assert!(decode(&raw[..end], &limits).is_ok());
+
+        let err = ChunkedEnd::default()
+            .find(b"3\r\nabcd\r\n", &limits)
+            .unwrap_err();
+        assert_eq!(err.status(), Some(crate::response::Status::BAD_REQUEST));
+        let small = Limits {
+            max_body: 4,
+            ..Limits::default()
+        };
+        let err = ChunkedEnd::default().find(b"5\r\n", &small).unwrap_err();
+        assert!(matches!(err, ParseError::PayloadTooLarge));
+    }
+
+    #[test]
+    fn rejects_bad_framing() {
+        let limits = Limits::default();
+        for raw in [
+            &b"x\r\nabc\r\n0\r\n\r\n"[..],
+            b"\r\n",
+            b"3\r\nabcd\r\n0\r\n\r\n",
+            b"11111111111111111\r\n",
+        ] {
+            let err = decode(raw, &limits).unwrap_err();
+            assert_eq!(err.status(), Some(crate::response::Status::BAD_REQUEST));
+        }
+        assert!(matches!(
+            decode(b"5\r\nhel", &limits),
+            Err(ParseError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn enforces_the_body_limit() {
+        let limits = Limits {
+            max_body: 4,
+            ..Limits::default()
+        };
+        assert!(decode(b"2\r\nab\r\n2\r\ncd\r\n0\r\n\r\n", &limits).is_ok());
+        let err = decode(b"2\r\nab\r\n3\r\ncde\r\n0\r\n\r\n", &limits).unwrap_err();
+        assert!(matches!(err, ParseError::PayloadTooLarge));
+        let err = decode(b"ffffffffffffffff\r\n", &limits).unwrap_err();
+        assert!(matches!(err, ParseError::PayloadTooLarge));
+    }
+}
@@ -0,0 +1,243 @@
+use std::io::{self, Write};
+
+use flate2::{
+    Compression,
+    write::{GzEncoder, ZlibEncoder},
+};
+
+/// A `Content-Encoding` this server can produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    /// HTTP's "deflate" is the zlib format (RFC 1950), not raw DEFLATE.
+    Deflate,
+    /// Only ever served from a precompressed `.br` file.
+    Brotli,
+}
+
+impl Encoding {
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// File-name suffix of a precompressed sibling, for encodings that have one.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Encoding::Gzip => Some("gz"),
+            Encoding::Brotli => Some("br"),
+            Encoding::Identity | Encoding::Deflate => None,
+        }
+    }
+}
+
+/// When and how file responses are compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    /// Compress compressible types with gzip/deflate as they are sent.
+    pub on_the_fly: bool,
+    /// Bodies smaller than this are sent as-is; the headers would eat the saving.
+    pub min_size: usize,
+    /// flate2 level, 0 (none) to 9 (best).
+    pub level: u32,
+    /// Serve `foo.js.br` / `foo.js.gz` in place of `foo.js` when the client accepts them.
+    pub precompressed: bool,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> CompressionPolicy {
+        CompressionPolicy {
+            on_the_fly: true,
+            min_size: 1024,
+            level: 6,
+            precompressed: true,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub fn disabled() -> CompressionPolicy {
+        CompressionPolicy {
+            on_the_fly: false,
+            precompressed: false,
+            ..CompressionPolicy::default()
+        }
+    }
+}
+
+/// Pick the best of `available` (listed in server preference order) for an
+/// `Accept-Encoding` header, or `Identity` when none is acceptable.
+///
+/// With no header at all any encoding is allowed, but we only compress for
+/// clients that ask: plenty of tools never send `Accept-Encoding` and would
+/// not decode the result.
+pub fn negotiate(accept_encoding: Option<&str>, available: &[Encoding]) -> Encoding {
+    let Some(header) = accept_encoding else {
+        return Encoding::Identity;
+    };
+
+    let prefs: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim();
+            if coding.is_empty() {
+                return None;
+            }
+            let q = parts
+                .find_map(|p| {
+                    p.trim()
+                        .strip_prefix("q=")
+                        .or_else(|| p.trim().strip_prefix("Q="))
+                })
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            Some((coding, q.clamp(0.0, 1.0)))
+        })
+        .collect();
+
+    let quality = |encoding: Encoding| -> f32 {
+        let token = encoding.token();
+        // x-gzip is an old alias that some clients still send
+        let exact = prefs.iter().find(|(c, _)| {
+            c.eq_ignore_ascii_case(token)
+                || (encoding == Encoding::Gzip && c.eq_ignore_ascii_case("x-gzip"))
+        });
+        match exact {
+            Some(&(_, q)) => q,
+            None => prefs
+                .iter()
+                .find(|(c, _)| *c == "*")
+                .map_or(0.0, |&(_, q)| q),
+        }
+    };
+
+    let mut best = Encoding::Identity;
+    let mut best_q = 0.0;
+    for &encoding in available {
+        let q = quality(encoding);
+        if q > best_q {
+            best = encoding;
+            best_q = q;
+        }
+    }
+    best
+}
+
+/// Whether a `Content-Type` is worth compressing. Images, audio, video and
+/// archives are already compressed; text-like formats shrink a lot.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/yaml"
+                | "application/wasm"
+                | "font/ttf"
+                | "font/otf"
+        )
+}
+
+/// Compress `bytes` with a gzip or deflate encoder at `level`.
+///
+/// # Panics
+///
+/// Panics for encodings that cannot be produced on the fly.
+pub fn encode(encoding: Encoding, bytes: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let level = Compression::new(level.min(9));
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), level);
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        Encoding::Identity | Encoding::Brotli => {
+            panic!("{} cannot be encoded on the fly", encoding.token())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    #[test]
+    fn negotiates_by_quality_then_preference() {
+        assert_eq!(negotiate(None, &ALL), Encoding::Identity);
+        assert_eq!(negotiate(Some(""), &ALL), Encoding::Identity);
+        assert_eq!(negotiate(Some("gzip, deflate, br"), &ALL), Encoding::Brotli);
+        assert_eq!(
+            negotiate(Some("gzip, deflate, br"), &ALL[1..]),
+            Encoding::Gzip
+        );
+        assert_eq!(
+            negotiate(Some("gzip;q=0.5, deflate"), &ALL),
+            Encoding::Deflate
+        );
+        assert_eq!(
+            negotiate(Some("deflate;q=0.2, *;q=0.4"), &ALL),
+            Encoding::Brotli
+        );
+        assert_eq!(negotiate(Some("x-gzip"), &ALL), Encoding::Gzip);
+        assert_eq!(negotiate(Some("GZIP;Q=0.9"), &ALL), Encoding::Gzip);
+    }
+
+    #[test]
+    fn zero_quality_refuses() {
+        assert_eq!(negotiate(Some("gzip;q=0"), &ALL), Encoding::Identity);
+        assert_eq!(negotiate(Some("*;q=0, identity"), &ALL), Encoding::Identity);
+        assert_eq!(negotiate(Some("gzip;q=bogus"), &ALL), Encoding::Identity);
+    }
+
+    #[test]
+    fn compressible_types() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/json"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/gzip"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "hello hello hello hello ".repeat(100);
+
+        let gz = encode(Encoding::Gzip, text.as_bytes(), 6).unwrap();
+        assert!(gz.len() < text.len());
+        let mut decoded = String::new();
+        GzDecoder::new(&gz[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        let zlib = encode(Encoding::Deflate, text.as_bytes(), 9).unwrap();
+        let mut decoded = String::new();
+        ZlibDecoder::new(&zlib[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+    }
+}
@@ -0,0 +1,1985 @@
+use std::{
+    fmt, fs, io,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use toml::{Table, Value};
+
+use crate::access_log::{AccessLog, LogFormat};
+use crate::auth::{self, Auth, Htpasswd, Rule};
+use crate::cgi::{self, Cgi};
+use crate::compress::CompressionPolicy;
+use crate::connection::{KeepAlive, Timeouts};
+use crate::engine::Engine;
+use crate::file_cache::{self, FileCache};
+use crate::files::StaticFiles;
+use crate::handler::{Handler, Params};
+use crate::headers::Headers;
+use crate::proxy::{self, Balance, Proxy};
+use crate::rate_limit::RateLimit;
+use crate::request::{Limits, Request};
+use crate::router::Router;
+use crate::server::{DEFAULT_DRAIN_TIMEOUT, DEFAULT_MAX_CONNECTIONS};
+use crate::shutdown::ShutdownHandle;
+use crate::tls::{CertFiles, CertStore, Tls};
+use crate::upload::Uploads;
+use crate::vhost::VirtualHosts;
+use crate::watch::{self, LiveReload, Watcher};
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+
+pub const USAGE: &str = "\
+usage: host [options]
+
+  -c, --config FILE        read settings from a TOML file; flags override it
+  -l, --listen ADDR        address to listen on, e.g. 0.0.0.0:8080 or [::1]:7878 (repeatable)
+  -r, --root DIR           document root (default: current directory)
+  -w, --workers N          worker threads (default: number of CPUs)
+      --engine ENGINE      threads, or async for many mostly idle connections (default: threads)
+      --no-listing         404 for directories without an index page
+      --watch              reload open pages when files under the document roots change
+      --idle-timeout DUR   close keep-alive connections idle this long (default: 5s)
+      --drain-timeout DUR  time given to open connections on shutdown (default: 10s)
+      --max-body SIZE      largest request body accepted (default: 1MiB)
+      --file-cache SIZE    memory for cached file contents, 0 for none (default: 64MiB)
+      --max-connections N  connections open at once before new ones get 503 (default: 1024)
+      --rate-limit N/DUR[,BURST]
+                           requests each client IP may make, e.g. 10/s or 100/1m,200 (default: no limit)
+      --access-log FILE    append the access log to FILE, or - for stdout (default)
+      --no-access-log      do not log requests
+      --log-format FORMAT  combined or json
+  -H, --header 'N: V'      add a header to every response (repeatable)
+      --tls-listen ADDR    address to accept HTTPS on (repeatable)
+      --tls-cert CERT,KEY  PEM certificate chain and key; the first is the default (repeatable)
+      --redirect-http ADDR redirect plain HTTP on ADDR to the first HTTPS address (repeatable)
+      --vhost NAME[,NAME...]=DIR
+                           serve DIR for these host names, e.g. aoc.localhost or *.localhost (repeatable)
+      --cgi PREFIX=DIR     run the executables in DIR for paths below PREFIX, e.g. /cgi-bin=cgi-bin (repeatable)
+      --upload PREFIX=DIR  serve DIR below PREFIX and accept PUT and form uploads into it (repeatable)
+      --proxy PREFIX=ADDR[,ADDR...]
+                           forward PREFIX and everything below it to upstream servers (repeatable)
+      --auth PREFIX=FILE   require a password from htpasswd FILE for PREFIX and below (repeatable)
+  -h, --help               show this help";
+
+/// Everything the binary needs to start a server, from a config file and/or flags.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub listen: Vec<String>,
+    /// `None` serves the current directory with `hello.html` as a fallback index.
+    pub root: Option<PathBuf>,
+    pub index: Option<Vec<String>>,
+    pub directory_listing: bool,
+    /// Live reload: HTML pages reload themselves when files under a document root change.
+    pub watch: bool,
+    pub workers: usize,
+    /// Thread per connection, or an event loop with `workers` threads.
+    pub engine: Engine,
+    pub limits: Limits,
+    pub keep_alive: KeepAlive,
+    pub timeouts: Timeouts,
+    pub drain_timeout: Duration,
+    pub max_connections: usize,
+    pub rate_limit: Option<RateLimit>,
+    pub log: LogConfig,
+    pub file_cache: FileCacheConfig,
+    pub headers: Headers,
+    pub proxies: Vec<ProxyConfig>,
+    pub cgi: Vec<CgiConfig>,
+    pub uploads: Vec<UploadConfig>,
+    /// Paths that need a password or token, across every site.
+    pub auth: Vec<AuthConfig>,
+    pub tls: TlsConfig,
+    /// Sites chosen by `Host`; requests for other names get the main site.
+    pub vhosts: Vec<VhostConfig>,
+}
+
+/// A directory of CGI scripts served below a path prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CgiConfig {
+    /// Such as `/cgi-bin`; `/cgi-bin/hello` runs `hello` from `dir`.
+    pub prefix: String,
+    pub dir: PathBuf,
+    pub timeout: Duration,
+}
+
+impl CgiConfig {
+    /// Add these scripts' routes to `router`.
+    pub fn mount(&self, router: Router) -> io::Result<Router> {
+        Ok(Cgi::new(&self.dir)?
+            .timeout(self.timeout)
+            .mount(router, &self.prefix))
+    }
+}
+
+/// A directory served below a path prefix that clients can write to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UploadConfig {
+    /// Such as `/uploads`; `PUT /uploads/a.txt` stores `a.txt` in `dir`.
+    pub prefix: String,
+    pub dir: PathBuf,
+    /// `None` leaves only `limits.body`.
+    pub max_size: Option<usize>,
+    pub delete: bool,
+}
+
+impl UploadConfig {
+    /// Add this directory's routes to `router`.
+    pub fn mount(&self, router: Router) -> io::Result<Router> {
+        let mut uploads = Uploads::new(&self.dir)?.allow_delete(self.delete);
+        if let Some(max_size) = self.max_size {
+            uploads = uploads.max_size(max_size);
+        }
+        Ok(uploads.mount(router, &self.prefix))
+    }
+}
+
+/// Who may see a path prefix: users from an htpasswd file and/or bearer tokens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthConfig {
+    /// Such as `/inputs`; the prefix itself and every path below it are protected.
+    pub prefix: String,
+    pub realm: String,
+    pub htpasswd: Option<PathBuf>,
+    /// `None` lets in every user in the htpasswd file.
+    pub users: Option<Vec<String>>,
+    pub tokens: Vec<String>,
+}
+
+impl AuthConfig {
+    fn new(prefix: String) -> AuthConfig {
+        AuthConfig {
+            prefix,
+            realm: auth::DEFAULT_REALM.to_string(),
+            htpasswd: None,
+            users: None,
+            tokens: Vec::new(),
+        }
+    }
+
+    /// The rule these settings describe, reading the htpasswd file.
+    pub fn rule(&self) -> io::Result<Rule> {
+        let mut rule = Rule::new(&self.prefix).realm(&self.realm);
+        if let Some(path) = &self.htpasswd {
+            rule = rule.htpasswd(Htpasswd::load(path)?);
+        }
+        if let Some(users) = &self.users {
+            rule = rule.allow(users.clone());
+        }
+        for token in &self.tokens {
+            rule = rule.token(token);
+        }
+        Ok(rule)
+    }
+}
+
+/// A site served for some host names from its own document root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VhostConfig {
+    /// Exact names such as `aoc.localhost`, or wildcards such as `*.localhost`.
+    pub names: Vec<String>,
+    pub root: PathBuf,
+    /// `None` takes the main site's setting.
+    pub index: Option<Vec<String>>,
+    /// `None` takes the main site's setting.
+    pub directory_listing: Option<bool>,
+}
+
+/// HTTPS listeners; off while `listen` is empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub listen: Vec<String>,
+    /// Chosen by the name the client asks for; the first is the fallback.
+    pub certificates: Vec<CertFiles>,
+    /// Plain-HTTP addresses that only redirect to HTTPS.
+    pub redirect: Vec<String>,
+}
+
+/// The in-memory cache of file contents shared by every site.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileCacheConfig {
+    /// Total bytes kept; 0 turns the cache off.
+    pub size: usize,
+    /// Bigger files are always read from disk.
+    pub max_file: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    /// `None` disables the access log; `-` is stdout.
+    pub access: Option<PathBuf>,
+    pub format: LogFormat,
+    /// Rotate the log file once it reaches this size.
+    pub max_size: u64,
+    /// Rotated files kept, as `FILE.1` to `FILE.<keep>`.
+    pub keep: usize,
+}
+
+/// A path prefix forwarded to a pool of upstream servers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// Such as `/api`; the prefix itself and every path below it are forwarded.
+    pub prefix: String,
+    /// `host:port` of each upstream.
+    pub upstreams: Vec<String>,
+    pub balance: Balance,
+    pub timeout: Duration,
+    pub strip_prefix: bool,
+    /// Path to poll on every upstream, and how often.
+    pub health_check: Option<(String, Duration)>,
+}
+
+impl ProxyConfig {
+    fn new(prefix: String, upstreams: Vec<String>) -> ProxyConfig {
+        ProxyConfig {
+            prefix,
+            upstreams,
+            balance: Balance::default(),
+            timeout: proxy::DEFAULT_TIMEOUT,
+            strip_prefix: false,
+            health_check: None,
+        }
+    }
+
+    /// Add this proxy's routes to `router`, starting its health checks.
+    pub fn mount(&self, router: Router) -> Router {
+        let mut proxy = Proxy::new(&self.upstreams)
+            .balance(self.balance)
+            .timeout(self.timeout)
+            .strip_prefix(self.strip_prefix);
+        if let Some((path, interval)) = &self.health_check {
+            proxy = proxy.health_check(path, *interval);
+        }
+        proxy.mount(router, &self.prefix)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![DEFAULT_LISTEN.to_string()],
+            root: None,
+            index: None,
+            directory_listing: true,
+            watch: false,
+            workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            engine: Engine::default(),
+            limits: Limits::default(),
+            keep_alive: KeepAlive::default(),
+            timeouts: Timeouts::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            rate_limit: None,
+            log: LogConfig {
+                access: Some(PathBuf::from("-")),
+                format: LogFormat::Combined,
+                max_size: 10 * 1024 * 1024,
+                keep: 5,
+            },
+            file_cache: FileCacheConfig {
+                size: file_cache::DEFAULT_CAPACITY,
+                max_file: file_cache::DEFAULT_MAX_FILE,
+            },
+            headers: Headers::new(),
+            proxies: Vec::new(),
+            cgi: Vec::new(),
+            uploads: Vec::new(),
+            auth: Vec::new(),
+            tls: TlsConfig::default(),
+            vhosts: Vec::new(),
+        }
+    }
+}
+
+/// A setting that could not be used, with the key or flag it came from.
+#[derive(Debug)]
+pub struct ConfigError {
+    /// The config file, for errors found in one.
+    pub file: Option<PathBuf>,
+    /// Dotted key such as `limits.body`, or a flag such as `--workers`.
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            file: None,
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        if self.key.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+type Result<T> = std::result::Result<T, ConfigError>;
+
+impl Config {
+    /// Parse command-line arguments (without the program name).
+    ///
+    /// `--config` is read first wherever it appears, so every other flag
+    /// overrides the file.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config> {
+        let args: Vec<String> = args.into_iter().collect();
+        let mut config = match args.iter().position(|a| a == "--config" || a == "-c") {
+            Some(i) => {
+                let path = args
+                    .get(i + 1)
+                    .ok_or_else(|| ConfigError::new("--config", "expects a file"))?;
+                Config::load(path)?
+            }
+            None => Config::default(),
+        };
+
+        // the first of each repeatable flag replaces what the file set for it
+        let mut from_flags: Vec<&'static str> = Vec::new();
+        let mut replace = |list: &'static str| {
+            let first = !from_flags.contains(&list);
+            from_flags.push(list);
+            first
+        };
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ConfigError::new(&flag, "expects a value"))
+            };
+            match flag.as_str() {
+                "--config" | "-c" => {
+                    value()?;
+                }
+                "--listen" | "-l" => {
+                    let addr = value()?;
+                    check_addr(&addr).map_err(|e| ConfigError::new(&flag, e))?;
+                    if replace("listen") {
+                        config.listen.clear();
+                    }
+                    config.listen.push(addr);
+                }
+                "--tls-listen" | "--redirect-http" => {
+                    let addr = value()?;
+                    check_addr(&addr).map_err(|e| ConfigError::new(&flag, e))?;
+                    let (name, list) = match flag.as_str() {
+                        "--tls-listen" => ("tls.listen", &mut config.tls.listen),
+                        _ => ("tls.redirect", &mut config.tls.redirect),
+                    };
+                    if replace(name) {
+                        list.clear();
+                    }
+                    list.push(addr);
+                }
+                "--tls-cert" => {
+                    let pair = value()?;
+                    let (cert, key) = pair
+                        .split_once(',')
+                        .ok_or_else(|| ConfigError::new(&flag, "expects CERT,KEY"))?;
+                    if replace("tls.certificates") {
+                        config.tls.certificates.clear();
+                    }
+                    config.tls.certificates.push(CertFiles {
+                        cert: PathBuf::from(cert),
+                        key: PathBuf::from(key),
+                    });
+                }
+                "--root" | "-r" => config.root = Some(PathBuf::from(value()?)),
+                "--workers" | "-w" => {
+                    config.workers = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| ConfigError::new(&flag, "expects a positive number"))?;
+                }
+                "--engine" => {
+                    config.engine = value()?.parse().map_err(|e| ConfigError::new(&flag, e))?;
+                }
+                "--no-listing" => config.directory_listing = false,
+                "--watch" => config.watch = true,
+                "--idle-timeout" => {
+                    config.keep_alive.idle_timeout =
+                        parse_duration(&value()?).map_err(|e| ConfigError::new(&flag, e))?;
+                }
+                "--drain-timeout" => {
+                    config.drain_timeout =
+                        parse_duration(&value()?).map_err(|e| ConfigError::new(&flag, e))?;
+                }
+                "--max-body" => {
+                    config.limits.max_body = parse_size(&value()?)
+                        .and_then(to_usize)
+                        .map_err(|e| ConfigError::new(&flag, e))?;
+                }
+                "--file-cache" => {
+                    config.file_cache.size = parse_size(&value()?)
+                        .and_then(to_usize)
+                        .map_err(|e| ConfigError::new(&flag, e))?;
+                }
+                "--max-connections" => {
+                    config.max_connections = value()?
+                        .parse()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| ConfigError::new(&flag, "expects a positive number"))?;
+                }
+                "--rate-limit" => {
+                    config.rate_limit =
+                        Some(parse_rate(&value()?).map_err(|e| ConfigError::new(&flag, e))?);
+                }
+                "--access-log" => config.log.access = Some(PathBuf::from(value()?)),
+                "--no-access-log" => config.log.access = None,
+                "--log-format" => {
+                    config.log.format = value()?.parse().map_err(|e| ConfigError::new(&flag, e))?;
+                }
+                "--header" | "-H" => {
+                    let header = value()?;
+                    let (name, value) = header
+                        .split_once(':')
+                        .ok_or_else(|| ConfigError::new(&flag, "expects 'Name: value'"))?;
+                    let (name, value) = (name.trim(), value.trim());
+                    check_header(name, value).map_err(|e| ConfigError::new(&flag, e))?;
+                    config.headers.set(name, value);
+                }
+                "--vhost" => {
+                    let spec = value()?;
+                    let (names, root) = spec
+                        .split_once('=')
+                        .ok_or_else(|| ConfigError::new(&flag, "expects NAME[,NAME...]=DIR"))?;
+                    let names: Vec<String> =
+                        names.split(',').map(|n| n.trim().to_string()).collect();
+                    check_host_names(&names).map_err(|e| ConfigError::new(&flag, e))?;
+                    if replace("vhost") {
+                        config.vhosts.clear();
+                    }
+                    config.vhosts.push(VhostConfig {
+                        names,
+                        root: PathBuf::from(root),
+                        index: None,
+                        directory_listing: None,
+                    });
+                }
+                "--cgi" => {
+                    let spec = value()?;
+                    let (prefix, dir) = spec
+                        .split_once('=')
+                        .ok_or_else(|| ConfigError::new(&flag, "expects PREFIX=DIR"))?;
+                    check_prefix(prefix).map_err(|e| ConfigError::new(&flag, e))?;
+                    if replace("cgi") {
+                        config.cgi.clear();
+                    }
+                    config.cgi.push(CgiConfig {
+                        prefix: prefix.to_string(),
+                        dir: PathBuf::from(dir),
+                        timeout: cgi::DEFAULT_TIMEOUT,
+                    });
+                }
+                "--proxy" => {
+                    let spec = value()?;
+                    let (prefix, upstreams) = spec
+                        .split_once('=')
+                        .ok_or_else(|| ConfigError::new(&flag, "expects PREFIX=ADDR[,ADDR...]"))?;
+                    let upstreams: Vec<String> =
+                        upstreams.split(',').map(|a| a.trim().to_string()).collect();
+                    check_proxy(prefix, &upstreams).map_err(|e| ConfigError::new(&flag, e))?;
+                    if replace("proxy") {
+                        config.proxies.clear();
+                    }
+                    config
+                        .proxies
+                        .push(ProxyConfig::new(prefix.to_string(), upstreams));
+                }
+                "--upload" => {
+                    let spec = value()?;
+                    let (prefix, dir) = spec
+                        .split_once('=')
+                        .ok_or_else(|| ConfigError::new(&flag, "expects PREFIX=DIR"))?;
+                    check_prefix(prefix).map_err(|e| ConfigError::new(&flag, e))?;
+                    if replace("upload") {
+                        config.uploads.clear();
+                    }
+                    config.uploads.push(UploadConfig {
+                        prefix: prefix.to_string(),
+                        dir: PathBuf::from(dir),
+                        max_size: None,
+                        delete: false,
+                    });
+                }
+                "--auth" => {
+                    let spec = value()?;
+                    let (prefix, file) = spec
+                        .split_once('=')
+                        .ok_or_else(|| ConfigError::new(&flag, "expects PREFIX=FILE"))?;
+                    check_prefix(prefix).map_err(|e| ConfigError::new(&flag, e))?;
+                    let mut auth = AuthConfig::new(prefix.to_string());
+                    auth.htpasswd = Some(PathBuf::from(file));
+                    if replace("auth") {
+                        config.auth.clear();
+                    }
+                    config.auth.push(auth);
+                }
+                _ => return Err(ConfigError::new(&flag, "unrecognized option")),
+            }
+        }
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Checks that span several settings, made once the file and flags are combined.
+    fn check(&self) -> Result<()> {
+        if self.listen.is_empty() && self.tls.listen.is_empty() {
+            return Err(ConfigError::new("listen", "needs at least one address"));
+        }
+        if !self.tls.listen.is_empty() && self.tls.certificates.is_empty() {
+            return Err(ConfigError::new(
+                "tls.certificates",
+                "needs at least one certificate to serve HTTPS",
+            ));
+        }
+        if !self.tls.redirect.is_empty() && self.tls.listen.is_empty() {
+            return Err(ConfigError::new(
+                "tls.redirect",
+                "needs a tls.listen address to redirect to",
+            ));
+        }
+        for (i, upload) in self.uploads.iter().enumerate() {
+            if upload
+                .max_size
+                .is_some_and(|max| max > self.limits.max_body)
+            {
+                return Err(ConfigError::new(
+                    format!("upload[{i}].max_size"),
+                    format!(
+                        "is more than limits.body ({} bytes), which caps every request",
+                        self.limits.max_body
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a TOML config file. Relative paths in it are taken relative to
+    /// the file's own directory.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config> {
+        let path = path.as_ref();
+        let in_file = |mut e: ConfigError| {
+            e.file = Some(path.to_path_buf());
+            e
+        };
+        let text =
+            fs::read_to_string(path).map_err(|e| in_file(ConfigError::new("", e.to_string())))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        Config::from_toml(&text, base).map_err(in_file)
+    }
+
+    /// Parse TOML text, resolving relative paths against `base`.
+    pub fn from_toml(text: &str, base: &Path) -> Result<Config> {
+        let table: Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| ConfigError::new("", e.to_string().trim_end()))?;
+        let mut config = Config::default();
+        let mut keys = Keys::new(&table, "");
+
+        if let Some(value) = keys.take("listen") {
+            config.listen = addresses(value, "listen")?;
+        }
+        if let Some(value) = keys.take("root") {
+            config.root = Some(base.join(string(value, "root")?));
+        }
+        if let Some(value) = keys.take("index") {
+            config.index = Some(strings(value, "index")?);
+        }
+        if let Some(value) = keys.take("directory_listing") {
+            config.directory_listing = boolean(value, "directory_listing")?;
+        }
+        if let Some(value) = keys.take("watch") {
+            config.watch = boolean(value, "watch")?;
+        }
+        if let Some(value) = keys.take("workers") {
+            config.workers = integer(value, "workers").and_then(|n| positive(n, "workers"))?;
+        }
+        if let Some(value) = keys.take("engine") {
+            config.engine = string(value, "engine")?
+                .parse()
+                .map_err(|e| ConfigError::new("engine", e))?;
+        }
+
+        if let Some(table) = keys.table("timeouts")? {
+            let mut keys = Keys::new(table, "timeouts");
+            if let Some(value) = keys.take("idle") {
+                config.keep_alive.idle_timeout = duration(value, "timeouts.idle")?;
+            }
+            if let Some(value) = keys.take("drain") {
+                config.drain_timeout = duration(value, "timeouts.drain")?;
+            }
+            let timeouts = &mut config.timeouts;
+            for (key, field) in [
+                ("header", &mut timeouts.header),
+                ("body", &mut timeouts.body),
+                ("write", &mut timeouts.write),
+            ] {
+                if let Some(value) = keys.take(key) {
+                    *field = duration(value, &format!("timeouts.{key}"))?;
+                }
+            }
+            keys.finish()?;
+        }
+
+        if let Some(table) = keys.table("limits")? {
+            let mut keys = Keys::new(table, "limits");
+            let limits = &mut config.limits;
+            for (key, field) in [
+                ("request_line", &mut limits.max_request_line),
+                ("header_line", &mut limits.max_header_line),
+                ("body", &mut limits.max_body),
+            ] {
+                if let Some(value) = keys.take(key) {
+                    let key = format!("limits.{key}");
+                    *field = size(value, &key)
+                        .and_then(|n| to_usize(n).map_err(|e| ConfigError::new(&key, e)))?;
+                }
+            }
+            if let Some(value) = keys.take("headers") {
+                limits.max_headers =
+                    integer(value, "limits.headers").and_then(|n| positive(n, "limits.headers"))?;
+            }
+            if let Some(value) = keys.take("requests_per_connection") {
+                config.keep_alive.max_requests =
+                    integer(value, "limits.requests_per_connection")
+                        .and_then(|n| positive(n, "limits.requests_per_connection"))?;
+            }
+            if let Some(value) = keys.take("connection_bytes") {
+                config.keep_alive.max_bytes = size(value, "limits.connection_bytes")?;
+            }
+            if let Some(value) = keys.take("connections") {
+                config.max_connections = integer(value, "limits.connections")
+                    .and_then(|n| positive(n, "limits.connections"))?;
+            }
+            keys.finish()?;
+        }
+
+        if let Some(table) = keys.table("rate_limit")? {
+            let mut keys = Keys::new(table, "rate_limit");
+            let requests = keys
+                .take("requests")
+                .ok_or_else(|| ConfigError::new("rate_limit.requests", "is required"))
+                .and_then(|value| integer(value, "rate_limit.requests"))
+                .and_then(|n| positive(n, "rate_limit.requests"))?;
+            let per = match keys.take("per") {
+                Some(value) => duration(value, "rate_limit.per")?,
+                None => Duration::from_secs(1),
+            };
+            if per.is_zero() {
+                return Err(ConfigError::new("rate_limit.per", "must be longer than 0s"));
+            }
+            let mut limit = RateLimit::new(saturate(requests), per);
+            if let Some(value) = keys.take("burst") {
+                let burst = integer(value, "rate_limit.burst")
+                    .and_then(|n| positive(n, "rate_limit.burst"))?;
+                limit = limit.burst(saturate(burst));
+            }
+            config.rate_limit = Some(limit);
+            keys.finish()?;
+        }
+
+        if let Some(table) = keys.table("log")? {
+            let mut keys = Keys::new(table, "log");
+            if let Some(value) = keys.take("access") {
+                config.log.access = match value {
+                    Value::Boolean(false) => None,
+                    Value::String(path) if path == "-" => Some(PathBuf::from("-")),
+                    Value::String(path) => Some(base.join(path)),
+                    other => {
+                        return Err(wrong_type("log.access", "a file, \"-\" or false", other));
+                    }
+                };
+            }
+            if let Some(value) = keys.take("format") {
+                config.log.format = string(value, "log.format")?
+                    .parse()
+                    .map_err(|e| ConfigError::new("log.format", e))?;
+            }
+            if let Some(value) = keys.take("max_size") {
+                config.log.max_size = size(value, "log.max_size")?;
+            }
+            if let Some(value) = keys.take("keep") {
+                config.log.keep = integer(value, "log.keep")?;
+            }
+            keys.finish()?;
+        }
+
+        if let Some(table) = keys.table("file_cache")? {
+            let mut keys = Keys::new(table, "file_cache");
+            let file_cache = &mut config.file_cache;
+            for (key, field) in [
+                ("size", &mut file_cache.size),
+                ("max_file", &mut file_cache.max_file),
+            ] {
+                if let Some(value) = keys.take(key) {
+                    let key = format!("file_cache.{key}");
+                    *field = size(value, &key)
+                        .and_then(|n| to_usize(n).map_err(|e| ConfigError::new(&key, e)))?;
+                }
+            }
+            keys.finish()?;
+        }
+
+        if let Some(table) = keys.table("tls")? {
+            let mut keys = Keys::new(table, "tls");
+            if let Some(value) = keys.take("listen") {
+                config.tls.listen = addresses(value, "tls.listen")?;
+            }
+            if let Some(value) = keys.take("redirect") {
+                config.tls.redirect = addresses(value, "tls.redirect")?;
+            }
+            if let Some(value) = keys.take("certificates") {
+                let tables = value.as_array().ok_or_else(|| {
+                    wrong_type("tls.certificates", "a list of { cert, key } tables", value)
+                })?;
+                for (i, table) in tables.iter().enumerate() {
+                    let key = format!("tls.certificates[{i}]");
+                    let table = table
+                        .as_table()
+                        .ok_or_else(|| wrong_type(&key, "a { cert, key } table", table))?;
+                    let mut keys = Keys::new(table, &key);
+                    let mut path = |name| {
+                        let field = format!("{key}.{name}");
+                        keys.take(name)
+                            .ok_or_else(|| ConfigError::new(&field, "is required"))
+                            .and_then(|value| string(value, &field))
+                            .map(|path| base.join(path))
+                    };
+                    let files = CertFiles {
+                        cert: path("cert")?,
+                        key: path("key")?,
+                    };
+                    keys.finish()?;
+                    config.tls.certificates.push(files);
+                }
+            }
+            keys.finish()?;
+        }
+
+        if let Some(table) = keys.table("headers")? {
+            for (name, value) in table {
+                let key = format!("headers.{name}");
+                let value = string(value, &key)?;
+                check_header(name, value).map_err(|e| ConfigError::new(&key, e))?;
+                config.headers.add(name.clone(), value);
+            }
+        }
+
+        if let Some(value) = keys.take("proxy") {
+            let tables = value
+                .as_array()
+                .ok_or_else(|| wrong_type("proxy", "a list of [[proxy]] tables", value))?;
+            for (i, table) in tables.iter().enumerate() {
+                let key = format!("proxy[{i}]");
+                let table = table
+                    .as_table()
+                    .ok_or_else(|| wrong_type(&key, "a table", table))?;
+                config.proxies.push(proxy_config(table, &key)?);
+            }
+        }
+
+        if let Some(value) = keys.take("cgi") {
+            let tables = value
+                .as_array()
+                .ok_or_else(|| wrong_type("cgi", "a list of [[cgi]] tables", value))?;
+            for (i, table) in tables.iter().enumerate() {
+                let key = format!("cgi[{i}]");
+                let table = table
+                    .as_table()
+                    .ok_or_else(|| wrong_type(&key, "a table", table))?;
+                config.cgi.push(cgi_config(table, &key, base)?);
+            }
+        }
+
+        if let Some(value) = keys.take("upload") {
+            let tables = value
+                .as_array()
+                .ok_or_else(|| wrong_type("upload", "a list of [[upload]] tables", value))?;
+            for (i, table) in tables.iter().enumerate() {
+                let key = format!("upload[{i}]");
+                let table = table
+                    .as_table()
+                    .ok_or_else(|| wrong_type(&key, "a table", table))?;
+                config.uploads.push(upload_config(table, &key, base)?);
+            }
+        }
+
+        if let Some(value) = keys.take("auth") {
+            let tables = value
+                .as_array()
+                .ok_or_else(|| wrong_type("auth", "a list of [[auth]] tables", value))?;
+            for (i, table) in tables.iter().enumerate() {
+                let key = format!("auth[{i}]");
+                let table = table
+                    .as_table()
+                    .ok_or_else(|| wrong_type(&key, "a table", table))?;
+                config.auth.push(auth_config(table, &key, base)?);
+            }
+        }
+
+        if let Some(value) = keys.take("vhost") {
+            let tables = value
+                .as_array()
+                .ok_or_else(|| wrong_type("vhost", "a list of [[vhost]] tables", value))?;
+            for (i, table) in tables.iter().enumerate() {
+                let key = format!("vhost[{i}]");
+                let table = table
+                    .as_table()
+                    .ok_or_else(|| wrong_type(&key, "a table", table))?;
+                config.vhosts.push(vhost_config(table, &key, base)?);
+            }
+        }
+
+        keys.finish()?;
+        Ok(config)
+    }
+
+    /// The virtual hosts these settings describe, sending every other host to `main`.
+    pub fn virtual_hosts(
+        &self,
+        main: impl Handler,
+        cache: Option<&Arc<FileCache>>,
+    ) -> io::Result<VirtualHosts> {
+        let mut hosts = VirtualHosts::new();
+        for vhost in &self.vhosts {
+            let index = vhost.index.as_ref().or(self.index.as_ref());
+            let mut files = StaticFiles::new(&vhost.root)?
+                .directory_listing(vhost.directory_listing.unwrap_or(self.directory_listing))
+                .compression(self.compression());
+            if let Some(index) = index {
+                files = files.index_files(index.clone());
+            }
+            if let Some(cache) = cache {
+                files = files.file_cache(Arc::clone(cache));
+            }
+            // every name shares one handler
+            let files = Arc::new(files);
+            for name in &vhost.names {
+                let files = Arc::clone(&files);
+                hosts = hosts.host(name, move |request: &Request, params: &Params| {
+                    files.handle(request, params)
+                });
+            }
+        }
+        Ok(hosts.fallback(main))
+    }
+
+    /// `handler` in live-reload mode if `watch` is on, watching every document
+    /// root until `shutdown` is triggered. Changes also clear `cache`.
+    pub fn live_reload(
+        &self,
+        handler: impl Handler,
+        cache: Option<&Arc<FileCache>>,
+        shutdown: &ShutdownHandle,
+    ) -> io::Result<Box<dyn Handler>> {
+        if !self.watch {
+            return Ok(Box::new(handler));
+        }
+        let root = self.root.clone().unwrap_or_else(|| PathBuf::from("."));
+        let mut dirs = vec![root];
+        dirs.extend(self.vhosts.iter().map(|vhost| vhost.root.clone()));
+        let watcher = Watcher::start(dirs, watch::DEFAULT_INTERVAL)?;
+        watcher.stop_on(shutdown.clone());
+        if let Some(cache) = cache {
+            let cache = Arc::clone(cache);
+            watcher.on_change(move || cache.clear());
+        }
+        Ok(Box::new(LiveReload::new(handler, watcher)))
+    }
+
+    /// `handler` behind the `[[auth]]` rules these settings describe.
+    pub fn auth(&self, handler: impl Handler) -> io::Result<Auth> {
+        let mut auth = Auth::new(handler);
+        for config in &self.auth {
+            auth = auth.rule(config.rule()?);
+        }
+        Ok(auth)
+    }
+
+    /// The file cache these settings describe, unless its size is 0.
+    pub fn file_cache(&self) -> Option<Arc<FileCache>> {
+        let FileCacheConfig { size, max_file } = self.file_cache;
+        (size > 0).then(|| Arc::new(FileCache::new(size).max_file(max_file)))
+    }
+
+    /// The document root handler these settings describe.
+    pub fn static_files(&self, cache: Option<&Arc<FileCache>>) -> io::Result<StaticFiles> {
+        let files = match &self.root {
+            Some(root) => StaticFiles::new(root)?,
+            // without a root, keep serving the crate's hello.html as the home page
+            None => StaticFiles::new(".")?
+                .index_files(vec!["index.html".to_string(), "hello.html".to_string()]),
+        };
+        let files = match &self.index {
+            Some(index) => files.index_files(index.clone()),
+            None => files,
+        };
+        let files = match cache {
+            Some(cache) => files.file_cache(Arc::clone(cache)),
+            None => files,
+        };
+        Ok(files
+            .directory_listing(self.directory_listing)
+            .compression(self.compression()))
+    }
+
+    /// Live reload injects its script into pages, so they are sent uncompressed.
+    fn compression(&self) -> CompressionPolicy {
+        if self.watch {
+            CompressionPolicy::disabled()
+        } else {
+            CompressionPolicy::default()
+        }
+    }
+
+    /// The HTTPS setup these settings describe, if any TLS address is set.
+    pub fn tls(&self) -> io::Result<Option<Arc<Tls>>> {
+        if self.tls.listen.is_empty() {
+            return Ok(None);
+        }
+        let certs = CertStore::load(self.tls.certificates.clone())?;
+        Ok(Some(Arc::new(Tls::new(Arc::new(certs))?)))
+    }
+
+    /// The access log these settings describe, if logging is on.
+    pub fn access_log(&self) -> io::Result<Option<AccessLog>> {
+        Ok(match &self.log.access {
+            None => None,
+            Some(path) if path.as_os_str() == "-" => Some(AccessLog::stdout(self.log.format)),
+            Some(path) => Some(AccessLog::file(
+                path,
+                self.log.format,
+                self.log.max_size,
+                self.log.keep,
+            )?),
+        })
+    }
+}
+
+/// One address or a list of them, each checked to resolve.
+fn addresses(value: &Value, key: &str) -> Result<Vec<String>> {
+    let addrs = match value {
+        Value::String(addr) => vec![addr.clone()],
+        Value::Array(_) => strings(value, key)?,
+        other => {
+            return Err(wrong_type(key, "an address or list of addresses", other));
+        }
+    };
+    for (i, addr) in addrs.iter().enumerate() {
+        check_addr(addr).map_err(|e| ConfigError::new(format!("{key}[{i}]"), e))?;
+    }
+    Ok(addrs)
+}
+
+/// One `[[proxy]]` table; `key` is its position, such as `proxy[0]`.
+fn proxy_config(table: &Table, key: &str) -> Result<ProxyConfig> {
+    let mut keys = Keys::new(table, key);
+    let field = |name: &str| format!("{key}.{name}");
+    let prefix = keys
+        .take("prefix")
+        .ok_or_else(|| ConfigError::new(field("prefix"), "is required"))
+        .and_then(|value| string(value, &field("prefix")))?;
+    let upstreams = keys
+        .take("upstreams")
+        .ok_or_else(|| ConfigError::new(field("upstreams"), "is required"))
+        .and_then(|value| strings(value, &field("upstreams")))?;
+    check_proxy(prefix, &upstreams).map_err(|e| ConfigError::new(key, e))?;
+    let mut proxy = ProxyConfig::new(prefix.to_string(), upstreams);
+
+    if let Some(value) = keys.take("balance") {
+        proxy.balance = string(value, &field("balance"))?
+            .parse()
+            .map_err(|e| ConfigError::new(field("balance"), e))?;
+    }
+    if let Some(value) = keys.take("timeout") {
+        proxy.timeout = duration(value, &field("timeout"))?;
+    }
+    if let Some(value) = keys.take("strip_prefix") {
+        proxy.strip_prefix = boolean(value, &field("strip_prefix"))?;
+    }
+    let interval = match keys.take("health_interval") {
+        Some(value) => duration(value, &field("health_interval"))?,
+        None => Duration::from_secs(10),
+    };
+    if let Some(value) = keys.take("health_check") {
+        let path = string(value, &field("health_check"))?;
+        if !path.starts_with('/') {
+            return Err(ConfigError::new(
+                field("health_check"),
+                "must be a path starting with '/'",
+            ));
+        }
+        proxy.health_check = Some((path.to_string(), interval));
+    }
+    keys.finish()?;
+    Ok(proxy)
+}
+
+/// One `[[cgi]]` table; `key` is its position, such as `cgi[0]`.
+fn cgi_config(table: &Table, key: &str, base: &Path) -> Result<CgiConfig> {
+    let mut keys = Keys::new(table, key);
+    let field = |name: &str| format!("{key}.{name}");
+    let mut required = |name: &'static str| {
+        keys.take(name)
+            .ok_or_else(|| ConfigError::new(field(name), "is required"))
+            .and_then(|value| string(value, &field(name)))
+    };
+    let prefix = required("prefix")?;
+    check_prefix(prefix).map_err(|e| ConfigError::new(field("prefix"), e))?;
+    let dir = base.join(required("dir")?);
+    let timeout = match keys.take("timeout") {
+        Some(value) => duration(value, &field("timeout"))?,
+        None => cgi::DEFAULT_TIMEOUT,
+    };
+    keys.finish()?;
+    Ok(CgiConfig {
+        prefix: prefix.to_string(),
+        dir,
+        timeout,
+    })
+}
+
+/// One `[[upload]]` table; `key` is its position, such as `upload[0]`.
+fn upload_config(table: &Table, key: &str, base: &Path) -> Result<UploadConfig> {
+    let mut keys = Keys::new(table, key);
+    let field = |name: &str| format!("{key}.{name}");
+    let mut required = |name: &'static str| {
+        keys.take(name)
+            .ok_or_else(|| ConfigError::new(field(name), "is required"))
+            .and_then(|value| string(value, &field(name)))
+    };
+    let prefix = required("prefix")?;
+    check_prefix(prefix).map_err(|e| ConfigError::new(field("prefix"), e))?;
+    let mut upload = UploadConfig {
+        prefix: prefix.to_string(),
+        dir: base.join(required("dir")?),
+        max_size: None,
+        delete: false,
+    };
+    if let Some(value) = keys.take("max_size") {
+        let key = field("max_size");
+        upload.max_size = Some(
+            size(value, &key).and_then(|n| to_usize(n).map_err(|e| ConfigError::new(&key, e)))?,
+        );
+    }
+    if let Some(value) = keys.take("delete") {
+        upload.delete = boolean(value, &field("delete"))?;
+    }
+    keys.finish()?;
+    Ok(upload)
+}
+
+/// One `[[auth]]` table; `key` is its position, such as `auth[0]`.
+fn auth_config(table: &Table, key: &str, base: &Path) -> Result<AuthConfig> {
+    let mut keys = Keys::new(table, key);
+    let field = |name: &str| format!("{key}.{name}");
+    let prefix = keys
+        .take("prefix")
+        .ok_or_else(|| ConfigError::new(field("prefix"), "is required"))
+        .and_then(|value| string(value, &field("prefix")))?;
+    check_prefix(prefix).map_err(|e| ConfigError::new(field("prefix"), e))?;
+    let mut auth = AuthConfig::new(prefix.to_string());
+    if let Some(value) = keys.take("realm") {
+        let realm = string(value, &field("realm"))?;
+        if realm
+            .chars()
+            .any(|c| c == '"' || c == '\\' || c.is_control())
+        {
+            return Err(ConfigError::new(
+                field("realm"),
+                "must not contain quotes, backslashes or control characters",
+            ));
+        }
+        auth.realm = realm.to_string();
+    }
+    if let Some(value) = keys.take("htpasswd") {
+        auth.htpasswd = Some(base.join(string(value, &field("htpasswd"))?));
+    }
+    if let Some(value) = keys.take("users") {
+        auth.users = Some(strings(value, &field("users"))?);
+    }
+    if let Some(value) = keys.take("tokens") {
+        auth.tokens = strings(value, &field("tokens"))?;
+        if auth
+            .tokens
+            .iter()
+            .any(|t| t.is_empty() || t.contains(char::is_whitespace))
+        {
+            return Err(ConfigError::new(
+                field("tokens"),
+                "tokens must be non-empty and without spaces",
+            ));
+        }
+    }
+    if auth.htpasswd.is_none() && auth.tokens.is_empty() {
+        return Err(ConfigError::new(key, "needs an htpasswd file or tokens"));
+    }
+    if auth.users.is_some() && auth.htpasswd.is_none() {
+        return Err(ConfigError::new(field("users"), "needs an htpasswd file"));
+    }
+    keys.finish()?;
+    Ok(auth)
+}
+
+/// One `[[vhost]]` table; `key` is its position, such as `vhost[0]`.
+fn vhost_config(table: &Table, key: &str, base: &Path) -> Result<VhostConfig> {
+    let mut keys = Keys::new(table, key);
+    let field = |name: &str| format!("{key}.{name}");
+    let names = match keys.take("names") {
+        None => return Err(ConfigError::new(field("names"), "is required")),
+        Some(Value::String(name)) => vec![name.clone()],
+        Some(value) => strings(value, &field("names"))?,
+    };
+    check_host_names(&names).map_err(|e| ConfigError::new(field("names"), e))?;
+    let root = keys
+        .take("root")
+        .ok_or_else(|| ConfigError::new(field("root"), "is required"))
+        .and_then(|value| string(value, &field("root")))?;
+    let mut vhost = VhostConfig {
+        names,
+        root: base.join(root),
+        index: None,
+        directory_listing: None,
+    };
+    if let Some(value) = keys.take("index") {
+        vhost.index = Some(strings(value, &field("index"))?);
+    }
+    if let Some(value) = keys.take("directory_listing") {
+        vhost.directory_listing = Some(boolean(value, &field("directory_listing"))?);
+    }
+    keys.finish()?;
+    Ok(vhost)
+}
+
+/// Hands out a table's keys one at a time, so whatever is left over at the
+/// end can be reported as unknown. Catches typos like `[limit]` or `wokers`.
+struct Keys<'a> {
+    table: &'a Table,
+    prefix: &'a str,
+    seen: Vec<&'a str>,
+}
+
+impl<'a> Keys<'a> {
+    fn new(table: &'a Table, prefix: &'a str) -> Keys<'a> {
+        Keys {
+            table,
+            prefix,
+            seen: Vec::new(),
+        }
+    }
+
+    fn take(&mut self, key: &'a str) -> Option<&'a Value> {
+        self.seen.push(key);
+        self.table.get(key)
+    }
+
+    fn table(&mut self, key: &'a str) -> Result<Option<&'a Table>> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Table(table)) => Ok(Some(table)),
+            Some(other) => Err(wrong_type(key, "a table", other)),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        match self.table.keys().find(|k| !self.seen.contains(&k.as_str())) {
+            Some(key) if self.prefix.is_empty() => Err(ConfigError::new(key, "unknown key")),
+            Some(key) => Err(ConfigError::new(
+                format!("{}.{key}", self.prefix),
+                "unknown key",
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn wrong_type(key: &str, expected: &str, found: &Value) -> ConfigError {
+    ConfigError::new(
+        key,
+        format!("expected {expected}, found {}", found.type_str()),
+    )
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> Result<&'a str> {
+    value
+        .as_str()
+        .ok_or_else(|| wrong_type(key, "a string", value))
+}
+
+fn strings(value: &Value, key: &str) -> Result<Vec<String>> {
+    let array = value
+        .as_array()
+        .ok_or_else(|| wrong_type(key, "a list of strings", value))?;
+    array
+        .iter()
+        .enumerate()
+        .map(|(i, item)| string(item, &format!("{key}[{i}]")).map(str::to_string))
+        .collect()
+}
+
+fn boolean(value: &Value, key: &str) -> Result<bool> {
+    value
+        .as_bool()
+        .ok_or_else(|| wrong_type(key, "true or false", value))
+}
+
+fn integer(value: &Value, key: &str) -> Result<usize> {
+    let n = value
+        .as_integer()
+        .ok_or_else(|| wrong_type(key, "a whole number", value))?;
+    usize::try_from(n).map_err(|_| ConfigError::new(key, format!("must not be negative, got {n}")))
+}
+
+fn positive(n: usize, key: &str) -> Result<usize> {
+    if n == 0 {
+        return Err(ConfigError::new(key, "must be at least 1"));
+    }
+    Ok(n)
+}
+
+/// A size in bytes: a number, or a string such as `"64KiB"` or `"1MB"`.
+fn size(value: &Value, key: &str) -> Result<u64> {
+    match value {
+        Value::Integer(n) => u64::try_from(*n)
+            .map_err(|_| ConfigError::new(key, format!("must not be negative, got {n}"))),
+        Value::String(s) => parse_size(s).map_err(|e| ConfigError::new(key, e)),
+        other => Err(wrong_type(key, "a size such as 1048576 or \"1MiB\"", other)),
+    }
+}
+
+/// A duration: whole seconds, or a string such as `"500ms"`, `"30s"` or `"2m"`.
+fn duration(value: &Value, key: &str) -> Result<Duration> {
+    match value {
+        Value::Integer(n) => u64::try_from(*n)
+            .map(Duration::from_secs)
+            .map_err(|_| ConfigError::new(key, format!("must not be negative, got {n}"))),
+        Value::String(s) => parse_duration(s).map_err(|e| ConfigError::new(key, e)),
+        other => Err(wrong_type(key, "a duration such as 30 or \"500ms\"", other)),
+    }
+}
+
+/// Split `"1.5MiB"` into `1.5` and `"MiB"`.
+fn number_and_unit(s: &str) -> Option<(f64, &str)> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let number = s[..split].parse::<f64>().ok()?;
+    Some((number, s[split..].trim()))
+}
+
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let invalid = || format!("invalid size {s:?} (expected e.g. 65536, \"64KiB\" or \"1MB\")");
+    let (number, unit) = number_and_unit(s).ok_or_else(invalid)?;
+    let scale: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "kib" | "k" => 1 << 10,
+        "mb" => 1000 * 1000,
+        "mib" | "m" => 1 << 20,
+        "gb" => 1000 * 1000 * 1000,
+        "gib" | "g" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    Ok((number * scale as f64) as u64)
+}
+
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let invalid =
+        || format!("invalid duration {s:?} (expected e.g. 30, \"500ms\", \"30s\" or \"2m\")");
+    let (number, unit) = number_and_unit(s).ok_or_else(invalid)?;
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(invalid()),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| invalid())
+}
+
+/// A rate such as `10/s`, `100/1m` or `5/10s,20`: requests per duration, then an optional burst.
+pub fn parse_rate(s: &str) -> std::result::Result<RateLimit, String> {
+    let invalid = || format!("invalid rate {s:?} (expected e.g. 10/s, 100/1m or 10/s,50)");
+    let (rate, burst) = match s.split_once(',') {
+        Some((rate, burst)) => (rate, Some(burst.trim())),
+        None => (s, None),
+    };
+    let (requests, per) = rate.split_once('/').ok_or_else(invalid)?;
+    let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+    let per = per.trim();
+    // "10/s" means one second
+    let per = if per.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        parse_duration(&format!("1{per}"))?
+    } else {
+        parse_duration(per)?
+    };
+    if requests == 0 || per.is_zero() {
+        return Err(invalid());
+    }
+    let limit = RateLimit::new(requests, per);
+    match burst {
+        None => Ok(limit),
+        Some(burst) => match burst.parse::<u32>() {
+            Ok(burst) if burst > 0 => Ok(limit.burst(burst)),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+fn saturate(n: usize) -> u32 {
+    u32::try_from(n).unwrap_or(u32::MAX)
+}
+
+fn to_usize(n: u64) -> std::result::Result<usize, String> {
+    usize::try_from(n).map_err(|_| format!("{n} is too large"))
+}
+
+fn check_prefix(prefix: &str) -> std::result::Result<(), String> {
+    if !prefix.starts_with('/') {
+        return Err(format!("prefix {prefix:?} must start with '/'"));
+    }
+    Ok(())
+}
+
+fn check_proxy(prefix: &str, upstreams: &[String]) -> std::result::Result<(), String> {
+    check_prefix(prefix)?;
+    if upstreams.is_empty() {
+        return Err("needs at least one upstream".to_string());
+    }
+    for upstream in upstreams {
+        if upstream.contains("://") && !upstream.starts_with("http://") {
+            return Err(format!("upstream {upstream:?} must be plain http"));
+        }
+        check_addr(
+            upstream
+                .strip_prefix("http://")
+                .unwrap_or(upstream)
+                .trim_end_matches('/'),
+        )?;
+    }
+    Ok(())
+}
+
+/// Host names as `VirtualHosts` takes them: no port, and `*` only as a leading `*.`.
+fn check_host_names(names: &[String]) -> std::result::Result<(), String> {
+    if names.is_empty() {
+        return Err("needs at least one host name".to_string());
+    }
+    for name in names {
+        let domain = name.strip_prefix("*.").unwrap_or(name);
+        let valid = |b: u8| b.is_ascii_alphanumeric() || b"-._[]:".contains(&b);
+        let has_port = match domain.strip_prefix('[') {
+            Some(ipv6) => !ipv6.ends_with(']'),
+            None => domain.contains(':'),
+        };
+        if domain.is_empty() || !domain.bytes().all(valid) || has_port {
+            return Err(format!(
+                "{name:?} is not a host name (or *.domain) without a port"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// An address must parse as `host:port`; IPv6 literals go in brackets.
+fn check_addr(addr: &str) -> std::result::Result<(), String> {
+    match addr.to_socket_addrs() {
+        Ok(addrs) if addrs.len() > 0 => Ok(()),
+        Ok(_) => Err(format!("{addr:?} does not resolve to any address")),
+        Err(e) => Err(format!("{addr:?} is not a valid address ({e})")),
+    }
+}
+
+fn check_header(name: &str, value: &str) -> std::result::Result<(), String> {
+    let is_token = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    if name.is_empty() || !name.bytes().all(is_token) {
+        return Err(format!("{name:?} is not a valid header name"));
+    }
+    if value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0) {
+        return Err(format!("value for {name} contains a line break"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config> {
+        Config::from_toml(text, Path::new("/etc/host"))
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    fn args(args: &[&str]) -> Result<Config> {
+        Config::from_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parses_a_full_config() {
+        let config = parse(
+            r#"
+            listen = ["0.0.0.0:8080", "[::1]:8080"]
+            root = "public"
+            index = ["index.htm"]
+            directory_listing = false
+            watch = true
+            workers = 3
+            engine = "async"
+
+            [timeouts]
+            idle = "1500ms"
+            drain = 30
+            header = "2s"
+            write = 60
+
+            [limits]
+            request_line = "4KiB"
+            header_line = 2048
+            headers = 50
+            body = "2MB"
+            requests_per_connection = 10
+            connection_bytes = "1MiB"
+            connections = 50
+
+            [rate_limit]
+            requests = 100
+            per = "1m"
+
+            [log]
+            access = "logs/access.log"
+            format = "json"
+            max_size = "1MiB"
+            keep = 2
+
+            [file_cache]
+            size = "8MiB"
+            max_file = "256KiB"
+
+            [headers]
+            X-Frame-Options = "DENY"
+            "Strict-Transport-Security" = "max-age=3600"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen, ["0.0.0.0:8080", "[::1]:8080"]);
+        assert_eq!(config.root, Some(PathBuf::from("/etc/host/public")));
+        assert_eq!(config.index, Some(vec!["index.htm".to_string()]));
+        assert!(!config.directory_listing);
+        assert!(config.watch);
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.engine, Engine::Async);
+        assert_eq!(config.keep_alive.idle_timeout, Duration::from_millis(1500));
+        assert_eq!(config.drain_timeout, Duration::from_secs(30));
+        assert_eq!(
+            config.limits,
+            Limits {
+                max_request_line: 4096,
+                max_header_line: 2048,
+                max_headers: 50,
+                max_body: 2_000_000,
+            }
+        );
+        assert_eq!(config.keep_alive.max_requests, 10);
+        assert_eq!(config.keep_alive.max_bytes, 1 << 20);
+        assert_eq!(
+            config.timeouts,
+            Timeouts {
+                header: Duration::from_secs(2),
+                body: Timeouts::default().body,
+                write: Duration::from_secs(60),
+            }
+        );
+        assert_eq!(config.max_connections, 50);
+        assert_eq!(
+            config.file_cache,
+            FileCacheConfig {
+                size: 8 << 20,
+                max_file: 256 << 10,
+            }
+        );
+        assert_eq!(
+            config.rate_limit,
+            Some(RateLimit::new(100, Duration::from_secs(60)))
+        );
+        assert_eq!(
+            config.log,
+            LogConfig {
+                access: Some(PathBuf::from("/etc/host/logs/access.log")),
+                format: LogFormat::Json,
+                max_size: 1 << 20,
+                keep: 2,
+            }
+        );
+        assert_eq!(config.headers.get("x-frame-options"), Some("DENY"));
+        assert_eq!(config.headers.len(), 2);
+    }
+
+    #[test]
+    fn errors_name_the_offending_key() {
+        assert_eq!(error("wokers = 2"), "wokers: unknown key");
+        assert_eq!(
+            error("[limits]\nbody_size = 1"),
+            "limits.body_size: unknown key"
+        );
+        assert_eq!(error("workers = 0"), "workers: must be at least 1");
+        assert_eq!(
+            error("workers = \"four\""),
+            "workers: expected a whole number, found string"
+        );
+        assert_eq!(
+            error("limits = 5"),
+            "limits: expected a table, found integer"
+        );
+        assert!(error("listen = [\"127.0.0.1:80\", \"nowhere\"]").starts_with("listen[1]: "));
+        assert!(
+            error("[timeouts]\nidle = \"soon\"").starts_with("timeouts.idle: invalid duration")
+        );
+        assert!(error("[limits]\nbody = \"1 parsec\"").starts_with("limits.body: invalid size"));
+        assert!(error("[log]\nformat = \"xml\"").starts_with("log.format: unknown log format"));
+        assert!(error("engine = \"fibers\"").starts_with("engine: unknown engine"));
+        assert!(error("[headers]\n\"Bad Name\" = \"x\"").starts_with("headers.Bad Name: "));
+        assert!(error("workers = ").contains("line 1"));
+    }
+
+    #[test]
+    fn parses_proxies() {
+        let config = parse(
+            r#"
+            [[proxy]]
+            prefix = "/api"
+            upstreams = ["127.0.0.1:3000", "http://127.0.0.1:3001"]
+            balance = "least-connections"
+            timeout = "2s"
+            strip_prefix = true
+            health_check = "/healthz"
+
+            [[proxy]]
+            prefix = "/docs"
+            upstreams = ["127.0.0.1:4000"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.proxies.len(), 2);
+        let api = &config.proxies[0];
+        assert_eq!(api.upstreams, ["127.0.0.1:3000", "http://127.0.0.1:3001"]);
+        assert_eq!(api.balance, Balance::LeastConnections);
+        assert_eq!(api.timeout, Duration::from_secs(2));
+        assert!(api.strip_prefix);
+        assert_eq!(
+            api.health_check,
+            Some(("/healthz".to_string(), Duration::from_secs(10)))
+        );
+        assert_eq!(config.proxies[1].balance, Balance::RoundRobin);
+
+        assert_eq!(
+            error("[[proxy]]\nprefix = \"/api\""),
+            "proxy[0].upstreams: is required"
+        );
+        assert_eq!(
+            error("[[proxy]]\nprefix = \"/api\"\nupstreams = [\"https://x:1\"]"),
+            "proxy[0]: upstream \"https://x:1\" must be plain http"
+        );
+        assert_eq!(
+            error(
+                "[[proxy]]\nprefix = \"/a\"\nupstreams = [\"127.0.0.1:1\"]\nbalance = \"random\""
+            ),
+            "proxy[0].balance: unknown balancing \"random\" (expected round-robin or least-connections)"
+        );
+
+        let config = args(&["--proxy", "/svc=127.0.0.1:1,127.0.0.1:2"]).unwrap();
+        assert_eq!(config.proxies[0].prefix, "/svc");
+        assert_eq!(config.proxies[0].upstreams, ["127.0.0.1:1", "127.0.0.1:2"]);
+        assert!(args(&["--proxy", "svc=127.0.0.1:1"]).is_err());
+    }
+
+    #[test]
+    fn parses_tls_and_checks_it_is_complete() {
+        let config = parse(
+            r#"
+            listen = []
+            [tls]
+            listen = "127.0.0.1:8443"
+            redirect = ["127.0.0.1:8080"]
+            certificates = [
+                { cert = "certs/a.pem", key = "certs/a-key.pem" },
+                { cert = "/abs/b.pem", key = "/abs/b-key.pem" },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert!(config.listen.is_empty());
+        assert_eq!(config.tls.listen, ["127.0.0.1:8443"]);
+        assert_eq!(config.tls.redirect, ["127.0.0.1:8080"]);
+        assert_eq!(
+            config.tls.certificates[0],
+            CertFiles {
+                cert: PathBuf::from("/etc/host/certs/a.pem"),
+                key: PathBuf::from("/etc/host/certs/a-key.pem"),
+            }
+        );
+        assert_eq!(config.tls.certificates[1].cert, PathBuf::from("/abs/b.pem"));
+        assert_eq!(
+            error("[tls]\ncertificates = [{ cert = \"a.pem\" }]"),
+            "tls.certificates[0].key: is required"
+        );
+
+        let message = |a: &[&str]| args(a).unwrap_err().to_string();
+        assert_eq!(
+            message(&["--tls-listen", "127.0.0.1:8443"]),
+            "tls.certificates: needs at least one certificate to serve HTTPS"
+        );
+        assert_eq!(
+            message(&["--redirect-http", "127.0.0.1:8080"]),
+            "tls.redirect: needs a tls.listen address to redirect to"
+        );
+        let config = args(&[
+            "--tls-listen",
+            "127.0.0.1:8443",
+            "--tls-cert",
+            "c.pem,k.pem",
+        ])
+        .unwrap();
+        assert_eq!(config.tls.certificates[0].key, PathBuf::from("k.pem"));
+    }
+
+    #[test]
+    fn parses_cgi_directories() {
+        let config = parse(
+            r#"
+            [[cgi]]
+            prefix = "/cgi-bin"
+            dir = "cgi-bin"
+            timeout = "5s"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.cgi,
+            [CgiConfig {
+                prefix: "/cgi-bin".to_string(),
+                dir: PathBuf::from("/etc/host/cgi-bin"),
+                timeout: Duration::from_secs(5),
+            }]
+        );
+        assert_eq!(error("[[cgi]]\nprefix = \"/x\""), "cgi[0].dir: is required");
+        assert_eq!(
+            error("[[cgi]]\nprefix = \"x\"\ndir = \"d\""),
+            "cgi[0].prefix: prefix \"x\" must start with '/'"
+        );
+
+        let config = args(&["--cgi", "/bin=scripts"]).unwrap();
+        assert_eq!(config.cgi[0].dir, PathBuf::from("scripts"));
+        assert_eq!(config.cgi[0].timeout, cgi::DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn parses_upload_directories() {
+        let config = parse(
+            r#"
+            [limits]
+            body = "64MiB"
+
+            [[upload]]
+            prefix = "/uploads"
+            dir = "uploads"
+            max_size = "32MiB"
+            delete = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.uploads,
+            [UploadConfig {
+                prefix: "/uploads".to_string(),
+                dir: PathBuf::from("/etc/host/uploads"),
+                max_size: Some(32 << 20),
+                delete: true,
+            }]
+        );
+        let too_big = parse("[[upload]]\nprefix = \"/up\"\ndir = \"up\"\nmax_size = \"2MiB\"");
+        assert_eq!(
+            too_big
+                .and_then(|config| config.check())
+                .unwrap_err()
+                .to_string(),
+            "upload[0].max_size: is more than limits.body (1048576 bytes), which caps every request"
+        );
+
+        let config = args(&["--upload", "/up=up"]).unwrap();
+        assert_eq!(config.uploads[0].dir, PathBuf::from("up"));
+        assert!(!config.uploads[0].delete);
+    }
+
+    #[test]
+    fn parses_auth_rules() {
+        let config = parse(
+            r#"
+            [[auth]]
+            prefix = "/inputs"
+            realm = "puzzle inputs"
+            htpasswd = "host.htpasswd"
+            users = ["alice"]
+
+            [[auth]]
+            prefix = "/api/private"
+            tokens = ["s3cret"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.auth,
+            [
+                AuthConfig {
+                    prefix: "/inputs".to_string(),
+                    realm: "puzzle inputs".to_string(),
+                    htpasswd: Some(PathBuf::from("/etc/host/host.htpasswd")),
+                    users: Some(vec!["alice".to_string()]),
+                    tokens: Vec::new(),
+                },
+                AuthConfig {
+                    tokens: vec!["s3cret".to_string()],
+                    ..AuthConfig::new("/api/private".to_string())
+                },
+            ]
+        );
+        assert_eq!(
+            error("[[auth]]\nprefix = \"/x\""),
+            "auth[0]: needs an htpasswd file or tokens"
+        );
+        assert_eq!(
+            error("[[auth]]\nprefix = \"/x\"\ntokens = [\"a b\"]"),
+            "auth[0].tokens: tokens must be non-empty and without spaces"
+        );
+        assert_eq!(
+            error("[[auth]]\nprefix = \"/x\"\nrealm = \"a\\\"b\"\ntokens = [\"t\"]"),
+            "auth[0].realm: must not contain quotes, backslashes or control characters"
+        );
+
+        let config = args(&["--auth", "/notes=notes.htpasswd"]).unwrap();
+        assert_eq!(
+            config.auth[0].htpasswd,
+            Some(PathBuf::from("notes.htpasswd"))
+        );
+        assert_eq!(config.auth[0].realm, auth::DEFAULT_REALM);
+    }
+
+    #[test]
+    fn parses_virtual_hosts() {
+        let config = parse(
+            r#"
+            directory_listing = false
+            [[vhost]]
+            names = ["aoc.localhost", "*.aoc.localhost"]
+            root = "sites/aoc"
+            [[vhost]]
+            names = "docs.localhost"
+            root = "/srv/docs"
+            directory_listing = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.vhosts[0],
+            VhostConfig {
+                names: vec!["aoc.localhost".to_string(), "*.aoc.localhost".to_string()],
+                root: PathBuf::from("/etc/host/sites/aoc"),
+                index: None,
+                directory_listing: None,
+            }
+        );
+        assert_eq!(config.vhosts[1].names, ["docs.localhost"]);
+        assert_eq!(config.vhosts[1].directory_listing, Some(true));
+
+        assert_eq!(
+            error("[[vhost]]\nnames = []\nroot = \"x\""),
+            "vhost[0].names: needs at least one host name"
+        );
+        assert_eq!(
+            error("[[vhost]]\nnames = [\"aoc.localhost:7878\"]\nroot = \"x\""),
+            "vhost[0].names: \"aoc.localhost:7878\" is not a host name (or *.domain) without a port"
+        );
+        assert!(
+            error("[[vhost]]\nnames = [\"a.*\"]\nroot = \"x\"").starts_with("vhost[0].names: ")
+        );
+        assert_eq!(
+            error("[[vhost]]\nnames = \"a\""),
+            "vhost[0].root: is required"
+        );
+
+        let config = args(&["--vhost", "a.localhost,[::1]=site"]).unwrap();
+        assert_eq!(config.vhosts[0].names, ["a.localhost", "[::1]"]);
+        assert_eq!(config.vhosts[0].root, PathBuf::from("site"));
+        assert!(args(&["--vhost", "a.localhost"]).is_err());
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("host.toml");
+        fs::write(&file, "listen = \"127.0.0.1:9000\"\nworkers = 2\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        let config = args(&["--workers", "5", "--config", file]).unwrap();
+        assert_eq!(config.listen, ["127.0.0.1:9000"]);
+        assert_eq!(config.workers, 5);
+
+        let config = args(&[
+            "-c",
+            file,
+            "-l",
+            "[::1]:0",
+            "--listen",
+            "127.0.0.1:0",
+            "--no-access-log",
+            "-H",
+            "X-Served-By: host",
+            "--max-body",
+            "64KiB",
+            "--watch",
+            "--file-cache",
+            "0",
+            "--engine",
+            "async",
+        ])
+        .unwrap();
+        assert_eq!(config.listen, ["[::1]:0", "127.0.0.1:0"]);
+        assert_eq!(config.log.access, None);
+        assert_eq!(config.headers.get("X-Served-By"), Some("host"));
+        assert_eq!(config.limits.max_body, 65536);
+        assert!(config.watch);
+        assert!(config.file_cache().is_none());
+        assert_eq!(config.engine, Engine::Async);
+    }
+
+    #[test]
+    fn repeatable_flags_replace_the_files_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("host.toml");
+        fs::write(
+            &file,
+            r#"
+            [[vhost]]
+            names = ["old.localhost"]
+            root = "old"
+
+            [[cgi]]
+            prefix = "/cgi-bin"
+            dir = "old"
+
+            [[proxy]]
+            prefix = "/api"
+            upstreams = ["127.0.0.1:3000"]
+
+            [[upload]]
+            prefix = "/uploads"
+            dir = "old"
+
+            [[auth]]
+            prefix = "/notes"
+            htpasswd = "old.htpasswd"
+            "#,
+        )
+        .unwrap();
+        let file = file.to_str().unwrap();
+        let with = |flag: &str, value: &str| args(&["-c", file, flag, value]).unwrap();
+
+        let config = with("--vhost", "new.localhost=new");
+        assert_eq!(config.vhosts.len(), 1);
+        assert_eq!(config.vhosts[0].names, ["new.localhost"]);
+
+        let config = with("--cgi", "/cgi-bin=new");
+        assert_eq!(config.cgi.len(), 1);
+        assert_eq!(config.cgi[0].dir, PathBuf::from("new"));
+
+        // the same prefix twice would leave the file's route shadowing the flag's
+        let config = with("--proxy", "/api=127.0.0.1:4000");
+        assert_eq!(config.proxies.len(), 1);
+        assert_eq!(config.proxies[0].upstreams, ["127.0.0.1:4000"]);
+
+        let config = with("--upload", "/uploads=new");
+        assert_eq!(config.uploads.len(), 1);
+        assert_eq!(config.uploads[0].dir, PathBuf::from("new"));
+
+        let config = with("--auth", "/notes=new.htpasswd");
+        assert_eq!(config.auth.len(), 1);
+        assert_eq!(config.auth[0].htpasswd, Some(PathBuf::from("new.htpasswd")));
+
+        // later copies of a flag add to the first
+        let config = args(&["-c", file, "--cgi", "/a=a", "--cgi", "/b=b"]).unwrap();
+        assert_eq!(config.cgi.len(), 2);
+        // and the file's other lists are left alone
+        assert_eq!(config.vhosts[0].names, ["old.localhost"]);
+    }
+
+    #[test]
+    fn flag_errors_name_the_flag() {
+        let message = |a: &[&str]| args(a).unwrap_err().to_string();
+        assert_eq!(
+            message(&["--workers", "0"]),
+            "--workers: expects a positive number"
+        );
+        assert_eq!(message(&["--root"]), "--root: expects a value");
+        assert_eq!(message(&["--bogus"]), "--bogus: unrecognized option");
+        assert!(message(&["--listen", "7878"]).starts_with("--listen: "));
+        assert!(message(&["-c", "/nonexistent/host.toml"]).starts_with("/nonexistent/host.toml: "));
+    }
+
+    #[test]
+    fn parses_rate_limits() {
+        let second = Duration::from_secs(1);
+        assert_eq!(parse_rate("10/s"), Ok(RateLimit::new(10, second)));
+        assert_eq!(
+            parse_rate("5/10s, 20"),
+            Ok(RateLimit::new(5, second * 10).burst(20))
+        );
+        assert!(parse_rate("0/s").is_err());
+        assert!(parse_rate("10").is_err());
+        assert!(parse_rate("10/s,0").is_err());
+
+        let config = args(&["--rate-limit", "30/m", "--max-connections", "8"]).unwrap();
+        assert_eq!(config.rate_limit, Some(RateLimit::new(30, second * 60)));
+        assert_eq!(config.max_connections, 8);
+        assert_eq!(
+            error("[rate_limit]\nper = \"1s\""),
+            "rate_limit.requests: is required"
+        );
+        assert_eq!(
+            error("[rate_limit]\nrequests = 1\nper = 0"),
+            "rate_limit.per: must be longer than 0s"
+        );
+    }
+
+    #[test]
+    fn parses_sizes_and_durations() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("1.5 KiB"), Ok(1536));
+        assert_eq!(parse_size("10MB"), Ok(10_000_000));
+        assert!(parse_size("-1").is_err());
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("1 fortnight").is_err());
+    }
+}
@@ -0,0 +1,906 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::access_log::{AccessLog, LogEntry};
+use crate::handler::{Handler, Params};
+use crate::headers::Headers;
+use crate::pool::ThreadPool;
+use crate::rate_limit::RateLimiter;
+use crate::request::{Limits, Method, ParseError, Request, Version};
+use crate::response::{Response, Status};
+use crate::shutdown::ShutdownHandle;
+use crate::tls::{Tls, Transport};
+use crate::upgrade::{UpgradeFn, Upgraded};
+
+/// Bound on how long and how much we drain from a client after an error response.
+pub(crate) const LINGER_TIMEOUT: Duration = Duration::from_millis(500);
+pub(crate) const LINGER_BYTES: u64 = 64 * 1024;
+
+/// Persistent-connection policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeepAlive {
+    /// How long an open connection may sit between requests before it is closed.
+    pub idle_timeout: Duration,
+    /// Requests served on one connection before it is closed; 1 disables keep-alive.
+    pub max_requests: usize,
+    /// Bytes read from one connection, over all its requests, before it is
+    /// closed. Checked between requests; each request is bounded by [`Limits`].
+    pub max_bytes: u64,
+}
+
+impl Default for KeepAlive {
+    fn default() -> KeepAlive {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// How long a client may take over each part of an exchange, so slow or
+/// stalled clients cannot hold on to a worker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time to send the request line and headers, from connecting or, on a
+    /// kept-alive connection, from the request's first byte. Slower clients get 408.
+    pub header: Duration,
+    /// Time to send the whole body once the headers are in. Slower clients get 408.
+    pub body: Duration,
+    /// Longest a single write may block; a client that stops reading is dropped.
+    pub write: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            header: Duration::from_secs(10),
+            body: Duration::from_secs(30),
+            write: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Everything a worker needs to serve a connection, shared by all of them.
+pub(crate) struct Context {
+    pub handler: Arc<dyn Handler>,
+    pub limits: Limits,
+    pub keep_alive: KeepAlive,
+    pub timeouts: Timeouts,
+    /// Shared by every listener, so a client has one budget however it connects.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub shutdown: ShutdownHandle,
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Added to every response that does not set them itself.
+    pub headers: Headers,
+    /// Set for HTTPS listeners.
+    pub tls: Option<Arc<Tls>>,
+}
+
+/// Serve requests on `stream` until either side asks to close.
+///
+/// Requests are read and answered strictly one after another through a single
+/// `BufReader`, so pipelined requests that arrive in one packet stay buffered
+/// and their responses go out in request order.
+pub(crate) fn serve(stream: TcpStream, context: &Context, guard: &ConnectionGuard) {
+    let _ = stream.set_write_timeout(Some(context.timeouts.write));
+    let transport = match &context.tls {
+        None => Transport::Plain(&stream),
+        Some(tls) => match tls.accept(&stream) {
+            Ok(transport) => transport,
+            Err(e) => {
+                eprintln!("TLS setup failed: {e}");
+                return;
+            }
+        },
+    };
+    // responses are written through the reader's transport, past its buffer
+    let mut reader = BufReader::new(Timed::new(transport, &stream));
+    let mut served = 0;
+    let remote = stream.peer_addr().ok();
+
+    loop {
+        if served > 0 {
+            // nothing pipelined means we are idle until the client speaks again
+            if reader.buffer().is_empty() {
+                guard.set_idle(true);
+                if context.shutdown.is_triggered() {
+                    return;
+                }
+            }
+            reader
+                .get_mut()
+                .deadline(Some(context.keep_alive.idle_timeout));
+            // wait for the first byte here so a mid-request pause is not an idle timeout
+            let waiting = reader.fill_buf().map(|buf| buf.is_empty());
+            guard.set_idle(false);
+            match waiting {
+                Ok(false) => {}
+                Ok(true) | Err(_) => return,
+            }
+        }
+
+        let (time, started) = (SystemTime::now(), Instant::now());
+        let mut request = match read_request(&mut reader, context) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => {
+                // after a malformed request we cannot tell where the next one starts
+                if let Some(status) = failure_status(&e, reader.get_ref().received) {
+                    let written = error_response(status, context).write_to(reader.get_mut(), false);
+                    log(context, remote, time, started, None, status, &written);
+                    if written.is_ok() {
+                        // dropping the reader first lets TLS send its close_notify
+                        drop(reader);
+                        linger_close(&stream);
+                    }
+                }
+                return;
+            }
+        };
+        served += 1;
+        request.remote = remote;
+        request.secure = context.tls.is_some();
+
+        let Answer {
+            response,
+            keep_alive,
+            upgrade,
+        } = answer(&request, context, served, reader.get_ref().received);
+        let head_only = request.method == Method::Head;
+        if upgrade.is_some() || (is_open_ended(&response) && !head_only) {
+            // it could be open for hours: let another thread take this worker's jobs
+            ThreadPool::detach();
+        }
+        let status = response.status;
+        let written = response.write_to(reader.get_mut(), head_only);
+        log(
+            context,
+            remote,
+            time,
+            started,
+            Some(&request),
+            status,
+            &written,
+        );
+        if let Err(e) = written {
+            eprintln!("Failed to write response: {e}");
+            return;
+        }
+        if let Some(upgrade) = upgrade {
+            let Ok(owned) = stream.try_clone() else {
+                return;
+            };
+            // an upgraded connection counts as idle: shutdown closes its read
+            // side, which the new protocol sees as the client going away
+            guard.set_idle(true);
+            upgrade(Upgraded::new(owned, reader.buffer().to_vec()));
+            return;
+        }
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+/// A stream with no length, such as an event stream, runs as long as its producer likes.
+fn is_open_ended(response: &Response) -> bool {
+    response.stream.is_some() && !response.headers.contains("Content-Length")
+}
+
+/// What a request gets: its response, whether the connection stays open
+/// afterwards, and what takes the connection over after a `101`.
+pub(crate) struct Answer {
+    pub response: Response,
+    pub keep_alive: bool,
+    pub upgrade: Option<UpgradeFn>,
+}
+
+/// Answer `request`, the `served`th on a connection that has read `received`
+/// bytes so far: apply the rate limit, run the handler and set the
+/// connection headers.
+pub(crate) fn answer(request: &Request, context: &Context, served: usize, received: u64) -> Answer {
+    let limited = match (&context.rate_limiter, request.remote) {
+        (Some(limiter), Some(remote)) => limiter.check(remote.ip(), Instant::now()).err(),
+        _ => None,
+    };
+    let mut response = match limited {
+        Some(wait) => Response::error(Status::TOO_MANY_REQUESTS).with_header(
+            "Retry-After",
+            (wait.as_secs_f64().ceil() as u64).max(1).to_string(),
+        ),
+        None => context.handler.handle(request, &Params::new()),
+    };
+    if response.upgrade.is_some() && context.tls.is_some() {
+        // an upgraded connection is handed a plain socket, which TLS cannot offer
+        response = Response::error(Status::NOT_IMPLEMENTED);
+    }
+    add_headers(&mut response, &context.headers);
+    if response.stream.is_some() && request.version == Version::Http10 {
+        // HTTP/1.0 has no chunked coding; the end of the body is the end of the connection
+        response.headers.remove("Transfer-Encoding");
+    }
+    let upgrade = match response.status {
+        Status::SWITCHING_PROTOCOLS => response.upgrade.take(),
+        _ => None,
+    };
+    let keep_alive = upgrade.is_none()
+        && wants_keep_alive(request, &response)
+        && served < context.keep_alive.max_requests
+        && received < context.keep_alive.max_bytes
+        && !context.shutdown.is_triggered();
+    if upgrade.is_some() {
+        // the handler's `Connection: Upgrade` stands
+    } else if keep_alive {
+        let remaining = context.keep_alive.max_requests - served;
+        response.headers.set("Connection", "keep-alive");
+        response.headers.set(
+            "Keep-Alive",
+            format!(
+                "timeout={}, max={remaining}",
+                context.keep_alive.idle_timeout.as_secs()
+            ),
+        );
+    } else {
+        response.headers.set("Connection", "close");
+    }
+    Answer {
+        response,
+        keep_alive,
+        upgrade,
+    }
+}
+
+/// Status to answer a request that could not be read with, after `received`
+/// bytes on the connection; `None` when there is nobody to answer.
+pub(crate) fn failure_status(e: &ParseError, received: u64) -> Option<Status> {
+    match e {
+        // a client that connected and said nothing is simply let go
+        ParseError::Io(e) if e.kind() == ErrorKind::TimedOut => {
+            (received > 0).then_some(Status::REQUEST_TIMEOUT)
+        }
+        e => e.status(),
+    }
+}
+
+/// The response to a request that could not be read, which ends the connection.
+pub(crate) fn error_response(status: Status, context: &Context) -> Response {
+    let mut response = Response::error(status);
+    add_headers(&mut response, &context.headers);
+    response.headers.set("Connection", "close");
+    response
+}
+
+/// Record one exchange in the access log, if there is one. `request` is
+/// `None` for requests too malformed to parse.
+pub(crate) fn log(
+    context: &Context,
+    remote: Option<SocketAddr>,
+    time: SystemTime,
+    started: Instant,
+    request: Option<&Request>,
+    status: Status,
+    written: &io::Result<u64>,
+) {
+    let Some(log) = &context.access_log else {
+        return;
+    };
+    log.log(&LogEntry {
+        remote,
+        time,
+        request_line: request
+            .map(|request| format!("{} {} {}", request.method, request.target, request.version)),
+        status,
+        bytes: *written.as_ref().unwrap_or(&0),
+        referer: request
+            .and_then(|r| r.header("Referer"))
+            .map(str::to_string),
+        user_agent: request
+            .and_then(|r| r.header("User-Agent"))
+            .map(str::to_string),
+        duration: started.elapsed(),
+    });
+}
+
+/// Read a request, giving its head and then its body their own deadlines.
+fn read_request(
+    reader: &mut BufReader<Timed<'_>>,
+    context: &Context,
+) -> Result<Option<Request>, ParseError> {
+    // on a new connection this runs from connecting, so saying nothing is cut off too
+    reader.get_mut().deadline(Some(context.timeouts.header));
+    let Some(mut request) = Request::read_head(reader, &context.limits)? else {
+        return Ok(None);
+    };
+    reader.get_mut().deadline(Some(context.timeouts.body));
+    request.read_body(reader, &context.limits)?;
+    reader.get_mut().deadline(None);
+    Ok(Some(request))
+}
+
+/// A connection's transport with a deadline on reads and a count of bytes read.
+///
+/// Socket timeouts only bound each read, so a client trickling a byte at a
+/// time would never trip one; the deadline bounds them all together.
+pub(crate) struct Timed<'a> {
+    transport: Transport<'a>,
+    stream: &'a TcpStream,
+    deadline: Option<Instant>,
+    received: u64,
+}
+
+impl<'a> Timed<'a> {
+    fn new(transport: Transport<'a>, stream: &'a TcpStream) -> Timed<'a> {
+        Timed {
+            transport,
+            stream,
+            deadline: None,
+            received: 0,
+        }
+    }
+
+    /// Fail reads with `TimedOut` once `timeout` from now has passed; `None` waits forever.
+    fn deadline(&mut self, timeout: Option<Duration>) {
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        if self.deadline.is_none() {
+            let _ = self.stream.set_read_timeout(None);
+        }
+    }
+}
+
+impl Read for Timed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            self.stream.set_read_timeout(Some(left))?;
+        }
+        match self.transport.read(buf) {
+            Ok(n) => {
+                self.received += n as u64;
+                Ok(n)
+            }
+            // what an expired socket timeout looks like varies by platform
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(ErrorKind::TimedOut.into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Write for Timed<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transport.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+}
+
+/// Turn away a connection over the server's limit with 503, without
+/// tying up a worker. The request is not read, so this is best effort.
+pub(crate) fn reject(mut stream: TcpStream) {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_write_timeout(Some(LINGER_TIMEOUT));
+    let response = Response::error(Status::SERVICE_UNAVAILABLE)
+        .with_header("Retry-After", "1")
+        .with_header("Connection", "close");
+    if response.write_to(&mut stream, false).is_ok() {
+        let _ = stream.shutdown(Shutdown::Write);
+        // read what has already arrived, so closing does not reset the connection
+        let _ = stream.set_nonblocking(true);
+        let _ = io::copy(&mut (&stream).take(LINGER_BYTES), &mut io::sink());
+    }
+}
+
+/// Add configured headers the handler did not set itself.
+pub(crate) fn add_headers(response: &mut Response, headers: &Headers) {
+    let missing: Vec<(&str, &str)> = headers
+        .iter()
+        .filter(|(name, _)| !response.headers.contains(name))
+        .collect();
+    for (name, value) in missing {
+        response.headers.add(name, value);
+    }
+}
+
+/// HTTP/1.1 connections persist unless either side sends `Connection: close`;
+/// HTTP/1.0 ones only when the client asks with `Connection: keep-alive`.
+/// A stream with no length or chunking is delimited by closing, so never persists.
+fn wants_keep_alive(request: &Request, response: &Response) -> bool {
+    if response.headers.has_token("Connection", "close") {
+        return false;
+    }
+    let delimited = response.headers.has_token("Transfer-Encoding", "chunked")
+        || response.headers.contains("Content-Length");
+    if response.stream.is_some() && !delimited {
+        return false;
+    }
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+/// Close after an error without resetting the connection.
+///
+/// If the client is still sending (say, the body of a rejected upload), closing
+/// with unread data makes the kernel send RST, which can discard our response
+/// before the client reads it. Half-close and drain briefly instead.
+fn linger_close(stream: &TcpStream) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(LINGER_TIMEOUT));
+    let _ = io::copy(&mut stream.take(LINGER_BYTES), &mut io::sink());
+}
+
+/// Clones of every open stream, so shutdown can close idle ones and abort stragglers.
+#[derive(Default)]
+pub(crate) struct Connections {
+    open: Mutex<HashMap<u64, Tracked>>,
+    next_id: Mutex<u64>,
+}
+
+struct Tracked {
+    stream: TcpStream,
+    idle: bool,
+}
+
+impl Connections {
+    pub fn track(connections: &Arc<Connections>, stream: &TcpStream) -> ConnectionGuard {
+        let id = {
+            let mut next = connections.next_id.lock().unwrap();
+            *next += 1;
+            *next
+        };
+        // without a clone the connection can still be served, it just cannot be aborted
+        if let Ok(stream) = stream.try_clone() {
+            let tracked = Tracked {
+                stream,
+                idle: false,
+            };
+            connections.open.lock().unwrap().insert(id, tracked);
+        }
+        ConnectionGuard {
+            connections: Arc::clone(connections),
+            id,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    /// Wake connections parked between keep-alive requests so they close now.
+    pub fn close_idle(&self) {
+        for tracked in self.open.lock().unwrap().values().filter(|t| t.idle) {
+            let _ = tracked.stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Shut down every remaining connection, returning how many there were.
+    pub fn abort_all(&self) -> usize {
+        let mut open = self.open.lock().unwrap();
+        for tracked in open.values() {
+            let _ = tracked.stream.shutdown(Shutdown::Both);
+        }
+        let aborted = open.len();
+        open.clear();
+        aborted
+    }
+}
+
+/// Removes its connection from the registry when the handler finishes or panics.
+pub(crate) struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    fn set_idle(&self, idle: bool) {
+        let mut open = self.connections.open.lock().unwrap();
+        if let Some(tracked) = open.get_mut(&self.id) {
+            tracked.idle = idle;
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self
+            .connections
+            .open
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        open.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::shutdown::ShutdownSummary;
+    use std::{
+        io::Write,
+        net::SocketAddr,
+        thread::{self, JoinHandle},
+    };
+
+    fn start(keep_alive: KeepAlive) -> (SocketAddr, ShutdownHandle, JoinHandle<ShutdownSummary>) {
+        start_with(|server| server.keep_alive(keep_alive))
+    }
+
+    fn start_with(
+        configure: impl FnOnce(Server) -> Server,
+    ) -> (SocketAddr, ShutdownHandle, JoinHandle<ShutdownSummary>) {
+        let handler = |request: &Request, _: &Params| match request.path() {
+            "/stream" => Response::new(crate::response::Status::OK).with_stream(|body| {
+                for part in ["first ", "second"] {
+                    body.write_all(part.as_bytes())?;
+                    body.flush()?;
+                }
+                body.trailer("X-Parts", "2");
+                Ok(())
+            }),
+            "/flood" => Response::new(crate::response::Status::OK).with_stream(|body| {
+                loop {
+                    body.write_all(&[b'x'; 64 * 1024])?;
+                }
+            }),
+            _ => Response::html(request.target.clone()),
+        };
+        let server = Server::bind("127.0.0.1:0", 2, handler)
+            .unwrap()
+            .drain_timeout(Duration::from_secs(2));
+        let server = configure(server);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        (addr, shutdown, thread::spawn(move || server.run()))
+    }
+
+    fn connect(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        (stream, reader)
+    }
+
+    /// Read one response head and its `Content-Length` body.
+    fn read_response(reader: &mut BufReader<TcpStream>) -> (String, String) {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let length: usize = head
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-Length: "))
+            .map_or(0, |n| n.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    fn is_closed(reader: &mut BufReader<TcpStream>) -> bool {
+        matches!(reader.fill_buf(), Ok([]))
+    }
+
+    #[test]
+    fn http11_connections_stay_open() {
+        let (addr, shutdown, server) = start(KeepAlive::default());
+        let (mut stream, mut reader) = connect(addr);
+
+        for target in ["/one", "/two", "/three"] {
+            write!(stream, "GET {target} HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let (head, body) = read_response(&mut reader);
+            assert!(head.contains("Connection: keep-alive\r\n"), "{head}");
+            assert_eq!(body, target);
+        }
+
+        stream
+            .write_all(b"GET /last HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: close\r\n"), "{head}");
+        assert!(is_closed(&mut reader));
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn http10_needs_keep_alive_token() {
+        let (addr, shutdown, server) = start(KeepAlive::default());
+
+        let (mut stream, mut reader) = connect(addr);
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: close\r\n"), "{head}");
+        assert!(is_closed(&mut reader));
+
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: keep-alive\r\n"), "{head}");
+        stream.write_all(b"GET /again HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut reader).1, "/again");
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streams_are_chunked_for_http11_and_close_delimited_for_http10() {
+        let (addr, shutdown, server) = start(KeepAlive::default());
+
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"GET /stream HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+        assert!(head.contains("Connection: keep-alive\r\n"), "{head}");
+        let (body, trailers) =
+            crate::chunked::read_chunked(&mut reader, &Limits::default()).unwrap();
+        assert_eq!(body, b"first second");
+        assert_eq!(trailers.get("X-Parts"), Some("2"));
+        // the connection is still usable after the last chunk
+        stream
+            .write_all(b"GET /after HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        assert_eq!(read_response(&mut reader).1, "/after");
+
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(!head.contains("Transfer-Encoding"), "{head}");
+        assert!(head.contains("Connection: close\r\n"), "{head}");
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "first second");
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let (addr, shutdown, server) = start(KeepAlive::default());
+        let (mut stream, mut reader) = connect(addr);
+
+        stream
+            .write_all(
+                b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\n\r\n\
+                  POST /c HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nxyzGET /d HTTP/1.1\r\nHost: x\r\n\r\n",
+            )
+            .unwrap();
+        for expected in ["/a", "/b", "/c", "/d"] {
+            assert_eq!(read_response(&mut reader).1, expected);
+        }
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn max_requests_closes_the_connection() {
+        let (addr, shutdown, server) = start(KeepAlive {
+            max_requests: 2,
+            ..KeepAlive::default()
+        });
+        let (mut stream, mut reader) = connect(addr);
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Keep-Alive: timeout=5, max=1\r\n"), "{head}");
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: close\r\n"), "{head}");
+        assert!(is_closed(&mut reader));
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn idle_connections_time_out() {
+        let (addr, shutdown, server) = start(KeepAlive {
+            idle_timeout: Duration::from_millis(100),
+            ..KeepAlive::default()
+        });
+        let (mut stream, mut reader) = connect(addr);
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        read_response(&mut reader);
+        thread::sleep(Duration::from_millis(300));
+        assert!(is_closed(&mut reader));
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_closes_idle_keep_alive_connections_promptly() {
+        let (addr, shutdown, server) = start(KeepAlive::default());
+        let (mut stream, mut reader) = connect(addr);
+
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        read_response(&mut reader);
+        thread::sleep(Duration::from_millis(100));
+
+        shutdown.trigger();
+        let summary = server.join().unwrap();
+        assert_eq!(summary.aborted, 0);
+        assert!(summary.elapsed < Duration::from_secs(1), "{summary}");
+        assert!(is_closed(&mut reader));
+    }
+
+    fn timeouts(header: u64, body: u64, write: u64) -> Timeouts {
+        Timeouts {
+            header: Duration::from_millis(header),
+            body: Duration::from_millis(body),
+            write: Duration::from_millis(write),
+        }
+    }
+
+    #[test]
+    fn slow_headers_and_bodies_get_408() {
+        let (addr, shutdown, server) = start_with(|s| s.timeouts(timeouts(300, 300, 5000)));
+
+        // a header line every 100ms never lets a per-read timeout fire
+        let (mut stream, mut reader) = connect(addr);
+        let started = Instant::now();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        for i in 0..5 {
+            thread::sleep(Duration::from_millis(100));
+            let _ = write!(stream, "X-Slow-{i}: 1\r\n");
+        }
+        let (head, _) = read_response(&mut reader);
+        assert!(
+            head.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{head}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(is_closed(&mut reader));
+
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nab")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.starts_with("HTTP/1.1 408 "), "{head}");
+        assert!(is_closed(&mut reader));
+
+        // a client that never says anything is closed without a response
+        let (_stream, mut reader) = connect(addr);
+        assert!(is_closed(&mut reader));
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn clients_that_stop_reading_are_dropped() {
+        let (addr, shutdown, server) = start_with(|s| s.timeouts(timeouts(5000, 5000, 200)));
+        let (mut flooded, _unread) = connect(addr);
+        flooded
+            .write_all(b"GET /flood HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+
+        // both workers would be stuck on flooded clients without a write timeout
+        let (mut second, _unread_too) = connect(addr);
+        second
+            .write_all(b"GET /flood HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"GET /ok HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        assert_eq!(read_response(&mut reader).1, "/ok");
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn max_bytes_closes_the_connection() {
+        let (addr, shutdown, server) = start(KeepAlive {
+            max_bytes: 64,
+            ..KeepAlive::default()
+        });
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: keep-alive\r\n"), "{head}");
+
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: x\r\nX-Pad: {}\r\n\r\n",
+            "a".repeat(40)
+        )
+        .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: close\r\n"), "{head}");
+        assert!(is_closed(&mut reader));
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rate_limited_clients_get_429() {
+        use crate::rate_limit::RateLimit;
+        let (addr, shutdown, server) =
+            start_with(|s| s.rate_limit(RateLimit::new(1, Duration::from_secs(2)).burst(2)));
+        let (mut stream, mut reader) = connect(addr);
+
+        let mut heads = Vec::new();
+        for _ in 0..3 {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+                .unwrap();
+            heads.push(read_response(&mut reader).0);
+        }
+        assert!(heads[1].starts_with("HTTP/1.1 200 OK\r\n"), "{}", heads[1]);
+        let head = &heads[2];
+        assert!(
+            head.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("Retry-After: 2\r\n"), "{head}");
+        // the connection stays usable
+        assert!(head.contains("Connection: keep-alive\r\n"), "{head}");
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connections_over_the_limit_get_503() {
+        let (addr, shutdown, server) = start_with(|s| s.max_connections(1));
+        let (mut first, mut first_reader) = connect(addr);
+        first
+            .write_all(b"GET /first HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        assert_eq!(read_response(&mut first_reader).1, "/first");
+
+        let (mut second, mut reader) = connect(addr);
+        let _ = second.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        let (head, _) = read_response(&mut reader);
+        assert!(head.starts_with("HTTP/1.1 503 "), "{head}");
+        assert!(head.contains("Retry-After: 1\r\n"), "{head}");
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+}
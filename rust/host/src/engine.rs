@@ -0,0 +1,930 @@
+//! The async connection engine: every connection is a task on one tokio
+//! runtime, so a connection waiting for its next request costs some memory
+//! rather than a worker thread. Handlers stay blocking and run on the
+//! runtime's blocking pool, one request at a time per connection. Streamed
+//! bodies and upgraded connections, which last as long as the client likes,
+//! get threads of their own instead, so they cannot use that pool up.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, ErrorKind, Write},
+    net::{self, Shutdown},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context as TaskContext, Poll},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    runtime::Builder,
+    sync::{mpsc, oneshot, watch},
+    task,
+    time::{self, timeout},
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+use crate::chunked::ChunkedEnd;
+use crate::connection::{self, Answer, Context, LINGER_BYTES, LINGER_TIMEOUT};
+use crate::request::{self, Framing, Limits, Method, ParseError, Request};
+use crate::response::{Response, Status};
+use crate::server::POLL_INTERVAL;
+use crate::shutdown::{ShutdownHandle, ShutdownSummary};
+use crate::upgrade::Upgraded;
+
+/// How a [`Server`](crate::Server) runs its connections.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// Each connection has a worker thread to itself while it is open,
+    /// idle keep-alive time included.
+    #[default]
+    Threads,
+    /// Connections are tasks on an event loop and only take a thread while
+    /// their handler runs.
+    Async,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Engine, String> {
+        match s {
+            "threads" => Ok(Engine::Threads),
+            "async" => Ok(Engine::Async),
+            _ => Err(format!("unknown engine {s:?} (expected threads or async)")),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Engine::Threads => "threads",
+            Engine::Async => "async",
+        })
+    }
+}
+
+/// Bytes asked of the socket per read.
+const READ_SIZE: usize = 16 * 1024;
+
+/// Writes a streamed body may get ahead of the client before it blocks.
+const STREAM_BACKLOG: usize = 16;
+
+/// Serve `listeners` on a runtime with `workers` event-loop threads until
+/// `shutdown` is triggered, then drain like the threaded engine does.
+pub(crate) fn run(
+    listeners: Vec<(net::TcpListener, Arc<Context>)>,
+    workers: usize,
+    max_connections: usize,
+    drain_timeout: Duration,
+    shutdown: ShutdownHandle,
+) -> ShutdownSummary {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(workers)
+        .thread_name("host-async")
+        .enable_all()
+        .build()
+        .expect("Failed to start the async runtime");
+    let summary = runtime.block_on(accept_all(
+        listeners,
+        max_connections,
+        drain_timeout,
+        shutdown,
+    ));
+    // whatever outlived the drain timeout is dropped with the runtime
+    runtime.shutdown_background();
+    summary
+}
+
+async fn accept_all(
+    listeners: Vec<(net::TcpListener, Arc<Context>)>,
+    max_connections: usize,
+    drain_timeout: Duration,
+    shutdown: ShutdownHandle,
+) -> ShutdownSummary {
+    let open = Arc::new(Open::default());
+    let served = Arc::new(AtomicU64::new(0));
+    let (closing, closed) = watch::channel(false);
+
+    let mut accepting = Vec::new();
+    for (socket, context) in listeners {
+        let listener = match TcpListener::from_std(socket) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Cannot listen: {e}");
+                continue;
+            }
+        };
+        let accept = Accept {
+            open: Arc::clone(&open),
+            served: Arc::clone(&served),
+            closed: closed.clone(),
+            max_connections,
+        };
+        accepting.push(tokio::spawn(accept.run(listener, context)));
+    }
+
+    // signal handlers can only set a flag, so poll it
+    while !shutdown.is_triggered() {
+        time::sleep(POLL_INTERVAL).await;
+    }
+
+    // stop accepting before draining so clients see a refused connection
+    // rather than one that is never answered
+    for task in &accepting {
+        task.abort();
+    }
+    for task in accepting {
+        let _ = task.await;
+    }
+    let started = Instant::now();
+    let still_open = open.len();
+    let _ = closing.send(true);
+    open.close_upgraded();
+    let deadline = started + drain_timeout;
+    while open.len() > 0 && Instant::now() < deadline {
+        time::sleep(POLL_INTERVAL).await;
+    }
+    let aborted = open.abort_all();
+
+    ShutdownSummary {
+        served: served.load(Ordering::Relaxed),
+        drained: still_open.saturating_sub(aborted),
+        aborted,
+        elapsed: started.elapsed(),
+    }
+}
+
+/// What each listener's accept loop shares with the rest.
+struct Accept {
+    open: Arc<Open>,
+    served: Arc<AtomicU64>,
+    /// Becomes `true` once shutdown starts.
+    closed: watch::Receiver<bool>,
+    max_connections: usize,
+}
+
+impl Accept {
+    async fn run(self, listener: TcpListener, context: Arc<Context>) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // e.g. out of file descriptors; give connections time to close
+                    eprintln!("Connection failed: {e}");
+                    time::sleep(POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+            if self.open.len() >= self.max_connections {
+                // plain text would only confuse a TLS client
+                if context.tls.is_none() {
+                    tokio::spawn(reject(stream));
+                }
+                continue;
+            }
+            self.served.fetch_add(1, Ordering::Relaxed);
+            let guard = Open::track(&self.open);
+            tokio::spawn(serve(
+                stream,
+                Arc::clone(&context),
+                guard,
+                self.closed.clone(),
+            ));
+        }
+    }
+}
+
+/// Serve requests on `stream` until either side asks to close, as
+/// [`connection::serve`] does on a thread.
+async fn serve(
+    stream: TcpStream,
+    context: Arc<Context>,
+    guard: Guard,
+    mut closed: watch::Receiver<bool>,
+) {
+    let remote = stream.peer_addr().ok();
+    let mut conn = match &context.tls {
+        None => Conn::Plain(stream),
+        Some(tls) => {
+            let acceptor = TlsAcceptor::from(tls.config());
+            match timeout(context.timeouts.header, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => Conn::Tls(Box::new(stream)),
+                // a failed handshake is a client gone before its first request
+                Ok(Err(_)) | Err(_) => return,
+            }
+        }
+    };
+    // read but not yet parsed, which may include pipelined requests
+    let mut buf = Vec::new();
+    let mut received = 0;
+    let mut served = 0;
+
+    loop {
+        if served > 0 && buf.is_empty() {
+            if context.shutdown.is_triggered() {
+                break;
+            }
+            // wait for the first byte here so a mid-request pause is not an idle timeout
+            let idle = context.keep_alive.idle_timeout;
+            let read = tokio::select! {
+                read = timeout(idle, read_more(&mut conn, &mut buf)) => read,
+                _ = closed.wait_for(|closed| *closed) => break,
+            };
+            match read {
+                Ok(Ok(n)) if n > 0 => received += n as u64,
+                _ => break,
+            }
+        }
+
+        let (time, started) = (SystemTime::now(), Instant::now());
+        let mut request = match read_request(&mut conn, &mut buf, &mut received, &context).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                // after a malformed request we cannot tell where the next one starts
+                if let Some(status) = connection::failure_status(&e, received) {
+                    let response = connection::error_response(status, &context);
+                    let written = send(&mut conn, response, false, &context).await;
+                    connection::log(&context, remote, time, started, None, status, &written);
+                    if written.is_ok() {
+                        linger_close(conn).await;
+                        return;
+                    }
+                }
+                break;
+            }
+        };
+        served += 1;
+        request.remote = remote;
+        request.secure = context.tls.is_some();
+
+        // handlers block, so they run off the event loop
+        let answering = Arc::clone(&context);
+        let answered = task::spawn_blocking(move || {
+            let answer = connection::answer(&request, &answering, served, received);
+            (request, answer)
+        })
+        .await;
+        let Ok((request, answer)) = answered else {
+            eprintln!("Recovered from a panicking handler");
+            return;
+        };
+        let Answer {
+            response,
+            keep_alive,
+            upgrade,
+        } = answer;
+        let status = response.status;
+        let head_only = request.method == Method::Head;
+        let written = send(&mut conn, response, head_only, &context).await;
+        connection::log(
+            &context,
+            remote,
+            time,
+            started,
+            Some(&request),
+            status,
+            &written,
+        );
+        if let Err(e) = written {
+            eprintln!("Failed to write response: {e}");
+            return;
+        }
+        if let Some(upgrade) = upgrade {
+            // TLS connections are never upgraded, see `connection::answer`
+            let Conn::Plain(stream) = conn else {
+                return;
+            };
+            let Ok(stream) = stream.into_std() else {
+                return;
+            };
+            let _ = stream.set_nonblocking(false);
+            let _ = stream.set_write_timeout(Some(context.timeouts.write));
+            // shutdown closes its read side, which the new protocol sees as
+            // the client going away
+            guard.upgraded(&stream);
+            let _ = on_own_thread(move || upgrade(Upgraded::new(stream, buf))).await;
+            return;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+    // for TLS this sends close_notify, so the client knows nothing was cut off
+    let _ = timeout(LINGER_TIMEOUT, conn.shutdown()).await;
+}
+
+/// Read a request, giving its head and then its body their own deadlines.
+/// Bytes past the end of it stay in `buf` for the next one.
+///
+/// The head is parsed once it is all there, and the body decoded once the
+/// whole of it is; in between, each read only looks at the bytes it added.
+async fn read_request(
+    conn: &mut Conn,
+    buf: &mut Vec<u8>,
+    received: &mut u64,
+    context: &Context,
+) -> Result<Option<Request>, ParseError> {
+    let limits = &context.limits;
+    // on a new connection this runs from connecting, so saying nothing is cut off too
+    let mut deadline = time::Instant::now() + context.timeouts.header;
+    let mut head: Option<Head> = None;
+    // how much of `buf` has been looked through for a line end
+    let mut searched: usize = 0;
+    loop {
+        match &mut head {
+            None => {
+                // RFC 9112 §2.2: empty lines before a request are ignored, so drop
+                // them rather than let them pile up
+                let blank = buf
+                    .iter()
+                    .take_while(|&&b| b == b'\r' || b == b'\n')
+                    .count();
+                buf.drain(..blank);
+                searched = searched.saturating_sub(blank);
+
+                if worth_parsing(buf, searched, limits)
+                    && let Some(parsed) = parse_head(buf, limits)?
+                {
+                    head = Some(parsed);
+                    deadline = time::Instant::now() + context.timeouts.body;
+                    continue;
+                }
+                searched = buf.len();
+            }
+            Some(pending) => {
+                if let Some(end) = pending.end(buf, limits)? {
+                    let Head {
+                        mut request, len, ..
+                    } = head.take().expect("matched above");
+                    request.read_body(&mut &buf[len..end], limits)?;
+                    buf.drain(..end);
+                    return Ok(Some(request));
+                }
+            }
+        }
+
+        let n = match time::timeout_at(deadline, read_more(conn, buf)).await {
+            Ok(read) => read?,
+            Err(_) => return Err(ParseError::Io(ErrorKind::TimedOut.into())),
+        };
+        if n == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(ParseError::Io(ErrorKind::UnexpectedEof.into()));
+        }
+        *received += n as u64;
+    }
+}
+
+async fn read_more(conn: &mut Conn, buf: &mut Vec<u8>) -> io::Result<usize> {
+    buf.reserve(READ_SIZE);
+    conn.read_buf(buf).await
+}
+
+/// A parsed request head, waiting for its body.
+struct Head {
+    request: Request,
+    /// Bytes the head takes up at the start of the buffer.
+    len: usize,
+    body: Body,
+}
+
+enum Body {
+    Length(u64),
+    Chunked(ChunkedEnd),
+}
+
+impl Head {
+    /// Where the request ends in `buf`, once all of it has arrived.
+    fn end(&mut self, buf: &[u8], limits: &Limits) -> Result<Option<usize>, ParseError> {
+        match &mut self.body {
+            Body::Length(length) => {
+                let end = self.len + *length as usize;
+                Ok((buf.len() >= end).then_some(end))
+            }
+            Body::Chunked(scan) => Ok(scan.find(&buf[self.len..], limits)?.map(|n| self.len + n)),
+        }
+    }
+}
+
+/// Whether parsing the head could get further than last time: a line has
+/// ended since, or the one still arriving is already too long to allow.
+fn worth_parsing(buf: &[u8], searched: usize, limits: &Limits) -> bool {
+    if buf[searched..].contains(&b'\n') {
+        return true;
+    }
+    let line_start = buf[..searched]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    buf.len() - line_start > limits.max_request_line.max(limits.max_header_line) + 2
+}
+
+/// Parse the head at the start of `buf` with the same parser the threaded
+/// engine reads sockets with: running out of bytes is an `UnexpectedEof`
+/// there and just means "not yet" here.
+fn parse_head(buf: &[u8], limits: &Limits) -> Result<Option<Head>, ParseError> {
+    let mut rest = buf;
+    let request = match Request::read_head(&mut rest, limits) {
+        Ok(Some(request)) => request,
+        Ok(None) | Err(ParseError::Io(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let body = match request::framing(&request.headers, limits)? {
+        Framing::Length(length) => Body::Length(length),
+        Framing::Chunked => Body::Chunked(ChunkedEnd::default()),
+    };
+    Ok(Some(Head {
+        request,
+        len: buf.len() - rest.len(),
+        body,
+    }))
+}
+
+/// Write `response`, returning the body bytes sent.
+async fn send(
+    conn: &mut Conn,
+    response: Response,
+    head_only: bool,
+    context: &Context,
+) -> io::Result<u64> {
+    let write_timeout = context.timeouts.write;
+    // a stream is never run for HEAD, so that needs no thread either
+    if response.stream.is_none() || head_only {
+        let mut bytes = Vec::with_capacity(response.body.len() + 512);
+        let sent = response.write_to(&mut bytes, head_only)?;
+        write_all(conn, &bytes, write_timeout).await?;
+        return Ok(sent);
+    }
+
+    // the stream's producer blocks, so it writes from a thread into a
+    // channel; once the client is gone its next write fails
+    let (tx, mut rx) = mpsc::channel(STREAM_BACKLOG);
+    let producer = on_own_thread(move || response.write_to(ChannelWriter(tx), head_only));
+    while let Some(bytes) = rx.recv().await {
+        write_all(conn, &bytes, write_timeout).await?;
+    }
+    producer
+        .await
+        .unwrap_or_else(|_| Err(io::Error::other("stream producer failed")))
+}
+
+/// Start `f` on a new thread rather than the blocking pool, for work that
+/// may last as long as a client likes: a few hundred open streams would
+/// otherwise take every blocking thread, and handlers would wait for them.
+///
+/// The receiver fails if the thread could not start or `f` panicked.
+fn on_own_thread<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> oneshot::Receiver<T> {
+    let (tx, rx) = oneshot::channel();
+    let spawned = thread::Builder::new()
+        .name("host-stream".to_string())
+        .spawn(move || {
+            let _ = tx.send(f());
+        });
+    if let Err(e) = spawned {
+        eprintln!("Failed to spawn stream thread: {e}");
+    }
+    rx
+}
+
+async fn write_all(conn: &mut Conn, bytes: &[u8], write_timeout: Duration) -> io::Result<()> {
+    let write = async {
+        conn.write_all(bytes).await?;
+        conn.flush().await
+    };
+    timeout(write_timeout, write)
+        .await
+        .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))
+}
+
+/// Hands a blocking writer's bytes to the connection's task.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // sent right away, as the threaded engine writes straight to the socket
+        self.0
+            .blocking_send(buf.to_vec())
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Turn away a connection over the server's limit with 503.
+async fn reject(mut stream: TcpStream) {
+    let mut bytes = Vec::new();
+    let _ = Response::error(Status::SERVICE_UNAVAILABLE)
+        .with_header("Retry-After", "1")
+        .with_header("Connection", "close")
+        .write_to(&mut bytes, false);
+    if let Ok(Ok(())) = timeout(LINGER_TIMEOUT, stream.write_all(&bytes)).await {
+        linger_close(Conn::Plain(stream)).await;
+    }
+}
+
+/// Close after an error without resetting the connection: half-close, then
+/// read and discard what the client is still sending, for a while.
+async fn linger_close(mut conn: Conn) {
+    let _ = timeout(LINGER_TIMEOUT, conn.shutdown()).await;
+    let mut rest = conn.take(LINGER_BYTES);
+    let _ = timeout(
+        LINGER_TIMEOUT,
+        tokio::io::copy(&mut rest, &mut tokio::io::sink()),
+    )
+    .await;
+}
+
+/// The byte stream a connection is served over: the socket itself, or TLS on top of it.
+enum Conn {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Conn::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Conn::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Conn::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Conn::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Conn::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The open connections, with a socket for each upgraded one so shutdown
+/// can reach protocols that have left the event loop.
+#[derive(Default)]
+struct Open {
+    connections: Mutex<HashMap<u64, Option<net::TcpStream>>>,
+    next_id: AtomicU64,
+}
+
+impl Open {
+    fn track(open: &Arc<Open>) -> Guard {
+        let id = open.next_id.fetch_add(1, Ordering::Relaxed);
+        open.connections.lock().unwrap().insert(id, None);
+        Guard {
+            open: Arc::clone(open),
+            id,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    fn close_upgraded(&self) {
+        for stream in self.connections.lock().unwrap().values().flatten() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Shut down the upgraded connections left and forget every connection,
+    /// returning how many there were. The rest close with the runtime.
+    fn abort_all(&self) -> usize {
+        let mut connections = self.connections.lock().unwrap();
+        for stream in connections.values().flatten() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let aborted = connections.len();
+        connections.clear();
+        aborted
+    }
+}
+
+/// Removes its connection from [`Open`] when its task ends or is dropped.
+struct Guard {
+    open: Arc<Open>,
+    id: u64,
+}
+
+impl Guard {
+    fn upgraded(&self, stream: &net::TcpStream) {
+        // without a clone the connection still works, it just cannot be closed at shutdown
+        if let Ok(stream) = stream.try_clone()
+            && let Some(slot) = self.open.connections.lock().unwrap().get_mut(&self.id)
+        {
+            *slot = Some(stream);
+        }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let mut connections = self
+            .open
+            .connections
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        connections.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{KeepAlive, Timeouts};
+    use crate::handler::Params;
+    use crate::server::Server;
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::SocketAddr,
+        thread::{self, JoinHandle},
+    };
+
+    fn start_with(
+        configure: impl FnOnce(Server) -> Server,
+    ) -> (SocketAddr, ShutdownHandle, JoinHandle<ShutdownSummary>) {
+        let handler = |request: &Request, _: &Params| match request.path() {
+            "/stream" => Response::new(Status::OK).with_stream(|body| {
+                for part in ["first ", "second"] {
+                    body.write_all(part.as_bytes())?;
+                    body.flush()?;
+                }
+                Ok(())
+            }),
+            // open until the client goes away
+            "/hold" => Response::new(Status::OK).with_stream(|body| {
+                loop {
+                    body.write_all(b".")?;
+                    body.flush()?;
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }),
+            "/echo" => Response::new(Status::OK).with_body(request.body.clone()),
+            _ => Response::html(request.target.clone()),
+        };
+        // one thread for the event loop: nothing below may need more
+        let server = Server::bind("127.0.0.1:0", 1, handler)
+            .unwrap()
+            .engine(Engine::Async)
+            .drain_timeout(Duration::from_secs(2));
+        let server = configure(server);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        (addr, shutdown, thread::spawn(move || server.run()))
+    }
+
+    fn connect(addr: SocketAddr) -> (net::TcpStream, BufReader<net::TcpStream>) {
+        let stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        (stream, reader)
+    }
+
+    /// Read one response head and its `Content-Length` body.
+    fn read_response(reader: &mut BufReader<net::TcpStream>) -> (String, String) {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+            head.push_str(&line);
+        }
+        let length: usize = head
+            .lines()
+            .find_map(|l| l.strip_prefix("Content-Length: "))
+            .map_or(0, |n| n.parse().unwrap());
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    fn is_closed(reader: &mut BufReader<net::TcpStream>) -> bool {
+        matches!(reader.fill_buf(), Ok([]))
+    }
+
+    #[test]
+    fn parses_engine_names() {
+        assert_eq!("async".parse(), Ok(Engine::Async));
+        assert_eq!("threads".parse(), Ok(Engine::Threads));
+        assert!("fibers".parse::<Engine>().is_err());
+        assert_eq!(Engine::Async.to_string(), "async");
+    }
+
+    #[test]
+    fn serves_pipelined_and_split_requests() {
+        let (addr, shutdown, server) = start_with(|s| s);
+        let (mut stream, mut reader) = connect(addr);
+
+        stream
+            .write_all(
+                b"\r\nGET /a HTTP/1.1\r\nHost: x\r\n\r\nPOST /echo HTTP/1.1\r\nHost: x\r\n\
+                  Content-Length: 3\r\n\r\nxyzGET /b HTTP/1.1\r\nHost: x\r\n\r\n",
+            )
+            .unwrap();
+        for expected in ["/a", "xyz", "/b"] {
+            assert_eq!(read_response(&mut reader).1, expected);
+        }
+
+        // a chunked body arriving a piece at a time
+        let parts: [&[u8]; 4] = [
+            b"POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-",
+            b"Encoding: chunked\r\n\r\n5\r\nhel",
+            b"lo\r\n6\r\n world\r\n",
+            b"0\r\n\r\n",
+        ];
+        for part in parts {
+            stream.write_all(part).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        let (head, body) = read_response(&mut reader);
+        assert!(head.contains("Connection: keep-alive\r\n"), "{head}");
+        assert_eq!(body, "hello world");
+
+        stream
+            .write_all(b"GET /stream HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert!(rest.contains("Transfer-Encoding: chunked\r\n"), "{rest}");
+        assert!(
+            rest.ends_with("6\r\nfirst \r\n6\r\nsecond\r\n0\r\n\r\n"),
+            "{rest}"
+        );
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn large_bodies_arrive_in_small_pieces() {
+        let (addr, shutdown, server) = start_with(|s| {
+            s.limits(Limits {
+                max_body: 4 * 1024 * 1024,
+                ..Limits::default()
+            })
+        });
+        let (mut stream, mut reader) = connect(addr);
+        let body = "0123456789abcdef".repeat(128 * 1024);
+
+        let mut request = format!(
+            "POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(
+            b"POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n",
+        );
+        for piece in body.as_bytes().chunks(1000) {
+            request.extend_from_slice(format!("{:x}\r\n", piece.len()).as_bytes());
+            request.extend_from_slice(piece);
+            request.extend_from_slice(b"\r\n");
+        }
+        request.extend_from_slice(b"0\r\n\r\n");
+
+        let writer = thread::spawn(move || {
+            for piece in request.chunks(512) {
+                stream.write_all(piece).unwrap();
+            }
+            stream
+        });
+        for _ in 0..2 {
+            let (head, echoed) = read_response(&mut reader);
+            assert!(head.starts_with("HTTP/1.1 200 "), "{head}");
+            assert!(echoed == body, "echoed {} bytes", echoed.len());
+        }
+        drop(writer.join().unwrap());
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn idle_connections_do_not_hold_up_others() {
+        let (addr, shutdown, server) = start_with(|s| s);
+        // far more open connections than the one thread the engine has
+        let idle: Vec<_> = (0..50).map(|_| connect(addr)).collect();
+        for (mut stream, mut reader) in idle.into_iter().take(2) {
+            stream
+                .write_all(b"GET /early HTTP/1.1\r\nHost: x\r\n\r\n")
+                .unwrap();
+            assert_eq!(read_response(&mut reader).1, "/early");
+        }
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"GET /late HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        assert_eq!(read_response(&mut reader).1, "/late");
+
+        // and they are closed straight away at shutdown
+        shutdown.trigger();
+        let summary = server.join().unwrap();
+        assert_eq!(summary.aborted, 0);
+        assert!(summary.elapsed < Duration::from_secs(1), "{summary}");
+        assert!(is_closed(&mut reader));
+    }
+
+    #[test]
+    fn open_streams_do_not_hold_up_handlers() {
+        let (addr, shutdown, server) = start_with(|s| s);
+        // more than the 512 threads of tokio's blocking pool
+        let streams: Vec<_> = (0..600)
+            .map(|_| {
+                let (mut stream, mut reader) = connect(addr);
+                stream
+                    .write_all(b"GET /hold HTTP/1.1\r\nHost: x\r\n\r\n")
+                    .unwrap();
+                let (head, _) = read_response(&mut reader);
+                assert!(head.starts_with("HTTP/1.1 200 "), "{head}");
+                (stream, reader)
+            })
+            .collect();
+
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"GET /late HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        assert_eq!(read_response(&mut reader).1, "/late");
+
+        drop(streams);
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn slow_and_idle_clients_time_out() {
+        let (addr, shutdown, server) = start_with(|s| {
+            s.timeouts(Timeouts {
+                header: Duration::from_millis(300),
+                body: Duration::from_millis(300),
+                write: Duration::from_secs(5),
+            })
+            .keep_alive(KeepAlive {
+                idle_timeout: Duration::from_millis(100),
+                ..KeepAlive::default()
+            })
+        });
+
+        let (mut stream, mut reader) = connect(addr);
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        for i in 0..5 {
+            thread::sleep(Duration::from_millis(100));
+            let _ = write!(stream, "X-Slow-{i}: 1\r\n");
+        }
+        let (head, _) = read_response(&mut reader);
+        assert!(head.starts_with("HTTP/1.1 408 "), "{head}");
+        assert!(is_closed(&mut reader));
+
+        let (mut stream, mut reader) = connect(addr);
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        read_response(&mut reader);
+        thread::sleep(Duration::from_millis(300));
+        assert!(is_closed(&mut reader));
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+//! An in-memory cache of file contents for [`StaticFiles`](crate::StaticFiles).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+
+use crate::caching::ETag;
+use crate::compress::Encoding;
+use crate::files::read_file;
+
+pub const DEFAULT_CAPACITY: usize = 64 * 1024 * 1024;
+
+/// Files bigger than this are read from disk every time by default.
+pub const DEFAULT_MAX_FILE: usize = 1024 * 1024;
+
+/// A file as read from disk. Cloning `contents` shares it rather than
+/// copying it, so responses can be built from a cached file for free.
+#[derive(Clone, Debug)]
+pub struct CachedFile {
+    pub contents: Bytes,
+    pub modified: Option<SystemTime>,
+    /// The strong `ETag` of `contents`, hashed as the file is cached.
+    pub etag: Option<ETag>,
+}
+
+/// What a [`FileCache`] holds and how well it is doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// Contents and compressed copies together.
+    pub bytes: usize,
+}
+
+/// Recently served files, kept in memory up to a total size; the least
+/// recently used go first when it fills up.
+///
+/// Every read checks the file's size and modification time, so an edited
+/// file is read again. [`clear`](FileCache::clear) drops everything, e.g.
+/// when a [`Watcher`](crate::Watcher) sees a change.
+pub struct FileCache {
+    capacity: usize,
+    max_file: usize,
+    state: Mutex<State>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    /// Paths by last use, oldest first.
+    order: BTreeMap<u64, PathBuf>,
+    clock: u64,
+    bytes: usize,
+}
+
+struct Entry {
+    file: Arc<CachedFile>,
+    /// Compressed copies, by encoding and level.
+    variants: Vec<(Encoding, u32, Bytes)>,
+    used: u64,
+    bytes: usize,
+}
+
+impl FileCache {
+    /// A cache holding up to `capacity` bytes.
+    pub fn new(capacity: usize) -> FileCache {
+        FileCache {
+            capacity,
+            max_file: DEFAULT_MAX_FILE.min(capacity),
+            state: Mutex::new(State::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Leave files bigger than `bytes` out, so one large download cannot
+    /// push out everything else.
+    pub fn max_file(mut self, bytes: usize) -> FileCache {
+        self.max_file = bytes.min(self.capacity);
+        self
+    }
+
+    /// Whether a file of `len` bytes would be kept.
+    pub fn keeps(&self, len: u64) -> bool {
+        len <= self.max_file as u64
+    }
+
+    /// The contents of `path`, from memory if they are still current.
+    pub fn read(&self, path: &Path) -> io::Result<Arc<CachedFile>> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified().ok();
+        if let Some(file) = self
+            .state
+            .lock()
+            .unwrap()
+            .get(path, metadata.len(), modified)
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(file);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let (contents, modified) = read_file(path)?;
+        let file = Arc::new(CachedFile {
+            etag: Some(ETag::from_contents(&contents)),
+            contents: contents.into(),
+            modified,
+        });
+        // without a modification time there is no telling when it goes stale
+        if file.contents.len() <= self.max_file && file.modified.is_some() {
+            let mut state = self.state.lock().unwrap();
+            state.insert(path, Arc::clone(&file));
+            state.evict(self.capacity);
+        }
+        Ok(file)
+    }
+
+    /// `file` compressed with `encoding` at `level`, by `encode` the first
+    /// time and from memory after that while `file` stays cached.
+    pub fn compressed(
+        &self,
+        path: &Path,
+        file: &Arc<CachedFile>,
+        encoding: Encoding,
+        level: u32,
+        encode: impl FnOnce(&[u8]) -> io::Result<Vec<u8>>,
+    ) -> io::Result<Bytes> {
+        if let Some(entry) = self.state.lock().unwrap().current(path, file)
+            && let Some((_, _, bytes)) = entry
+                .variants
+                .iter()
+                .find(|(e, l, _)| (*e, *l) == (encoding, level))
+        {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(bytes.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let bytes = Bytes::from(encode(&file.contents)?);
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.current(path, file)
+            && !entry
+                .variants
+                .iter()
+                .any(|(e, l, _)| (*e, *l) == (encoding, level))
+        {
+            entry.variants.push((encoding, level, bytes.clone()));
+            entry.bytes += bytes.len();
+            state.bytes += bytes.len();
+            state.evict(self.capacity);
+        }
+        Ok(bytes)
+    }
+
+    /// Forget every file.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.order.clear();
+        state.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: state.entries.len(),
+            bytes: state.bytes,
+        }
+    }
+}
+
+impl fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCache")
+            .field("capacity", &self.capacity)
+            .field("max_file", &self.max_file)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl State {
+    /// The cached file at `path` if it still has this size and modification
+    /// time, marked as just used. A stale one is dropped.
+    fn get(
+        &mut self,
+        path: &Path,
+        len: u64,
+        modified: Option<SystemTime>,
+    ) -> Option<Arc<CachedFile>> {
+        let entry = self.entries.get(path)?;
+        if entry.file.contents.len() as u64 != len || entry.file.modified != modified {
+            self.remove(path);
+            return None;
+        }
+        self.touch(path);
+        self.entries.get(path).map(|entry| Arc::clone(&entry.file))
+    }
+
+    /// The entry for `path`, if it still holds `file` itself.
+    fn current(&mut self, path: &Path, file: &Arc<CachedFile>) -> Option<&mut Entry> {
+        let current = self
+            .entries
+            .get(path)
+            .is_some_and(|entry| Arc::ptr_eq(&entry.file, file));
+        if !current {
+            return None;
+        }
+        self.touch(path);
+        self.entries.get_mut(path)
+    }
+
+    fn touch(&mut self, path: &Path) {
+        self.clock += 1;
+        let entry = self
+            .entries
+            .get_mut(path)
+            .expect("touched an entry not cached");
+        self.order.remove(&entry.used);
+        entry.used = self.clock;
+        self.order.insert(self.clock, path.to_path_buf());
+    }
+
+    fn insert(&mut self, path: &Path, file: Arc<CachedFile>) {
+        self.remove(path);
+        self.clock += 1;
+        let bytes = file.contents.len();
+        self.entries.insert(
+            path.to_path_buf(),
+            Entry {
+                file,
+                variants: Vec::new(),
+                used: self.clock,
+                bytes,
+            },
+        );
+        self.order.insert(self.clock, path.to_path_buf());
+        self.bytes += bytes;
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.order.remove(&entry.used);
+            self.bytes -= entry.bytes;
+        }
+    }
+
+    /// Drop the least recently used files until the rest fit in `capacity`.
+    fn evict(&mut self, capacity: usize) {
+        while self.bytes > capacity {
+            let Some((_, path)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&path) {
+                self.bytes -= entry.bytes;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn reads_each_file_once_until_it_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("page.html");
+        fs::write(&path, "one").unwrap();
+        let cache = FileCache::new(1024);
+
+        let first = cache.read(&path).unwrap();
+        let second = cache.read(&path).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.etag, Some(ETag::from_contents(b"one")));
+
+        // some filesystems keep coarse modification times
+        thread::sleep(Duration::from_millis(20));
+        fs::write(&path, "two").unwrap();
+        assert_eq!(cache.read(&path).unwrap().contents, &b"two"[..]);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                entries: 1,
+                bytes: 3,
+            }
+        );
+
+        cache.clear();
+        assert_eq!(cache.stats().entries, 0);
+        fs::remove_file(&path).unwrap();
+        assert!(cache.read(&path).is_err());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        for (name, size) in [("a", 40), ("b", 40), ("c", 40), ("big", 60)] {
+            fs::write(path(name), vec![b'x'; size]).unwrap();
+        }
+        let cache = FileCache::new(100).max_file(50);
+
+        cache.read(&path("a")).unwrap();
+        cache.read(&path("b")).unwrap();
+        cache.read(&path("a")).unwrap();
+        // "b" is the older of the two now
+        cache.read(&path("c")).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (2, 80));
+        cache.read(&path("a")).unwrap();
+        cache.read(&path("c")).unwrap();
+        assert_eq!(cache.stats().hits, 3);
+
+        // too big to keep
+        cache.read(&path("big")).unwrap();
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn keeps_compressed_copies_with_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.js");
+        fs::write(&path, "let x = 1;").unwrap();
+        let cache = FileCache::new(1024);
+        let file = cache.read(&path).unwrap();
+
+        let mut runs = 0;
+        let mut gzip = || {
+            cache
+                .compressed(&path, &file, Encoding::Gzip, 6, |bytes| {
+                    runs += 1;
+                    Ok(bytes.to_ascii_uppercase())
+                })
+                .unwrap()
+        };
+        assert_eq!(gzip(), &b"LET X = 1;"[..]);
+        assert_eq!(gzip(), &b"LET X = 1;"[..]);
+        assert_eq!(runs, 1);
+        assert_eq!(cache.stats().bytes, 20);
+    }
+}
@@ -0,0 +1,987 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+
+use crate::caching::{self, CachePolicy, ETagKind, Validators};
+use crate::compress::{self, CompressionPolicy, Encoding};
+use crate::file_cache::{CachedFile, FileCache};
+use crate::handler::{Handler, Params};
+use crate::listing;
+use crate::mime;
+use crate::range;
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+use crate::url::percent_decode;
+
+/// Files bigger than this are streamed from disk rather than read into
+/// memory, unless the file cache keeps files this big.
+pub const STREAM_ABOVE: u64 = 1024 * 1024;
+
+/// Serves files from beneath a document root.
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Vec<String>,
+    cache: CachePolicy,
+    compression: CompressionPolicy,
+    listing: bool,
+    upload_form: bool,
+    file_cache: Option<Arc<FileCache>>,
+}
+
+/// Where a request path landed inside the document root.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resolved {
+    File(PathBuf),
+    /// A directory whose URL already ends in `/`.
+    Dir(PathBuf),
+    /// A directory requested without its trailing slash; relative links
+    /// inside it only work once the client is sent to the slashed URL.
+    AddSlash,
+}
+
+impl StaticFiles {
+    /// Serve `root`, which must be an existing directory.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<StaticFiles> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(StaticFiles {
+            root,
+            index: vec!["index.html".to_string()],
+            cache: CachePolicy::default(),
+            compression: CompressionPolicy::default(),
+            listing: false,
+            upload_form: false,
+            file_cache: None,
+        })
+    }
+
+    /// File names tried, in order, when a directory is requested.
+    pub fn index_files(mut self, names: Vec<String>) -> StaticFiles {
+        self.index = names;
+        self
+    }
+
+    /// How `ETag`, `Last-Modified` and `Cache-Control` are sent.
+    pub fn cache_policy(mut self, policy: CachePolicy) -> StaticFiles {
+        self.cache = policy;
+        self
+    }
+
+    /// When responses are compressed or served from `.gz`/`.br` siblings.
+    pub fn compression(mut self, policy: CompressionPolicy) -> StaticFiles {
+        self.compression = policy;
+        self
+    }
+
+    /// List the contents of directories that have no index file, instead of 404.
+    pub fn directory_listing(mut self, enabled: bool) -> StaticFiles {
+        self.listing = enabled;
+        self
+    }
+
+    /// Keep file contents in `cache`, which can be shared with other handlers.
+    pub fn file_cache(mut self, cache: Arc<FileCache>) -> StaticFiles {
+        self.file_cache = Some(cache);
+        self
+    }
+
+    /// Put an upload form on directory listings.
+    pub(crate) fn upload_form(mut self, enabled: bool) -> StaticFiles {
+        self.upload_form = enabled;
+        self
+    }
+
+    /// The canonical document root.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn serve(&self, request: &Request) -> Response {
+        self.serve_at(request, request.path())
+    }
+
+    /// Serve `url_path` from the root, for handlers mounted below a prefix
+    /// that `request.path()` still includes.
+    pub(crate) fn serve_at(&self, request: &Request, url_path: &str) -> Response {
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return Response::error(Status::METHOD_NOT_ALLOWED).with_header("Allow", "GET, HEAD");
+        }
+
+        let path = match self.resolve(url_path) {
+            Ok(Resolved::File(path)) => path,
+            Ok(Resolved::Dir(dir)) => match self.find_index(&dir) {
+                Some(path) => path,
+                None if self.listing => {
+                    return listing::respond(request, &dir, &self.root, self.upload_form)
+                        .unwrap_or_else(|e| error_for(&dir, e));
+                }
+                None => return Response::error(Status::NOT_FOUND),
+            },
+            Ok(Resolved::AddSlash) => {
+                let location = match request.query() {
+                    Some(query) => format!("{}/?{query}", request.path()),
+                    None => format!("{}/", request.path()),
+                };
+                return Response::error(Status::MOVED_PERMANENTLY)
+                    .with_header("Location", location);
+            }
+            Err(status) => return Response::error(status),
+        };
+
+        self.serve_file(request, &path)
+    }
+
+    fn serve_file(&self, request: &Request, path: &Path) -> Response {
+        let len = match path.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return error_for(path, e),
+        };
+        let in_memory =
+            len <= STREAM_ABOVE || self.file_cache.as_ref().is_some_and(|c| c.keeps(len));
+        if !in_memory {
+            return self.serve_large_file(request, path);
+        }
+        let file = match self.read(path) {
+            Ok(file) => file,
+            Err(e) => return error_for(path, e),
+        };
+        let modified = file.modified;
+        let content_type = mime::from_path(path);
+
+        let available = self.encodings(path, content_type, file.contents.len(), modified);
+        let (encoding, sibling) = choose_encoding(request, &available);
+
+        let mut validators = match &file.etag {
+            // hashed once, when the file was cached
+            Some(etag) if self.cache.etag == ETagKind::Strong => Validators {
+                etag: Some(etag.clone()),
+                last_modified: modified.filter(|_| self.cache.last_modified),
+            },
+            _ => Validators::for_file(&self.cache, &file.contents, modified),
+        };
+        if encoding != Encoding::Identity {
+            // each encoding is its own representation and needs its own tag
+            if let Some(etag) = &mut validators.etag {
+                etag.tag = format!("{}-{}", etag.tag, encoding.token());
+            }
+        }
+        let vary = !available.is_empty();
+
+        if validators.not_modified(request) {
+            let mut response = caching::not_modified(&validators, &self.cache);
+            if vary {
+                response.headers.set("Vary", "Accept-Encoding");
+            }
+            return response;
+        }
+
+        let body = match (encoding, sibling) {
+            (Encoding::Identity, _) => file.contents.clone(),
+            (_, Some(sibling)) => match self.read(&sibling) {
+                Ok(compressed) => compressed.contents.clone(),
+                Err(e) => return error_for(&sibling, e),
+            },
+            (encoding, None) => {
+                let level = self.compression.level;
+                let encode = |bytes: &[u8]| compress::encode(encoding, bytes, level);
+                let compressed = match &self.file_cache {
+                    Some(cache) => cache.compressed(path, &file, encoding, level, encode),
+                    None => encode(&file.contents).map(Bytes::from),
+                };
+                match compressed {
+                    Ok(compressed) => compressed,
+                    Err(e) => return error_for(path, e),
+                }
+            }
+        };
+
+        let response = self.full_response(content_type, encoding, vary, &validators);
+        range::apply(request, response.with_body(body), &validators)
+    }
+
+    /// [`serve_file`](StaticFiles::serve_file) for a file too big to hold in
+    /// memory: it is streamed from disk, ranges and all, and only compressed
+    /// if it has a precompressed sibling.
+    fn serve_large_file(&self, request: &Request, path: &Path) -> Response {
+        let open = |path: &Path| {
+            let file = File::open(path)?;
+            let metadata = file.metadata()?;
+            io::Result::Ok((file, metadata.len(), metadata.modified().ok()))
+        };
+        let (file, len, modified) = match open(path) {
+            Ok(opened) => opened,
+            Err(e) => return error_for(path, e),
+        };
+        let content_type = mime::from_path(path);
+
+        let mut available = self.encodings(path, content_type, len as usize, modified);
+        available.retain(|(_, sibling)| sibling.is_some());
+        let (encoding, sibling) = choose_encoding(request, &available);
+
+        let mut validators = Validators::for_large_file(&self.cache, len, modified);
+        if encoding != Encoding::Identity
+            && let Some(etag) = &mut validators.etag
+        {
+            etag.tag = format!("{}-{}", etag.tag, encoding.token());
+        }
+        let vary = !available.is_empty();
+
+        if validators.not_modified(request) {
+            let mut response = caching::not_modified(&validators, &self.cache);
+            if vary {
+                response.headers.set("Vary", "Accept-Encoding");
+            }
+            return response;
+        }
+
+        let (body, body_len) = match sibling {
+            Some(sibling) => match open(&sibling) {
+                Ok((file, len, _)) => (file, len),
+                Err(e) => return error_for(&sibling, e),
+            },
+            None => (file, len),
+        };
+        let response = self.full_response(content_type, encoding, vary, &validators);
+        range::apply_file(request, response, body, body_len, &validators)
+    }
+
+    /// A `200` for the file, headers only.
+    fn full_response(
+        &self,
+        content_type: &str,
+        encoding: Encoding,
+        vary: bool,
+        validators: &Validators,
+    ) -> Response {
+        let mut response = Response::new(Status::OK).with_header("Content-Type", content_type);
+        if encoding != Encoding::Identity {
+            response.headers.set("Content-Encoding", encoding.token());
+        }
+        if vary {
+            response.headers.set("Vary", "Accept-Encoding");
+        }
+        validators.apply(&mut response);
+        if let Some(cache_control) = &self.cache.cache_control {
+            response.headers.set("Cache-Control", cache_control.clone());
+        }
+        response
+    }
+
+    /// Encodings we could send `path` in besides identity, in preference
+    /// order, with the precompressed sibling to read for each if there is one.
+    fn encodings(
+        &self,
+        path: &Path,
+        content_type: &str,
+        len: usize,
+        modified: Option<SystemTime>,
+    ) -> Vec<(Encoding, Option<PathBuf>)> {
+        let policy = &self.compression;
+        let on_the_fly =
+            policy.on_the_fly && len >= policy.min_size && compress::is_compressible(content_type);
+
+        let mut available = Vec::new();
+        for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+            let sibling = encoding
+                .extension()
+                .filter(|_| policy.precompressed)
+                .and_then(|ext| self.sibling(path, ext, modified));
+            if sibling.is_some() {
+                available.push((encoding, sibling));
+            } else if on_the_fly && encoding != Encoding::Brotli {
+                available.push((encoding, None));
+            }
+        }
+        available
+    }
+
+    /// `path` + `.ext`, if it exists under the root and is at least as new as `path`.
+    fn sibling(&self, path: &Path, ext: &str, modified: Option<SystemTime>) -> Option<PathBuf> {
+        let mut name = path.file_name()?.to_os_string();
+        name.push(".");
+        name.push(ext);
+        let sibling = path.with_file_name(name).canonicalize().ok()?;
+        let metadata = sibling.metadata().ok()?;
+        let fresh = match (metadata.modified().ok(), modified) {
+            (Some(sibling_mtime), Some(mtime)) => sibling_mtime >= mtime,
+            _ => true,
+        };
+        (sibling.starts_with(&self.root) && metadata.is_file() && fresh).then_some(sibling)
+    }
+
+    /// Map a URL path to a file or directory under the root.
+    ///
+    /// Paths are percent-decoded before being split, so `%2e%2e` and `%2f`
+    /// cannot smuggle in a `..` segment, and the final path is canonicalized
+    /// so a symlink pointing outside the root is refused as well.
+    pub fn resolve(&self, url_path: &str) -> Result<Resolved, Status> {
+        if !url_path.starts_with('/') {
+            return Err(Status::BAD_REQUEST);
+        }
+        let decoded = percent_decode(url_path).ok_or(Status::BAD_REQUEST)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| Status::NOT_FOUND)?;
+        if decoded.contains('\0') {
+            return Err(Status::BAD_REQUEST);
+        }
+
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return Err(Status::FORBIDDEN),
+                // a backslash is a separator on Windows
+                s if s.contains('\\') => return Err(Status::FORBIDDEN),
+                s => path.push(s),
+            }
+        }
+
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                ) =>
+            {
+                return Err(Status::NOT_FOUND);
+            }
+            Err(_) => return Err(Status::FORBIDDEN),
+        };
+        if !path.starts_with(&self.root) {
+            return Err(Status::FORBIDDEN);
+        }
+
+        if path.is_dir() {
+            if decoded.ends_with('/') {
+                Ok(Resolved::Dir(path))
+            } else {
+                Ok(Resolved::AddSlash)
+            }
+        } else {
+            Ok(Resolved::File(path))
+        }
+    }
+
+    /// `path` from the file cache, or straight from disk without one.
+    fn read(&self, path: &Path) -> io::Result<Arc<CachedFile>> {
+        if let Some(cache) = &self.file_cache {
+            return cache.read(path);
+        }
+        let (contents, modified) = read_file(path)?;
+        Ok(Arc::new(CachedFile {
+            contents: contents.into(),
+            modified,
+            etag: None,
+        }))
+    }
+
+    /// The first index file in `dir`, skipping any that is a symlink out of the root.
+    fn find_index(&self, dir: &Path) -> Option<PathBuf> {
+        self.index.iter().map(|name| dir.join(name)).find(|path| {
+            path.canonicalize()
+                .is_ok_and(|real| real.starts_with(&self.root) && real.is_file())
+        })
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, request: &Request, _params: &Params) -> Response {
+        self.serve(request)
+    }
+}
+
+/// The encoding to send among `available`, and the sibling file holding it.
+/// Ranges apply to the file's own bytes, so a range request is never compressed.
+fn choose_encoding(
+    request: &Request,
+    available: &[(Encoding, Option<PathBuf>)],
+) -> (Encoding, Option<PathBuf>) {
+    if request.headers.contains("Range") {
+        return (Encoding::Identity, None);
+    }
+    let offered: Vec<Encoding> = available.iter().map(|(e, _)| *e).collect();
+    let chosen = compress::negotiate(request.header("Accept-Encoding"), &offered);
+    let sibling = available
+        .iter()
+        .find(|(e, _)| *e == chosen)
+        .and_then(|(_, sibling)| sibling.clone());
+    (chosen, sibling)
+}
+
+/// Contents and modification time, read from one open handle so they agree.
+pub(crate) fn read_file(path: &Path) -> io::Result<(Vec<u8>, Option<SystemTime>)> {
+    let mut file = File::open(path)?;
+    let modified = file.metadata()?.modified().ok();
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok((contents, modified))
+}
+
+fn error_for(path: &Path, e: io::Error) -> Response {
+    match e.kind() {
+        io::ErrorKind::NotFound => Response::error(Status::NOT_FOUND),
+        io::ErrorKind::PermissionDenied => Response::error(Status::FORBIDDEN),
+        _ => {
+            eprintln!("Failed to read {}: {e}", path.display());
+            Response::error(Status::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caching::{ETag, ETagKind};
+    use crate::request::Limits;
+    use std::{
+        fs,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn request(method: &str, target: &str) -> Request {
+        request_with(method, target, "")
+    }
+
+    fn request_with(method: &str, target: &str, headers: &str) -> Request {
+        let raw = format!("{method} {target} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn site() -> (tempfile::TempDir, StaticFiles) {
+        let dir = tempfile::tempdir().unwrap();
+        let public = dir.path().join("public");
+        fs::create_dir_all(public.join("docs/empty")).unwrap();
+        fs::write(public.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(public.join("docs/guide.md"), "# guide").unwrap();
+        fs::write(public.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(public.join("docs/a b.txt"), "spaced").unwrap();
+        fs::write(dir.path().join("secret.txt"), "top secret").unwrap();
+        let files = StaticFiles::new(&public).unwrap();
+        (dir, files)
+    }
+
+    #[test]
+    fn serves_files_with_content_type() {
+        let (_dir, files) = site();
+
+        let response = files.serve(&request("GET", "/docs/guide.md"));
+        assert_eq!(response.status, Status::OK);
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/markdown; charset=utf-8")
+        );
+        assert_eq!(response.body, &b"# guide"[..]);
+
+        let response = files.serve(&request("GET", "/docs/a%20b.txt?x=1"));
+        assert_eq!(response.body, &b"spaced"[..]);
+    }
+
+    #[test]
+    fn directories_use_index_html_and_get_a_trailing_slash() {
+        let (_dir, files) = site();
+
+        assert_eq!(
+            files.serve(&request("GET", "/")).body,
+            &b"<h1>home</h1>"[..]
+        );
+        assert_eq!(
+            files.serve(&request("GET", "/docs/")).body,
+            &b"<h1>docs</h1>"[..]
+        );
+
+        let response = files.serve(&request("GET", "/docs?v=2"));
+        assert_eq!(response.status, Status::MOVED_PERMANENTLY);
+        assert_eq!(response.headers.get("Location"), Some("/docs/?v=2"));
+
+        assert_eq!(
+            files.serve(&request("GET", "/docs/empty/")).status,
+            Status::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn missing_files_are_404() {
+        let (_dir, files) = site();
+        assert_eq!(
+            files.serve(&request("GET", "/nope.html")).status,
+            Status::NOT_FOUND
+        );
+        assert_eq!(
+            files.serve(&request("GET", "/index.html/x")).status,
+            Status::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn only_get_and_head_are_allowed() {
+        let (_dir, files) = site();
+        let response = files.serve(&request("POST", "/"));
+        assert_eq!(response.status, Status::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD"));
+        assert_eq!(files.serve(&request("HEAD", "/")).status, Status::OK);
+    }
+
+    #[test]
+    fn traversal_is_rejected() {
+        let (_dir, files) = site();
+        for target in [
+            "/../secret.txt",
+            "/docs/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/%2E%2E%2Fsecret.txt",
+            "/docs/..%2f..%2fsecret.txt",
+            "/..\\secret.txt",
+        ] {
+            let response = files.serve(&request("GET", target));
+            assert_eq!(response.status, Status::FORBIDDEN, "{target}");
+        }
+        assert_eq!(
+            files.serve(&request("GET", "/%00")).status,
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            files.serve(&request("GET", "/%zz")).status,
+            Status::BAD_REQUEST
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_escaping_the_root_are_rejected() {
+        let (dir, files) = site();
+        let public = dir.path().join("public");
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), public.join("leak.txt")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), public.join("up")).unwrap();
+        std::os::unix::fs::symlink(public.join("docs/guide.md"), public.join("guide.md")).unwrap();
+
+        assert_eq!(
+            files.serve(&request("GET", "/leak.txt")).status,
+            Status::FORBIDDEN
+        );
+        assert_eq!(
+            files.serve(&request("GET", "/up/secret.txt")).status,
+            Status::FORBIDDEN
+        );
+        // links that stay inside the root are fine
+        assert_eq!(
+            files.serve(&request("GET", "/guide.md")).body,
+            &b"# guide"[..]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn index_symlinks_escaping_the_root_are_skipped() {
+        let (dir, files) = site();
+        let public = dir.path().join("public");
+        fs::create_dir(public.join("d")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), public.join("d/index.html"))
+            .unwrap();
+
+        let response = files.serve(&request("GET", "/d/"));
+        assert!(!String::from_utf8_lossy(&response.body).contains("top secret"));
+        let files = files.directory_listing(false);
+        assert_eq!(
+            files.serve(&request("GET", "/d/")).status,
+            Status::NOT_FOUND
+        );
+
+        // an index linked to a file inside the root is still served
+        std::os::unix::fs::symlink(public.join("docs/index.html"), public.join("d/index.htm"))
+            .unwrap();
+        let files = files.index_files(vec!["index.html".into(), "index.htm".into()]);
+        assert_eq!(
+            files.serve(&request("GET", "/d/")).body,
+            &b"<h1>docs</h1>"[..]
+        );
+    }
+
+    fn set_mtime(path: &Path, secs: u64) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn sends_validators_and_cache_control() {
+        let (dir, files) = site();
+        set_mtime(&dir.path().join("public/docs/guide.md"), 784_111_777);
+
+        let response = files.serve(&request("GET", "/docs/guide.md"));
+        assert_eq!(
+            response.headers.get("Last-Modified"),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+        assert!(response.headers.get("ETag").unwrap().starts_with("W/\""));
+        assert_eq!(response.headers.get("Cache-Control"), Some("no-cache"));
+    }
+
+    #[test]
+    fn conditional_requests_get_304() {
+        let (dir, files) = site();
+        let guide = dir.path().join("public/docs/guide.md");
+        set_mtime(&guide, 784_111_777);
+
+        let etag = files
+            .serve(&request("GET", "/docs/guide.md"))
+            .headers
+            .get("ETag")
+            .unwrap()
+            .to_string();
+
+        let response = files.serve(&request_with(
+            "GET",
+            "/docs/guide.md",
+            &format!("If-None-Match: {etag}\r\n"),
+        ));
+        assert_eq!(response.status, Status::NOT_MODIFIED);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get("ETag"), Some(etag.as_str()));
+
+        let since = "If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n";
+        assert_eq!(
+            files
+                .serve(&request_with("HEAD", "/docs/guide.md", since))
+                .status,
+            Status::NOT_MODIFIED
+        );
+
+        // touching the file invalidates both validators
+        set_mtime(&guide, 784_111_778);
+        let response = files.serve(&request_with(
+            "GET",
+            "/docs/guide.md",
+            &format!("If-None-Match: {etag}\r\n"),
+        ));
+        assert_eq!(response.status, Status::OK);
+        assert_eq!(
+            files
+                .serve(&request_with("GET", "/docs/guide.md", since))
+                .status,
+            Status::OK
+        );
+    }
+
+    #[test]
+    fn strong_etags_ignore_mtime() {
+        let (dir, files) = site();
+        let files = files.cache_policy(CachePolicy {
+            etag: ETagKind::Strong,
+            last_modified: false,
+            cache_control: Some("public, max-age=60".to_string()),
+        });
+        let guide = dir.path().join("public/docs/guide.md");
+
+        let response = files.serve(&request("GET", "/docs/guide.md"));
+        let etag = response.headers.get("ETag").unwrap().to_string();
+        assert!(!etag.starts_with("W/"));
+        assert!(!response.headers.contains("Last-Modified"));
+        assert_eq!(
+            response.headers.get("Cache-Control"),
+            Some("public, max-age=60")
+        );
+
+        set_mtime(&guide, 1);
+        let header = format!("If-None-Match: {etag}\r\n");
+        assert_eq!(
+            files
+                .serve(&request_with("GET", "/docs/guide.md", &header))
+                .status,
+            Status::NOT_MODIFIED
+        );
+
+        fs::write(&guide, "# changed").unwrap();
+        assert_eq!(
+            files
+                .serve(&request_with("GET", "/docs/guide.md", &header))
+                .status,
+            Status::OK
+        );
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let (_dir, files) = site();
+
+        let response = files.serve(&request_with(
+            "GET",
+            "/docs/guide.md",
+            "Range: bytes=2-\r\n",
+        ));
+        assert_eq!(response.status, Status::PARTIAL_CONTENT);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-6/7"));
+        assert_eq!(response.body, &b"guide"[..]);
+
+        let response = files.serve(&request_with(
+            "GET",
+            "/docs/guide.md",
+            "Range: bytes=7-\r\n",
+        ));
+        assert_eq!(response.status, Status::RANGE_NOT_SATISFIABLE);
+    }
+
+    fn gunzip(bytes: &[u8]) -> String {
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(bytes)
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    #[test]
+    fn compresses_text_for_clients_that_accept_it() {
+        let (dir, files) = site();
+        let page = "<p>hello</p>\n".repeat(200);
+        fs::write(dir.path().join("public/big.html"), &page).unwrap();
+
+        let response = files.serve(&request_with(
+            "GET",
+            "/big.html",
+            "Accept-Encoding: gzip, deflate\r\n",
+        ));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert!(response.headers.get("ETag").unwrap().ends_with("-gzip\""));
+        assert_eq!(gunzip(&response.body), page);
+
+        // the compressed representation revalidates against its own tag
+        let etag = response.headers.get("ETag").unwrap();
+        let headers = format!("Accept-Encoding: gzip\r\nIf-None-Match: {etag}\r\n");
+        let response = files.serve(&request_with("GET", "/big.html", &headers));
+        assert_eq!(response.status, Status::NOT_MODIFIED);
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+
+        let response = files.serve(&request("GET", "/big.html"));
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body, page.as_bytes());
+    }
+
+    /// Status line and headers, and the body, as they would be sent.
+    fn sent(response: Response) -> (String, Vec<u8>) {
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes, false).unwrap();
+        let at = bytes.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = bytes.split_off(at);
+        (String::from_utf8(bytes).unwrap(), body)
+    }
+
+    #[test]
+    fn large_files_are_streamed_from_disk() {
+        let (dir, files) = site();
+        let contents: Vec<u8> = (0..STREAM_ABOVE + 100).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.path().join("public/build.bin"), &contents).unwrap();
+        let files = files.cache_policy(CachePolicy {
+            etag: ETagKind::Strong,
+            ..CachePolicy::default()
+        });
+
+        let response = files.serve(&request("GET", "/build.bin"));
+        assert!(response.stream.is_some() && response.body.is_empty());
+        // a strong tag from metadata: hashing would read the whole file every time
+        let modified = fs::metadata(dir.path().join("public/build.bin"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        let etag = ETag::from_metadata(contents.len() as u64, modified);
+        assert_eq!(
+            response.headers.get("ETag"),
+            Some(format!("\"{}\"", etag.tag).as_str())
+        );
+        let (head, body) = sent(response);
+        assert!(!head.contains("Transfer-Encoding"), "{head}");
+        assert!(head.contains(&format!("Content-Length: {}\r\n", contents.len())));
+        assert!(body == contents);
+
+        let response = files.serve(&request_with("GET", "/build.bin", "Range: bytes=-100\r\n"));
+        assert_eq!(response.status, Status::PARTIAL_CONTENT);
+        let (head, body) = sent(response);
+        assert!(head.contains("Content-Length: 100\r\n"), "{head}");
+        assert!(head.contains(&format!(
+            "Content-Range: bytes {}-{}/{}\r\n",
+            STREAM_ABOVE,
+            STREAM_ABOVE + 99,
+            STREAM_ABOVE + 100
+        )));
+        assert_eq!(body, &contents[STREAM_ABOVE as usize..]);
+
+        let response = files.serve(&request_with(
+            "GET",
+            "/build.bin",
+            "Range: bytes=0-1,10-11\r\n",
+        ));
+        let (head, body) = sent(response);
+        assert!(
+            head.contains(&format!("Content-Length: {}\r\n", body.len())),
+            "{head}"
+        );
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("Content-Range: bytes 10-11/"), "{body}");
+        assert!(body.ends_with("--\r\n"));
+
+        let response = files.serve(&request_with(
+            "GET",
+            "/build.bin",
+            &format!("Range: bytes={}-\r\n", STREAM_ABOVE + 100),
+        ));
+        assert_eq!(response.status, Status::RANGE_NOT_SATISFIABLE);
+    }
+
+    #[test]
+    fn serves_repeat_requests_from_the_file_cache() {
+        let (dir, files) = site();
+        let page = "<p>hello</p>\n".repeat(200);
+        fs::write(dir.path().join("public/big.html"), &page).unwrap();
+        let cache = Arc::new(FileCache::new(1 << 20));
+        let files = files.file_cache(Arc::clone(&cache));
+        let gzip = "Accept-Encoding: gzip\r\n";
+
+        let first = files.serve(&request_with("GET", "/big.html", gzip));
+        let second = files.serve(&request_with("GET", "/big.html", gzip));
+        assert_eq!(gunzip(&second.body), page);
+        assert_eq!(first.headers.get("ETag"), second.headers.get("ETag"));
+        // the file and its gzip copy, each read once and then hit
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 2, 1));
+        // hits share the cached bytes rather than copying them
+        assert_eq!(first.body.as_ptr(), second.body.as_ptr());
+        let plain = files.serve(&request("GET", "/big.html"));
+        let again = files.serve(&request("GET", "/big.html"));
+        assert_eq!(plain.body.as_ptr(), again.body.as_ptr());
+        let ranged = files.serve(&request_with("GET", "/big.html", "Range: bytes=13-\r\n"));
+        assert_eq!(ranged.body.as_ptr(), plain.body[13..].as_ptr());
+
+        fs::write(dir.path().join("public/big.html"), "edited").unwrap();
+        assert_eq!(
+            files.serve(&request("GET", "/big.html")).body,
+            &b"edited"[..]
+        );
+    }
+
+    #[test]
+    fn skips_small_incompressible_and_ranged_responses() {
+        let (dir, files) = site();
+        let public = dir.path().join("public");
+        fs::write(public.join("big.html"), "x".repeat(4096)).unwrap();
+        fs::write(public.join("big.png"), vec![0u8; 4096]).unwrap();
+        let gzip = "Accept-Encoding: gzip\r\n";
+
+        let small = files.serve(&request_with("GET", "/index.html", gzip));
+        assert!(!small.headers.contains("Content-Encoding"));
+        assert!(!small.headers.contains("Vary"));
+
+        let png = files.serve(&request_with("GET", "/big.png", gzip));
+        assert!(!png.headers.contains("Content-Encoding"));
+
+        let ranged = files.serve(&request_with(
+            "GET",
+            "/big.html",
+            "Accept-Encoding: gzip\r\nRange: bytes=0-9\r\n",
+        ));
+        assert_eq!(ranged.status, Status::PARTIAL_CONTENT);
+        assert!(!ranged.headers.contains("Content-Encoding"));
+        assert_eq!(ranged.body, &b"xxxxxxxxxx"[..]);
+
+        let off = files.compression(CompressionPolicy::disabled());
+        assert!(
+            !off.serve(&request_with("GET", "/big.html", gzip))
+                .headers
+                .contains("Content-Encoding")
+        );
+    }
+
+    #[test]
+    fn serves_precompressed_siblings() {
+        let (dir, files) = site();
+        let public = dir.path().join("public");
+        fs::write(public.join("app.js"), "console.log(1)").unwrap();
+        fs::write(public.join("app.js.br"), "pretend brotli").unwrap();
+        fs::write(
+            public.join("app.js.gz"),
+            compress::encode(Encoding::Gzip, b"console.log(1)", 9).unwrap(),
+        )
+        .unwrap();
+
+        let response = files.serve(&request_with(
+            "GET",
+            "/app.js",
+            "Accept-Encoding: gzip, br\r\n",
+        ));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(response.body, &b"pretend brotli"[..]);
+
+        let response = files.serve(&request_with("GET", "/app.js", "Accept-Encoding: gzip\r\n"));
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(gunzip(&response.body), "console.log(1)");
+
+        // a sibling older than its source is stale and ignored
+        set_mtime(&public.join("app.js.br"), 1);
+        let response = files.serve(&request_with("GET", "/app.js", "Accept-Encoding: br\r\n"));
+        assert!(!response.headers.contains("Content-Encoding"));
+    }
+
+    #[test]
+    fn directories_without_an_index_can_be_listed() {
+        let (dir, files) = site();
+        let public = dir.path().join("public");
+        fs::write(public.join("docs/empty/.hidden"), "").unwrap();
+        fs::write(public.join("docs/empty/day10.txt"), "1234").unwrap();
+        fs::write(public.join("docs/empty/day2.txt"), "12").unwrap();
+        let files = files.directory_listing(true);
+
+        let response = files.serve(&request("GET", "/docs/empty/?sort=size&order=desc"));
+        assert_eq!(response.status, Status::OK);
+        assert_eq!(response.headers.get("Vary"), Some("Accept"));
+        let page = String::from_utf8(response.body.to_vec()).unwrap();
+        assert!(!page.contains(".hidden"));
+        let (ten, two) = (
+            page.find("day10.txt").unwrap(),
+            page.find("day2.txt").unwrap(),
+        );
+        assert!(ten < two, "{page}");
+
+        let response = files.serve(&request_with(
+            "GET",
+            "/docs/empty/",
+            "Accept: application/json\r\n",
+        ));
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("application/json")
+        );
+        let json = String::from_utf8(response.body.to_vec()).unwrap();
+        assert!(
+            json.starts_with(
+                r#"{"path":"/docs/empty/","entries":[{"name":"day2.txt","type":"file","size":2,"#
+            ),
+            "{json}"
+        );
+
+        // an index file still wins over the listing
+        assert_eq!(
+            files.serve(&request("GET", "/docs/")).body,
+            &b"<h1>docs</h1>"[..]
+        );
+    }
+}
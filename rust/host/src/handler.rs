@@ -0,0 +1,67 @@
+use crate::request::Request;
+use crate::response::Response;
+use crate::url::percent_decode;
+
+/// Anything that can turn a request into a response.
+///
+/// Closures of the form `Fn(&Request, &Params) -> Response` implement this,
+/// so most endpoints never need a named type.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &Request, params: &Params) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request, &Params) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        self(request, params)
+    }
+}
+
+impl Handler for Box<dyn Handler> {
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        self.as_ref().handle(request, params)
+    }
+}
+
+/// Values captured by `:name` and `*name` segments of a route pattern.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn new() -> Params {
+        Params::default()
+    }
+
+    /// The decoded value captured for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.values.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Record `raw` (still percent-encoded) under `name`.
+    ///
+    /// Returns false if `raw` is not a valid escape sequence of UTF-8.
+    pub(crate) fn push_encoded(&mut self, name: &str, raw: &str) -> bool {
+        match percent_decode(raw).and_then(|bytes| String::from_utf8(bytes).ok()) {
+            Some(value) => {
+                self.values.push((name.to_string(), value));
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! host — a small HTTP server that grew out of the Rust Book's web server chapter.
+
+pub mod access_log;
+pub mod auth;
+pub mod caching;
+pub mod cgi;
+pub mod chunked;
+pub mod compress;
+pub mod config;
+pub mod connection;
+pub mod date;
+pub mod engine;
+pub mod escape;
+pub mod file_cache;
+pub mod files;
+pub mod handler;
+pub mod headers;
+pub mod listing;
+pub mod mime;
+pub mod multipart;
+pub mod pool;
+pub mod proxy;
+pub mod range;
+pub mod rate_limit;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod shutdown;
+pub mod sse;
+pub mod tls;
+pub mod upgrade;
+pub mod upload;
+pub mod url;
+pub mod vhost;
+pub mod watch;
+pub mod websocket;
+
+pub use access_log::{AccessLog, LogFormat};
+pub use auth::Auth;
+pub use caching::{CachePolicy, ETagKind};
+pub use cgi::Cgi;
+pub use chunked::{BodyStream, BodyWriter};
+pub use compress::CompressionPolicy;
+pub use config::{Config, ConfigError};
+pub use connection::{KeepAlive, Timeouts};
+pub use engine::Engine;
+pub use file_cache::FileCache;
+pub use files::StaticFiles;
+pub use handler::{Handler, Params};
+pub use headers::Headers;
+pub use pool::ThreadPool;
+pub use rate_limit::RateLimit;
+pub use request::{Limits, Method, ParseError, Request, Version};
+pub use response::{Response, Status};
+pub use router::Router;
+pub use server::Server;
+pub use shutdown::{ShutdownHandle, ShutdownSummary};
+pub use tls::{CertFiles, CertStore, Tls};
+pub use upload::Uploads;
+pub use vhost::VirtualHosts;
+pub use watch::{LiveReload, Watcher};
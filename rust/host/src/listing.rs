@@ -0,0 +1,389 @@
+use std::{cmp::Ordering, fs, io, path::Path, time::SystemTime};
+
+use crate::date::{DateTime, rfc3339};
+use crate::escape;
+use crate::request::Request;
+use crate::response::Response;
+use crate::url::{percent_decode, percent_encode, query_param};
+
+/// One file or subdirectory shown in a listing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Size in bytes; zero for directories.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+/// Listing order, from `?sort=name|size|modified&order=asc|desc`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl Sort {
+    /// Unknown or missing values fall back to ascending by name.
+    pub fn from_query(query: Option<&str>) -> Sort {
+        let param = |name| query.and_then(|q| query_param(q, name));
+        let key = match param("sort").as_deref() {
+            Some("size") => SortKey::Size,
+            Some("modified") => SortKey::Modified,
+            _ => SortKey::Name,
+        };
+        Sort {
+            key,
+            descending: param("order").as_deref() == Some("desc"),
+        }
+    }
+
+    /// Directories always come first; within each group `key` decides and
+    /// the name breaks ties.
+    pub fn apply(self, entries: &mut [Entry]) {
+        entries.sort_by(|a, b| {
+            let by_key = match self.key {
+                SortKey::Name => Ordering::Equal,
+                SortKey::Size => a.size.cmp(&b.size),
+                SortKey::Modified => a.modified.cmp(&b.modified),
+            }
+            .then_with(|| natural_cmp(&a.name, &b.name));
+            let by_key = if self.descending {
+                by_key.reverse()
+            } else {
+                by_key
+            };
+            b.is_dir.cmp(&a.is_dir).then(by_key)
+        });
+    }
+}
+
+/// Visible entries of `dir`.
+///
+/// Dotfiles are hidden, as are names that are not UTF-8 (they could not be
+/// requested anyway), dangling symlinks and symlinks leading outside `root`.
+pub fn read_dir(dir: &Path, root: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_symlink()
+            && !entry
+                .path()
+                .canonicalize()
+                .is_ok_and(|target| target.starts_with(root))
+        {
+            continue;
+        }
+        let Ok(metadata) = fs::metadata(entry.path()) else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Listing of `dir` as HTML, or as JSON for clients that prefer it.
+///
+/// With `upload_form`, the page has a form that posts files to the directory.
+pub fn respond(
+    request: &Request,
+    dir: &Path,
+    root: &Path,
+    upload_form: bool,
+) -> io::Result<Response> {
+    let sort = Sort::from_query(request.query());
+    let mut entries = read_dir(dir, root)?;
+    sort.apply(&mut entries);
+
+    let path = percent_decode(request.path())
+        .and_then(|p| String::from_utf8(p).ok())
+        .unwrap_or_else(|| request.path().to_string());
+    let response = if prefers_json(request.header("Accept")) {
+        Response::json(json(&path, &entries))
+    } else {
+        Response::html(html(&path, &entries, sort, upload_form))
+    };
+    Ok(response
+        .with_header("Vary", "Accept")
+        .with_header("Cache-Control", "no-cache"))
+}
+
+/// Whether `Accept` rates `application/json` above `text/html`.
+///
+/// Only an explicit `application/json` counts: browsers and curl send `*/*`
+/// and should get the page.
+fn prefers_json(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            Some((range, q))
+        })
+        .collect();
+    let best = |matches: &dyn Fn(&str) -> bool| {
+        ranges
+            .iter()
+            .filter(|(range, _)| matches(range))
+            .map(|&(_, q)| q)
+            .fold(0.0, f32::max)
+    };
+
+    let json = best(&|r| r.eq_ignore_ascii_case("application/json"));
+    let html = best(&|r| {
+        r == "*/*" || r.eq_ignore_ascii_case("text/*") || r.eq_ignore_ascii_case("text/html")
+    });
+    json > 0.0 && json >= html
+}
+
+fn html(path: &str, entries: &[Entry], sort: Sort, upload_form: bool) -> String {
+    let title = escape::html(&format!("Index of {path}"));
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         <style>body{{font-family:sans-serif}}td,th{{padding:0 1em;text-align:left}}\
+         td.size{{text-align:right}}</style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    if upload_form {
+        page.push_str(
+            "<form method=\"post\" enctype=\"multipart/form-data\">\
+             <input type=\"file\" name=\"file\" multiple required> \
+             <button>Upload</button></form>\n",
+        );
+    }
+    page.push_str("<table>\n<tr>");
+    for (key, label) in [
+        (SortKey::Name, "Name"),
+        (SortKey::Size, "Size"),
+        (SortKey::Modified, "Modified"),
+    ] {
+        // clicking the current column flips its order
+        let (order, arrow) = match (sort.key == key, sort.descending) {
+            (true, false) => ("desc", " &#9650;"),
+            (true, true) => ("asc", " &#9660;"),
+            (false, _) => ("asc", ""),
+        };
+        page.push_str(&format!(
+            "<th><a href=\"?sort={}&amp;order={order}\">{label}</a>{arrow}</th>",
+            key.as_str()
+        ));
+    }
+    page.push_str("</tr>\n");
+
+    if path != "/" {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            human_size(entry.size)
+        };
+        let modified = entry.modified.map_or_else(String::new, |time| {
+            let t = DateTime::from_system_time(time);
+            format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}",
+                t.year, t.month, t.day, t.hour, t.minute
+            )
+        });
+        page.push_str(&format!(
+            "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td class=\"size\">{size}</td>\
+             <td>{modified}</td></tr>\n",
+            percent_encode(&entry.name),
+            escape::html(&entry.name),
+        ));
+    }
+    page.push_str("</table>\n</body>\n</html>\n");
+    page
+}
+
+fn json(path: &str, entries: &[Entry]) -> String {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let modified = entry
+                .modified
+                .map_or_else(|| "null".to_string(), |t| escape::json(&rfc3339(t)));
+            format!(
+                r#"{{"name":{},"type":"{}","size":{},"modified":{modified}}}"#,
+                escape::json(&entry.name),
+                if entry.is_dir { "directory" } else { "file" },
+                entry.size,
+            )
+        })
+        .collect();
+    format!(
+        r#"{{"path":{},"entries":[{}]}}"#,
+        escape::json(path),
+        items.join(",")
+    )
+}
+
+/// `1536` as `1.5 KiB`.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Compare names with runs of digits taken as numbers, so `day2` sorts
+/// before `day10`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let digits = |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+                let (na, nb) = (digits(a), digits(b));
+                let trim = |s: &[u8]| {
+                    let zeros = s.iter().take_while(|&&c| c == b'0').count();
+                    s[zeros..].to_vec()
+                };
+                let (da, db) = (trim(&a[..na]), trim(&b[..nb]));
+                let ordering = da.len().cmp(&db.len()).then_with(|| da.cmp(&db));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a = &a[na..];
+                b = &b[nb..];
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry(name: &str, is_dir: bool, size: u64, secs: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sorts_directories_first_then_by_key() {
+        let mut entries = vec![
+            entry("input.txt", false, 300, 3),
+            entry("day10", true, 0, 1),
+            entry("README.md", false, 100, 5),
+            entry("day2", true, 0, 2),
+        ];
+
+        Sort::from_query(None).apply(&mut entries);
+        assert_eq!(names(&entries), ["day2", "day10", "input.txt", "README.md"]);
+
+        Sort::from_query(Some("sort=size&order=desc")).apply(&mut entries);
+        assert_eq!(names(&entries), ["day10", "day2", "input.txt", "README.md"]);
+
+        Sort::from_query(Some("sort=modified")).apply(&mut entries);
+        assert_eq!(names(&entries), ["day10", "day2", "input.txt", "README.md"]);
+
+        Sort::from_query(Some("sort=modified&order=desc")).apply(&mut entries);
+        assert_eq!(names(&entries), ["day2", "day10", "README.md", "input.txt"]);
+    }
+
+    #[test]
+    fn json_only_when_preferred() {
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("text/html;q=0.5, application/json")));
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(!prefers_json(Some(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        )));
+        assert!(!prefers_json(Some("application/json;q=0, */*")));
+    }
+
+    #[test]
+    fn renders_escaped_html_and_json() {
+        let entries = vec![
+            entry("sub dir", true, 0, 0),
+            entry("<b>.txt", false, 1536, 784_111_777),
+        ];
+
+        let page = html("/a&b/", &entries, Sort::from_query(None), false);
+        assert!(page.contains("<title>Index of /a&amp;b/</title>"));
+        assert!(page.contains("<a href=\"../\">../</a>"));
+        assert!(page.contains("<a href=\"sub%20dir/\">sub dir/</a>"));
+        assert!(page.contains("<a href=\"%3Cb%3E.txt\">&lt;b&gt;.txt</a>"));
+        assert!(page.contains("1.5 KiB"));
+        assert!(page.contains("1994-11-06 08:49"));
+        assert!(page.contains("href=\"?sort=name&amp;order=desc\">Name</a> &#9650;"));
+
+        assert_eq!(
+            json("/", &entries[1..]),
+            r#"{"path":"/","entries":[{"name":"<b>.txt","type":"file","size":1536,"modified":"1994-11-06T08:49:37Z"}]}"#
+        );
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
@@ -1,59 +1,159 @@
-// https://doc.rust-lang.org/book/ch21-01-single-threaded.html
+use std::{process, sync::Arc, thread, time::Duration};
 
-use std::{
-    fs,
-    io::{BufReader, prelude::*},
-    net::{TcpListener, TcpStream},
-};
+use host::config::USAGE;
+use host::sse::{Event, EventStream};
+use host::websocket::{self, Room};
+use host::{Config, Params, Request, Response, Router, Server, ShutdownHandle};
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").expect("Failed to bind to 127.0.0.1:7878");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        println!("{USAGE}");
+        return;
+    }
+    let config = Config::from_args(args).unwrap_or_else(|e| usage(&e.to_string()));
+
+    let file_cache = config.file_cache();
+    let files = config
+        .static_files(file_cache.as_ref())
+        .unwrap_or_else(|e| {
+            let root = config.root.clone().unwrap_or_default();
+            usage(&format!("Cannot serve {}: {e}", root.display()))
+        });
+    let access_log = config
+        .access_log()
+        .unwrap_or_else(|e| usage(&format!("Cannot open access log: {e}")));
+
+    let mut router = Router::new()
+        .get("/api/health", |_: &Request, _: &Params| {
+            Response::json(r#"{"status":"ok"}"#)
+        })
+        .get("/api/progress", |request: &Request, _: &Params| {
+            progress(request)
+        })
+        .get("/ws/echo", websocket::handler(websocket::echo))
+        .get("/ws/room", websocket::room_handler(Arc::new(Room::new())));
+    if let Some(cache) = &file_cache {
+        let cache = Arc::clone(cache);
+        router = router.get("/api/cache", move |_: &Request, _: &Params| {
+            let stats = cache.stats();
+            Response::json(format!(
+                r#"{{"hits":{},"misses":{},"entries":{},"bytes":{}}}"#,
+                stats.hits, stats.misses, stats.entries, stats.bytes
+            ))
+        });
+    }
+    for proxy in &config.proxies {
+        router = proxy.mount(router);
+    }
+    for cgi in &config.cgi {
+        router = cgi.mount(router).unwrap_or_else(|e| {
+            usage(&format!(
+                "Cannot run scripts from {}: {e}",
+                cgi.dir.display()
+            ))
+        });
+    }
+    for upload in &config.uploads {
+        router = upload.mount(router).unwrap_or_else(|e| {
+            usage(&format!(
+                "Cannot store uploads in {}: {e}",
+                upload.dir.display()
+            ))
+        });
+    }
+    let router = router.fallback(files);
+    let sites = config
+        .virtual_hosts(router, file_cache.as_ref())
+        .unwrap_or_else(|e| usage(&format!("Cannot serve a virtual host: {e}")));
+    // shared with the watcher, so live reload winds down with the server
+    let shutdown = ShutdownHandle::new();
+    let sites = config
+        .live_reload(sites, file_cache.as_ref(), &shutdown)
+        .unwrap_or_else(|e| usage(&format!("Cannot watch the document root: {e}")));
+    let site = config
+        .auth(sites)
+        .unwrap_or_else(|e| usage(&format!("Cannot load a password file: {e}")));
+
+    let tls = config
+        .tls()
+        .unwrap_or_else(|e| usage(&format!("Cannot load TLS certificates: {e}")));
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => handle_connection(stream),
-            Err(e) => eprintln!("Connection failed: {}", e),
+    let mut server = Server::new(config.workers, site)
+        .shutdown(shutdown)
+        .engine(config.engine)
+        .limits(config.limits)
+        .keep_alive(config.keep_alive)
+        .timeouts(config.timeouts)
+        .max_connections(config.max_connections)
+        .drain_timeout(config.drain_timeout)
+        .headers(config.headers.clone());
+    for addr in &config.listen {
+        server = server.listen(addr).unwrap_or_else(|e| bind_failed(addr, e));
+    }
+    if let Some(tls) = tls {
+        tls.certs()
+            .reload_on_sighup()
+            .expect("Failed to install SIGHUP handler");
+        for addr in &config.tls.listen {
+            server = server
+                .listen_tls(addr, Arc::clone(&tls))
+                .unwrap_or_else(|e| bind_failed(addr, e));
+        }
+        for addr in &config.tls.redirect {
+            server = server
+                .redirect_to_https(addr)
+                .unwrap_or_else(|e| bind_failed(addr, e));
         }
     }
+    if let Some(limit) = config.rate_limit {
+        server = server.rate_limit(limit);
+    }
+    if let Some(log) = access_log {
+        server = server.access_log(log);
+    }
+    server
+        .shutdown_handle()
+        .register_signals()
+        .expect("Failed to install signal handlers");
+
+    for url in server.local_urls().unwrap_or_default() {
+        println!(
+            "Listening on {url} with {} workers ({} engine)",
+            config.workers, config.engine
+        );
+    }
+    let summary = server.run();
+    println!("Shut down: {summary}");
+}
+
+/// Counts to 100% as `progress` events, picking up after `Last-Event-ID` on reconnect.
+fn progress(request: &Request) -> Response {
+    EventStream::new(request).respond(|events| {
+        let start = match events.last_event_id().and_then(|id| id.parse::<u32>().ok()) {
+            Some(id) => id + 1,
+            None => 0,
+        };
+        for percent in start..=100 {
+            thread::sleep(Duration::from_millis(100));
+            let event = Event::new(percent.to_string())
+                .id(percent.to_string())
+                .event("progress");
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+        let _ = events.send(Event::new("done").event("done"));
+    })
+}
+
+fn bind_failed(addr: &str, e: std::io::Error) -> ! {
+    eprintln!("Failed to bind to {addr}: {e}");
+    process::exit(1);
 }
 
-// --snip--
-
-// fn handle_connection(mut stream: TcpStream) {
-//     let buf_reader = BufReader::new(&stream);
-//     let _http_request: Vec<_> = buf_reader
-//         .lines()
-//         .map(|result| result.unwrap())
-//         .take_while(|line| !line.is_empty())
-//         .collect();
-
-//     let status_line = "HTTP/1.1 200 OK";
-
-//     let length = contents.len();
-
-//     let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
-
-//     stream.write_all(response.as_bytes()).unwrap();
-// }
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&stream);
-    let _http_request: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
-
-    let status_line = "HTTP/1.1 200 OK";
-    // embed the HTML at compile time (file is host/hello.html or ../hello.html relative to Cargo.toml)
-    // let contents = include_str!("hello.html");
-    let contents = fs::read_to_string("hello.html").unwrap();
-    let length = contents.len();
-
-    let response = format!(
-        "{status_line}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {length}\r\nConnection: close\r\n\r\n{contents}"
-    );
-
-    stream
-        .write_all(response.as_bytes())
-        .expect("Failed to write response");
+fn usage(msg: &str) -> ! {
+    eprintln!("{msg}");
+    eprintln!("Run `host --help` for usage.");
+    process::exit(2);
 }
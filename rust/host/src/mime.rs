@@ -0,0 +1,68 @@
+use std::path::Path;
+
+/// Content type used when the extension is unknown.
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// `Content-Type` for a file, guessed from its extension.
+pub fn from_path(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" | "log" | "rs" | "toml" | "lock" | "sh" | "clj" | "go" | "py" => {
+            "text/plain; charset=utf-8"
+        }
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "yaml" | "yml" => "application/yaml",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "avif" => "image/avif",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => DEFAULT_TYPE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guesses_from_extension() {
+        assert_eq!(
+            from_path(Path::new("a/index.HTML")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            from_path(Path::new("input.txt")),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(from_path(Path::new("logo.svg")), "image/svg+xml");
+        assert_eq!(from_path(Path::new("Makefile")), DEFAULT_TYPE);
+        assert_eq!(from_path(Path::new("archive.tar.gz")), "application/gzip");
+    }
+}
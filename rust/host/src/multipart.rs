@@ -0,0 +1,204 @@
+//! `multipart/form-data` request bodies (RFC 7578), as browsers send file uploads.
+
+use crate::headers::Headers;
+use crate::request::{Limits, read_headers};
+
+/// One field of a form.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Part<'a> {
+    /// The form field's `name`.
+    pub name: String,
+    /// The file's name on the client, for file fields.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: &'a [u8],
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (media_type, params) = content_type.split_once(';')?;
+    if !media_type
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    let boundary = param(params, "boundary")?;
+    // RFC 2046 §5.1.1: 1 to 70 characters
+    (!boundary.is_empty() && boundary.len() <= 70).then_some(boundary)
+}
+
+/// The parts of `body`, or `None` if it is not a complete multipart body.
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> Option<Vec<Part<'a>>> {
+    let delimiter = format!("--{boundary}");
+    let delimiter = delimiter.as_bytes();
+    let next = [b"\r\n", delimiter].concat();
+    // anything before the first delimiter is a preamble to ignore
+    let mut rest = if body.starts_with(delimiter) {
+        body
+    } else {
+        &body[find_delimiter(body, &next)? + 2..]
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        let after = skip_padding(&rest[delimiter.len()..]);
+        if after.starts_with(b"--") {
+            return Some(parts);
+        }
+        let content = after.strip_prefix(b"\r\n")?;
+        let end = find_delimiter(content, &next)?;
+        parts.push(part(&content[..end])?);
+        rest = &content[end + 2..];
+    }
+}
+
+/// Where `next` (CRLF and the delimiter) occurs as a delimiter line rather
+/// than as part of the content, i.e. followed by `--` or a line break.
+fn find_delimiter(haystack: &[u8], next: &[u8]) -> Option<usize> {
+    let mut from = 0;
+    loop {
+        let at = from + find(&haystack[from..], next)?;
+        let after = skip_padding(&haystack[at + next.len()..]);
+        if after.starts_with(b"--") || after.starts_with(b"\r\n") {
+            return Some(at);
+        }
+        from = at + 1;
+    }
+}
+
+/// RFC 2046 allows spaces and tabs between a delimiter and its line break.
+fn skip_padding(bytes: &[u8]) -> &[u8] {
+    let padding = bytes
+        .iter()
+        .take_while(|&&b| b == b' ' || b == b'\t')
+        .count();
+    &bytes[padding..]
+}
+
+fn part(bytes: &[u8]) -> Option<Part<'_>> {
+    let mut reader = bytes;
+    let headers: Headers = read_headers(&mut reader, &Limits::default()).ok()?;
+    let disposition = headers.get("Content-Disposition")?;
+    let (kind, params) = disposition.split_once(';')?;
+    if !kind.trim().eq_ignore_ascii_case("form-data") {
+        return None;
+    }
+    Some(Part {
+        name: utf8(&param(params, "name")?)?,
+        filename: param(params, "filename").and_then(|name| utf8(&name)),
+        content_type: headers.get("Content-Type").map(str::to_string),
+        data: reader,
+    })
+}
+
+/// Header values are read as Latin-1; browsers send names as UTF-8.
+fn utf8(latin1: &str) -> Option<String> {
+    let bytes: Option<Vec<u8>> = latin1.chars().map(|c| u8::try_from(c).ok()).collect();
+    String::from_utf8(bytes?).ok()
+}
+
+/// Value of `name` in `; key=value; key="quoted value"` parameters.
+fn param(params: &str, name: &str) -> Option<String> {
+    let mut rest = params;
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        if rest.is_empty() {
+            return None;
+        }
+        let (key, after) = rest.split_once('=')?;
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => unquote(quoted)?,
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = after;
+    }
+}
+
+/// The quoted string `quoted` starts with, minus its opening quote, and what follows it.
+fn unquote(quoted: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &quoted[i + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_boundary() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=----x123").as_deref(),
+            Some("----x123")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b;c\"").as_deref(),
+            Some("a b;c")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=x"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary=\"\""), None);
+    }
+
+    #[test]
+    fn splits_fields_and_files() {
+        let body = "preamble\r\n--XyZ\r\n\
+                    Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+                    hello\r\n--XyZ\r\n\
+                    Content-Disposition: form-data; name=\"file\"; filename=\"caf\u{e9}; \\\"1\\\".txt\"\r\n\
+                    Content-Type: text/plain\r\n\r\n\
+                    line 1\r\n--XyZ not yet\r\n\r\n--XyZ--\r\nepilogue";
+        let parts = parse(body.as_bytes(), "XyZ").unwrap();
+        assert_eq!(
+            parts,
+            [
+                Part {
+                    name: "note".to_string(),
+                    filename: None,
+                    content_type: None,
+                    data: b"hello",
+                },
+                Part {
+                    name: "file".to_string(),
+                    filename: Some("caf\u{e9}; \"1\".txt".to_string()),
+                    content_type: Some("text/plain".to_string()),
+                    data: b"line 1\r\n--XyZ not yet\r\n",
+                },
+            ]
+        );
+        assert_eq!(parse(b"--XyZ--\r\n", "XyZ"), Some(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_incomplete_bodies() {
+        let part = "--b\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nx";
+        assert_eq!(parse(part.as_bytes(), "b"), None);
+        assert_eq!(parse(format!("{part}\r\n--b").as_bytes(), "b"), None);
+        assert!(parse(format!("{part}\r\n--b--").as_bytes(), "b").is_some());
+        assert_eq!(
+            parse(b"--b\r\nContent-Type: text/plain\r\n\r\nx\r\n--b--", "b"),
+            None
+        );
+        assert_eq!(parse(b"no delimiter", "b"), None);
+    }
+}
@@ -0,0 +1,329 @@
+// https://doc.rust-lang.org/book/ch21-02-multithreaded.html
+
+use std::{
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Default number of jobs that may wait in the queue per worker.
+pub const QUEUE_PER_WORKER: usize = 16;
+
+/// A fixed set of worker threads pulling jobs from a bounded queue.
+///
+/// `execute` blocks once the queue is full, so a caller that must not wait,
+/// such as the accept loop, checks [`is_full`](ThreadPool::is_full) first
+/// and turns the job away. A job that is going to run indefinitely can
+/// [`detach`](ThreadPool::detach) so it does not keep a worker from the rest.
+pub struct ThreadPool {
+    size: usize,
+    /// Workers plus queue slots: how many jobs fit before `execute` blocks.
+    capacity: usize,
+    shared: Arc<Shared>,
+    sender: Option<mpsc::SyncSender<Job>>,
+}
+
+/// What the workers, and replacements for detached ones, share.
+struct Shared {
+    receiver: Mutex<mpsc::Receiver<Job>>,
+    /// Worker threads still running, detached ones included, to join on drop.
+    threads: Mutex<Vec<(usize, thread::JoinHandle<()>)>>,
+    next_id: AtomicUsize,
+    /// Jobs queued or running on a worker that has not detached.
+    busy: AtomicUsize,
+}
+
+thread_local! {
+    /// The pool the current thread works for, until it detaches.
+    static POOL: RefCell<Option<Arc<Shared>>> = const { RefCell::new(None) };
+}
+
+impl ThreadPool {
+    /// Create a pool of `size` workers with a queue of `size * QUEUE_PER_WORKER` jobs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::with_queue(size, size * QUEUE_PER_WORKER)
+    }
+
+    /// Create a pool of `size` workers whose queue holds at most `queue_len` pending jobs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn with_queue(size: usize, queue_len: usize) -> ThreadPool {
+        assert!(size > 0, "thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::sync_channel(queue_len);
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            threads: Mutex::new(Vec::new()),
+            next_id: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+        });
+        for _ in 0..size {
+            spawn_worker(&shared);
+        }
+
+        ThreadPool {
+            size,
+            capacity: size + queue_len,
+            shared,
+            sender: Some(sender),
+        }
+    }
+
+    /// Number of worker threads in the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether every worker is busy and every queue slot taken, so that
+    /// `execute` would block.
+    pub fn is_full(&self) -> bool {
+        self.shared.busy.load(Ordering::SeqCst) >= self.capacity
+    }
+
+    /// Queue `f` to run on the next free worker, blocking while the queue is full.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job = Box::new(f);
+        self.shared.busy.fetch_add(1, Ordering::SeqCst);
+        self.sender
+            .as_ref()
+            .expect("pool is shutting down")
+            .send(job)
+            .expect("all workers have exited");
+    }
+
+    /// Give the calling worker's place in its pool to a new thread, for a
+    /// job about to run for as long as a client likes, such as an event
+    /// stream or a WebSocket. The job carries on and its thread exits when
+    /// it is done, instead of taking another.
+    ///
+    /// Returns `false`, doing nothing, on a thread that is not a pool worker
+    /// or has already detached.
+    pub fn detach() -> bool {
+        let Some(shared) = POOL.with(|pool| pool.borrow_mut().take()) else {
+            return false;
+        };
+        // the job no longer takes up the pool's room
+        shared.busy.fetch_sub(1, Ordering::SeqCst);
+        spawn_worker(&shared);
+        true
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // closing the channel makes every worker's `recv` fail once the queue drains
+        drop(self.sender.take());
+
+        // a worker detaching meanwhile adds a thread, so take them one at a time
+        loop {
+            let next = self.shared.threads.lock().unwrap().pop();
+            let Some((id, thread)) = next else {
+                break;
+            };
+            if thread.join().is_err() {
+                eprintln!("Worker {id} exited abnormally");
+            }
+        }
+    }
+}
+
+fn spawn_worker(shared: &Arc<Shared>) {
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let pool = Arc::clone(shared);
+    let thread = thread::Builder::new()
+        .name(format!("host-worker-{id}"))
+        .spawn(move || {
+            let receiver = Arc::clone(&pool);
+            POOL.with(|current| *current.borrow_mut() = Some(pool));
+            loop {
+                // the guard is dropped at the end of this statement, so other
+                // workers can pick up jobs while this one is busy
+                let message = receiver
+                    .receiver
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .recv();
+
+                match message {
+                    Ok(job) => {
+                        // a panicking handler must not take the worker down with it
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            eprintln!("Worker {id} recovered from a panicking job");
+                        }
+                    }
+                    Err(_) => break,
+                }
+                if POOL.with(|current| current.borrow().is_none()) {
+                    // detached: a replacement is taking jobs in its place
+                    break;
+                }
+                receiver.busy.fetch_sub(1, Ordering::SeqCst);
+            }
+        })
+        .expect("Failed to spawn worker thread");
+    let mut threads = shared.threads.lock().unwrap();
+    // detached workers exit when their job does: forget those, or every
+    // event stream and WebSocket ever served would leave a handle behind
+    threads.retain(|(_, thread)| !thread.is_finished());
+    threads.push((id, thread));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn jobs_run_in_parallel() {
+        // every job waits on the barrier, so this only completes if all four run at once
+        let pool = ThreadPool::new(4);
+        let barrier = Arc::new(Barrier::new(4));
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..4 {
+            let barrier = Arc::clone(&barrier);
+            let tx = tx.clone();
+            pool.execute(move || {
+                barrier.wait();
+                tx.send(()).unwrap();
+            });
+        }
+
+        for _ in 0..4 {
+            rx.recv_timeout(Duration::from_secs(5))
+                .expect("jobs did not run concurrently");
+        }
+    }
+
+    #[test]
+    fn worker_survives_panicking_job() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+
+        pool.execute(|| panic!("handler blew up"));
+        pool.execute(move || tx.send(42).unwrap());
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(42));
+    }
+
+    #[test]
+    fn drop_finishes_queued_jobs() {
+        let (tx, rx) = mpsc::channel();
+        {
+            let pool = ThreadPool::with_queue(2, 8);
+            for i in 0..8 {
+                let tx = tx.clone();
+                pool.execute(move || tx.send(i).unwrap());
+            }
+        }
+        drop(tx);
+
+        let mut done: Vec<i32> = rx.iter().collect();
+        done.sort();
+        assert_eq!(done, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn detached_jobs_do_not_hold_up_the_queue() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            assert!(ThreadPool::detach());
+            assert!(!ThreadPool::detach());
+            let _ = released.recv();
+        });
+        pool.execute(move || tx.send(42).unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(42));
+        assert!(!ThreadPool::detach());
+
+        // dropping the pool waits for the detached job too
+        release.send(()).unwrap();
+        drop(pool);
+    }
+
+    #[test]
+    fn finished_detached_workers_are_forgotten() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..20 {
+            let tx = tx.clone();
+            pool.execute(move || {
+                ThreadPool::detach();
+                tx.send(()).unwrap();
+            });
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        // each detach reaps the threads that have exited by then
+        assert!(pool.shared.threads.lock().unwrap().len() < 10);
+    }
+
+    #[test]
+    fn full_until_a_worker_frees_up() {
+        let pool = ThreadPool::with_queue(1, 1);
+        let (release, released) = mpsc::channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..2 {
+            assert!(!pool.is_full());
+            let released = Arc::clone(&released);
+            let tx = tx.clone();
+            pool.execute(move || {
+                let _ = released.lock().unwrap().recv();
+                tx.send(()).unwrap();
+            });
+        }
+        assert!(pool.is_full());
+
+        release.send(()).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        // the finished job's count drops just after it sends
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.is_full() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!pool.is_full());
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn detached_jobs_do_not_count_towards_full() {
+        let pool = ThreadPool::with_queue(1, 0);
+        let (tx, rx) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        pool.execute(move || {
+            ThreadPool::detach();
+            tx.send(()).unwrap();
+            let _ = released.recv();
+        });
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(!pool.is_full());
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one worker")]
+    fn zero_workers_is_rejected() {
+        ThreadPool::new(0);
+    }
+}
@@ -0,0 +1,682 @@
+//! Reverse proxy: forwards requests under a path prefix to a pool of
+//! upstream HTTP/1.1 servers.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::{
+        Arc, Once, Weak,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::chunked::{self, ChunkedReader};
+use crate::handler::{Handler, Params};
+use crate::headers::Headers;
+use crate::request::{Limits, Line, Method, Request, read_headers, read_line};
+use crate::response::{Response, Status};
+use crate::router::Router;
+
+/// How long to wait on an upstream to connect, accept the request or answer.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that describe one connection rather than the message (RFC 9110 §7.6.1).
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// How a request picks among the healthy upstreams.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in flight; ties go round-robin.
+    LeastConnections,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Balance, String> {
+        match s {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-connections" => Ok(Balance::LeastConnections),
+            _ => Err(format!(
+                "unknown balancing {s:?} (expected round-robin or least-connections)"
+            )),
+        }
+    }
+}
+
+struct Upstream {
+    /// `host:port`.
+    addr: String,
+    healthy: AtomicBool,
+    active: AtomicUsize,
+}
+
+struct Pool {
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    /// Starts the health checks, once for the proxy and all its clones.
+    checking: Once,
+}
+
+impl Pool {
+    /// Indices of the healthy upstreams, best first.
+    fn candidates(&self, balance: Balance) -> Vec<usize> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let n = self.upstreams.len();
+        let mut candidates: Vec<usize> = (0..n)
+            .map(|i| (start + i) % n)
+            .filter(|&i| self.upstreams[i].healthy.load(Ordering::Relaxed))
+            .collect();
+        if balance == Balance::LeastConnections {
+            // stable, so equally loaded upstreams keep their round-robin order
+            candidates.sort_by_key(|&i| self.upstreams[i].active.load(Ordering::Relaxed));
+        }
+        candidates
+    }
+}
+
+/// Counts a request against its upstream until the response body is relayed.
+struct Active(Arc<Pool>, usize);
+
+impl Active {
+    fn upstream(&self) -> &Upstream {
+        &self.0.upstreams[self.1]
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.upstream().active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A [`Handler`] that forwards every request to one of its upstreams.
+///
+/// ```no_run
+/// # use host::{Router, proxy::{Balance, Proxy}};
+/// # use std::time::Duration;
+/// let router = Proxy::new(["127.0.0.1:3000", "127.0.0.1:3001"])
+///     .balance(Balance::LeastConnections)
+///     .health_check("/healthz", Duration::from_secs(5))
+///     .mount(Router::new(), "/api");
+/// ```
+#[derive(Clone)]
+pub struct Proxy {
+    pool: Arc<Pool>,
+    balance: Balance,
+    timeout: Duration,
+    /// Path and interval, if the upstreams are health-checked.
+    health_check: Option<(String, Duration)>,
+    strip_prefix: bool,
+    prefix: String,
+}
+
+impl Proxy {
+    /// A proxy to `upstreams`, each `host:port` with an optional `http://`.
+    pub fn new<S: AsRef<str>>(upstreams: impl IntoIterator<Item = S>) -> Proxy {
+        let upstreams = upstreams
+            .into_iter()
+            .map(|addr| {
+                let addr = addr.as_ref();
+                Upstream {
+                    addr: addr
+                        .strip_prefix("http://")
+                        .unwrap_or(addr)
+                        .trim_end_matches('/')
+                        .to_string(),
+                    healthy: AtomicBool::new(true),
+                    active: AtomicUsize::new(0),
+                }
+            })
+            .collect();
+        Proxy {
+            pool: Arc::new(Pool {
+                upstreams,
+                next: AtomicUsize::new(0),
+                checking: Once::new(),
+            }),
+            balance: Balance::default(),
+            timeout: DEFAULT_TIMEOUT,
+            health_check: None,
+            strip_prefix: false,
+            prefix: String::new(),
+        }
+    }
+
+    pub fn balance(mut self, balance: Balance) -> Proxy {
+        self.balance = balance;
+        self
+    }
+
+    /// Set the connect, send and per-read timeout for upstream requests.
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Remove the mount prefix before forwarding, so `/api/users` reaches the
+    /// upstream as `/users`.
+    pub fn strip_prefix(mut self, strip: bool) -> Proxy {
+        self.strip_prefix = strip;
+        self
+    }
+
+    /// `GET path` on every upstream each `interval` on a background thread.
+    /// Upstreams that fail to answer 2xx or 3xx get no traffic until they do.
+    /// The thread starts when the proxy is mounted or first used, and stops
+    /// once the proxy is dropped.
+    pub fn health_check(mut self, path: impl Into<String>, interval: Duration) -> Proxy {
+        self.health_check = Some((path.into(), interval));
+        self
+    }
+
+    /// Start the health checks, with the settings the proxy ended up with.
+    fn start_health_checks(&self) {
+        let Some((path, interval)) = self.health_check.clone() else {
+            return;
+        };
+        self.pool.checking.call_once(|| {
+            let (pool, timeout) = (Arc::downgrade(&self.pool), self.timeout);
+            thread::spawn(move || run_health_checks(&pool, &path, interval, timeout));
+        });
+    }
+
+    /// Route `prefix` and everything below it, for every method, to this proxy.
+    pub fn mount(mut self, router: Router, prefix: &str) -> Router {
+        self.start_health_checks();
+        let prefix = prefix.trim_end_matches('/');
+        self.prefix = prefix.to_string();
+        if prefix.is_empty() {
+            return router.any("/*path", self);
+        }
+        router
+            .any(prefix, self.clone())
+            .any(&format!("{prefix}/*path"), self)
+    }
+
+    fn target<'a>(&self, request: &'a Request) -> std::borrow::Cow<'a, str> {
+        let target = request.target.as_str();
+        match target.strip_prefix(self.prefix.as_str()) {
+            Some(rest) if self.strip_prefix && !self.prefix.is_empty() => {
+                if rest.starts_with('/') {
+                    rest.into()
+                } else {
+                    format!("/{rest}").into()
+                }
+            }
+            _ => target.into(),
+        }
+    }
+
+    fn forward(&self, request: &Request, active: Active, stream: TcpStream) -> Response {
+        let addr = active.upstream().addr.clone();
+        let _ = stream.set_read_timeout(Some(self.timeout));
+        let _ = stream.set_write_timeout(Some(self.timeout));
+
+        let head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            request.method.as_str(),
+            self.target(request),
+            addr
+        );
+        let mut headers = end_to_end(&request.headers);
+        for name in ["Host", "Content-Length", "X-Forwarded-For"] {
+            headers.remove(name);
+        }
+        if let Some(host) = request.header("Host") {
+            headers.set("X-Forwarded-Host", host);
+        }
+        let proto = if request.secure { "https" } else { "http" };
+        headers.set("X-Forwarded-Proto", proto);
+        let mut forwarded_for: Vec<&str> = request.headers.get_all("X-Forwarded-For").collect();
+        let remote = request.remote.map(|addr| addr.ip().to_string());
+        forwarded_for.extend(remote.as_deref());
+        if !forwarded_for.is_empty() {
+            headers.set("X-Forwarded-For", forwarded_for.join(", "));
+        }
+        if !request.body.is_empty() || matches!(request.method, Method::Post | Method::Put) {
+            headers.add("Content-Length", request.body.len().to_string());
+        }
+        headers.add("Connection", "close");
+        let mut head = head.into_bytes();
+        for (name, value) in headers.iter() {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend(raw(value));
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+
+        let mut writer = &stream;
+        if let Err(e) = writer
+            .write_all(&head)
+            .and_then(|()| writer.write_all(&request.body))
+        {
+            return upstream_error(&addr, &e);
+        }
+
+        let mut reader = BufReader::new(stream);
+        let (status, headers) = match read_response_head(&mut reader) {
+            Ok(head) => head,
+            Err(e) => return upstream_error(&addr, &e),
+        };
+
+        let mut response = Response::new(status);
+        for (name, value) in end_to_end(&headers).iter() {
+            match String::from_utf8(raw(value).collect()) {
+                Ok(value) => response.headers.add(name, value),
+                // a response can only hold text, which would garble these bytes
+                Err(_) => eprintln!("Upstream {addr} sent a {name} header that is not UTF-8"),
+            }
+        }
+        if !status.allows_body() {
+            return response;
+        }
+        if request.method == Method::Head {
+            // without a length of its own, a buffered response would claim
+            // `Content-Length: 0`; a stream, never run, is framed like the GET
+            return match headers.contains("Content-Length") {
+                true => response,
+                false => response.with_stream(|_| Ok(())),
+            };
+        }
+        if headers.has_token("Transfer-Encoding", "chunked") {
+            let body = ChunkedReader::new(reader, Limits::default());
+            return response.with_stream(relay(body, active));
+        }
+        match headers
+            .get("Content-Length")
+            .map(|n| n.trim().parse::<u64>())
+        {
+            // relayed as-is, since the client keeps its Content-Length
+            Some(Ok(len)) => response.with_stream(relay(reader.take(len), active)),
+            Some(Err(_)) => Response::error(Status::BAD_GATEWAY),
+            // delimited by the upstream closing; re-framed as chunks
+            None => response.with_stream(relay(reader, active)),
+        }
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: &Request, _params: &Params) -> Response {
+        self.start_health_checks();
+        let candidates = self.pool.candidates(self.balance);
+        if candidates.is_empty() {
+            return Response::error(Status::SERVICE_UNAVAILABLE);
+        }
+        // nothing has been sent until a connection succeeds, so trying the
+        // next upstream is safe for every method
+        let mut last_error = None;
+        for index in candidates {
+            let upstream = &self.pool.upstreams[index];
+            upstream.active.fetch_add(1, Ordering::Relaxed);
+            let active = Active(Arc::clone(&self.pool), index);
+            match connect(&upstream.addr, self.timeout) {
+                Ok(stream) => return self.forward(request, active, stream),
+                Err(e) => last_error = Some((upstream, e)),
+            }
+        }
+        let (upstream, e) = last_error.expect("at least one upstream was tried");
+        upstream_error(&upstream.addr, &e)
+    }
+}
+
+/// Copy the body to the client, holding the upstream's connection count until done.
+fn relay<R: Read + Send + 'static>(
+    body: R,
+    active: Active,
+) -> impl FnOnce(&mut crate::BodyWriter<'_>) -> io::Result<()> + Send + 'static {
+    move |out| {
+        let _active = active;
+        (chunked::from_reader(body))(out)
+    }
+}
+
+fn upstream_error(addr: &str, e: &io::Error) -> Response {
+    eprintln!("Upstream {addr} failed: {e}");
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            Response::error(Status::GATEWAY_TIMEOUT)
+        }
+        _ => Response::error(Status::BAD_GATEWAY),
+    }
+}
+
+/// A parsed header value's bytes as they arrived, one per char (see
+/// `request::parse_header_line`).
+fn raw(value: &str) -> impl Iterator<Item = u8> + '_ {
+    value.chars().map(|c| c as u8)
+}
+
+/// `headers` without the hop-by-hop fields, including any `Connection` names.
+fn end_to_end(headers: &Headers) -> Headers {
+    let listed: Vec<String> = headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    let mut out = Headers::new();
+    for (name, value) in headers.iter() {
+        let hop = HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+            || listed.iter().any(|l| l.eq_ignore_ascii_case(name));
+        if !hop {
+            out.add(name, value);
+        }
+    }
+    out
+}
+
+fn connect(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{addr} resolves to no address"),
+    );
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Read a status line and headers, skipping interim 1xx responses.
+fn read_response_head<R: BufRead>(reader: &mut R) -> io::Result<(Status, Headers)> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let limits = Limits::default();
+    loop {
+        let line = match read_line(reader, limits.max_header_line)? {
+            Some(Line::Complete(line)) => line,
+            Some(Line::TooLong) => return Err(invalid("status line too long")),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        let line = String::from_utf8_lossy(&line);
+        let mut parts = line.splitn(3, ' ');
+        let (Some(version), Some(code)) = (parts.next(), parts.next()) else {
+            return Err(invalid("malformed status line"));
+        };
+        let code: u16 = match code.parse() {
+            Ok(code) if version.starts_with("HTTP/1.") && (100..600).contains(&code) => code,
+            _ => return Err(invalid("malformed status line")),
+        };
+        let headers = read_headers(reader, &limits).map_err(|e| match e {
+            crate::ParseError::Io(e) => e,
+            e => invalid(&e.to_string()),
+        })?;
+        match code {
+            101 => return Err(invalid("upgrades are not proxied")),
+            100..=199 => continue,
+            _ => return Ok((Status(code), headers)),
+        }
+    }
+}
+
+fn run_health_checks(pool: &Weak<Pool>, path: &str, interval: Duration, timeout: Duration) {
+    loop {
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        for upstream in &pool.upstreams {
+            let healthy = check(&upstream.addr, path, timeout.min(interval));
+            if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                let state = if healthy { "up" } else { "down" };
+                eprintln!("Upstream {} is {state}", upstream.addr);
+            }
+        }
+        drop(pool);
+        thread::sleep(interval);
+    }
+}
+
+fn check(addr: &str, path: &str, timeout: Duration) -> bool {
+    let answer = || -> io::Result<Status> {
+        let mut stream = connect(addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n"
+        )?;
+        Ok(read_response_head(&mut BufReader::new(stream))?.0)
+    };
+    answer().is_ok_and(|status| (200..400).contains(&status.code()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{SocketAddr, TcpListener},
+        sync::Mutex,
+        time::Instant,
+    };
+
+    /// An upstream that answers each connection with `respond(request head)`
+    /// and records every request head it saw.
+    struct Stub {
+        addr: SocketAddr,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    fn stub(respond: impl Fn(&str) -> String + Send + 'static) -> Stub {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                while reader.read_line(&mut head).unwrap_or(0) > 2 {}
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("Content-Length: "))
+                    .map_or(0, |n| n.parse().unwrap());
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                head.push_str(&String::from_utf8_lossy(&body));
+                let _ = stream.write_all(respond(&head).as_bytes());
+                log.lock().unwrap().push(head);
+            }
+        });
+        Stub { addr, seen }
+    }
+
+    fn request(raw: &str) -> Request {
+        let mut request = Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap();
+        request.remote = Some("10.0.0.7:5000".parse().unwrap());
+        request
+    }
+
+    fn body(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out[out.find("\r\n\r\n").unwrap() + 4..].to_string()
+    }
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[test]
+    fn forwards_with_rewritten_headers() {
+        let upstream = stub(|_| {
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nX-Up: 1\r\nConnection: close, X-Up\r\n\
+             Transfer-Encoding: chunked\r\n\r\n3\r\nnew\r\n0\r\n\r\n"
+                .to_string()
+        });
+        let router = Proxy::new([format!("http://{}/", upstream.addr)])
+            .strip_prefix(true)
+            .mount(Router::new(), "/svc/");
+        let response = router.handle(
+            &request(
+                "POST /svc/items?x=1 HTTP/1.1\r\nHost: example.test\r\nX-Forwarded-For: 192.0.2.1\r\n\
+                 Keep-Alive: timeout=5\r\nContent-Length: 4\r\n\r\ndata",
+            ),
+            &Params::new(),
+        );
+        assert_eq!(response.status, Status::CREATED);
+        assert_eq!(response.headers.get("X-Up"), None);
+        assert_eq!(body(response), "3\r\nnew\r\n0\r\n\r\n");
+
+        let seen = upstream.seen.lock().unwrap();
+        let head = &seen[0];
+        assert!(head.starts_with("POST /items?x=1 HTTP/1.1\r\n"), "{head}");
+        assert!(
+            head.contains(&format!("Host: {}\r\n", upstream.addr)),
+            "{head}"
+        );
+        assert!(
+            head.contains("X-Forwarded-Host: example.test\r\n"),
+            "{head}"
+        );
+        assert!(
+            head.contains("X-Forwarded-For: 192.0.2.1, 10.0.0.7\r\n"),
+            "{head}"
+        );
+        assert!(!head.contains("Keep-Alive"), "{head}");
+        assert!(head.ends_with("\r\n\r\ndata"), "{head}");
+    }
+
+    #[test]
+    fn non_ascii_header_bytes_are_relayed_unchanged() {
+        let upstream = stub(|_| {
+            "HTTP/1.1 200 OK\r\nX-Name: caf\u{e9}\r\nContent-Length: 0\r\n\r\n".to_string()
+        });
+        let proxy = Proxy::new([upstream.addr.to_string()]);
+        let response = proxy.handle(
+            &request("GET / HTTP/1.1\r\nHost: h\r\nX-Name: na\u{ef}ve\r\n\r\n"),
+            &Params::new(),
+        );
+        assert_eq!(response.headers.get("X-Name"), Some("caf\u{e9}"));
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        assert!(
+            String::from_utf8(out)
+                .unwrap()
+                .contains("X-Name: caf\u{e9}\r\n")
+        );
+
+        let seen = upstream.seen.lock().unwrap();
+        assert!(seen[0].contains("X-Name: na\u{ef}ve\r\n"), "{}", seen[0]);
+    }
+
+    #[test]
+    fn head_of_a_chunked_upstream_gets_no_length() {
+        let upstream =
+            stub(|_| "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_string());
+        let proxy = Proxy::new([upstream.addr.to_string()]);
+        let response = proxy.handle(
+            &request("HEAD / HTTP/1.1\r\nHost: h\r\n\r\n"),
+            &Params::new(),
+        );
+        let mut out = Vec::new();
+        response.write_to(&mut out, true).unwrap();
+        let head = String::from_utf8(out).unwrap();
+        assert!(!head.contains("Content-Length"), "{head}");
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"), "{head}");
+        assert!(head.ends_with("\r\n\r\n"), "{head}");
+    }
+
+    #[test]
+    fn balances_and_fails_over() {
+        let a = stub(|_| ok("a"));
+        let b = stub(|_| ok("b"));
+        // reserved and closed again, so connecting is refused
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let proxy = Proxy::new([a.addr, dead, b.addr].map(|a| a.to_string()));
+        let get = request("GET /x HTTP/1.1\r\nHost: h\r\n\r\n");
+
+        // the dead upstream's turns go to the one after it
+        let bodies: Vec<String> = (0..6)
+            .map(|_| body(proxy.handle(&get, &Params::new())))
+            .collect();
+        assert_eq!(bodies, ["a", "b", "b", "a", "b", "b"]);
+
+        let proxy = Proxy::new([dead.to_string()]);
+        assert_eq!(
+            proxy.handle(&get, &Params::new()).status,
+            Status::BAD_GATEWAY
+        );
+    }
+
+    #[test]
+    fn least_connections_skips_busy_upstreams() {
+        let a = stub(|_| ok("a"));
+        let b = stub(|_| ok("b"));
+        let proxy =
+            Proxy::new([a.addr, b.addr].map(|a| a.to_string())).balance(Balance::LeastConnections);
+        let get = request("GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+
+        // hold a's body open: the upstream still counts it as in flight
+        let first = proxy.handle(&get, &Params::new());
+        assert_eq!(proxy.pool.upstreams[0].active.load(Ordering::Relaxed), 1);
+        for _ in 0..3 {
+            assert_eq!(body(proxy.handle(&get, &Params::new())), "b");
+        }
+        assert_eq!(body(first), "a");
+        assert_eq!(proxy.pool.upstreams[0].active.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn times_out_and_health_checks_mark_upstreams_down() {
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy::new([silent.local_addr().unwrap().to_string()])
+            .timeout(Duration::from_millis(100));
+        let get = request("GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(
+            proxy.handle(&get, &Params::new()).status,
+            Status::GATEWAY_TIMEOUT
+        );
+
+        let sick = stub(|head| match head.starts_with("GET /healthz ") {
+            true => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_string(),
+            false => ok("sick"),
+        });
+        // any order of the builder calls gives the same proxy
+        let proxy = Proxy::new([sick.addr.to_string()])
+            .health_check("/healthz", Duration::from_millis(20))
+            .balance(Balance::LeastConnections)
+            .timeout(Duration::from_millis(500));
+        assert_eq!(
+            (proxy.balance, proxy.timeout),
+            (Balance::LeastConnections, Duration::from_millis(500))
+        );
+        // the first request starts the checks
+        proxy.handle(&get, &Params::new());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while proxy.pool.upstreams[0].healthy.load(Ordering::Relaxed) {
+            assert!(Instant::now() < deadline, "upstream never marked down");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            proxy.handle(&get, &Params::new()).status,
+            Status::SERVICE_UNAVAILABLE
+        );
+    }
+}
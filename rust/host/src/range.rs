@@ -0,0 +1,466 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::caching::{ETag, Validators};
+use crate::date::{http_date, parse_http_date};
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+
+/// More ranges than this in one request are ignored and the whole body is
+/// sent, so a client cannot make us build a huge multipart response out of
+/// thousands of tiny overlapping slices.
+pub const MAX_RANGES: usize = 32;
+
+/// One `first-last`, `first-` or `-suffix` element of a `Range` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `first-last` or, with no `last`, `first-` to the end.
+    From { first: u64, last: Option<u64> },
+    /// `-n`: the final `n` bytes.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Concrete byte positions within a body of `len` bytes, if any are selected.
+    pub fn resolve(self, len: u64) -> Option<RangeInclusive<u64>> {
+        match self {
+            ByteRange::From { first, .. } if first >= len => None,
+            ByteRange::From { first, last } => {
+                Some(first..=last.map_or(len - 1, |last| last.min(len - 1)))
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(_) if len == 0 => None,
+            ByteRange::Suffix(n) => Some(len.saturating_sub(n)..=len - 1),
+        }
+    }
+}
+
+/// Parse a `Range` header. `None` means "ignore it": another unit, a syntax
+/// error, or too many ranges.
+pub fn parse(header: &str) -> Option<Vec<ByteRange>> {
+    let (unit, set) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in set.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = spec.split_once('-')?;
+        let number = |s: &str| -> Option<u64> {
+            s.bytes()
+                .all(|b| b.is_ascii_digit())
+                .then(|| s.parse().ok())?
+        };
+        let range = match (first.trim(), last.trim()) {
+            ("", "") => return None,
+            ("", suffix) => ByteRange::Suffix(number(suffix)?),
+            (first, "") => ByteRange::From {
+                first: number(first)?,
+                last: None,
+            },
+            (first, last) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if last < first {
+                    return None;
+                }
+                ByteRange::From {
+                    first,
+                    last: Some(last),
+                }
+            }
+        };
+        ranges.push(range);
+    }
+
+    (!ranges.is_empty() && ranges.len() <= MAX_RANGES).then_some(ranges)
+}
+
+/// Narrow a full `200 OK` response to the ranges the client asked for.
+///
+/// Returns the response untouched when there is no usable `Range`, when an
+/// `If-Range` validator no longer matches, or for anything but `GET`. Ranges
+/// that all fall past the end give 416 with the real length.
+pub fn apply(request: &Request, response: Response, validators: &Validators) -> Response {
+    let mut response = response.with_header("Accept-Ranges", "bytes");
+    let len = response.body.len() as u64;
+    let Some(ranges) = select(request, &response, validators, len) else {
+        return response;
+    };
+    let Some(pieces) = pieces(&mut response, &ranges, len) else {
+        return unsatisfiable(len);
+    };
+    let body = std::mem::take(&mut response.body);
+    response.body = match pieces.as_slice() {
+        // one range is a view of the body, which may be shared with a cache
+        [Piece::Slice { first, last }] => body.slice(*first as usize..=*last as usize),
+        _ => {
+            let mut parts = Vec::new();
+            for piece in pieces {
+                match piece {
+                    Piece::Bytes(bytes) => parts.extend_from_slice(&bytes),
+                    Piece::Slice { first, last } => {
+                        parts.extend_from_slice(&body[first as usize..=last as usize]);
+                    }
+                }
+            }
+            parts.into()
+        }
+    };
+    response
+}
+
+/// [`apply`] for a body of `len` bytes still in `file`, which is streamed
+/// rather than read into memory. `response` should have no body of its own.
+pub fn apply_file(
+    request: &Request,
+    response: Response,
+    mut file: File,
+    len: u64,
+    validators: &Validators,
+) -> Response {
+    let mut response = response.with_header("Accept-Ranges", "bytes");
+    let pieces = match select(request, &response, validators, len) {
+        None if len == 0 => Vec::new(),
+        None => vec![Piece::Slice {
+            first: 0,
+            last: len - 1,
+        }],
+        Some(ranges) => match pieces(&mut response, &ranges, len) {
+            Some(pieces) => pieces,
+            None => return unsatisfiable(len),
+        },
+    };
+
+    let length: u64 = pieces.iter().map(Piece::len).sum();
+    response.headers.set("Content-Length", length.to_string());
+    response.with_stream(move |out| {
+        for piece in pieces {
+            match piece {
+                Piece::Bytes(bytes) => out.write_all(&bytes)?,
+                Piece::Slice { first, last } => {
+                    file.seek(SeekFrom::Start(first))?;
+                    let wanted = last - first + 1;
+                    // a file cut short since it was opened must not leave the
+                    // client waiting for the rest of `Content-Length`
+                    if io::copy(&mut (&mut file).take(wanted), out)? < wanted {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// The ranges to send, or `None` to send the whole body.
+fn select(
+    request: &Request,
+    response: &Response,
+    validators: &Validators,
+    len: u64,
+) -> Option<Vec<RangeInclusive<u64>>> {
+    if request.method != Method::Get || response.status != Status::OK {
+        return None;
+    }
+    let ranges = request.header("Range").and_then(parse)?;
+    if let Some(if_range) = request.header("If-Range")
+        && !if_range_matches(if_range, validators)
+    {
+        return None;
+    }
+    Some(coalesce(
+        ranges.iter().filter_map(|r| r.resolve(len)).collect(),
+    ))
+}
+
+/// Merge overlapping and adjacent ranges (RFC 9110 §14.2), in ascending order,
+/// so no byte is sent twice however the client repeats itself.
+fn coalesce(mut ranges: Vec<RangeInclusive<u64>>) -> Vec<RangeInclusive<u64>> {
+    ranges.sort_by_key(|range| *range.start());
+    let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                *last = *last.start()..=*last.end().max(range.end());
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// `If-Range` holds either an entity-tag, which must match strongly, or a
+/// date, which must equal `Last-Modified` exactly (RFC 9110 §13.1.5).
+fn if_range_matches(if_range: &str, validators: &Validators) -> bool {
+    if let Some(tag) = ETag::parse(if_range) {
+        return validators
+            .etag
+            .as_ref()
+            .is_some_and(|etag| etag.strong_eq(&tag));
+    }
+    match (parse_http_date(if_range), validators.last_modified) {
+        (Some(date), Some(modified)) => http_date(modified) == http_date(date),
+        _ => false,
+    }
+}
+
+fn unsatisfiable(len: u64) -> Response {
+    Response::error(Status::RANGE_NOT_SATISFIABLE)
+        .with_header("Content-Range", format!("bytes */{len}"))
+}
+
+/// A 206 body in order: framing of our own, and slices of the full body.
+enum Piece {
+    Bytes(Vec<u8>),
+    Slice { first: u64, last: u64 },
+}
+
+impl Piece {
+    fn len(&self) -> u64 {
+        match self {
+            Piece::Bytes(bytes) => bytes.len() as u64,
+            Piece::Slice { first, last } => last - first + 1,
+        }
+    }
+}
+
+/// Turn `response` into a 206 for `ranges` of a `len`-byte body, returning
+/// what its body is made of: the one range, or a `multipart/byteranges`
+/// around several. `None` if no range is satisfiable.
+fn pieces(response: &mut Response, ranges: &[RangeInclusive<u64>], len: u64) -> Option<Vec<Piece>> {
+    let slice = |range: &RangeInclusive<u64>| Piece::Slice {
+        first: *range.start(),
+        last: *range.end(),
+    };
+    let pieces = match ranges {
+        [] => return None,
+        [range] => {
+            let (first, last) = (range.start(), range.end());
+            response
+                .headers
+                .set("Content-Range", format!("bytes {first}-{last}/{len}"));
+            vec![slice(range)]
+        }
+        _ => {
+            let content_type = response.headers.get("Content-Type").map(str::to_string);
+            let boundary = boundary();
+            let mut pieces = Vec::new();
+            for range in ranges {
+                let (first, last) = (range.start(), range.end());
+                let mut head = format!("--{boundary}\r\n");
+                if let Some(content_type) = &content_type {
+                    head.push_str(&format!("Content-Type: {content_type}\r\n"));
+                }
+                head.push_str(&format!(
+                    "Content-Range: bytes {first}-{last}/{len}\r\n\r\n"
+                ));
+                pieces.push(Piece::Bytes(head.into_bytes()));
+                pieces.push(slice(range));
+                pieces.push(Piece::Bytes(b"\r\n".to_vec()));
+            }
+            pieces.push(Piece::Bytes(format!("--{boundary}--\r\n").into_bytes()));
+            response.headers.set(
+                "Content-Type",
+                format!("multipart/byteranges; boundary={boundary}"),
+            );
+            pieces
+        }
+    };
+    response.status = Status::PARTIAL_CONTENT;
+    Some(pieces)
+}
+
+/// A boundary that will not plausibly occur in the body.
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("host-{nanos:016x}{count:08x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+    use std::time::Duration;
+
+    fn request(method: &str, headers: &str) -> Request {
+        let raw = format!("{method} / HTTP/1.1\r\nHost: x\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn full() -> Response {
+        Response::new(Status::OK)
+            .with_header("Content-Type", "text/plain")
+            .with_body("0123456789")
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: Some(ETag::parse(r#""v1""#).unwrap()),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(784_111_777)),
+        }
+    }
+
+    fn ranged(headers: &str) -> Response {
+        apply(&request("GET", headers), full(), &validators())
+    }
+
+    #[test]
+    fn parses_range_sets() {
+        assert_eq!(
+            parse("bytes=0-4, 6-, -3"),
+            Some(vec![
+                ByteRange::From {
+                    first: 0,
+                    last: Some(4)
+                },
+                ByteRange::From {
+                    first: 6,
+                    last: None
+                },
+                ByteRange::Suffix(3),
+            ])
+        );
+        for bad in [
+            "items=0-1",
+            "bytes=",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=-",
+            "bytes=1-+2",
+            "0-1",
+        ] {
+            assert_eq!(parse(bad), None, "{bad}");
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&many), None);
+    }
+
+    #[test]
+    fn resolves_against_length() {
+        let from = |first, last| ByteRange::From { first, last };
+        assert_eq!(from(2, Some(4)).resolve(10), Some(2..=4));
+        assert_eq!(from(2, Some(40)).resolve(10), Some(2..=9));
+        assert_eq!(from(2, None).resolve(10), Some(2..=9));
+        assert_eq!(from(10, None).resolve(10), None);
+        assert_eq!(ByteRange::Suffix(3).resolve(10), Some(7..=9));
+        assert_eq!(ByteRange::Suffix(30).resolve(10), Some(0..=9));
+        assert_eq!(ByteRange::Suffix(0).resolve(10), None);
+        assert_eq!(ByteRange::Suffix(3).resolve(0), None);
+    }
+
+    #[test]
+    fn single_range_is_206() {
+        let response = ranged("Range: bytes=2-4\r\n");
+        assert_eq!(response.status, Status::PARTIAL_CONTENT);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.headers.get("Content-Type"), Some("text/plain"));
+        assert_eq!(response.body, &b"234"[..]);
+
+        let response = ranged("Range: bytes=-3\r\n");
+        assert_eq!(response.body, &b"789"[..]);
+    }
+
+    #[test]
+    fn multiple_ranges_are_multipart() {
+        let response = ranged("Range: bytes=0-1,-2\r\n");
+        assert_eq!(response.status, Status::PARTIAL_CONTENT);
+        let content_type = response.headers.get("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let expected = format!(
+            "--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+             --{boundary}--\r\n"
+        );
+        assert_eq!(String::from_utf8(response.body.to_vec()).unwrap(), expected);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let response = ranged(&format!(
+            "Range: bytes={}\r\n",
+            vec!["0-"; MAX_RANGES].join(",")
+        ));
+        assert_eq!(response.status, Status::PARTIAL_CONTENT);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 0-9/10"));
+        assert_eq!(response.body, &b"0123456789"[..]);
+
+        // adjacent ones too, whatever order they come in
+        let response = ranged("Range: bytes=4-6, 0-1, 2-3\r\n");
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes 0-6/10"));
+        let response = ranged("Range: bytes=8-, 0-0, 1-1\r\n");
+        let body = String::from_utf8(response.body.to_vec()).unwrap();
+        assert!(
+            body.contains("Content-Range: bytes 0-1/10\r\n\r\n01\r\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"),
+            "{body}"
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_is_416() {
+        let response = ranged("Range: bytes=10-20\r\n");
+        assert_eq!(response.status, Status::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers.get("Content-Range"), Some("bytes */10"));
+
+        // one satisfiable range among several is enough
+        let response = ranged("Range: bytes=50-60, 9-\r\n");
+        assert_eq!(response.status, Status::PARTIAL_CONTENT);
+        assert_eq!(response.body, &b"9"[..]);
+    }
+
+    #[test]
+    fn ignored_ranges_send_everything() {
+        for headers in ["", "Range: lines=1-2\r\n", "Range: bytes=oops\r\n"] {
+            let response = ranged(headers);
+            assert_eq!(response.status, Status::OK, "{headers}");
+            assert_eq!(response.headers.get("Accept-Ranges"), Some("bytes"));
+        }
+        let head = apply(
+            &request("HEAD", "Range: bytes=0-1\r\n"),
+            full(),
+            &validators(),
+        );
+        assert_eq!(head.status, Status::OK);
+    }
+
+    #[test]
+    fn if_range_needs_a_current_validator() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(
+            ranged("Range: bytes=0-1\r\nIf-Range: \"v1\"\r\n").status,
+            Status::PARTIAL_CONTENT
+        );
+        assert_eq!(
+            ranged(&format!("Range: bytes=0-1\r\nIf-Range: {date}\r\n")).status,
+            Status::PARTIAL_CONTENT
+        );
+
+        // a stale or weak validator means the client's partial copy is out of date
+        assert_eq!(
+            ranged("Range: bytes=0-1\r\nIf-Range: \"v0\"\r\n").status,
+            Status::OK
+        );
+        assert_eq!(
+            ranged("Range: bytes=0-1\r\nIf-Range: W/\"v1\"\r\n").status,
+            Status::OK
+        );
+        let later = "Range: bytes=0-1\r\nIf-Range: Sun, 06 Nov 1994 08:49:38 GMT\r\n";
+        assert_eq!(ranged(later).status, Status::OK);
+    }
+}
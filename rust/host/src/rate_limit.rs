@@ -0,0 +1,130 @@
+//! Per-client request rate limiting with token buckets.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Buckets kept before full ones are forgotten; a full bucket is the same as a new one.
+const PRUNE_AT: usize = 10_000;
+
+/// How many requests each client IP may make: `requests` every `per`,
+/// with up to `burst` at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per: Duration,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// `requests` every `per`, all of which may come at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `requests` or `per` is zero.
+    pub fn new(requests: u32, per: Duration) -> RateLimit {
+        assert!(
+            requests > 0 && !per.is_zero(),
+            "rate limit must allow some requests"
+        );
+        RateLimit {
+            requests,
+            per,
+            burst: requests,
+        }
+    }
+
+    /// Allow up to `burst` requests at once.
+    pub fn burst(mut self, burst: u32) -> RateLimit {
+        self.burst = burst.max(1);
+        self
+    }
+
+    fn per_second(&self) -> f64 {
+        f64::from(self.requests) / self.per.as_secs_f64()
+    }
+}
+
+/// One token bucket per client IP.
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for `ip`, or say how long until one is available.
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let (per_second, burst) = (self.limit.per_second(), f64::from(self.limit.burst));
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_AT {
+            buckets.retain(|_, b| b.refilled(now, per_second, burst) < burst);
+        }
+        // an IPv4 client seen through an IPv6 socket is still one client
+        let bucket = buckets.entry(ip.to_canonical()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, per_second, burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, per_second: f64, burst: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * per_second).min(burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_bursts_then_refills() {
+        let limiter = RateLimiter::new(RateLimit::new(2, Duration::from_secs(1)).burst(3));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ip, start), Ok(()));
+        }
+        assert_eq!(limiter.check(ip, start), Err(Duration::from_millis(500)));
+        // other clients have buckets of their own
+        assert_eq!(limiter.check("192.0.2.2".parse().unwrap(), start), Ok(()));
+        // the same client over an IPv6 socket shares its bucket
+        let mapped: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+        assert!(limiter.check(mapped, start).is_err());
+
+        let later = start + Duration::from_millis(750);
+        assert_eq!(limiter.check(ip, later), Ok(()));
+        assert_eq!(limiter.check(ip, later), Err(Duration::from_millis(250)));
+        // refills stop at the burst size
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(ip, much_later), Ok(()));
+        }
+        assert!(limiter.check(ip, much_later).is_err());
+    }
+}
@@ -0,0 +1,699 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+    net::SocketAddr,
+    str::FromStr,
+};
+
+use crate::chunked;
+use crate::headers::Headers;
+use crate::response::Status;
+
+/// Request method. Unknown tokens are kept so handlers can answer 405 with an `Allow` list.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+    Patch,
+    Trace,
+    Connect,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(token) => token,
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    /// Methods are case-sensitive tokens (RFC 9110 §9.1).
+    fn from_str(s: &str) -> Result<Method, ParseError> {
+        Ok(match s {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            _ if !s.is_empty() && s.bytes().all(is_token_byte) => Method::Other(s.to_string()),
+            _ => return Err(ParseError::BadRequest("invalid method")),
+        })
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Version, ParseError> {
+        let digits = s
+            .strip_prefix("HTTP/")
+            .ok_or(ParseError::BadRequest("invalid HTTP version"))?;
+        match digits.as_bytes() {
+            b"1.0" => Ok(Version::Http10),
+            b"1.1" => Ok(Version::Http11),
+            [major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                Err(ParseError::VersionNotSupported)
+            }
+            [major] if major.is_ascii_digit() => Err(ParseError::VersionNotSupported),
+            _ => Err(ParseError::BadRequest("invalid HTTP version")),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Size limits applied while reading a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest request line accepted, in bytes; longer ones get 414.
+    pub max_request_line: usize,
+    /// Longest single header line accepted; longer ones get 431.
+    pub max_header_line: usize,
+    /// Most header fields accepted; more get 431.
+    pub max_headers: usize,
+    /// Largest body accepted; larger ones get 413.
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_request_line: 8 * 1024,
+            max_header_line: 8 * 1024,
+            max_headers: 100,
+            max_body: 1024 * 1024,
+        }
+    }
+}
+
+/// Why a request could not be read. Everything but `Io` maps to an error status.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The connection failed or closed mid-request; there is nobody to answer.
+    Io(io::Error),
+    BadRequest(&'static str),
+    UriTooLong,
+    HeadersTooLarge,
+    PayloadTooLarge,
+    VersionNotSupported,
+    NotImplemented(&'static str),
+}
+
+impl ParseError {
+    /// Status to answer with, or `None` when the client is gone.
+    pub fn status(&self) -> Option<Status> {
+        match self {
+            ParseError::Io(_) => None,
+            ParseError::BadRequest(_) => Some(Status::BAD_REQUEST),
+            ParseError::UriTooLong => Some(Status::URI_TOO_LONG),
+            ParseError::HeadersTooLarge => Some(Status::HEADER_FIELDS_TOO_LARGE),
+            ParseError::PayloadTooLarge => Some(Status::PAYLOAD_TOO_LARGE),
+            ParseError::VersionNotSupported => Some(Status::VERSION_NOT_SUPPORTED),
+            ParseError::NotImplemented(_) => Some(Status::NOT_IMPLEMENTED),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "I/O error reading request: {e}"),
+            ParseError::BadRequest(why) | ParseError::NotImplemented(why) => f.write_str(why),
+            ParseError::UriTooLong => f.write_str("request line too long"),
+            ParseError::HeadersTooLarge => f.write_str("request headers too large"),
+            ParseError::PayloadTooLarge => f.write_str("request body too large"),
+            ParseError::VersionNotSupported => f.write_str("HTTP version not supported"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+/// A parsed HTTP/1.x request with its body fully read and, if chunked, decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: Method,
+    /// The request-target exactly as sent, e.g. `/days/1?part=2`.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// Trailer fields sent after a chunked body; empty otherwise.
+    pub trailers: Headers,
+    /// The client's address, filled in by the server; `None` for parsed-only requests.
+    pub remote: Option<SocketAddr>,
+    /// Whether the request arrived over TLS.
+    pub secure: bool,
+}
+
+impl Request {
+    /// Read one request from `reader`.
+    ///
+    /// Returns `Ok(None)` if the peer closed the connection before sending anything.
+    pub fn read_from<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<Option<Request>, ParseError> {
+        let Some(mut request) = Request::read_head(reader, limits)? else {
+            return Ok(None);
+        };
+        request.read_body(reader, limits)?;
+        Ok(Some(request))
+    }
+
+    /// Read the request line and headers, leaving the body for [`Request::read_body`].
+    pub(crate) fn read_head<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<Option<Request>, ParseError> {
+        // RFC 9112 §2.2: ignore at least one empty line before the request line
+        let line = loop {
+            match read_line(reader, limits.max_request_line)? {
+                None => return Ok(None),
+                Some(Line::TooLong) => return Err(ParseError::UriTooLong),
+                Some(Line::Complete(line)) if line.is_empty() => continue,
+                Some(Line::Complete(line)) => break line,
+            }
+        };
+        let (method, target, version) = parse_request_line(&line)?;
+        let headers = read_headers(reader, limits)?;
+
+        Ok(Some(Request {
+            method,
+            target,
+            version,
+            headers,
+            body: Vec::new(),
+            trailers: Headers::new(),
+            remote: None,
+            secure: false,
+        }))
+    }
+
+    /// Read the body announced by the headers, then check the request is complete.
+    pub(crate) fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<(), ParseError> {
+        (self.body, self.trailers) = read_body(reader, &self.headers, limits)?;
+        check_host(self.version, &self.headers)
+    }
+
+    /// Target without its query string.
+    pub fn path(&self) -> &str {
+        self.target
+            .split_once('?')
+            .map_or(&self.target, |(path, _)| path)
+    }
+
+    /// Query string after `?`, if any.
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The `Host` header without its port, e.g. `example.test` or `[::1]`.
+    /// `None` if the header is missing or empty.
+    pub fn host(&self) -> Option<&str> {
+        let host = self.header("Host")?.trim();
+        // minding IPv6 literals such as [::1]:8080
+        let name = match host.rfind(':') {
+            Some(colon) if !host[colon..].contains(']') => &host[..colon],
+            _ => host,
+        };
+        (!name.is_empty()).then_some(name)
+    }
+}
+
+pub(crate) enum Line {
+    Complete(Vec<u8>),
+    TooLong,
+}
+
+/// Read up to and including `\n`, returning the line without its `\r\n`.
+///
+/// At most `limit` bytes are buffered; the rest of an oversized line is not read.
+pub(crate) fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> io::Result<Option<Line>> {
+    let mut line = Vec::new();
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (chunk, found) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (&available[..=i], true),
+            None => (available, false),
+        };
+        if line.len() + chunk.len() > limit + 2 {
+            return Ok(Some(Line::TooLong));
+        }
+        line.extend_from_slice(chunk);
+        let used = chunk.len();
+        reader.consume(used);
+
+        if found {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.len() > limit {
+                return Ok(Some(Line::TooLong));
+            }
+            return Ok(Some(Line::Complete(line)));
+        }
+    }
+}
+
+fn parse_request_line(line: &[u8]) -> Result<(Method, String, Version), ParseError> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .filter(|l| l.is_ascii())
+        .ok_or(ParseError::BadRequest("request line is not ASCII"))?;
+
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::BadRequest("malformed request line"));
+    };
+
+    let method = method.parse()?;
+    let version = version.parse()?;
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+
+    Ok((method, target.to_string(), version))
+}
+
+pub(crate) fn read_headers<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+    loop {
+        let line = match read_line(reader, limits.max_header_line)? {
+            None => return Err(ParseError::Io(io::ErrorKind::UnexpectedEof.into())),
+            Some(Line::TooLong) => return Err(ParseError::HeadersTooLarge),
+            Some(Line::Complete(line)) => line,
+        };
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        let (name, value) = parse_header_line(&line)?;
+        headers.add(name, value);
+    }
+}
+
+fn parse_header_line(line: &[u8]) -> Result<(String, String), ParseError> {
+    if matches!(line.first(), Some(b' ' | b'\t')) {
+        return Err(ParseError::BadRequest("obsolete header line folding"));
+    }
+    let colon = line
+        .iter()
+        .position(|&b| b == b':')
+        .ok_or(ParseError::BadRequest("header line without colon"))?;
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+
+    // no whitespace is allowed between the name and the colon (RFC 9112 §5.1)
+    if name.is_empty() || !name.iter().copied().all(is_token_byte) {
+        return Err(ParseError::BadRequest("invalid header name"));
+    }
+    if value.iter().any(|&b| b.is_ascii_control() && b != b'\t') {
+        return Err(ParseError::BadRequest("invalid header value"));
+    }
+
+    // values are opaque octets; Latin-1 maps each one to a char without failing
+    let value: String = value.trim_ascii().iter().map(|&b| b as char).collect();
+    let name = String::from_utf8(name.to_vec()).expect("token bytes are ASCII");
+    Ok((name, value))
+}
+
+fn read_body<R: BufRead>(
+    reader: &mut R,
+    headers: &Headers,
+    limits: &Limits,
+) -> Result<(Vec<u8>, Headers), ParseError> {
+    let length = match framing(headers, limits)? {
+        Framing::Chunked => return chunked::read_chunked(reader, limits),
+        Framing::Length(0) => return Ok((Vec::new(), Headers::new())),
+        Framing::Length(length) => length,
+    };
+
+    let mut body = Vec::with_capacity(length as usize);
+    reader.take(length).read_to_end(&mut body)?;
+    if body.len() as u64 != length {
+        return Err(ParseError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok((body, Headers::new()))
+}
+
+/// How the end of a request body is found (RFC 9112 §6.3).
+pub(crate) enum Framing {
+    /// This many bytes, already checked against `max_body`.
+    Length(u64),
+    Chunked,
+}
+
+pub(crate) fn framing(headers: &Headers, limits: &Limits) -> Result<Framing, ParseError> {
+    if headers.contains("Transfer-Encoding") {
+        if headers.contains("Content-Length") {
+            return Err(ParseError::BadRequest(
+                "both Transfer-Encoding and Content-Length",
+            ));
+        }
+        // every field line counts, as if joined by commas (RFC 9110 §5.3), or a
+        // proxy further along could see a different last coding than this does
+        let codings: Vec<&str> = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|c| !c.is_empty() && !c.eq_ignore_ascii_case("identity"))
+            .collect();
+        match codings.as_slice() {
+            [] => {}
+            [coding] if coding.eq_ignore_ascii_case("chunked") => return Ok(Framing::Chunked),
+            // without chunked last the body length is unknowable (RFC 9112 §6.3)
+            [.., last] if !last.eq_ignore_ascii_case("chunked") => {
+                return Err(ParseError::BadRequest("chunked is not the final encoding"));
+            }
+            _ => return Err(ParseError::NotImplemented("unsupported transfer encoding")),
+        }
+    }
+
+    let length = content_length(headers)?;
+    if length > limits.max_body as u64 {
+        return Err(ParseError::PayloadTooLarge);
+    }
+    Ok(Framing::Length(length))
+}
+
+/// HTTP/1.1 requests need exactly one `Host`; none may have two (RFC 9112 §3.2).
+fn check_host(version: Version, headers: &Headers) -> Result<(), ParseError> {
+    let mut hosts = headers.get_all("Host");
+    match (hosts.next(), hosts.next()) {
+        (None, _) if version == Version::Http11 => {
+            Err(ParseError::BadRequest("missing Host header"))
+        }
+        (None, _) => Ok(()),
+        (Some(_), Some(_)) => Err(ParseError::BadRequest("more than one Host header")),
+        (Some(host), None) => {
+            // reg-name or IP literal, then an optional port (RFC 3986 §3.2.2)
+            let valid = |b: u8| b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=%:[]".contains(&b);
+            if host.bytes().all(valid) {
+                Ok(())
+            } else {
+                Err(ParseError::BadRequest("invalid Host header"))
+            }
+        }
+    }
+}
+
+/// `Content-Length`, requiring every copy (and every list element) to agree.
+fn content_length(headers: &Headers) -> Result<u64, ParseError> {
+    let mut length = None;
+    for value in headers.get_all("Content-Length").flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::BadRequest("invalid Content-Length"));
+        }
+        // a number too big for u64 is certainly too big for the body limit
+        let parsed = value.parse::<u64>().unwrap_or(u64::MAX);
+        if length.is_some_and(|l| l != parsed) {
+            return Err(ParseError::BadRequest("conflicting Content-Length"));
+        }
+        length = Some(parsed);
+    }
+    Ok(length.unwrap_or(0))
+}
+
+/// `tchar` from RFC 9110 §5.6.2.
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &[u8]) -> Result<Option<Request>, ParseError> {
+        Request::read_from(&mut &raw[..], &Limits::default())
+    }
+
+    fn status(raw: &[u8]) -> Status {
+        parse(raw)
+            .expect_err("request should have been rejected")
+            .status()
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_request_line_headers_and_body() {
+        let request = parse(
+            b"POST /days/1?part=2 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello",
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.target, "/days/1?part=2");
+        assert_eq!(request.path(), "/days/1");
+        assert_eq!(request.query(), Some("part=2"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn accepts_bare_lf_and_leading_blank_line() {
+        let request = parse(b"\r\nGET / HTTP/1.0\nAccept:  */*  \n\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.version, Version::Http10);
+        assert_eq!(request.header("Accept"), Some("*/*"));
+    }
+
+    #[test]
+    fn empty_connection_is_not_an_error() {
+        assert!(parse(b"").unwrap().is_none());
+    }
+
+    #[test]
+    fn non_utf8_is_rejected_not_panicked_on() {
+        assert_eq!(status(b"GET /\xff HTTP/1.1\r\n\r\n"), Status::BAD_REQUEST);
+
+        let request = parse(b"GET / HTTP/1.1\r\nHost: x\r\nX-Name: caf\xe9\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.header("x-name"), Some("café"));
+    }
+
+    #[test]
+    fn malformed_requests_get_400() {
+        assert_eq!(status(b"GET /\r\n\r\n"), Status::BAD_REQUEST);
+        assert_eq!(status(b"GET  / HTTP/1.1\r\n\r\n"), Status::BAD_REQUEST);
+        assert_eq!(status(b"G(T / HTTP/1.1\r\n\r\n"), Status::BAD_REQUEST);
+        assert_eq!(status(b"GET / HTTX/1.1\r\n\r\n"), Status::BAD_REQUEST);
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nNoColon\r\n\r\n"),
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nHost : x\r\n\r\n"),
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n"),
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"),
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            Status::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn host_is_required_once_in_http_11() {
+        assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n"), Status::BAD_REQUEST);
+        assert_eq!(
+            status(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"),
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            status(b"GET / HTTP/1.0\r\nHost: a/b\r\n\r\n"),
+            Status::BAD_REQUEST
+        );
+        assert!(parse(b"GET / HTTP/1.0\r\n\r\n").unwrap().is_some());
+
+        let request = parse(b"GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.host(), Some("[::1]"));
+        let request = parse(b"GET / HTTP/1.1\r\nHost:\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.host(), None);
+    }
+
+    #[test]
+    fn unknown_versions_get_505() {
+        assert_eq!(
+            status(b"GET / HTTP/2.0\r\n\r\n"),
+            Status::VERSION_NOT_SUPPORTED
+        );
+        assert_eq!(
+            status(b"GET / HTTP/3\r\n\r\n"),
+            Status::VERSION_NOT_SUPPORTED
+        );
+    }
+
+    #[test]
+    fn unknown_methods_are_kept() {
+        let request = parse(b"BREW /pot HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, Method::Other("BREW".to_string()));
+    }
+
+    #[test]
+    fn limits_map_to_their_statuses() {
+        let limits = Limits {
+            max_request_line: 32,
+            max_header_line: 32,
+            max_headers: 2,
+            max_body: 4,
+        };
+        let check = |raw: &[u8]| {
+            Request::read_from(&mut &raw[..], &limits)
+                .unwrap_err()
+                .status()
+                .unwrap()
+        };
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40));
+        assert_eq!(check(long_target.as_bytes()), Status::URI_TOO_LONG);
+
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(40));
+        assert_eq!(
+            check(long_header.as_bytes()),
+            Status::HEADER_FIELDS_TOO_LARGE
+        );
+
+        assert_eq!(
+            check(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+            Status::HEADER_FIELDS_TOO_LARGE
+        );
+        assert_eq!(
+            check(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"),
+            Status::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn truncated_body_is_an_io_error() {
+        let err = parse(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").unwrap_err();
+        assert!(matches!(err, ParseError::Io(_)));
+    }
+
+    #[test]
+    fn chunked_bodies_are_decoded() {
+        let request = parse(
+            b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n2;ext\r\nde\r\n0\r\nX-Sum: 5\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.body, b"abcde");
+        assert_eq!(request.trailers.get("X-Sum"), Some("5"));
+
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n"),
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n"),
+            Status::BAD_REQUEST
+        );
+        assert_eq!(
+            status(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+            Status::NOT_IMPLEMENTED
+        );
+        // the same codings on separate lines frame the body the same way
+        assert_eq!(
+            status(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n"
+            ),
+            Status::NOT_IMPLEMENTED
+        );
+        assert_eq!(
+            status(
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n\r\n"
+            ),
+            Status::BAD_REQUEST
+        );
+    }
+}
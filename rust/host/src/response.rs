@@ -0,0 +1,327 @@
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use bytes::Bytes;
+
+use crate::chunked::{BodyStream, BodyWriter};
+use crate::headers::Headers;
+use crate::upgrade::{UpgradeFn, Upgraded};
+
+/// HTTP status code with its canonical reason phrase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Status(pub u16);
+
+impl Status {
+    pub const SWITCHING_PROTOCOLS: Status = Status(101);
+    pub const OK: Status = Status(200);
+    pub const CREATED: Status = Status(201);
+    pub const NO_CONTENT: Status = Status(204);
+    pub const PARTIAL_CONTENT: Status = Status(206);
+    pub const MOVED_PERMANENTLY: Status = Status(301);
+    pub const FOUND: Status = Status(302);
+    pub const SEE_OTHER: Status = Status(303);
+    pub const NOT_MODIFIED: Status = Status(304);
+    pub const PERMANENT_REDIRECT: Status = Status(308);
+    pub const BAD_REQUEST: Status = Status(400);
+    pub const UNAUTHORIZED: Status = Status(401);
+    pub const FORBIDDEN: Status = Status(403);
+    pub const NOT_FOUND: Status = Status(404);
+    pub const METHOD_NOT_ALLOWED: Status = Status(405);
+    pub const REQUEST_TIMEOUT: Status = Status(408);
+    pub const CONFLICT: Status = Status(409);
+    pub const PAYLOAD_TOO_LARGE: Status = Status(413);
+    pub const URI_TOO_LONG: Status = Status(414);
+    pub const UNSUPPORTED_MEDIA_TYPE: Status = Status(415);
+    pub const RANGE_NOT_SATISFIABLE: Status = Status(416);
+    pub const UPGRADE_REQUIRED: Status = Status(426);
+    pub const TOO_MANY_REQUESTS: Status = Status(429);
+    pub const HEADER_FIELDS_TOO_LARGE: Status = Status(431);
+    pub const INTERNAL_SERVER_ERROR: Status = Status(500);
+    pub const NOT_IMPLEMENTED: Status = Status(501);
+    pub const BAD_GATEWAY: Status = Status(502);
+    pub const SERVICE_UNAVAILABLE: Status = Status(503);
+    pub const GATEWAY_TIMEOUT: Status = Status(504);
+    pub const VERSION_NOT_SUPPORTED: Status = Status(505);
+
+    pub fn code(self) -> u16 {
+        self.0
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self.0 {
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            413 => "Content Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            _ => "Unknown",
+        }
+    }
+
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    /// Whether responses with this status carry a body (and so a `Content-Length`).
+    pub fn allows_body(self) -> bool {
+        !(100..200).contains(&self.0) && self.0 != 204 && self.0 != 304
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.reason())
+    }
+}
+
+/// A response, normally buffered in memory before it is written.
+///
+/// A response with a `stream` sends that instead of `body`, for content whose
+/// length is not known up front. A `101` response with an `upgrade` hands the
+/// connection over once the headers are sent.
+pub struct Response {
+    pub status: Status,
+    pub headers: Headers,
+    pub body: Bytes,
+    pub stream: Option<BodyStream>,
+    pub upgrade: Option<UpgradeFn>,
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .field("body", &self.body)
+            .field("stream", &self.stream.as_ref().map(|_| ".."))
+            .field("upgrade", &self.upgrade.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl Response {
+    pub fn new(status: Status) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Bytes::new(),
+            stream: None,
+            upgrade: None,
+        }
+    }
+
+    pub fn html(body: impl Into<Bytes>) -> Response {
+        Response::new(Status::OK)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    pub fn json(body: impl Into<Bytes>) -> Response {
+        Response::new(Status::OK)
+            .with_header("Content-Type", "application/json")
+            .with_body(body)
+    }
+
+    /// Plain-text page such as `404 Not Found`, used for every error status.
+    pub fn error(status: Status) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(format!("{status}\n"))
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.set(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Bytes>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Stream the body from `write` instead of sending `body`, chunked.
+    ///
+    /// The connection drops back to close-delimiting for HTTP/1.0 clients.
+    /// A `Content-Length` header set before this call sends the stream
+    /// unchunked instead, and `write` must then produce exactly that many bytes.
+    pub fn with_stream<F>(mut self, write: F) -> Response
+    where
+        F: FnOnce(&mut BodyWriter<'_>) -> io::Result<()> + Send + 'static,
+    {
+        if !self.headers.contains("Content-Length") {
+            self.headers.set("Transfer-Encoding", "chunked");
+        }
+        self.stream = Some(Box::new(write));
+        self
+    }
+
+    /// Switch protocols: after this `101` response is sent, `upgrade` is
+    /// given the connection and the HTTP exchange on it is over.
+    pub fn switching_protocols<F>(protocol: &str, upgrade: F) -> Response
+    where
+        F: FnOnce(Upgraded) + Send + 'static,
+    {
+        let mut response = Response::new(Status::SWITCHING_PROTOCOLS)
+            .with_header("Upgrade", protocol)
+            .with_header("Connection", "Upgrade");
+        response.upgrade = Some(Box::new(upgrade));
+        response
+    }
+
+    /// Serialize as HTTP/1.1, adding `Content-Length` to buffered bodies and,
+    /// unless a `Connection` header is already set, `Connection: close`.
+    ///
+    /// With `head_only` the headers describe the body but the body itself is
+    /// left out, which is how `HEAD` responses are sent. A stream is
+    /// chunk-encoded if `Transfer-Encoding: chunked` is set and written as-is
+    /// otherwise.
+    ///
+    /// Returns the number of body bytes sent, for the access log.
+    pub fn write_to<W: Write>(mut self, mut out: W, head_only: bool) -> io::Result<u64> {
+        let buffered = self.stream.is_none();
+        let chunked = !buffered && self.headers.has_token("Transfer-Encoding", "chunked");
+        if chunked {
+            // never both (RFC 9112 §6.2): intermediaries could disagree on where the body ends
+            self.headers.remove("Content-Length");
+        }
+        let mut head = format!("HTTP/1.1 {}\r\n{}", self.status, self.headers);
+        if buffered && self.status.allows_body() && !self.headers.contains("Content-Length") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        if !self.headers.contains("Connection") {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        if head_only || !self.status.allows_body() {
+            out.write_all(head.as_bytes())?;
+            out.flush()?;
+            return Ok(0);
+        }
+        match self.stream {
+            None => {
+                // one write, so Nagle's algorithm does not hold the body back
+                // until the client acknowledges the head
+                let mut bytes = head.into_bytes();
+                bytes.extend_from_slice(&self.body);
+                out.write_all(&bytes)?;
+                out.flush()?;
+                Ok(self.body.len() as u64)
+            }
+            Some(stream) => {
+                out.write_all(head.as_bytes())?;
+                let mut writer = BodyWriter::new(&mut out, chunked);
+                stream(&mut writer)?;
+                writer.finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_status_headers_and_length() {
+        let mut out = Vec::new();
+        Response::html("<p>hi</p>")
+            .write_to(&mut out, false)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+             Content-Length: 9\r\nConnection: close\r\n\r\n<p>hi</p>"
+        );
+    }
+
+    #[test]
+    fn head_only_keeps_length_but_drops_body() {
+        let mut out = Vec::new();
+        Response::error(Status::NOT_FOUND)
+            .write_to(&mut out, true)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(text.contains("Content-Length: 14\r\n"));
+        assert!(text.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn streams_are_chunked() {
+        let mut out = Vec::new();
+        Response::new(Status::OK)
+            .with_stream(|body| {
+                body.write_all(b"one")?;
+                body.write_all(b"two")?;
+                body.trailer("X-Count", "2");
+                Ok(())
+            })
+            .write_to(&mut out, false)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             3\r\none\r\n3\r\ntwo\r\n0\r\nX-Count: 2\r\n\r\n"
+        );
+
+        // without the header the stream is the raw body, delimited by closing
+        let mut response = Response::new(Status::OK).with_stream(|body| body.write_all(b"raw"));
+        response.headers.remove("Transfer-Encoding");
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        assert!(out.ends_with(b"Connection: close\r\n\r\nraw"));
+    }
+
+    #[test]
+    fn streams_with_a_length_are_not_chunked() {
+        let mut out = Vec::new();
+        Response::new(Status::OK)
+            .with_header("Content-Length", "5")
+            .with_stream(|body| body.write_all(b"hello"))
+            .write_to(&mut out, false)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"
+        );
+
+        // a length set after chunking was chosen is dropped rather than sent alongside it
+        let mut out = Vec::new();
+        Response::new(Status::OK)
+            .with_stream(|body| body.write_all(b"hello"))
+            .with_header("Content-Length", "5")
+            .write_to(&mut out, false)
+            .unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(!text.contains("Content-Length"), "{text}");
+        assert!(text.ends_with("5\r\nhello\r\n0\r\n\r\n"), "{text}");
+    }
+}
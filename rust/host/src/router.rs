@@ -0,0 +1,333 @@
+use crate::handler::{Handler, Params};
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+
+/// Dispatches on method and path pattern, e.g. `GET /api/days/:day`.
+///
+/// Patterns are `/`-separated segments: literals match exactly, `:name`
+/// captures one segment, and a final `*name` captures the rest of the path.
+/// Routes are tried in the order they were added. A path that matches no
+/// pattern goes to the fallback handler (404 by default); a path that
+/// matches only under other methods gets 405 with an `Allow` header.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+struct Route {
+    /// `None` matches every method.
+    method: Option<Method>,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Add a route.
+    ///
+    /// A `GET` route also answers `HEAD`; the server drops the body.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` does not start with `/`, has an unnamed parameter,
+    /// or has a `*` segment anywhere but last.
+    pub fn route(mut self, method: Method, pattern: &str, handler: impl Handler) -> Router {
+        self.routes.push(Route {
+            method: Some(method),
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Add a route that answers every method, e.g. for a reverse proxy.
+    pub fn any(mut self, pattern: &str, handler: impl Handler) -> Router {
+        self.routes.push(Route {
+            method: None,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: impl Handler) -> Router {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler) -> Router {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler) -> Router {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler) -> Router {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Handler for paths no route matches, such as `StaticFiles`.
+    pub fn fallback(mut self, handler: impl Handler) -> Router {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Methods with a route matching `path`, in `Allow` header order.
+    fn allowed(&self, path: &str) -> Vec<&str> {
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in self
+            .routes
+            .iter()
+            .filter(|r| r.pattern.matches(path).is_some())
+        {
+            // a matching `any` route has already answered
+            let Some(method) = &route.method else {
+                continue;
+            };
+            let method = method.as_str();
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+            if method == "GET" && !allowed.contains(&"HEAD") {
+                allowed.push("HEAD");
+            }
+        }
+        allowed
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &Request, _params: &Params) -> Response {
+        let path = request.path();
+        for route in &self.routes {
+            let method_matches = match &route.method {
+                None => true,
+                Some(method) => {
+                    *method == request.method
+                        || (*method == Method::Get && request.method == Method::Head)
+                }
+            };
+            if !method_matches {
+                continue;
+            }
+            if let Some(params) = route.pattern.matches(path) {
+                return route.handler.handle(request, &params);
+            }
+        }
+
+        let allowed = self.allowed(path);
+        if allowed.is_empty() {
+            return match &self.fallback {
+                Some(fallback) => fallback.handle(request, &Params::new()),
+                None => Response::error(Status::NOT_FOUND),
+            };
+        }
+
+        let mut allow = allowed.join(", ");
+        if request.method == Method::Options {
+            if !allowed.contains(&"OPTIONS") {
+                allow.push_str(", OPTIONS");
+            }
+            return Response::new(Status::NO_CONTENT).with_header("Allow", allow);
+        }
+        Response::error(Status::METHOD_NOT_ALLOWED).with_header("Allow", allow)
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+#[derive(Debug)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Pattern {
+        let body = pattern
+            .strip_prefix('/')
+            .unwrap_or_else(|| panic!("route pattern {pattern:?} must start with '/'"));
+
+        let parts: Vec<&str> = body.split('/').collect();
+        let segments = parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                if let Some(name) = part.strip_prefix(':') {
+                    assert!(!name.is_empty(), "unnamed parameter in route {pattern:?}");
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = part.strip_prefix('*') {
+                    assert!(!name.is_empty(), "unnamed wildcard in route {pattern:?}");
+                    assert!(
+                        i == parts.len() - 1,
+                        "wildcard must be last in route {pattern:?}"
+                    );
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Literal(part.to_string())
+                }
+            })
+            .collect();
+
+        Pattern { segments }
+    }
+
+    /// Captured parameters if `path` matches.
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut rest = path.strip_prefix('/')?;
+        let mut params = Params::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            if let Segment::Rest(name) = segment {
+                return params.push_encoded(name, rest).then_some(params);
+            }
+
+            let (part, tail) = match rest.split_once('/') {
+                Some((part, tail)) => (part, Some(tail)),
+                None => (rest, None),
+            };
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Param(name) if !part.is_empty() => {
+                    if !params.push_encoded(name, part) {
+                        return None;
+                    }
+                }
+                _ => return None,
+            }
+
+            let last = i == self.segments.len() - 1;
+            match (tail, last) {
+                (None, true) => return Some(params),
+                (Some(tail), false) => rest = tail,
+                _ => return None,
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{method} {target} HTTP/1.1\r\nHost: x\r\n\r\n");
+        Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(&response.body).unwrap()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_: &Request, _: &Params| Response::html("home"))
+            .get("/api/days/:day", |_: &Request, p: &Params| {
+                Response::json(format!(r#"{{"day":"{}"}}"#, p.get("day").unwrap()))
+            })
+            .post("/api/days/:day", |_: &Request, _: &Params| {
+                Response::new(Status::NO_CONTENT)
+            })
+            .get("/api/days/:day/parts/:part", |_: &Request, p: &Params| {
+                Response::html(format!(
+                    "{}-{}",
+                    p.get("day").unwrap(),
+                    p.get("part").unwrap()
+                ))
+            })
+            .get("/files/*path", |_: &Request, p: &Params| {
+                Response::html(p.get("path").unwrap().to_string())
+            })
+    }
+
+    #[test]
+    fn matches_literals_and_params() {
+        let router = router();
+        let response = router.handle(&request("GET", "/api/days/7?verbose=1"), &Params::new());
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("application/json")
+        );
+        assert_eq!(body(&response), r#"{"day":"7"}"#);
+
+        let response = router.handle(&request("GET", "/api/days/7/parts/2"), &Params::new());
+        assert_eq!(body(&response), "7-2");
+
+        let response = router.handle(&request("GET", "/api/days/day%20one"), &Params::new());
+        assert_eq!(body(&response), r#"{"day":"day one"}"#);
+    }
+
+    #[test]
+    fn wildcard_takes_the_rest() {
+        let router = router();
+        let response = router.handle(&request("GET", "/files/a/b%2Fc.txt"), &Params::new());
+        assert_eq!(body(&response), "a/b/c.txt");
+        let response = router.handle(&request("GET", "/files/"), &Params::new());
+        assert_eq!(body(&response), "");
+    }
+
+    #[test]
+    fn unmatched_paths_are_404_or_fallback() {
+        let router = router();
+        for target in ["/api", "/api/days", "/api/days/", "/api/days/7/", "/nope"] {
+            let response = router.handle(&request("GET", target), &Params::new());
+            assert_eq!(response.status, Status::NOT_FOUND, "{target}");
+        }
+
+        let router = router.fallback(|_: &Request, _: &Params| Response::html("fallback"));
+        assert_eq!(
+            body(&router.handle(&request("GET", "/nope"), &Params::new())),
+            "fallback"
+        );
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow() {
+        let router = router();
+        let response = router.handle(&request("DELETE", "/api/days/1"), &Params::new());
+        assert_eq!(response.status, Status::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, POST"));
+
+        let response = router.handle(&request("OPTIONS", "/api/days/1"), &Params::new());
+        assert_eq!(response.status, Status::NO_CONTENT);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, POST, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn head_uses_the_get_route() {
+        let response = router().handle(&request("HEAD", "/"), &Params::new());
+        assert_eq!(response.status, Status::OK);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be last")]
+    fn wildcard_must_be_last() {
+        Router::new().get("/*rest/more", |_: &Request, _: &Params| Response::html(""));
+    }
+
+    #[test]
+    fn any_routes_answer_every_method() {
+        let router = router().any("/proxy/*rest", |r: &Request, p: &Params| {
+            Response::html(format!("{} {}", r.method.as_str(), p.get("rest").unwrap()))
+        });
+        for method in ["GET", "DELETE", "PATCH", "PURGE"] {
+            let response = router.handle(&request(method, "/proxy/a/b"), &Params::new());
+            assert_eq!(body(&response), format!("{method} a/b"));
+        }
+    }
+}
@@ -0,0 +1,550 @@
+// https://doc.rust-lang.org/book/ch21-01-single-threaded.html
+
+use std::{
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::access_log::AccessLog;
+use crate::connection::{self, Connections, Context, KeepAlive, Timeouts};
+use crate::engine::{self, Engine};
+use crate::handler::Handler;
+use crate::headers::Headers;
+use crate::pool::ThreadPool;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::request::Limits;
+use crate::shutdown::{ShutdownHandle, ShutdownSummary};
+use crate::tls::{self, Tls};
+
+/// How long in-flight connections get to finish once shutdown starts.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections open at once before new ones are turned away with 503.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// How often the accept loop and the drain loop check for progress.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(25);
+
+/// Listening sockets plus the workers that handle their connections.
+pub struct Server {
+    listeners: Vec<Listener>,
+    workers: usize,
+    engine: Engine,
+    handler: Arc<dyn Handler>,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+    limits: Limits,
+    keep_alive: KeepAlive,
+    timeouts: Timeouts,
+    max_connections: usize,
+    rate_limit: Option<RateLimit>,
+    access_log: Option<Arc<AccessLog>>,
+    headers: Headers,
+}
+
+struct Listener {
+    socket: TcpListener,
+    kind: ListenerKind,
+}
+
+enum ListenerKind {
+    Plain,
+    Tls(Arc<Tls>),
+    /// Answers everything with a redirect to HTTPS on this port.
+    Redirect(u16),
+}
+
+impl Server {
+    /// A server whose `workers` threads answer with `handler`, with no
+    /// listeners yet; add them with [`Server::listen`] and friends.
+    pub fn new(workers: usize, handler: impl Handler) -> Server {
+        Server {
+            listeners: Vec::new(),
+            workers,
+            engine: Engine::default(),
+            handler: Arc::new(handler),
+            shutdown: ShutdownHandle::new(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            limits: Limits::default(),
+            keep_alive: KeepAlive::default(),
+            timeouts: Timeouts::default(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            rate_limit: None,
+            access_log: None,
+            headers: Headers::new(),
+        }
+    }
+
+    /// Bind `addr` and spin up `workers` threads that answer with `handler`.
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        workers: usize,
+        handler: impl Handler,
+    ) -> io::Result<Server> {
+        Server::bind_all(&[addr], workers, handler)
+    }
+
+    /// Like [`Server::bind`], but listen on every one of `addrs`, e.g. an IPv4
+    /// and an IPv6 address. All listeners share the same workers.
+    pub fn bind_all<A: ToSocketAddrs>(
+        addrs: &[A],
+        workers: usize,
+        handler: impl Handler,
+    ) -> io::Result<Server> {
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "no addresses to listen on",
+            ));
+        }
+        let mut server = Server::new(workers, handler);
+        for addr in addrs {
+            server = server.listen(addr)?;
+        }
+        Ok(server)
+    }
+
+    /// Also accept plain HTTP on `addr`.
+    pub fn listen<A: ToSocketAddrs>(self, addr: A) -> io::Result<Server> {
+        self.add_listener(addr, ListenerKind::Plain)
+    }
+
+    /// Also accept HTTPS on `addr`.
+    pub fn listen_tls<A: ToSocketAddrs>(self, addr: A, tls: Arc<Tls>) -> io::Result<Server> {
+        self.add_listener(addr, ListenerKind::Tls(tls))
+    }
+
+    /// Accept plain HTTP on `addr` only to redirect it to the first HTTPS
+    /// listener, which must already be bound.
+    pub fn redirect_to_https<A: ToSocketAddrs>(self, addr: A) -> io::Result<Server> {
+        let https = self
+            .listeners
+            .iter()
+            .find(|l| matches!(l.kind, ListenerKind::Tls(_)))
+            .ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "no HTTPS listener to redirect to")
+            })?;
+        let port = https.socket.local_addr()?.port();
+        self.add_listener(addr, ListenerKind::Redirect(port))
+    }
+
+    fn add_listener<A: ToSocketAddrs>(mut self, addr: A, kind: ListenerKind) -> io::Result<Server> {
+        let socket = TcpListener::bind(addr)?;
+        // non-blocking so the accept loop can notice a shutdown request
+        socket.set_nonblocking(true)?;
+        self.listeners.push(Listener { socket, kind });
+        Ok(self)
+    }
+
+    /// Choose how connections are run. With [`Engine::Async`] the workers
+    /// run the event loop, and handlers get threads of their own as needed.
+    pub fn engine(mut self, engine: Engine) -> Server {
+        self.engine = engine;
+        self
+    }
+
+    /// Set how long open connections may keep running after shutdown is triggered.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Server {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Set the request size limits enforced on every connection.
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.limits = limits;
+        self
+    }
+
+    /// Set the idle timeout and request cap for persistent connections.
+    pub fn keep_alive(mut self, keep_alive: KeepAlive) -> Server {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Set how long clients get to send requests and to take responses.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Server {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Set how many connections may be open at once. Connections over the
+    /// limit get 503 straight from the accept loop.
+    pub fn max_connections(mut self, max: usize) -> Server {
+        self.max_connections = max;
+        self
+    }
+
+    /// Limit how often each client IP may make requests; requests over the
+    /// limit get 429 with `Retry-After`. There is no limit by default.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Server {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Record every request in `log`. Nothing is logged by default.
+    pub fn access_log(mut self, log: AccessLog) -> Server {
+        self.access_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Add `headers` to every response that does not already set them.
+    pub fn headers(mut self, headers: Headers) -> Server {
+        self.headers = headers;
+        self
+    }
+
+    /// Address the first listener is bound to (useful when binding port 0).
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.listeners.first() {
+            Some(listener) => listener.socket.local_addr(),
+            None => Err(io::Error::new(ErrorKind::NotFound, "no listeners")),
+        }
+    }
+
+    /// Addresses of every listener, in the order they were bound.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .map(|l| l.socket.local_addr())
+            .collect()
+    }
+
+    /// `http://` or `https://` URL of every listener, in the order they were bound.
+    pub fn local_urls(&self) -> io::Result<Vec<String>> {
+        self.listeners
+            .iter()
+            .map(|l| {
+                let scheme = match l.kind {
+                    ListenerKind::Tls(_) => "https",
+                    ListenerKind::Plain | ListenerKind::Redirect(_) => "http",
+                };
+                Ok(format!("{scheme}://{}", l.socket.local_addr()?))
+            })
+            .collect()
+    }
+
+    /// Stop when `shutdown` is triggered, sharing the handle with whatever
+    /// else should wind down with the server, such as a [`Watcher`](crate::Watcher).
+    pub fn shutdown(mut self, shutdown: ShutdownHandle) -> Server {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Handle that stops `run` from another thread or a signal handler.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Accept connections until shutdown is triggered, then drain and join the workers.
+    pub fn run(self) -> ShutdownSummary {
+        let contexts = self.contexts();
+        match self.engine {
+            Engine::Threads => self.run_threads(contexts),
+            Engine::Async => {
+                let listeners = self.listeners.into_iter().map(|l| l.socket);
+                engine::run(
+                    listeners.zip(contexts).collect(),
+                    self.workers,
+                    self.max_connections,
+                    self.drain_timeout,
+                    self.shutdown,
+                )
+            }
+        }
+    }
+
+    /// What the connections of each listener are served with.
+    fn contexts(&self) -> Vec<Arc<Context>> {
+        let access_log = self.access_log.clone();
+        let rate_limiter = self
+            .rate_limit
+            .map(|limit| Arc::new(RateLimiter::new(limit)));
+        self.listeners
+            .iter()
+            .map(|listener| {
+                let (handler, tls): (Arc<dyn Handler>, _) = match &listener.kind {
+                    ListenerKind::Plain => (Arc::clone(&self.handler), None),
+                    ListenerKind::Tls(tls) => (Arc::clone(&self.handler), Some(Arc::clone(tls))),
+                    ListenerKind::Redirect(port) => (Arc::new(tls::redirect_to_https(*port)), None),
+                };
+                Arc::new(Context {
+                    handler,
+                    limits: self.limits,
+                    keep_alive: self.keep_alive,
+                    timeouts: self.timeouts,
+                    rate_limiter: rate_limiter.clone(),
+                    shutdown: self.shutdown.clone(),
+                    access_log: access_log.clone(),
+                    headers: self.headers.clone(),
+                    tls,
+                })
+            })
+            .collect()
+    }
+
+    fn run_threads(self, contexts: Vec<Arc<Context>>) -> ShutdownSummary {
+        let pool = ThreadPool::new(self.workers);
+        let connections = Arc::new(Connections::default());
+        let mut served = 0;
+
+        while !self.shutdown.is_triggered() {
+            let mut accepted = false;
+            for (listener, context) in self.listeners.iter().zip(&contexts) {
+                match listener.socket.accept() {
+                    Ok((stream, _)) => {
+                        accepted = true;
+                        // a full pool would block this loop, and with it shutdown
+                        if connections.len() >= self.max_connections || pool.is_full() {
+                            // plain text would only confuse a TLS client
+                            if context.tls.is_none() {
+                                connection::reject(stream);
+                            }
+                            continue;
+                        }
+                        served += 1;
+                        if let Err(e) = stream.set_nonblocking(false) {
+                            eprintln!("Connection failed: {}", e);
+                            continue;
+                        }
+                        let guard = Connections::track(&connections, &stream);
+                        let context = Arc::clone(context);
+                        pool.execute(move || connection::serve(stream, &context, &guard));
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => eprintln!("Connection failed: {}", e),
+                }
+            }
+            if !accepted {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        // stop accepting before draining so clients see a refused connection
+        // rather than one that is never answered
+        drop(self.listeners);
+        let started = Instant::now();
+        let open = connections.len();
+        let deadline = started + self.drain_timeout;
+        while connections.len() > 0 && Instant::now() < deadline {
+            // repeated because a connection may finish a response and go idle mid-drain
+            connections.close_idle();
+            thread::sleep(POLL_INTERVAL);
+        }
+        let aborted = connections.abort_all();
+        drop(pool);
+
+        ShutdownSummary {
+            served,
+            drained: open.saturating_sub(aborted),
+            aborted,
+            elapsed: started.elapsed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::StaticFiles;
+    use crate::pool::QUEUE_PER_WORKER;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    fn read_all(mut stream: TcpStream) -> String {
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+    }
+
+    fn start(
+        workers: usize,
+        drain: Duration,
+    ) -> (
+        SocketAddr,
+        ShutdownHandle,
+        thread::JoinHandle<ShutdownSummary>,
+    ) {
+        // the crate directory, whose hello.html stands in for an index page
+        let files = StaticFiles::new(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .index_files(vec!["hello.html".to_string()]);
+        let server = Server::bind("127.0.0.1:0", workers, files)
+            .unwrap()
+            .drain_timeout(drain);
+        let addr = server.local_addr().unwrap();
+        let handle = server.shutdown_handle();
+        (addr, handle, thread::spawn(move || server.run()))
+    }
+
+    #[test]
+    fn slow_client_does_not_block_others() {
+        let (addr, shutdown, server) = start(2, Duration::from_millis(100));
+
+        // connects but never finishes its headers, pinning one worker
+        let mut idle = connect(addr);
+        idle.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+        let mut stream = connect(addr);
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let response = read_all(stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_lets_in_flight_request_finish() {
+        let (addr, shutdown, server) = start(2, Duration::from_secs(5));
+
+        let mut slow = connect(addr);
+        slow.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        // give the accept loop a chance to hand the connection to a worker
+        thread::sleep(POLL_INTERVAL * 4);
+
+        shutdown.trigger();
+        thread::sleep(POLL_INTERVAL * 4);
+        slow.write_all(b"Host: localhost\r\n\r\n").unwrap();
+
+        let response = read_all(slow);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+        let summary = server.join().unwrap();
+        assert_eq!(summary.served, 1);
+        assert_eq!(summary.drained, 1);
+        assert_eq!(summary.aborted, 0);
+
+        assert!(
+            TcpStream::connect(addr).is_err(),
+            "listener still accepting"
+        );
+    }
+
+    #[test]
+    fn full_pool_answers_503_and_still_shuts_down() {
+        let (addr, shutdown, server) = start(1, Duration::from_millis(100));
+
+        // idle keep-alive connections take the worker and every queue slot
+        let mut held = Vec::new();
+        for _ in 0..1 + QUEUE_PER_WORKER {
+            let mut stream = connect(addr);
+            stream
+                .write_all(b"GET /hello.html HTTP/1.1\r\nHost: x\r\n\r\n")
+                .unwrap();
+            held.push(stream);
+        }
+        let mut first = [0; 12];
+        held[0].read_exact(&mut first).unwrap();
+        assert_eq!(&first, b"HTTP/1.1 200");
+
+        let response = read_all(connect(addr));
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable"),
+            "{response}"
+        );
+
+        shutdown.trigger();
+        let summary = server.join().unwrap();
+        assert_eq!(summary.served, 1 + QUEUE_PER_WORKER as u64);
+    }
+
+    #[test]
+    fn drain_timeout_aborts_stuck_connections() {
+        let (addr, shutdown, server) = start(1, Duration::from_millis(100));
+
+        let mut stuck = connect(addr);
+        stuck.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(POLL_INTERVAL * 4);
+
+        shutdown.trigger();
+        let summary = server.join().unwrap();
+        assert_eq!(summary.aborted, 1);
+        assert_eq!(read_all(stuck), "");
+    }
+
+    #[test]
+    fn requests_are_access_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let log = AccessLog::file(&path, crate::LogFormat::Combined, 1 << 20, 1).unwrap();
+        let files = StaticFiles::new(env!("CARGO_MANIFEST_DIR"))
+            .unwrap()
+            .index_files(vec!["hello.html".to_string()]);
+        let server = Server::bind("127.0.0.1:0", 1, files)
+            .unwrap()
+            .access_log(log);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let mut stream = connect(addr);
+        stream
+            .write_all(
+                b"GET /hello.html HTTP/1.1\r\nHost: x\r\nUser-Agent: test/1.0\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        read_all(stream);
+        let mut stream = connect(addr);
+        stream.write_all(b"NOT HTTP\r\n\r\n").unwrap();
+        read_all(stream);
+        shutdown.trigger();
+        server.join().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        let size = std::fs::metadata(concat!(env!("CARGO_MANIFEST_DIR"), "/hello.html"))
+            .unwrap()
+            .len();
+        assert_eq!(lines.len(), 2, "{log}");
+        assert!(lines[0].starts_with("127.0.0.1 - - ["), "{log}");
+        assert!(
+            lines[0].contains(&format!(
+                "\"GET /hello.html HTTP/1.1\" 200 {size} \"-\" \"test/1.0\" "
+            )),
+            "{log}"
+        );
+        assert!(lines[1].contains("\"-\" 400 "), "{log}");
+    }
+
+    #[test]
+    fn every_listener_is_served_with_configured_headers() {
+        let handler = |_: &crate::Request, _: &crate::Params| crate::Response::html("hi");
+        let mut headers = Headers::new();
+        headers.add("X-Frame-Options", "DENY");
+        let server = Server::bind_all(&["127.0.0.1:0", "127.0.0.1:0"], 2, handler)
+            .unwrap()
+            .headers(headers);
+        let addrs = server.local_addrs().unwrap();
+        assert_eq!(addrs.len(), 2);
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        for addr in addrs {
+            let mut stream = connect(addr);
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let response = read_all(stream);
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+            assert!(response.contains("X-Frame-Options: DENY\r\n"), "{response}");
+        }
+
+        shutdown.trigger();
+        assert_eq!(server.join().unwrap().served, 2);
+    }
+}
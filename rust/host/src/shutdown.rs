@@ -0,0 +1,66 @@
+use std::{
+    fmt, io,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use signal_hook::{consts::TERM_SIGNALS, flag};
+
+/// Cloneable handle that asks a running `Server` to stop accepting and drain.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> ShutdownHandle {
+        ShutdownHandle::default()
+    }
+
+    /// Request a graceful shutdown. Safe to call more than once.
+    pub fn trigger(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Trigger this handle on SIGINT/SIGTERM/SIGQUIT.
+    ///
+    /// A second signal while draining exits the process immediately, so a
+    /// stuck drain can still be interrupted with another Ctrl-C.
+    pub fn register_signals(&self) -> io::Result<()> {
+        for &sig in TERM_SIGNALS {
+            flag::register_conditional_shutdown(sig, 1, Arc::clone(&self.requested))?;
+            flag::register(sig, Arc::clone(&self.requested))?;
+        }
+        Ok(())
+    }
+}
+
+/// What happened to the connections that were open when shutdown began.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Connections accepted over the server's lifetime.
+    pub served: u64,
+    /// Connections still open at shutdown that finished within the drain timeout.
+    pub drained: usize,
+    /// Connections forcibly closed when the drain timeout expired.
+    pub aborted: usize,
+    /// Time spent draining and joining workers.
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for ShutdownSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "served {} connections; drained {}, aborted {} in {:.1?}",
+            self.served, self.drained, self.aborted, self.elapsed
+        )
+    }
+}
@@ -0,0 +1,242 @@
+//! Server-Sent Events: a `text/event-stream` response that handlers feed from
+//! a thread of their own.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    sync::mpsc::{self, RecvTimeoutError, SyncSender},
+    thread,
+    time::Duration,
+};
+
+use crate::request::Request;
+use crate::response::{Response, Status};
+
+/// How often a comment is sent while no events are, so proxies and browsers
+/// do not give up on an idle stream.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Events queued between the producer and the connection before `send` blocks.
+const QUEUE: usize = 16;
+
+/// One event. Only `data` is required; browsers dispatch events without an
+/// `event` name as `message`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    /// Set the id a reconnecting browser reports in `Last-Event-ID`.
+    pub fn id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set the event type, for `EventSource.addEventListener(name, ..)`.
+    pub fn event(mut self, name: impl Into<String>) -> Event {
+        self.event = Some(name.into());
+        self
+    }
+
+    /// Ask the browser to wait this long before reconnecting.
+    pub fn retry(mut self, after: Duration) -> Event {
+        self.retry = Some(after);
+        self
+    }
+
+    /// The wire format. Multi-line data becomes several `data:` lines; line
+    /// breaks in the id or event name, which the format cannot carry, become spaces.
+    pub fn encode(&self) -> String {
+        let single_line = |value: &str| value.replace(['\r', '\n', '\0'], " ");
+        let mut out = String::new();
+        if let Some(id) = &self.id {
+            let _ = writeln!(out, "id: {}", single_line(id));
+        }
+        if let Some(event) = &self.event {
+            let _ = writeln!(out, "event: {}", single_line(event));
+        }
+        if let Some(retry) = self.retry {
+            let _ = writeln!(out, "retry: {}", retry.as_millis());
+        }
+        for line in self.data.split("\r\n").flat_map(|l| l.split(['\r', '\n'])) {
+            let _ = writeln!(out, "data: {line}");
+        }
+        out.push('\n');
+        out
+    }
+}
+
+/// Builds an event-stream response.
+///
+/// ```no_run
+/// # use host::{Request, Response, sse::{Event, EventStream}};
+/// fn progress(request: &Request) -> Response {
+///     EventStream::new(request).respond(|events| {
+///         let start = events.last_event_id().and_then(|id| id.parse().ok()).unwrap_or(0);
+///         for percent in start..=100 {
+///             if events.send(Event::new(percent.to_string()).id(percent.to_string())).is_err() {
+///                 return; // the client went away
+///             }
+///         }
+///     })
+/// }
+/// ```
+pub struct EventStream {
+    last_event_id: Option<String>,
+    retry: Option<Duration>,
+    keep_alive: Duration,
+}
+
+impl EventStream {
+    /// A stream answering `request`, resuming after its `Last-Event-ID`, if any.
+    pub fn new(request: &Request) -> EventStream {
+        EventStream {
+            last_event_id: request
+                .header("Last-Event-ID")
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(str::to_string),
+            retry: None,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+        }
+    }
+
+    /// Send a `retry` hint before the first event.
+    pub fn retry(mut self, after: Duration) -> EventStream {
+        self.retry = Some(after);
+        self
+    }
+
+    /// Set how long the stream may go quiet before a keep-alive comment is sent.
+    pub fn keep_alive(mut self, interval: Duration) -> EventStream {
+        self.keep_alive = interval;
+        self
+    }
+
+    /// Run `produce` on its own thread and stream whatever it sends. The
+    /// response ends when `produce` returns.
+    pub fn respond<F>(self, produce: F) -> Response
+    where
+        F: FnOnce(Events) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel::<Event>(QUEUE);
+        let events = Events {
+            tx,
+            last_event_id: self.last_event_id,
+        };
+        let (retry, keep_alive) = (self.retry, self.keep_alive);
+
+        Response::new(Status::OK)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            // nginx would otherwise hold events back until its buffer fills
+            .with_header("X-Accel-Buffering", "no")
+            .with_stream(move |out| {
+                // started here so nothing is produced for a HEAD request
+                thread::spawn(move || produce(events));
+                if let Some(retry) = retry {
+                    write!(out, "retry: {}\n\n", retry.as_millis())?;
+                    out.flush()?;
+                }
+                loop {
+                    match rx.recv_timeout(keep_alive) {
+                        Ok(event) => out.write_all(event.encode().as_bytes())?,
+                        Err(RecvTimeoutError::Timeout) => out.write_all(b": keep-alive\n\n")?,
+                        Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                    }
+                    out.flush()?;
+                }
+            })
+    }
+}
+
+/// The producer's end of an event stream.
+pub struct Events {
+    tx: SyncSender<Event>,
+    last_event_id: Option<String>,
+}
+
+impl Events {
+    /// The `Last-Event-ID` the client reconnected with, to resume after.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Queue `event`, waiting if the client is reading slowly. Fails once the
+    /// client has gone away, which is the producer's cue to stop.
+    pub fn send(&self, event: Event) -> io::Result<()> {
+        self.tx
+            .send(event)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event stream closed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(headers: &str) -> Request {
+        let raw = format!("GET /events HTTP/1.1\r\nHost: x\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn encodes_events() {
+        assert_eq!(Event::new("hi").encode(), "data: hi\n\n");
+        assert_eq!(
+            Event::new("one\ntwo\r\nthree")
+                .id("7\n")
+                .event("progress")
+                .retry(Duration::from_secs(3))
+                .encode(),
+            "id: 7 \nevent: progress\nretry: 3000\ndata: one\ndata: two\ndata: three\n\n"
+        );
+    }
+
+    #[test]
+    fn streams_events_with_keep_alives_and_resumes() {
+        let stream = EventStream::new(&request("Last-Event-ID: 41\r\n"))
+            .retry(Duration::from_millis(500))
+            .keep_alive(Duration::from_millis(20));
+        let response = stream.respond(|events| {
+            let next = events.last_event_id().unwrap().parse::<u32>().unwrap() + 1;
+            events.send(Event::new("a").id(next.to_string())).unwrap();
+            thread::sleep(Duration::from_millis(70));
+            events.send(Event::new("b")).unwrap();
+        });
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/event-stream")
+        );
+
+        let mut out = Vec::new();
+        response.write_to(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let body = &out[out.find("\r\n\r\n").unwrap() + 4..];
+        let body: String = body
+            .split("\r\n")
+            .skip(1)
+            .step_by(2)
+            .collect::<Vec<_>>()
+            .concat();
+        assert!(
+            body.starts_with("retry: 500\n\nid: 42\ndata: a\n\n: keep-alive\n\n"),
+            "{body:?}"
+        );
+        assert!(body.ends_with("data: b\n\n"), "{body:?}");
+    }
+}
@@ -0,0 +1,372 @@
+//! HTTPS: rustls server configuration, certificates chosen by SNI, and the
+//! plain-HTTP redirect handler.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use rustls::{
+    ServerConfig, ServerConnection, StreamOwned,
+    crypto::{CryptoProvider, ring},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject};
+
+use crate::handler::{Handler, Params};
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+
+/// A PEM certificate chain and the PEM private key that goes with it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// The certificates one server presents, reloadable while it runs.
+///
+/// Each handshake gets the first certificate valid for the name the client
+/// asked for (SNI), wildcards included. Clients that ask for no name, or for
+/// one no certificate covers, get the first certificate.
+pub struct CertStore {
+    files: Vec<CertFiles>,
+    provider: Arc<CryptoProvider>,
+    loaded: RwLock<Vec<Arc<CertifiedKey>>>,
+}
+
+impl CertStore {
+    pub fn load(files: Vec<CertFiles>) -> io::Result<CertStore> {
+        if files.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no certificates given",
+            ));
+        }
+        let provider = Arc::new(ring::default_provider());
+        let loaded = load_all(&files, &provider)?;
+        Ok(CertStore {
+            files,
+            provider,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    /// Read every certificate and key again. On failure the certificates in
+    /// use are kept, so a half-renewed certificate never takes the site down.
+    pub fn reload(&self) -> io::Result<()> {
+        let loaded = load_all(&self.files, &self.provider)?;
+        *self.loaded.write().unwrap_or_else(|e| e.into_inner()) = loaded;
+        Ok(())
+    }
+
+    /// Reload on every SIGHUP, reporting the outcome on stderr.
+    pub fn reload_on_sighup(self: &Arc<CertStore>) -> io::Result<()> {
+        let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+        let store = Arc::clone(self);
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                match store.reload() {
+                    Ok(()) => eprintln!("Reloaded {} TLS certificates", store.files.len()),
+                    Err(e) => eprintln!("Keeping current TLS certificates: {e}"),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn pick(&self, name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().unwrap_or_else(|e| e.into_inner());
+        let matching = name
+            .and_then(|name| ServerName::try_from(name).ok())
+            .and_then(|name| {
+                loaded.iter().find(|key| {
+                    webpki::EndEntityCert::try_from(&key.cert[0])
+                        .is_ok_and(|cert| cert.verify_is_valid_for_subject_name(&name).is_ok())
+                })
+            });
+        matching.or(loaded.first()).cloned()
+    }
+}
+
+impl fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertStore")
+            .field("files", &self.files)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.pick(client_hello.server_name())
+    }
+}
+
+fn load_all(files: &[CertFiles], provider: &CryptoProvider) -> io::Result<Vec<Arc<CertifiedKey>>> {
+    files
+        .iter()
+        .map(|files| load_one(files, provider).map(Arc::new))
+        .collect()
+}
+
+fn load_one(files: &CertFiles, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+    let invalid = |path: &Path, e: &dyn fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {e}", path.display()),
+        )
+    };
+    let chain = CertificateDer::pem_file_iter(&files.cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| invalid(&files.cert, &e))?;
+    if chain.is_empty() {
+        return Err(invalid(&files.cert, &"no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(&files.key).map_err(|e| invalid(&files.key, &e))?;
+    CertifiedKey::from_der(chain, key, provider).map_err(|e| invalid(&files.key, &e))
+}
+
+/// What a TLS listener needs to accept connections.
+#[derive(Debug)]
+pub struct Tls {
+    config: Arc<ServerConfig>,
+    certs: Arc<CertStore>,
+}
+
+impl Tls {
+    pub fn new(certs: Arc<CertStore>) -> io::Result<Tls> {
+        let mut config = ServerConfig::builder_with_provider(Arc::clone(&certs.provider))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&certs) as Arc<dyn ResolvesServerCert>);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Tls {
+            config: Arc::new(config),
+            certs,
+        })
+    }
+
+    pub fn certs(&self) -> &Arc<CertStore> {
+        &self.certs
+    }
+
+    /// The rustls settings, for accepting connections on the async engine.
+    pub(crate) fn config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config)
+    }
+
+    /// Wrap an accepted socket; the handshake happens on first read or write.
+    pub(crate) fn accept<'a>(&self, stream: &'a TcpStream) -> io::Result<Transport<'a>> {
+        let conn = ServerConnection::new(Arc::clone(&self.config)).map_err(io::Error::other)?;
+        Ok(Transport::Tls(Box::new(StreamOwned::new(conn, stream))))
+    }
+}
+
+/// The byte stream a connection is served over: the socket itself, or TLS on top of it.
+pub(crate) enum Transport<'a> {
+    Plain(&'a TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, &'a TcpStream>>),
+}
+
+impl Read for Transport<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf),
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Drop for Transport<'_> {
+    fn drop(&mut self) {
+        // tell the client the response really ended, rather than being cut off
+        if let Transport::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.conn.complete_io(&mut stream.sock);
+        }
+    }
+}
+
+/// A [`Handler`] that sends every request to the same URL over HTTPS on `port`.
+///
+/// `GET` and `HEAD` get 301; other methods get 308 so the method and body are kept.
+pub fn redirect_to_https(port: u16) -> impl Handler {
+    move |request: &Request, _: &Params| {
+        let Some(name) = request.host() else {
+            return Response::error(Status::BAD_REQUEST);
+        };
+        let authority = match port {
+            443 => name.to_string(),
+            port => format!("{name}:{port}"),
+        };
+        let status = match request.method {
+            Method::Get | Method::Head => Status::MOVED_PERMANENTLY,
+            _ => Status::PERMANENT_REDIRECT,
+        };
+        Response::error(status)
+            .with_header("Location", format!("https://{authority}{}", request.target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::{fs, net::SocketAddr, time::Duration};
+
+    struct Cert {
+        der: CertificateDer<'static>,
+        files: CertFiles,
+    }
+
+    /// A self-signed certificate for `names`, written to `dir`.
+    fn self_signed(dir: &Path, file: &str, names: &[&str]) -> Cert {
+        let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names).unwrap();
+        let files = CertFiles {
+            cert: dir.join(format!("{file}.pem")),
+            key: dir.join(format!("{file}-key.pem")),
+        };
+        fs::write(&files.cert, generated.cert.pem()).unwrap();
+        fs::write(&files.key, generated.signing_key.serialize_pem()).unwrap();
+        Cert {
+            der: generated.cert.der().clone(),
+            files,
+        }
+    }
+
+    /// Connect trusting `roots`, ask for `name`, and return the certificate
+    /// the server presented along with the response to `GET /`.
+    fn fetch(addr: SocketAddr, name: &str, roots: &[&Cert]) -> (CertificateDer<'static>, String) {
+        let mut store = RootCertStore::empty();
+        for cert in roots {
+            store.add(cert.der.clone()).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        let conn = ClientConnection::new(
+            Arc::new(config),
+            ServerName::try_from(name.to_string()).unwrap(),
+        )
+        .unwrap();
+        let sock = TcpStream::connect(addr).unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut tls = StreamOwned::new(conn, sock);
+        write!(
+            tls,
+            "GET / HTTP/1.1\r\nHost: {name}\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        tls.read_to_string(&mut response).unwrap();
+        let presented = tls.conn.peer_certificates().unwrap()[0].clone();
+        (presented, response)
+    }
+
+    #[test]
+    fn picks_certificates_by_sni_and_reloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let localhost = self_signed(dir.path(), "localhost", &["localhost"]);
+        let wildcard = self_signed(dir.path(), "dev", &["*.dev.test"]);
+        let store = Arc::new(
+            CertStore::load(vec![localhost.files.clone(), wildcard.files.clone()]).unwrap(),
+        );
+        let handler =
+            |request: &Request, _: &Params| Response::html(format!("secure={}", request.secure));
+        let server = Server::new(2, handler)
+            .listen_tls(
+                "127.0.0.1:0",
+                Arc::new(Tls::new(Arc::clone(&store)).unwrap()),
+            )
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let (presented, response) = fetch(addr, "api.dev.test", &[&localhost, &wildcard]);
+        assert_eq!(presented, wildcard.der);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("secure=true"), "{response}");
+        assert_eq!(fetch(addr, "localhost", &[&localhost]).0, localhost.der);
+
+        // a renewed certificate is picked up on reload; a broken one is not
+        let renewed = self_signed(dir.path(), "localhost", &["localhost"]);
+        store.reload().unwrap();
+        assert_eq!(fetch(addr, "localhost", &[&renewed]).0, renewed.der);
+        fs::write(&renewed.files.key, "not a key").unwrap();
+        assert!(store.reload().is_err());
+        assert_eq!(fetch(addr, "localhost", &[&renewed]).0, renewed.der);
+
+        shutdown.trigger();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        let redirect = redirect_to_https(8443);
+        let request = |raw: &str| {
+            Request::read_from(&mut raw.as_bytes(), &crate::request::Limits::default())
+                .unwrap()
+                .unwrap()
+        };
+        let location = |raw: &str| {
+            let response = redirect.handle(&request(raw), &Params::new());
+            (
+                response.status,
+                response.headers.get("Location").map(str::to_string),
+            )
+        };
+        assert_eq!(
+            location("GET /a?b HTTP/1.1\r\nHost: example.test:8080\r\n\r\n"),
+            (
+                Status::MOVED_PERMANENTLY,
+                Some("https://example.test:8443/a?b".to_string())
+            )
+        );
+        assert_eq!(
+            location("POST /form HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n"),
+            (
+                Status::PERMANENT_REDIRECT,
+                Some("https://[::1]:8443/form".to_string())
+            )
+        );
+        assert_eq!(location("GET / HTTP/1.0\r\n\r\n").0, Status::BAD_REQUEST);
+        assert_eq!(
+            redirect_to_https(443)
+                .handle(
+                    &request("GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n"),
+                    &Params::new()
+                )
+                .headers
+                .get("Location"),
+            Some("https://[::1]/")
+        );
+    }
+}
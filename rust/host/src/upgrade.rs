@@ -0,0 +1,54 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+/// Takes over a connection after a `101 Switching Protocols` response.
+pub type UpgradeFn = Box<dyn FnOnce(Upgraded) + Send>;
+
+/// A connection handed over to another protocol, such as WebSocket.
+///
+/// Reads first return whatever the client sent after the request that was
+/// already buffered, then continue from the socket.
+#[derive(Debug)]
+pub struct Upgraded {
+    stream: TcpStream,
+    buffered: Vec<u8>,
+    pos: usize,
+}
+
+impl Upgraded {
+    pub(crate) fn new(stream: TcpStream, buffered: Vec<u8>) -> Upgraded {
+        Upgraded {
+            stream,
+            buffered,
+            pos: 0,
+        }
+    }
+
+    /// The underlying socket, e.g. to clone a write half or set timeouts.
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buffered.len() {
+            let n = (&self.buffered[self.pos..]).read(buf)?;
+            self.pos += n;
+            return Ok(n);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
@@ -0,0 +1,448 @@
+//! Writable directories: `PUT`, `DELETE` and browser form uploads.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::files::StaticFiles;
+use crate::handler::{Handler, Params};
+use crate::multipart;
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+use crate::router::Router;
+
+/// Numbers temporary files, so concurrent uploads never share one.
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Serves a directory below a path prefix and lets clients write to it.
+///
+/// - `PUT /uploads/day7/input.txt` stores the body as `day7/input.txt`,
+///   creating `day7` if needed: 201 for a new file, 204 for a replaced one.
+/// - `POST /uploads/day7/` with a `multipart/form-data` body stores every
+///   file in the form in `day7`, then redirects back to it with 303.
+/// - `DELETE /uploads/day7/input.txt` removes the file, once enabled.
+/// - `GET` serves the files, with a directory listing that has an upload form.
+///
+/// Files are written to a temporary file and renamed into place, so nobody
+/// ever reads half an upload. Names starting with `.`, and paths leading
+/// outside the directory through `..` or a symlink, get 403.
+#[derive(Clone, Debug)]
+pub struct Uploads {
+    dir: PathBuf,
+    prefix: String,
+    files: StaticFiles,
+    max_size: Option<usize>,
+    delete: bool,
+}
+
+impl Uploads {
+    /// Store uploads in `dir`, which must exist.
+    pub fn new(dir: impl AsRef<Path>) -> io::Result<Uploads> {
+        let files = StaticFiles::new(dir)?
+            .index_files(Vec::new())
+            .directory_listing(true)
+            .upload_form(true);
+        Ok(Uploads {
+            dir: files.root().to_path_buf(),
+            prefix: String::new(),
+            files,
+            max_size: None,
+            delete: false,
+        })
+    }
+
+    /// Refuse bodies over `bytes` with 413. The server's body limit applies
+    /// either way, as uploads are read into memory first.
+    pub fn max_size(mut self, bytes: usize) -> Uploads {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Let `DELETE` remove files.
+    pub fn allow_delete(mut self, enabled: bool) -> Uploads {
+        self.delete = enabled;
+        self
+    }
+
+    /// Route `prefix` and everything below it, for every method, to this directory.
+    pub fn mount(mut self, router: Router, prefix: &str) -> Router {
+        let prefix = prefix.trim_end_matches('/');
+        self.prefix = prefix.to_string();
+        if prefix.is_empty() {
+            return router.any("/*path", self);
+        }
+        router
+            .any(prefix, self.clone())
+            .any(&format!("{prefix}/*path"), self)
+    }
+
+    fn allow(&self) -> &'static str {
+        if self.delete {
+            "GET, HEAD, PUT, POST, DELETE, OPTIONS"
+        } else {
+            "GET, HEAD, PUT, POST, OPTIONS"
+        }
+    }
+
+    fn put(&self, request: &Request, path: &str) -> Response {
+        let Some(mut segments) = segments(path) else {
+            return Response::error(Status::FORBIDDEN);
+        };
+        let Some(name) = segments.pop().filter(|_| !path.ends_with('/')) else {
+            return Response::error(Status::CONFLICT);
+        };
+        let dir = match self.make_dirs(&segments) {
+            Ok(dir) => dir,
+            Err(response) => return response,
+        };
+        match store(&dir, name, &request.body) {
+            Ok(true) => Response::new(Status::CREATED).with_header("Location", request.path()),
+            Ok(false) => Response::new(Status::NO_CONTENT),
+            Err(response) => response,
+        }
+    }
+
+    fn post(&self, request: &Request, path: &str) -> Response {
+        let Some(boundary) = request.header("Content-Type").and_then(multipart::boundary) else {
+            return Response::error(Status::UNSUPPORTED_MEDIA_TYPE);
+        };
+        let Some(parts) = multipart::parse(&request.body, &boundary) else {
+            return Response::error(Status::BAD_REQUEST);
+        };
+        let Some(segments) = segments(path) else {
+            return Response::error(Status::FORBIDDEN);
+        };
+        let mut uploads = Vec::new();
+        for part in &parts {
+            // some browsers send the whole client-side path
+            let Some(filename) = &part.filename else {
+                continue;
+            };
+            let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+            if name.is_empty() {
+                continue;
+            }
+            if !is_allowed(name) {
+                return Response::error(Status::FORBIDDEN);
+            }
+            uploads.push((name, part.data));
+        }
+        if uploads.is_empty() {
+            return Response::error(Status::BAD_REQUEST);
+        }
+
+        let dir = match self.make_dirs(&segments) {
+            Ok(dir) => dir,
+            Err(response) => return response,
+        };
+        for (name, data) in uploads {
+            if let Err(response) = store(&dir, name, data) {
+                return response;
+            }
+        }
+        let location = match request.path() {
+            dir if dir.ends_with('/') => dir.to_string(),
+            dir => format!("{dir}/"),
+        };
+        Response::new(Status::SEE_OTHER).with_header("Location", location)
+    }
+
+    fn delete(&self, path: &str) -> Response {
+        let Some(segments) = segments(path).filter(|s| !s.is_empty()) else {
+            return Response::error(Status::FORBIDDEN);
+        };
+        let file = segments
+            .iter()
+            .fold(self.dir.clone(), |file, s| file.join(s));
+        // the file itself may be a symlink, which is removed, not followed
+        let inside = file
+            .parent()
+            .and_then(|parent| parent.canonicalize().ok())
+            .is_some_and(|parent| parent.starts_with(&self.dir));
+        if !inside {
+            return Response::error(Status::NOT_FOUND);
+        }
+        match fs::symlink_metadata(&file) {
+            Ok(metadata) if metadata.is_dir() => Response::error(Status::CONFLICT),
+            Ok(_) => match fs::remove_file(&file) {
+                Ok(()) => Response::new(Status::NO_CONTENT),
+                Err(e) => error_for(&file, e),
+            },
+            Err(e) => error_for(&file, e),
+        }
+    }
+
+    /// The directory `segments` names, created if missing.
+    fn make_dirs(&self, segments: &[&str]) -> Result<PathBuf, Response> {
+        let mut dir = self.dir.clone();
+        for segment in segments {
+            dir.push(segment);
+            match fs::create_dir(&dir) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(error_for(&dir, e)),
+            }
+            // an existing symlink could point anywhere
+            let real = dir.canonicalize().map_err(|e| error_for(&dir, e))?;
+            if !real.starts_with(&self.dir) {
+                return Err(Response::error(Status::FORBIDDEN));
+            }
+            if !real.is_dir() {
+                return Err(Response::error(Status::CONFLICT));
+            }
+        }
+        Ok(dir)
+    }
+}
+
+impl Handler for Uploads {
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        let path = params.get("path").unwrap_or_default();
+        if let Some(max_size) = self.max_size
+            && request.body.len() > max_size
+        {
+            return Response::error(Status::PAYLOAD_TOO_LARGE);
+        }
+        match request.method {
+            Method::Get | Method::Head => {
+                match &request.path()[self.prefix.len()..] {
+                    // the prefix itself
+                    "" => Response::error(Status::MOVED_PERMANENTLY)
+                        .with_header("Location", format!("{}/", self.prefix)),
+                    url_path => self.files.serve_at(request, url_path),
+                }
+            }
+            Method::Put => self.put(request, path),
+            Method::Post => self.post(request, path),
+            Method::Delete if self.delete => self.delete(path),
+            Method::Options => Response::new(Status::NO_CONTENT).with_header("Allow", self.allow()),
+            _ => Response::error(Status::METHOD_NOT_ALLOWED).with_header("Allow", self.allow()),
+        }
+    }
+}
+
+/// The segments of a decoded path, or `None` if one is not an allowed name.
+fn segments(path: &str) -> Option<Vec<&str>> {
+    path.split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| is_allowed(s).then_some(s))
+        .collect()
+}
+
+/// No `..`, no hidden files, and nothing a filesystem would read as a separator.
+fn is_allowed(name: &str) -> bool {
+    !name.starts_with('.') && !name.contains(['\\', '\0'])
+}
+
+/// Write `data` to `dir/name` by way of a temporary file. True if the file is new.
+fn store(dir: &Path, name: &str, data: &[u8]) -> Result<bool, Response> {
+    let file = dir.join(name);
+    let existed = match fs::symlink_metadata(&file) {
+        Ok(metadata) if metadata.is_dir() => return Err(Response::error(Status::CONFLICT)),
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    let temp = dir.join(format!(
+        ".upload-{}-{}",
+        process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .and_then(|mut out| {
+            out.write_all(data)?;
+            out.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp, &file));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(error_for(&file, e));
+    }
+    Ok(!existed)
+}
+
+fn error_for(path: &Path, e: io::Error) -> Response {
+    match e.kind() {
+        ErrorKind::NotFound => Response::error(Status::NOT_FOUND),
+        ErrorKind::PermissionDenied => Response::error(Status::FORBIDDEN),
+        _ => {
+            eprintln!("Failed to write {}: {e}", path.display());
+            Response::error(Status::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(method: &str, target: &str, headers: &str, body: &str) -> Request {
+        let raw = format!(
+            "{method} {target} HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n{headers}\r\n{body}",
+            body.len()
+        );
+        Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn site(uploads: Uploads) -> Router {
+        uploads.mount(Router::new(), "/up")
+    }
+
+    fn send(router: &Router, method: &str, target: &str, body: &str) -> Response {
+        router.handle(&request(method, target, "", body), &Params::new())
+    }
+
+    fn read(dir: &Path, name: &str) -> String {
+        fs::read_to_string(dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn put_creates_and_replaces_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = site(Uploads::new(dir.path()).unwrap().max_size(10));
+
+        let response = send(&router, "PUT", "/up/day7/input.txt", "1 2 3");
+        assert_eq!(response.status, Status::CREATED);
+        assert_eq!(response.headers.get("Location"), Some("/up/day7/input.txt"));
+        assert_eq!(read(dir.path(), "day7/input.txt"), "1 2 3");
+        assert_eq!(
+            send(&router, "PUT", "/up/day7/input.txt", "4").status,
+            Status::NO_CONTENT
+        );
+        assert_eq!(read(dir.path(), "day7/input.txt"), "4");
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(dir.path().join("day7")).unwrap().count(), 1);
+
+        let response = send(&router, "GET", "/up/day7/input.txt", "");
+        assert_eq!(
+            (response.status, &response.body[..]),
+            (Status::OK, &b"4"[..])
+        );
+        assert_eq!(
+            send(&router, "PUT", "/up/day7", "x").status,
+            Status::CONFLICT
+        );
+        assert_eq!(
+            send(&router, "PUT", "/up/day7/", "x").status,
+            Status::CONFLICT
+        );
+        assert_eq!(
+            send(&router, "PUT", "/up/day7/input.txt/x", "x").status,
+            Status::CONFLICT
+        );
+        assert_eq!(
+            send(&router, "PUT", "/up/big", "12345678901").status,
+            Status::PAYLOAD_TOO_LARGE
+        );
+        for target in ["/up/.env", "/up/%2E%2E/x", "/up/a%5Cb", "/up/day7/../../x"] {
+            assert_eq!(
+                send(&router, "PUT", target, "x").status,
+                Status::FORBIDDEN,
+                "{target}"
+            );
+        }
+        assert!(!dir.path().join("big").exists());
+    }
+
+    #[test]
+    fn delete_only_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/a.txt"), "a").unwrap();
+
+        let router = site(Uploads::new(dir.path()).unwrap());
+        let response = send(&router, "DELETE", "/up/sub/a.txt", "");
+        assert_eq!(response.status, Status::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, PUT, POST, OPTIONS")
+        );
+
+        let router = site(Uploads::new(dir.path()).unwrap().allow_delete(true));
+        assert_eq!(
+            send(&router, "DELETE", "/up/sub/a.txt", "").status,
+            Status::NO_CONTENT
+        );
+        assert!(!dir.path().join("sub/a.txt").exists());
+        assert_eq!(
+            send(&router, "DELETE", "/up/sub/a.txt", "").status,
+            Status::NOT_FOUND
+        );
+        assert_eq!(
+            send(&router, "DELETE", "/up/sub", "").status,
+            Status::CONFLICT
+        );
+        assert_eq!(
+            send(&router, "DELETE", "/up/", "").status,
+            Status::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn stores_files_posted_from_a_form() {
+        let dir = tempfile::tempdir().unwrap();
+        let router = site(Uploads::new(dir.path()).unwrap());
+
+        let page = send(&router, "GET", "/up/", "");
+        assert!(String::from_utf8_lossy(&page.body).contains("enctype=\"multipart/form-data\""));
+        assert_eq!(
+            send(&router, "GET", "/up", "").headers.get("Location"),
+            Some("/up/")
+        );
+
+        let body = "--b\r\n\
+                    Content-Disposition: form-data; name=\"file\"; filename=\"C:\\\\inputs\\\\day1.txt\"\r\n\r\n\
+                    abc\r\n--b\r\n\
+                    Content-Disposition: form-data; name=\"file\"; filename=\"day2.txt\"\r\n\r\n\
+                    def\r\n--b\r\n\
+                    Content-Disposition: form-data; name=\"note\"\r\n\r\n\
+                    ignored\r\n--b--\r\n";
+        let form = "Content-Type: multipart/form-data; boundary=b\r\n";
+        let response = router.handle(&request("POST", "/up/aoc", form, body), &Params::new());
+        assert_eq!(response.status, Status::SEE_OTHER);
+        assert_eq!(response.headers.get("Location"), Some("/up/aoc/"));
+        assert_eq!(read(dir.path(), "aoc/day1.txt"), "abc");
+        assert_eq!(read(dir.path(), "aoc/day2.txt"), "def");
+
+        let no_files = "--b\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nx\r\n--b--";
+        let response = router.handle(&request("POST", "/up/", form, no_files), &Params::new());
+        assert_eq!(response.status, Status::BAD_REQUEST);
+        let hidden = body.replace("day2.txt", ".bashrc");
+        let response = router.handle(&request("POST", "/up/", form, &hidden), &Params::new());
+        assert_eq!(response.status, Status::FORBIDDEN);
+        assert_eq!(
+            send(&router, "POST", "/up/", "abc").status,
+            Status::UNSUPPORTED_MEDIA_TYPE
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_lead_outside() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("keep.txt"), "keep").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+
+        let router = site(Uploads::new(dir.path()).unwrap().allow_delete(true));
+        assert_eq!(
+            send(&router, "PUT", "/up/link/x.txt", "x").status,
+            Status::FORBIDDEN
+        );
+        assert_eq!(
+            send(&router, "DELETE", "/up/link/keep.txt", "").status,
+            Status::NOT_FOUND
+        );
+        assert!(!outside.path().join("x.txt").exists());
+        assert!(outside.path().join("keep.txt").exists());
+    }
+}
@@ -0,0 +1,78 @@
+/// Decode `%XX` escapes. Returns `None` for a truncated or non-hex escape.
+///
+/// `+` is left alone: it only means space in form-encoded query strings.
+pub fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hi = hex_value(*bytes.get(i + 1)?)?;
+            let lo = hex_value(*bytes.get(i + 2)?)?;
+            out.push(hi << 4 | lo);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+/// Escape everything but RFC 3986 unreserved characters, so `segment` can
+/// be used as one path segment or query value.
+pub fn percent_encode(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for &b in segment.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// First value of `name` in a form-encoded query string, decoded.
+pub fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let decode =
+            |s: &str| percent_decode(&s.replace('+', " ")).and_then(|b| String::from_utf8(b).ok());
+        (decode(key)? == name).then(|| decode(value))?
+    })
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("/a%20b/%2e%2E").unwrap(), b"/a b/..");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café".as_bytes());
+        assert_eq!(percent_decode("a+b").unwrap(), b"a+b");
+    }
+
+    #[test]
+    fn rejects_bad_escapes() {
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+    }
+
+    #[test]
+    fn encodes_and_reads_query_values() {
+        assert_eq!(percent_encode("a b/ü~.txt"), "a%20b%2F%C3%BC~.txt");
+        let query = "sort=size&order=desc&q=a+b%21&flag";
+        assert_eq!(query_param(query, "order").as_deref(), Some("desc"));
+        assert_eq!(query_param(query, "q").as_deref(), Some("a b!"));
+        assert_eq!(query_param(query, "flag").as_deref(), Some(""));
+        assert_eq!(query_param(query, "missing"), None);
+    }
+}
@@ -0,0 +1,180 @@
+//! Name-based virtual hosts: one process serving several sites, chosen by the
+//! `Host` header.
+
+use crate::handler::{Handler, Params};
+use crate::request::Request;
+use crate::response::{Response, Status};
+
+/// Dispatches on the request's host name.
+///
+/// Names match exactly, case-insensitively; `*.example.test` matches any
+/// subdomain of `example.test` but not `example.test` itself. Exact names win
+/// over wildcards, and longer wildcards over shorter ones. Requests for any
+/// other host, or with no `Host` at all (HTTP/1.0), go to the fallback handler,
+/// or get 404 without one.
+///
+/// ```no_run
+/// # use host::{Response, Request, Params, StaticFiles, VirtualHosts};
+/// let sites = VirtualHosts::new()
+///     .host("aoc.localhost", StaticFiles::new("aoc")?)
+///     .host("*.docs.localhost", StaticFiles::new("docs")?)
+///     .fallback(|_: &Request, _: &Params| Response::html("no such site"));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Default)]
+pub struct VirtualHosts {
+    sites: Vec<Site>,
+    fallback: Option<Box<dyn Handler>>,
+}
+
+struct Site {
+    name: Name,
+    handler: Box<dyn Handler>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Name {
+    Exact(String),
+    /// The part after `*.`, with its leading dot, e.g. `.example.test`.
+    Wildcard(String),
+}
+
+impl Name {
+    fn parse(name: &str) -> Name {
+        let name = normalize(name);
+        match name.strip_prefix("*.") {
+            Some(domain) => {
+                assert!(!domain.is_empty(), "wildcard host {name:?} has no domain");
+                Name::Wildcard(format!(".{domain}"))
+            }
+            None => {
+                assert!(
+                    !name.is_empty() && !name.contains('*'),
+                    "invalid host name {name:?}"
+                );
+                Name::Exact(name)
+            }
+        }
+    }
+}
+
+/// Lowercase, without the trailing dot of a fully qualified name.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl VirtualHosts {
+    pub fn new() -> VirtualHosts {
+        VirtualHosts::default()
+    }
+
+    /// Serve `name` with `handler`. `name` is a host such as `aoc.localhost`
+    /// or a wildcard such as `*.localhost`, without a port.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is empty or has a `*` anywhere but a leading `*.`.
+    pub fn host(mut self, name: &str, handler: impl Handler) -> VirtualHosts {
+        self.sites.push(Site {
+            name: Name::parse(name),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Handler for hosts no name matches.
+    pub fn fallback(mut self, handler: impl Handler) -> VirtualHosts {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    fn site(&self, host: &str) -> Option<&dyn Handler> {
+        let host = normalize(host);
+        let exact = self.sites.iter().find(|site| match &site.name {
+            Name::Exact(name) => *name == host,
+            Name::Wildcard(_) => false,
+        });
+        let site = exact.or_else(|| {
+            self.sites
+                .iter()
+                .filter(|site| match &site.name {
+                    Name::Wildcard(suffix) => host.ends_with(suffix.as_str()),
+                    Name::Exact(_) => false,
+                })
+                .max_by_key(|site| match &site.name {
+                    Name::Wildcard(suffix) => suffix.len(),
+                    Name::Exact(_) => 0,
+                })
+        });
+        site.map(|site| site.handler.as_ref())
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        let site = request.host().and_then(|host| self.site(host));
+        match site.or(self.fallback.as_deref()) {
+            Some(handler) => handler.handle(request, params),
+            None => Response::error(Status::NOT_FOUND),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn site(name: &'static str) -> impl Handler {
+        move |_: &Request, _: &Params| Response::html(name)
+    }
+
+    fn serve(hosts: &VirtualHosts, raw: &str) -> (Status, String) {
+        let request = Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap();
+        let response = hosts.handle(&request, &Params::new());
+        (
+            response.status,
+            String::from_utf8_lossy(&response.body).into_owned(),
+        )
+    }
+
+    fn get(hosts: &VirtualHosts, host: &str) -> String {
+        serve(hosts, &format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n")).1
+    }
+
+    #[test]
+    fn picks_exact_names_then_the_longest_wildcard() {
+        let hosts = VirtualHosts::new()
+            .host("*.localhost", site("any"))
+            .host("aoc.localhost", site("aoc"))
+            .host("*.docs.localhost", site("docs"))
+            .host("[::1]", site("ipv6"))
+            .fallback(site("default"));
+
+        assert_eq!(get(&hosts, "aoc.localhost"), "aoc");
+        assert_eq!(get(&hosts, "AOC.Localhost.:7878"), "aoc");
+        assert_eq!(get(&hosts, "v2.docs.localhost"), "docs");
+        assert_eq!(get(&hosts, "docs.localhost"), "any");
+        assert_eq!(get(&hosts, "[::1]:7878"), "ipv6");
+        assert_eq!(get(&hosts, "localhost"), "default");
+        assert_eq!(get(&hosts, "example.test"), "default");
+        assert_eq!(serve(&hosts, "GET / HTTP/1.0\r\n\r\n").1, "default");
+    }
+
+    #[test]
+    fn unknown_hosts_are_404_without_a_default() {
+        let hosts = VirtualHosts::new().host("aoc.localhost", site("aoc"));
+        assert_eq!(
+            serve(&hosts, "GET / HTTP/1.1\r\nHost: other\r\n\r\n").0,
+            Status::NOT_FOUND
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid host name")]
+    fn rejects_misplaced_wildcards() {
+        VirtualHosts::new().host("aoc.*", site("aoc"));
+    }
+}
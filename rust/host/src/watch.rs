@@ -0,0 +1,398 @@
+//! Live reload for development: watch directories for changes and have the
+//! open pages reload themselves.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::handler::{Handler, Params};
+use crate::request::{Method, Request};
+use crate::response::{Response, Status};
+use crate::shutdown::ShutdownHandle;
+use crate::sse::{Event, EventStream};
+
+/// How often the watched directories are scanned.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Directories of build output and downloaded packages, which change often
+/// and are never served; they are not watched wherever they appear.
+const SKIPPED_DIRS: &[&str] = &["target", "node_modules"];
+
+/// Where pages listen for reloads.
+pub const RELOAD_PATH: &str = "/.host/reload";
+
+/// How long a reload stream waits for a change before checking its client is still there.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Added to HTML pages; reloads them when the server says so.
+const SCRIPT: &str = "<script>new EventSource(\"/.host/reload\")\
+                      .addEventListener(\"reload\", () => location.reload());</script>\n";
+
+/// Counts changes to the files under some directories, found by polling.
+///
+/// A change is any file or directory added, removed, or given a new size or
+/// modification time. Hidden entries such as `.git` are not watched, nor are
+/// `target` and `node_modules` directories.
+///
+/// Scanning stops once the watcher is [stopped](Watcher::stop), its
+/// [shutdown handle](Watcher::stop_on) is triggered, or it is dropped.
+pub struct Watcher {
+    changes: Mutex<u64>,
+    changed: Condvar,
+    callbacks: Mutex<Vec<Box<dyn Fn() + Send + Sync>>>,
+    stopped: AtomicBool,
+    shutdown: Mutex<Option<ShutdownHandle>>,
+}
+
+/// What a scan saw: each entry's size and modification time.
+type Snapshot = HashMap<PathBuf, (u64, Option<SystemTime>)>;
+
+impl Watcher {
+    /// Scan `dirs` every `interval` on a thread of its own.
+    pub fn start(dirs: Vec<PathBuf>, interval: Duration) -> io::Result<Arc<Watcher>> {
+        let mut last = Snapshot::new();
+        for dir in &dirs {
+            scan(dir, &mut last)?;
+        }
+        let watcher = Arc::new(Watcher {
+            changes: Mutex::new(0),
+            changed: Condvar::new(),
+            callbacks: Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+            shutdown: Mutex::new(None),
+        });
+        // weak, so that dropping the last handle to the watcher ends the thread too
+        let notify = Arc::downgrade(&watcher);
+        thread::Builder::new()
+            .name("host-watch".to_string())
+            .spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    let Some(notify) = notify.upgrade() else {
+                        return;
+                    };
+                    let shutting_down = notify
+                        .shutdown
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .is_some_and(ShutdownHandle::is_triggered);
+                    if shutting_down {
+                        notify.stop();
+                    }
+                    if notify.is_stopped() {
+                        return;
+                    }
+                    let mut now = Snapshot::new();
+                    for dir in &dirs {
+                        // a directory can vanish mid-scan; the next scan will tell
+                        let _ = scan(dir, &mut now);
+                    }
+                    if now != last {
+                        last = now;
+                        notify.notify();
+                    }
+                }
+            })?;
+        Ok(watcher)
+    }
+
+    /// Stop when `shutdown` is triggered, e.g. a server's, so that scanning
+    /// ends with it and open reload streams let the server drain.
+    pub fn stop_on(&self, shutdown: ShutdownHandle) {
+        *self.shutdown.lock().unwrap() = Some(shutdown);
+    }
+
+    /// Stop scanning, and wake everyone waiting for a change.
+    pub fn stop(&self) {
+        // under the lock, so a waiter cannot check the flag and then miss the wakeup
+        let _changes = self.changes.lock().unwrap();
+        self.stopped.store(true, Ordering::SeqCst);
+        self.changed.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Call `callback` on the watcher's thread after each change, before
+    /// anyone waiting hears of it.
+    pub fn on_change(&self, callback: impl Fn() + Send + Sync + 'static) {
+        self.callbacks.lock().unwrap().push(Box::new(callback));
+    }
+
+    /// Changes seen so far.
+    pub fn changes(&self) -> u64 {
+        *self.changes.lock().unwrap()
+    }
+
+    /// Wait until there have been more than `seen` changes, `timeout`
+    /// passes, or the watcher stops. Returns the count either way.
+    pub fn wait(&self, seen: u64, timeout: Duration) -> u64 {
+        let changes = self.changes.lock().unwrap();
+        let (changes, _) = self
+            .changed
+            .wait_timeout_while(changes, timeout, |changes| {
+                *changes <= seen && !self.is_stopped()
+            })
+            .unwrap();
+        *changes
+    }
+
+    fn notify(&self) {
+        // e.g. a file cache must forget old contents before pages reload
+        for callback in self.callbacks.lock().unwrap().iter() {
+            callback();
+        }
+        *self.changes.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+}
+
+/// Record everything under `dir` in `snapshot`, without following symlinked directories.
+fn scan(dir: &Path, snapshot: &mut Snapshot) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            if SKIPPED_DIRS.contains(&name.as_ref()) {
+                continue;
+            }
+            // only its entries count: its own time changes with editors' hidden swap files
+            snapshot.insert(entry.path(), (0, None));
+            let _ = scan(&entry.path(), snapshot);
+        } else {
+            snapshot.insert(entry.path(), (metadata.len(), metadata.modified().ok()));
+        }
+    }
+    Ok(())
+}
+
+/// Puts a handler in live-reload mode: HTML pages it serves get a script
+/// that listens on [`RELOAD_PATH`] and reloads the page after any change
+/// the watcher sees.
+pub struct LiveReload {
+    inner: Box<dyn Handler>,
+    watcher: Arc<Watcher>,
+}
+
+impl LiveReload {
+    pub fn new(inner: impl Handler, watcher: Arc<Watcher>) -> LiveReload {
+        LiveReload {
+            inner: Box::new(inner),
+            watcher,
+        }
+    }
+
+    fn events(&self, request: &Request) -> Response {
+        let watcher = Arc::clone(&self.watcher);
+        let seen = watcher.changes();
+        EventStream::new(request).respond(move |events| {
+            loop {
+                if watcher.wait(seen, PING_INTERVAL) > seen {
+                    let _ = events.send(Event::new("").event("reload"));
+                    return;
+                }
+                // the server is shutting down: end the stream rather than hold up the drain
+                if watcher.is_stopped() {
+                    return;
+                }
+                // fails once the page is gone, ending the stream
+                if events.send(Event::new("").event("ping")).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+}
+
+impl Handler for LiveReload {
+    fn handle(&self, request: &Request, params: &Params) -> Response {
+        if request.path() == RELOAD_PATH && matches!(request.method, Method::Get | Method::Head) {
+            return self.events(request);
+        }
+        let mut response = self.inner.handle(request, params);
+        let html = response
+            .headers
+            .get("Content-Type")
+            .is_some_and(|t| t.trim_start().to_ascii_lowercase().starts_with("text/html"));
+        // compressed or partial bodies cannot be edited
+        if html
+            && response.status == Status::OK
+            && response.stream.is_none()
+            && !response.headers.contains("Content-Encoding")
+        {
+            let mut page = Vec::from(std::mem::take(&mut response.body));
+            inject(&mut page);
+            response.body = page.into();
+            // ranges of the file on disk would not line up with this body, and
+            // its validators would let a cache mix the two up
+            response.headers.remove("Accept-Ranges");
+            response.headers.remove("ETag");
+            response.headers.remove("Last-Modified");
+            if response.headers.contains("Content-Length") {
+                response
+                    .headers
+                    .set("Content-Length", response.body.len().to_string());
+            }
+        }
+        response
+    }
+}
+
+/// Put the reload script before `</body>`, or at the end if there is none.
+fn inject(page: &mut Vec<u8>) {
+    let at = page
+        .windows(7)
+        .rposition(|window| window.eq_ignore_ascii_case(b"</body>"))
+        .unwrap_or(page.len());
+    page.splice(at..at, SCRIPT.bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Instant;
+
+    fn get(handler: &impl Handler, path: &str) -> Response {
+        let raw = format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n");
+        let request = Request::read_from(&mut raw.as_bytes(), &Limits::default())
+            .unwrap()
+            .unwrap();
+        handler.handle(&request, &Params::new())
+    }
+
+    #[test]
+    fn counts_changes_under_the_watched_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/page.html"), "one").unwrap();
+        let watcher =
+            Watcher::start(vec![dir.path().to_path_buf()], Duration::from_millis(10)).unwrap();
+        let calls = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&calls);
+        watcher.on_change(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        let wait = |seen| watcher.wait(seen, Duration::from_secs(5));
+        fs::write(dir.path().join("sub/page.html"), "two!").unwrap();
+        let seen = wait(0);
+        assert!(seen > 0);
+        assert!(calls.load(Ordering::Relaxed) >= seen);
+        // hidden files and build output are not watched
+        fs::write(dir.path().join("sub/.page.html.swp"), "x").unwrap();
+        fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+        fs::write(dir.path().join("target/debug/host"), "x").unwrap();
+        let start = Instant::now();
+        assert_eq!(watcher.wait(seen, Duration::from_millis(100)), seen);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        fs::remove_file(dir.path().join("sub/page.html")).unwrap();
+        assert!(wait(seen) > seen);
+
+        // stopping wakes waiters straight away
+        let shutdown = ShutdownHandle::new();
+        watcher.stop_on(shutdown.clone());
+        shutdown.trigger();
+        let start = Instant::now();
+        let seen = watcher.changes();
+        assert_eq!(watcher.wait(seen, Duration::from_secs(5)), seen);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(watcher.is_stopped());
+    }
+
+    #[test]
+    fn injects_the_script_into_html_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = Watcher::start(vec![dir.path().to_path_buf()], DEFAULT_INTERVAL).unwrap();
+        let site = LiveReload::new(
+            |request: &Request, _: &Params| match request.path() {
+                "/" => Response::html("<h1>hi</h1></BODY></html>")
+                    .with_header("ETag", "\"file\"")
+                    .with_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                "/plain" => Response::html("<p>no body tag"),
+                "/missing" => Response::error(Status::NOT_FOUND),
+                _ => Response::json("{}"),
+            },
+            watcher,
+        );
+
+        let body = |path| String::from_utf8(get(&site, path).body.to_vec()).unwrap();
+        assert_eq!(body("/"), format!("<h1>hi</h1>{SCRIPT}</BODY></html>"));
+        let page = get(&site, "/");
+        assert!(!page.headers.contains("ETag") && !page.headers.contains("Last-Modified"));
+        assert_eq!(body("/plain"), format!("<p>no body tag{SCRIPT}"));
+        assert_eq!(body("/api"), "{}");
+        assert!(!body("/missing").contains("script"));
+
+        let events = get(&site, RELOAD_PATH);
+        assert_eq!(
+            events.headers.get("Content-Type"),
+            Some("text/event-stream")
+        );
+    }
+
+    #[test]
+    fn open_reload_streams_do_not_use_up_the_workers() {
+        use crate::server::Server;
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpStream;
+
+        let dir = tempfile::tempdir().unwrap();
+        let watcher = Watcher::start(vec![dir.path().to_path_buf()], DEFAULT_INTERVAL).unwrap();
+        let site = LiveReload::new(
+            |_: &Request, _: &Params| Response::html("<p>hi"),
+            Arc::clone(&watcher),
+        );
+        let server = Server::bind("127.0.0.1:0", 2, site)
+            .unwrap()
+            .drain_timeout(Duration::from_secs(10));
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        watcher.stop_on(shutdown.clone());
+        let server = thread::spawn(move || server.run());
+
+        let open = |target: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            write!(stream, "GET {target} HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let mut status = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut status)
+                .unwrap();
+            (stream, status)
+        };
+        // one tab more than there are workers
+        let tabs: Vec<_> = (0..3).map(|_| open(RELOAD_PATH)).collect();
+        for (_, status) in &tabs {
+            assert!(status.starts_with("HTTP/1.1 200 "), "{status}");
+        }
+        let (_page, status) = open("/");
+        assert!(status.starts_with("HTTP/1.1 200 "), "{status}");
+
+        // the streams end on shutdown instead of running out the drain timeout
+        shutdown.trigger();
+        let summary = server.join().unwrap();
+        assert_eq!(summary.aborted, 0);
+        assert!(summary.elapsed < Duration::from_secs(5), "{summary}");
+    }
+}
@@ -0,0 +1,857 @@
+//! WebSocket connections (RFC 6455) on top of an upgraded HTTP/1.1 connection.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use sha1::{Digest, Sha1};
+
+use crate::handler::{Handler, Params};
+use crate::request::{Method, Request, Version};
+use crate::response::{Response, Status};
+
+/// Appended to `Sec-WebSocket-Key` before hashing (RFC 6455 §1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages larger than this, reassembled, close the connection with 1009.
+pub const DEFAULT_MAX_MESSAGE: usize = 1024 * 1024;
+
+/// An upgraded socket quiet for this long is pinged, and given up on when
+/// as long again passes without a frame from the peer.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A status code sent in a close frame (RFC 6455 §7.4).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    pub const NORMAL: CloseCode = CloseCode(1000);
+    pub const GOING_AWAY: CloseCode = CloseCode(1001);
+    pub const PROTOCOL_ERROR: CloseCode = CloseCode(1002);
+    pub const UNSUPPORTED_DATA: CloseCode = CloseCode(1003);
+    pub const INVALID_DATA: CloseCode = CloseCode(1007);
+    pub const POLICY_VIOLATION: CloseCode = CloseCode(1008);
+    pub const MESSAGE_TOO_BIG: CloseCode = CloseCode(1009);
+    pub const INTERNAL_ERROR: CloseCode = CloseCode(1011);
+
+    /// Whether a peer may send this code. 1005, 1006 and 1015 only exist
+    /// inside APIs, and the rest of 1000-2999 is reserved for the spec.
+    fn is_sendable(self) -> bool {
+        matches!(self.0, 1000..=1003 | 1007..=1011 | 3000..=4999)
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A complete, reassembled data message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Message {
+    fn opcode(&self) -> u8 {
+        match self {
+            Message::Text(_) => OP_TEXT,
+            Message::Binary(_) => OP_BINARY,
+        }
+    }
+
+    fn payload(&self) -> &[u8] {
+        match self {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(bytes) => bytes,
+        }
+    }
+}
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{}{GUID}", key.trim()).as_bytes());
+    BASE64.encode(digest)
+}
+
+/// Answer a WebSocket handshake: 101 with `on_open` taking over the
+/// connection, or an error response when `request` is not a valid handshake.
+///
+/// Subprotocols and extensions are not negotiated; clients asking for them
+/// get a plain connection, which the spec allows.
+pub fn upgrade<F>(request: &Request, on_open: F) -> Response
+where
+    F: FnOnce(WebSocket) + Send + 'static,
+{
+    if request.method != Method::Get {
+        return Response::error(Status::METHOD_NOT_ALLOWED).with_header("Allow", "GET");
+    }
+    let is_upgrade = request.headers.has_token("Upgrade", "websocket")
+        && request.headers.has_token("Connection", "upgrade");
+    if !is_upgrade || request.version != Version::Http11 {
+        return Response::error(Status::UPGRADE_REQUIRED)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+    }
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Response::error(Status::UPGRADE_REQUIRED)
+            .with_header("Sec-WebSocket-Version", "13");
+    }
+    // the key must be a base64-encoded 16-byte nonce
+    let Some(key) = request.header("Sec-WebSocket-Key").filter(|key| {
+        BASE64
+            .decode(key.trim())
+            .is_ok_and(|nonce| nonce.len() == 16)
+    }) else {
+        return Response::error(Status::BAD_REQUEST);
+    };
+
+    Response::switching_protocols("websocket", move |conn| {
+        let Ok(writer) = conn.stream().try_clone() else {
+            return;
+        };
+        // reads time out so that a peer which vanished without a FIN is noticed
+        let _ = conn.stream().set_read_timeout(Some(PING_INTERVAL));
+        on_open(WebSocket::new(conn, writer));
+    })
+    .with_header("Sec-WebSocket-Accept", accept_key(key))
+}
+
+/// A [`Handler`] that upgrades every request and runs `on_open` for each
+/// connection, e.g. `router.get("/ws/echo", websocket::handler(echo))`.
+pub fn handler<F>(on_open: F) -> impl Handler
+where
+    F: Fn(WebSocket, &Request) + Send + Sync + 'static,
+{
+    let on_open = Arc::new(on_open);
+    move |request: &Request, _: &Params| {
+        let on_open = Arc::clone(&on_open);
+        let owned = request.clone();
+        upgrade(request, move |ws| on_open(ws, &owned))
+    }
+}
+
+/// The server side of an open WebSocket.
+pub struct WebSocket {
+    reader: Box<dyn Read + Send>,
+    sender: Sender,
+    max_message: usize,
+    /// A ping went out after a read timed out, and nothing has come back yet.
+    pinged: bool,
+}
+
+impl WebSocket {
+    pub fn new(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> WebSocket {
+        WebSocket {
+            reader: Box::new(reader),
+            sender: Sender {
+                out: Arc::new(Mutex::new(Box::new(writer))),
+                closed: Arc::new(AtomicBool::new(false)),
+            },
+            max_message: DEFAULT_MAX_MESSAGE,
+            pinged: false,
+        }
+    }
+
+    /// Largest reassembled message accepted before closing with 1009.
+    pub fn max_message_size(mut self, bytes: usize) -> WebSocket {
+        self.max_message = bytes;
+        self
+    }
+
+    /// A handle for sending from other threads, e.g. to broadcast.
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        self.sender.send(message)
+    }
+
+    /// Start the closing handshake; keep calling `recv` until it returns `None`
+    /// to see the client's reply.
+    pub fn close(&self, code: CloseCode, reason: &str) -> io::Result<()> {
+        self.sender.close(code, reason)
+    }
+
+    /// Wait for the next message.
+    ///
+    /// Pings are answered and pongs skipped along the way. When a read times
+    /// out the client is pinged, and a second timeout in a row counts as it
+    /// going away. Returns `Ok(None)` once the connection is closed, by a
+    /// close frame or by the client going away. A protocol violation closes
+    /// the connection with the matching code and returns an `InvalidData` error.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        let mut partial: Option<(u8, Vec<u8>)> = None;
+        loop {
+            // waiting for a frame to start is where an idle peer times out
+            let mut first = [0; 1];
+            match self.reader.read(&mut first) {
+                Ok(0) => {
+                    let _ = self.sender.close(CloseCode::GOING_AWAY, "");
+                    return Ok(None);
+                }
+                Ok(_) => self.pinged = false,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if is_timeout(&e) && !self.pinged => {
+                    self.pinged = true;
+                    self.sender.write_frame(OP_PING, b"")?;
+                    continue;
+                }
+                Err(e) if is_timeout(&e) || is_disconnect(&e) => {
+                    let _ = self.sender.close(CloseCode::GOING_AWAY, "");
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+
+            let mut reader = first.as_slice().chain(&mut self.reader);
+            let frame = match read_frame(&mut reader, self.max_message) {
+                Ok(frame) => frame,
+                Err(FrameError::Io(e)) if is_disconnect(&e) => {
+                    // tell the client, if it is still there, why we are going
+                    let _ = self.sender.close(CloseCode::GOING_AWAY, "");
+                    return Ok(None);
+                }
+                Err(FrameError::Io(e)) => return Err(e),
+                Err(FrameError::Protocol(code, reason)) => return self.fail(code, reason),
+            };
+
+            let ends_message = frame.fin && !frame.is_control();
+            match frame.opcode {
+                OP_CLOSE => {
+                    let (code, reason) = match frame.payload.as_slice() {
+                        [] => (CloseCode::NORMAL, ""),
+                        [_] => return self.fail(CloseCode::PROTOCOL_ERROR, "truncated close code"),
+                        [hi, lo, reason @ ..] => {
+                            let code = CloseCode(u16::from_be_bytes([*hi, *lo]));
+                            let Ok(reason) = std::str::from_utf8(reason) else {
+                                return self
+                                    .fail(CloseCode::INVALID_DATA, "close reason is not UTF-8");
+                            };
+                            if !code.is_sendable() {
+                                return self.fail(CloseCode::PROTOCOL_ERROR, "invalid close code");
+                            }
+                            (code, reason)
+                        }
+                    };
+                    // echo the code back, unless this is the reply to our own close
+                    self.sender.close(code, reason)?;
+                    return Ok(None);
+                }
+                OP_PING => self.sender.write_frame(OP_PONG, &frame.payload)?,
+                OP_PONG => {}
+                OP_TEXT | OP_BINARY if partial.is_some() => {
+                    return self.fail(CloseCode::PROTOCOL_ERROR, "expected a continuation frame");
+                }
+                OP_TEXT | OP_BINARY => partial = Some((frame.opcode, frame.payload)),
+                OP_CONTINUATION => match &mut partial {
+                    None => {
+                        return self
+                            .fail(CloseCode::PROTOCOL_ERROR, "unexpected continuation frame");
+                    }
+                    Some((_, payload))
+                        if payload.len() + frame.payload.len() > self.max_message =>
+                    {
+                        return self.fail(CloseCode::MESSAGE_TOO_BIG, "message too big");
+                    }
+                    Some((_, payload)) => payload.extend_from_slice(&frame.payload),
+                },
+                _ => unreachable!("read_frame rejects unknown opcodes"),
+            }
+
+            if ends_message {
+                let (opcode, payload) = partial.take().expect("a data frame was just stored");
+                return match opcode {
+                    OP_TEXT => match String::from_utf8(payload) {
+                        Ok(text) => Ok(Some(Message::Text(text))),
+                        Err(_) => self.fail(CloseCode::INVALID_DATA, "text message is not UTF-8"),
+                    },
+                    _ => Ok(Some(Message::Binary(payload))),
+                };
+            }
+        }
+    }
+
+    fn fail<T>(&self, code: CloseCode, reason: &'static str) -> io::Result<T> {
+        let _ = self.sender.close(code, reason);
+        Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Sends frames on a WebSocket; cheap to clone and safe to share between threads.
+#[derive(Clone)]
+pub struct Sender {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Sender {
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "WebSocket is closed",
+            ));
+        }
+        self.write_frame(message.opcode(), message.payload())
+    }
+
+    /// Send a close frame, once; later calls do nothing.
+    pub fn close(&self, code: CloseCode, reason: &str) -> io::Result<()> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut payload = code.0.to_be_bytes().to_vec();
+        // control frames carry at most 125 bytes
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.write_frame(OP_CLOSE, &payload)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn write_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(&frame)?;
+        out.flush()
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn is_control(&self) -> bool {
+        self.opcode & 0x8 != 0
+    }
+}
+
+enum FrameError {
+    Io(io::Error),
+    Protocol(CloseCode, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> FrameError {
+        FrameError::Io(e)
+    }
+}
+
+/// Read and unmask one client frame (RFC 6455 §5.2).
+fn read_frame<R: Read + ?Sized>(reader: &mut R, max_payload: usize) -> Result<Frame, FrameError> {
+    use FrameError::Protocol;
+
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[0] & 0x70 != 0 {
+        return Err(Protocol(CloseCode::PROTOCOL_ERROR, "reserved bits set"));
+    }
+    if !matches!(
+        opcode,
+        OP_CONTINUATION | OP_TEXT | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG
+    ) {
+        return Err(Protocol(CloseCode::PROTOCOL_ERROR, "unknown opcode"));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(Protocol(
+            CloseCode::PROTOCOL_ERROR,
+            "client frames must be masked",
+        ));
+    }
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if opcode & 0x8 != 0 && (len > 125 || !fin) {
+        return Err(Protocol(CloseCode::PROTOCOL_ERROR, "invalid control frame"));
+    }
+    if len > max_payload as u64 {
+        return Err(Protocol(CloseCode::MESSAGE_TOO_BIG, "message too big"));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+/// A set of connected sockets that each receive every broadcast message.
+#[derive(Default)]
+pub struct Room {
+    members: Mutex<HashMap<u64, Sender>>,
+    next_id: AtomicU64,
+}
+
+impl Room {
+    pub fn new() -> Room {
+        Room::default()
+    }
+
+    /// Add a socket; returns the id to `leave` with.
+    pub fn join(&self, ws: &WebSocket) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.lock().insert(id, ws.sender());
+        id
+    }
+
+    pub fn leave(&self, id: u64) {
+        self.lock().remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send `message` to every member, dropping any that can no longer be
+    /// reached. Returns how many received it.
+    ///
+    /// The writes happen outside the lock, so a slow client holds up this
+    /// broadcast but not others, nor anyone joining or leaving.
+    pub fn broadcast(&self, message: &Message) -> usize {
+        let members: Vec<(u64, Sender)> = self
+            .lock()
+            .iter()
+            .map(|(id, sender)| (*id, sender.clone()))
+            .collect();
+        let failed: Vec<u64> = members
+            .iter()
+            .filter(|(_, sender)| sender.send(message).is_err())
+            .map(|(id, _)| *id)
+            .collect();
+        if !failed.is_empty() {
+            let mut members = self.lock();
+            for id in &failed {
+                members.remove(id);
+            }
+        }
+        members.len() - failed.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Sender>> {
+        self.members.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Send every message straight back.
+pub fn echo(mut ws: WebSocket, _request: &Request) {
+    while let Ok(Some(message)) = ws.recv() {
+        if ws.send(&message).is_err() {
+            break;
+        }
+    }
+}
+
+/// A handler where every message from any member goes to all members.
+pub fn room_handler(room: Arc<Room>) -> impl Handler {
+    handler(move |mut ws, _request| {
+        let id = room.join(&ws);
+        while let Ok(Some(message)) = ws.recv() {
+            room.broadcast(&message);
+        }
+        room.leave(id);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use crate::server::Server;
+    use std::{
+        io::{BufRead, BufReader},
+        net::{SocketAddr, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    /// A masked client frame.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// Run `input` through a socket and collect what the server sent back.
+    fn exchange(input: Vec<u8>) -> (Vec<io::Result<Option<Message>>>, Vec<u8>) {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let mut ws = WebSocket::new(io::Cursor::new(input), out.clone()).max_message_size(16);
+        let mut results = Vec::new();
+        loop {
+            let result = ws.recv();
+            let done = !matches!(result, Ok(Some(_)));
+            results.push(result);
+            if done {
+                break;
+            }
+        }
+        let sent = out.0.lock().unwrap().clone();
+        (results, sent)
+    }
+
+    fn close_code(sent: &[u8]) -> u16 {
+        let close = sent
+            .windows(2)
+            .rposition(|w| w[0] == 0x88)
+            .expect("no close frame sent");
+        u16::from_be_bytes([sent[close + 2], sent[close + 3]])
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // the example from RFC 6455 §1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn reassembles_fragments_and_answers_pings() {
+        let mut input = client_frame(false, OP_TEXT, b"hel");
+        input.extend(client_frame(true, OP_PING, b"p"));
+        input.extend(client_frame(true, OP_CONTINUATION, b"lo"));
+        input.extend(client_frame(true, OP_BINARY, &[1, 2]));
+        input.extend(client_frame(true, OP_CLOSE, &[0x03, 0xE8]));
+
+        let (results, sent) = exchange(input);
+        let messages: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            messages,
+            [
+                Some(Message::Text("hello".to_string())),
+                Some(Message::Binary(vec![1, 2])),
+                None
+            ]
+        );
+        // pong with the ping's payload, then the close echoed back
+        assert_eq!(sent, [0x8A, 1, b'p', 0x88, 2, 0x03, 0xE8]);
+    }
+
+    /// Reads that time out, as an idle socket's would, between bits of `input`.
+    struct Idle(Vec<Option<Vec<u8>>>);
+
+    impl Read for Idle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.first_mut() {
+                None => Ok(0),
+                Some(None) => {
+                    self.0.remove(0);
+                    Err(io::ErrorKind::WouldBlock.into())
+                }
+                Some(Some(bytes)) => {
+                    let n = bytes.as_slice().read(buf)?;
+                    bytes.drain(..n);
+                    if bytes.is_empty() {
+                        self.0.remove(0);
+                    }
+                    Ok(n)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn idle_peers_are_pinged_then_dropped() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        struct Out(Arc<Mutex<Vec<u8>>>);
+        impl Write for Out {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let input = Idle(vec![
+            None,
+            Some(client_frame(true, OP_PONG, b"")),
+            None,
+            Some(client_frame(true, OP_TEXT, b"still here")),
+            None,
+            None,
+        ]);
+        let mut ws = WebSocket::new(input, Out(Arc::clone(&out)));
+        assert_eq!(ws.recv().unwrap(), Some(Message::Text("still here".into())));
+        assert_eq!(ws.recv().unwrap(), None);
+        // a ping each time it went quiet, then 1001 when the second one went unanswered
+        assert_eq!(
+            *out.lock().unwrap(),
+            [0x89, 0, 0x89, 0, 0x89, 0, 0x88, 2, 0x03, 0xE9]
+        );
+    }
+
+    #[test]
+    fn a_slow_member_does_not_lock_the_room() {
+        struct Stuck(std::sync::mpsc::Receiver<()>);
+        impl Write for Stuck {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let _ = self.0.recv();
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let (unstick, stuck) = std::sync::mpsc::channel();
+        let room = Arc::new(Room::new());
+        room.join(&WebSocket::new(io::empty(), Stuck(stuck)));
+
+        let broadcasting = Arc::clone(&room);
+        let broadcast = thread::spawn(move || broadcasting.broadcast(&Message::Text("hi".into())));
+        thread::sleep(Duration::from_millis(50));
+        // joining and leaving go ahead while that write is stuck
+        let id = room.join(&WebSocket::new(io::empty(), io::sink()));
+        assert_eq!(room.len(), 2);
+        room.leave(id);
+        unstick.send(()).unwrap();
+        assert_eq!(broadcast.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn protocol_violations_close_with_a_code() {
+        let unmasked = vec![0x81, 0x01, b'x'];
+        let mut bad_utf8 = client_frame(true, OP_TEXT, &[0xFF]);
+        bad_utf8.extend(client_frame(true, OP_CLOSE, &[]));
+        let cases = [
+            (unmasked, 1002),
+            (client_frame(true, 0x3, b""), 1002),
+            (client_frame(true, OP_CONTINUATION, b"x"), 1002),
+            (client_frame(false, OP_PING, b""), 1002),
+            (client_frame(true, OP_BINARY, &[0; 17]), 1009),
+            (client_frame(true, OP_CLOSE, &[0x03, 0xED]), 1002),
+            (bad_utf8, 1007),
+        ];
+        for (input, code) in cases {
+            let (results, sent) = exchange(input);
+            let last = results.last().unwrap();
+            assert!(
+                matches!(last, Err(e) if e.kind() == io::ErrorKind::InvalidData),
+                "{code}: {last:?}"
+            );
+            assert_eq!(close_code(&sent), code);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_handshakes() {
+        let request = |headers: &str| {
+            let raw = format!("GET /ws HTTP/1.1\r\nHost: x\r\n{headers}\r\n");
+            Request::read_from(&mut raw.as_bytes(), &crate::request::Limits::default())
+                .unwrap()
+                .unwrap()
+        };
+        let status = |headers: &str| upgrade(&request(headers), |_| {}).status;
+        let upgrade_headers = "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n";
+
+        assert_eq!(status(""), Status::UPGRADE_REQUIRED);
+        assert_eq!(
+            status(&format!("{upgrade_headers}Sec-WebSocket-Version: 8\r\n")),
+            Status::UPGRADE_REQUIRED
+        );
+        assert_eq!(
+            status(&format!(
+                "{upgrade_headers}Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n"
+            )),
+            Status::BAD_REQUEST
+        );
+        let response = upgrade(
+            &request(&format!(
+                "{upgrade_headers}Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"
+            )),
+            |_| {},
+        );
+        assert_eq!(response.status, Status::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            response.headers.get("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+    }
+
+    /// Handshake on `path`, sending `early` in the same write.
+    fn open(addr: SocketAddr, path: &str, early: &[u8]) -> (TcpStream, BufReader<TcpStream>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut handshake = format!(
+            "GET {path} HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        )
+        .into_bytes();
+        handshake.extend_from_slice(early);
+        stream.write_all(&handshake).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols\r\n");
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+        }
+        (stream, reader)
+    }
+
+    /// Read one unfragmented server frame.
+    fn read_server_frame(reader: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0, "server frames are not masked");
+        let mut payload = vec![0; usize::from(head[1] & 0x7F)];
+        reader.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0F, payload)
+    }
+
+    #[test]
+    fn echo_and_broadcast_over_tcp() {
+        let room = Arc::new(Room::new());
+        let router = Router::new()
+            .get("/ws/echo", handler(echo))
+            .get("/ws/room", room_handler(Arc::clone(&room)));
+        let server = Server::bind("127.0.0.1:0", 4, router)
+            .unwrap()
+            .drain_timeout(Duration::from_secs(2));
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        // pipelined right behind the handshake, so it arrives already buffered
+        let (mut stream, mut reader) =
+            open(addr, "/ws/echo", &client_frame(true, OP_TEXT, b"ping?"));
+        assert_eq!(read_server_frame(&mut reader), (OP_TEXT, b"ping?".to_vec()));
+        stream
+            .write_all(&client_frame(true, OP_CLOSE, &[0x03, 0xE8]))
+            .unwrap();
+        assert_eq!(read_server_frame(&mut reader), (OP_CLOSE, vec![0x03, 0xE8]));
+
+        let (mut alice, mut alice_reader) = open(addr, "/ws/room", &[]);
+        let (_bob, mut bob_reader) = open(addr, "/ws/room", &[]);
+        while room.len() < 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        alice
+            .write_all(&client_frame(true, OP_TEXT, b"hi all"))
+            .unwrap();
+        assert_eq!(read_server_frame(&mut alice_reader).1, b"hi all");
+        assert_eq!(read_server_frame(&mut bob_reader).1, b"hi all");
+        assert_eq!(room.broadcast(&Message::Text("from the server".into())), 2);
+        assert_eq!(read_server_frame(&mut bob_reader).1, b"from the server");
+
+        // shutdown closes open sockets with 1001 rather than waiting them out
+        shutdown.trigger();
+        assert_eq!(
+            read_server_frame(&mut bob_reader),
+            (OP_CLOSE, vec![0x03, 0xE9])
+        );
+        let summary = server.join().unwrap();
+        assert_eq!(summary.aborted, 0);
+    }
+}